@@ -0,0 +1,53 @@
+#ifndef BITCASK_H
+#define BITCASK_H
+
+/*
+ * C interface of bitcask-engine-rs.
+ *
+ * Functions returning int use BITCASK_OK on success, BITCASK_NOT_FOUND when
+ * the key does not exist and BITCASK_ERROR on failure. A panic inside the
+ * library is caught and reported as BITCASK_ERROR (or NULL).
+ */
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define BITCASK_OK 0
+#define BITCASK_NOT_FOUND 1
+#define BITCASK_ERROR (-1)
+
+/* Opaque store handle. */
+typedef struct bitcask bitcask_t;
+
+/* Opens (or creates) the store in `path`. Returns NULL on failure. */
+bitcask_t *bitcask_open(const char *path);
+
+/* Reads the value of `key`. On BITCASK_OK, `*value` must be released with
+ * bitcask_free_value(*value, *value_len). IO errors and corrupted records
+ * return BITCASK_ERROR rather than BITCASK_NOT_FOUND. */
+int bitcask_get(bitcask_t *db, const uint8_t *key, size_t key_len,
+                uint8_t **value, size_t *value_len);
+
+/* Inserts or overwrites `key`. */
+int bitcask_put(bitcask_t *db, const uint8_t *key, size_t key_len,
+                const uint8_t *value, size_t value_len);
+
+/* Deletes `key`. */
+int bitcask_delete(bitcask_t *db, const uint8_t *key, size_t key_len);
+
+/* Releases a value returned by bitcask_get. */
+void bitcask_free_value(uint8_t *value, size_t value_len);
+
+/* Closes the store cleanly (syncs writes, saves the keydir and writes the
+ * clean-shutdown marker) and releases the handle, even when closing fails. */
+int bitcask_close(bitcask_t *db);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* BITCASK_H */
@@ -0,0 +1,112 @@
+//! `pybitcask` 是基于pyo3的Python绑定，提供打开、读写、删除、范围扫描以及压缩接口。
+//!
+//! 所有涉及磁盘IO的调用都会释放GIL，因此多个Python线程可以并发访问同一个存储。
+
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage, Key, PutOption};
+use bitcask_engine_rs::error::BitCaskError;
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::ops::Bound as KeyBound;
+use std::path::PathBuf;
+
+create_exception!(pybitcask, Error, PyException, "BitCask存储引擎返回的错误。");
+
+/// 将存储引擎的错误转换为Python异常。
+fn to_py_err(e: BitCaskError) -> PyErr {
+    Error::new_err(e.to_string())
+}
+
+/// Python中的`pybitcask.BitCask`对象，内部持有一个可以廉价克隆的`BitCask`句柄。
+#[pyclass(name = "BitCask", module = "pybitcask")]
+struct PyBitCask {
+    inner: BitCask,
+}
+
+#[pymethods]
+impl PyBitCask {
+    /// 打开（或创建）位于`data_dir`的存储。
+    #[new]
+    fn new(py: Python<'_>, data_dir: PathBuf) -> PyResult<Self> {
+        let inner = py.allow_threads(|| BitCask::new(data_dir)).map_err(to_py_err)?;
+        Ok(Self { inner })
+    }
+
+    /// 根据键读取值，键不存在时返回`None`。
+    fn get(&self, py: Python<'_>, key: &[u8]) -> Option<Py<PyBytes>> {
+        let key: Key = key.to_vec();
+        let value = py.allow_threads(|| self.inner.get(&key));
+        value.map(|value| PyBytes::new(py, &value).unbind())
+    }
+
+    /// 写入一个键值对。`nx=True`时仅在键不存在时写入，`xx=True`时仅在键存在时写入。
+    #[pyo3(signature = (key, value, nx = false, xx = false))]
+    fn put(&self, py: Python<'_>, key: &[u8], value: &[u8], nx: bool, xx: bool) -> PyResult<()> {
+        let option = match (nx, xx) {
+            (true, _) => PutOption::nx(),
+            (_, true) => PutOption::xx(),
+            _ => PutOption::none(),
+        };
+        let (key, value) = (key.to_vec(), value.to_vec());
+        let mut inner = self.inner.clone();
+        py.allow_threads(move || inner.put_with_option(&key, &value, option))
+            .map_err(to_py_err)
+    }
+
+    /// 删除一个键。
+    fn delete(&self, py: Python<'_>, key: &[u8]) -> PyResult<()> {
+        let key = key.to_vec();
+        let mut inner = self.inner.clone();
+        py.allow_threads(move || inner.delete(&key)).map_err(to_py_err)
+    }
+
+    /// 按键的顺序返回`[start, end)`范围内的`(key, value)`列表，省略的边界表示不限制。
+    #[pyo3(signature = (start = None, end = None))]
+    fn scan(
+        &self,
+        py: Python<'_>,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> PyResult<Vec<(Py<PyBytes>, Py<PyBytes>)>> {
+        let start = start.map_or(KeyBound::Unbounded, |start| KeyBound::Included(start.to_vec()));
+        let end = end.map_or(KeyBound::Unbounded, |end| KeyBound::Excluded(end.to_vec()));
+        let pairs = py
+            .allow_threads(|| self.inner.scan((start, end)))
+            .map_err(to_py_err)?;
+        Ok(pairs
+            .into_iter()
+            .map(|(key, value)| {
+                (
+                    PyBytes::new(py, &key).unbind(),
+                    PyBytes::new(py, &value).unbind(),
+                )
+            })
+            .collect())
+    }
+
+    /// 将存储压缩到新的目录中，完成后当前对象自动切换到新目录。
+    fn compact_to_new_dir(&self, py: Python<'_>, data_dir: PathBuf) -> PyResult<()> {
+        py.allow_threads(|| self.inner.compact_to_new_dir(data_dir))
+            .map_err(to_py_err)
+    }
+
+    /// 返回存储中键的数量。
+    fn __len__(&self) -> usize {
+        self.inner.size()
+    }
+}
+
+/// 打开（或创建）位于`data_dir`的存储，等价于`BitCask(data_dir)`。
+#[pyfunction]
+fn open(py: Python<'_>, data_dir: PathBuf) -> PyResult<PyBitCask> {
+    PyBitCask::new(py, data_dir)
+}
+
+#[pymodule]
+fn pybitcask(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyBitCask>()?;
+    m.add_function(wrap_pyfunction!(open, m)?)?;
+    m.add("Error", m.py().get_type::<Error>())?;
+    Ok(())
+}
@@ -0,0 +1,307 @@
+use crate::bitcask::{BitCask, KVStorage, Key, PutOption, Value};
+use crate::error::BitCaskError;
+use crate::options::BitCaskOptions;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+/// 发送给存储所在线程的命令。
+enum Command {
+    /// 在存储所在的线程上执行的操作，结果通过操作内部的`Responder`返回。
+    Call(Box<dyn FnOnce(&mut BitCask) + Send>),
+    /// 关闭存储并结束线程。
+    Close(Responder<()>),
+}
+
+/// `BitCaskActor` 在一个专门的线程中持有存储，其他线程通过`Handle`以消息传递的方式使用它。
+pub struct BitCaskActor;
+
+impl BitCaskActor {
+    /// 打开存储并启动持有它的线程，返回用于发送命令的句柄。
+    ///
+    /// 存储在调用方的线程中打开，打开失败时直接返回错误。所有句柄都被丢弃或者调用`Handle::close`之后线程结束。
+    /// 设置了`BitCaskOptions::op_timeout`时还会启动一个计时线程，让超时的结果以`BitCaskError::Timeout`完成。
+    pub fn spawn<T: Into<PathBuf>>(data_dir: T, options: BitCaskOptions) -> Result<Handle, BitCaskError> {
+        let op_timeout = options.op_timeout;
+        let mut bitcask = BitCask::new_with_options(data_dir, options)?;
+        let (sender, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("bitcask-actor".to_string())
+            .spawn(move || {
+                for command in receiver {
+                    match command {
+                        Command::Call(f) => f(&mut bitcask),
+                        Command::Close(responder) => {
+                            responder.send(bitcask.close());
+                            return;
+                        }
+                    }
+                }
+            })?;
+        let timer = match op_timeout {
+            Some(timeout) => {
+                let (timer, deadlines) = mpsc::channel();
+                std::thread::Builder::new()
+                    .name("bitcask-actor-timer".to_string())
+                    .spawn(move || timer_loop(deadlines))?;
+                Some((timeout, timer))
+            }
+            None => None,
+        };
+        Ok(Handle { sender, timer })
+    }
+}
+
+/// `Handle` 是`BitCaskActor::spawn`返回的句柄，克隆的代价只是复制一个通道的发送端。
+///
+/// 每个方法把命令发送给持有存储的线程并立即返回`Reply`，可以通过`Reply::wait`阻塞等待结果，
+/// 也可以在异步运行时中直接`await`，不会阻塞运行时的线程。存储只在一个线程中使用，调用方不会遇到锁中毒；
+/// 线程已经结束（被关闭，或者某个操作panic）时结果是`BitCaskError::ActorStopped`。
+#[derive(Clone)]
+pub struct Handle {
+    sender: Sender<Command>,
+    /// `BitCaskOptions::op_timeout`以及向计时线程登记期限的通道，没有设置超时时为`None`。
+    timer: Option<(Duration, Sender<Deadline>)>,
+}
+
+impl Handle {
+    /// 在持有存储的线程上执行任意操作，命令按发送的顺序执行。
+    pub fn call<R, F>(&self, f: F) -> Reply<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut BitCask) -> Result<R, BitCaskError> + Send + 'static,
+    {
+        let (responder, reply) = reply_pair();
+        // 线程已经结束时命令连同responder一起被丢弃，reply随即完成为ActorStopped
+        let _ = self.sender.send(Command::Call(Box::new(move |bitcask| responder.send(f(bitcask)))));
+        reply
+    }
+
+    /// 与`call`相同，但设置了`BitCaskOptions::op_timeout`时，结果在超时之后以`BitCaskError::Timeout`完成。
+    fn call_with_timeout<R, F>(&self, f: F) -> Reply<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut BitCask) -> Result<R, BitCaskError> + Send + 'static,
+    {
+        let reply = self.call(f);
+        if let Some((timeout, timer)) = &self.timer {
+            let shared: Arc<dyn Expire> = reply.shared.clone();
+            let _ = timer.send(Deadline {
+                at: Instant::now() + *timeout,
+                reply: Arc::downgrade(&shared),
+            });
+        }
+        reply
+    }
+
+    /// 读取键的值，见`BitCask::get_with_meta`。受`BitCaskOptions::op_timeout`限制。
+    pub fn get(&self, key: Key) -> Reply<Option<Value>> {
+        self.call_with_timeout(move |bitcask| Ok(bitcask.get_with_meta(&key)?.map(|meta| meta.value)))
+    }
+
+    /// 写入键值对，见`KVStorage::put_with_option`。受`BitCaskOptions::op_timeout`限制，
+    /// 超时的写入仍然可能在之后完成。
+    pub fn put(&self, key: Key, value: Value, option: Option<PutOption>) -> Reply<()> {
+        self.call_with_timeout(move |bitcask| bitcask.put_with_option(&key, &value, option))
+    }
+
+    /// 删除键，见`KVStorage::delete`。与`put`相同，受`BitCaskOptions::op_timeout`限制。
+    pub fn delete(&self, key: Key) -> Reply<()> {
+        self.call_with_timeout(move |bitcask| bitcask.delete(&key))
+    }
+
+    /// 将目前为止的所有写入持久化到磁盘，见`BitCask::sync`。
+    pub fn sync(&self) -> Reply<()> {
+        self.call(|bitcask| bitcask.sync())
+    }
+
+    /// 在之前发送的命令执行完之后关闭存储并结束线程，见`BitCask::close`。之后通过任何句柄发送的命令都以
+    /// `BitCaskError::ActorStopped`失败。
+    pub fn close(&self) -> Reply<()> {
+        let (responder, reply) = reply_pair();
+        let _ = self.sender.send(Command::Close(responder));
+        reply
+    }
+}
+
+/// 一次命令的结果所在的位置。
+struct Slot<T> {
+    result: Option<Result<T, BitCaskError>>,
+    waker: Option<Waker>,
+    /// 结果是否已经确定。超时之后命令本身的结果被丢弃。
+    done: bool,
+}
+
+/// `Reply`和`Responder`共享的状态。
+struct Shared<T> {
+    slot: Mutex<Slot<T>>,
+    ready: Condvar,
+}
+
+impl<T> Shared<T> {
+    /// 获取结果的锁。持有锁时不会panic，因此忽略中毒。
+    fn lock(&self) -> MutexGuard<'_, Slot<T>> {
+        self.slot.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// 确定结果并唤醒等待的调用方，结果已经确定时忽略。
+    fn complete(&self, result: Result<T, BitCaskError>) {
+        let waker = {
+            let mut slot = self.lock();
+            if slot.done {
+                return;
+            }
+            slot.done = true;
+            slot.result = Some(result);
+            slot.waker.take()
+        };
+        self.ready.notify_all();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// 计时线程通过它让超时的结果完成，而不必知道结果的类型。
+trait Expire: Send + Sync {
+    /// 结果尚未确定时以`BitCaskError::Timeout`完成。
+    fn expire(&self);
+}
+
+impl<T: Send> Expire for Shared<T> {
+    fn expire(&self) {
+        self.complete(Err(BitCaskError::Timeout));
+    }
+}
+
+/// 创建一对结果的发送端和接收端。
+fn reply_pair<T>() -> (Responder<T>, Reply<T>) {
+    let shared = Arc::new(Shared {
+        slot: Mutex::new(Slot {
+            result: None,
+            waker: None,
+            done: false,
+        }),
+        ready: Condvar::new(),
+    });
+    (Responder { shared: Some(shared.clone()) }, Reply { shared })
+}
+
+/// 结果的发送端，没有发送结果就被丢弃时（线程已经结束或者操作panic）结果为`BitCaskError::ActorStopped`。
+struct Responder<T> {
+    shared: Option<Arc<Shared<T>>>,
+}
+
+impl<T> Responder<T> {
+    fn send(mut self, result: Result<T, BitCaskError>) {
+        self.complete(result);
+    }
+
+    fn complete(&mut self, result: Result<T, BitCaskError>) {
+        if let Some(shared) = self.shared.take() {
+            shared.complete(result);
+        }
+    }
+}
+
+impl<T> Drop for Responder<T> {
+    fn drop(&mut self) {
+        self.complete(Err(BitCaskError::ActorStopped));
+    }
+}
+
+/// `Reply` 是通过`Handle`发送的命令的结果，可以阻塞等待，也可以作为`Future`等待。
+pub struct Reply<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Reply<T> {
+    /// 阻塞当前线程直到命令执行完毕。
+    pub fn wait(self) -> Result<T, BitCaskError> {
+        let mut slot = self.shared.lock();
+        loop {
+            if let Some(result) = slot.result.take() {
+                return result;
+            }
+            slot = self.shared.ready.wait(slot).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+impl<T> Future for Reply<T> {
+    type Output = Result<T, BitCaskError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.shared.lock();
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// 登记在计时线程中的一个结果的期限。
+struct Deadline {
+    at: Instant,
+    /// 结果被丢弃之后不再需要让它超时。
+    reply: Weak<dyn Expire>,
+}
+
+impl PartialEq for Deadline {
+    fn eq(&self, other: &Self) -> bool {
+        self.at == other.at
+    }
+}
+
+impl Eq for Deadline {}
+
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Deadline {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.at.cmp(&other.at)
+    }
+}
+
+/// 计时线程：让到期的结果以`BitCaskError::Timeout`完成，所有句柄都被丢弃并且没有未到期的结果之后退出。
+fn timer_loop(receiver: Receiver<Deadline>) {
+    let mut deadlines = BinaryHeap::new();
+    let mut connected = true;
+    loop {
+        let now = Instant::now();
+        while deadlines.peek().is_some_and(|Reverse(deadline): &Reverse<Deadline>| deadline.at <= now) {
+            let Reverse(deadline) = deadlines.pop().unwrap();
+            if let Some(reply) = deadline.reply.upgrade() {
+                reply.expire();
+            }
+        }
+        let next = deadlines.peek().map(|Reverse(deadline)| deadline.at.saturating_duration_since(now));
+        let received = match (next, connected) {
+            (Some(timeout), true) => receiver.recv_timeout(timeout),
+            (None, true) => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            (Some(timeout), false) => {
+                std::thread::sleep(timeout);
+                continue;
+            }
+            (None, false) => return,
+        };
+        match received {
+            Ok(deadline) => deadlines.push(Reverse(deadline)),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => connected = false,
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use crate::clock::now_millis;
+use crate::error::BitCaskError;
+use crc::{Crc, CRC_64_ECMA_182};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use tracing::error;
+
+const CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_ECMA_182);
+
+/// 当前审计文件的文件名，轮转后的文件依次命名为`audit.log.1`、`audit.log.2`……，数字越大越旧。
+pub const AUDIT_FILE: &str = "audit.log";
+
+/// `AuditOptions` 结构体配置审计日志，见`BitCaskOptions::audit`。
+///
+/// 每次操作都会向审计目录中的`audit.log`追加一行JSON，包含时间（Unix毫秒时间戳）、
+/// 调用方通过`BitCask::with_client_id`提供的客户端ID、操作名、键的CRC64哈希（不记录键本身）、
+/// 值的字节数以及操作是否成功，例如：
+/// `{"ts":1700000000000,"client":"billing","op":"put","key_hash":"9f0c...","size":42,"ok":true}`。
+#[derive(Debug, Clone)]
+pub struct AuditOptions {
+    /// 审计文件所在的目录，不存在时会被创建，应该与数据目录分开。
+    pub dir: PathBuf,
+    /// 当前审计文件超过该字节数后轮转。
+    pub max_file_size: u64,
+    /// 保留的轮转后的审计文件数量，更旧的文件会被删除。
+    pub max_files: usize,
+    /// 是否同时审计读取（`get`、`get_with_meta`和`get_ref`），默认只审计写入和删除。
+    pub audit_reads: bool,
+}
+
+impl AuditOptions {
+    /// 返回把审计文件写入`dir`的默认配置：每个文件64MB，保留10个轮转后的文件，不审计读取。
+    pub fn new<T: Into<PathBuf>>(dir: T) -> Self {
+        Self {
+            dir: dir.into(),
+            max_file_size: 64 * 1024 * 1024, // 64MB
+            max_files: 10,
+            audit_reads: false,
+        }
+    }
+}
+
+/// `AuditLog` 把审计记录追加到审计文件中，由同一个存储的所有`BitCask`句柄共享。
+pub(crate) struct AuditLog {
+    options: AuditOptions,
+    /// 当前的审计文件及其大小，写入和轮转都在锁内进行。
+    file: Mutex<(File, u64)>,
+}
+
+impl AuditLog {
+    /// 打开（或创建）审计目录中的当前审计文件。
+    pub(crate) fn open(options: AuditOptions) -> Result<Self, BitCaskError> {
+        std::fs::create_dir_all(&options.dir)?;
+        let file = open_append(&options.dir.join(AUDIT_FILE))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            options,
+            file: Mutex::new((file, size)),
+        })
+    }
+
+    /// 追加一条审计记录，读取操作只在开启`audit_reads`时记录。
+    ///
+    /// 写入审计文件失败只会打印错误，不会让被审计的操作失败。
+    pub(crate) fn record(&self, client_id: Option<&str>, op: &str, key: &[u8], size: u64, ok: bool, read: bool) {
+        if read && !self.options.audit_reads {
+            return;
+        }
+        let client = match client_id {
+            Some(client_id) => format!("\"{}\"", escape_json(client_id)),
+            None => "null".to_string(),
+        };
+        let line = format!(
+            "{{\"ts\":{},\"client\":{},\"op\":\"{}\",\"key_hash\":\"{:016x}\",\"size\":{},\"ok\":{}}}\n",
+            now_millis(),
+            client,
+            op,
+            CRC64.checksum(key),
+            size,
+            ok
+        );
+        if let Err(e) = self.append(line.as_bytes()) {
+            error!("Error while writing audit record: {:?}", e);
+        }
+    }
+
+    /// 把一行追加到当前审计文件，超过大小限制时先轮转。
+    fn append(&self, line: &[u8]) -> Result<(), BitCaskError> {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        if file.1 > 0 && file.1 + line.len() as u64 > self.options.max_file_size {
+            self.rotate()?;
+            *file = (open_append(&self.options.dir.join(AUDIT_FILE))?, 0);
+        }
+        file.0.write_all(line)?;
+        file.1 += line.len() as u64;
+        Ok(())
+    }
+
+    /// 把`audit.log.N`依次重命名为`audit.log.N+1`，再把当前文件重命名为`audit.log.1`，
+    /// 超出`max_files`的最旧文件被删除。
+    fn rotate(&self) -> Result<(), BitCaskError> {
+        let rotated = |index: usize| self.options.dir.join(format!("{}.{}", AUDIT_FILE, index));
+        let oldest = rotated(self.options.max_files);
+        if oldest.exists() || self.options.max_files == 0 {
+            let _ = std::fs::remove_file(&oldest);
+        }
+        for index in (1..self.options.max_files).rev() {
+            if rotated(index).exists() {
+                std::fs::rename(rotated(index), rotated(index + 1))?;
+            }
+        }
+        let current = self.options.dir.join(AUDIT_FILE);
+        match self.options.max_files {
+            0 => std::fs::remove_file(current)?,
+            _ => std::fs::rename(current, rotated(1))?,
+        }
+        Ok(())
+    }
+}
+
+/// 以追加模式打开文件，不存在时创建。
+fn open_append(path: &Path) -> Result<File, BitCaskError> {
+    Ok(OpenOptions::new().create(true).append(true).open(path)?)
+}
+
+/// 转义JSON字符串中的引号、反斜杠和控制字符。
+fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
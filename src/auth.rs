@@ -0,0 +1,148 @@
+use crate::error::BitCaskError;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// `Permission` 是一个凭据允许的操作种类。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// 只能读取。
+    ReadOnly,
+    /// 可以读取、写入和删除。
+    ReadWrite,
+}
+
+/// `Grant` 是一个凭据的权限：允许的操作，以及可以访问的键的前缀。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub permission: Permission,
+    /// 只能访问以该前缀开头的键，为空时可以访问所有键。
+    pub key_prefix: Vec<u8>,
+}
+
+impl Grant {
+    /// 返回可以读取所有键的权限。
+    pub fn read_only() -> Self {
+        Self {
+            permission: Permission::ReadOnly,
+            key_prefix: Vec::new(),
+        }
+    }
+
+    /// 返回可以读写所有键的权限。
+    pub fn read_write() -> Self {
+        Self {
+            permission: Permission::ReadWrite,
+            key_prefix: Vec::new(),
+        }
+    }
+
+    /// 把权限限制在以`prefix`开头的键上。
+    pub fn with_prefix(mut self, prefix: &[u8]) -> Self {
+        self.key_prefix = prefix.to_vec();
+        self
+    }
+
+    /// 是否允许读取给定的键。
+    pub fn can_read(&self, key: &[u8]) -> bool {
+        key.starts_with(&self.key_prefix)
+    }
+
+    /// 是否允许写入或删除给定的键。
+    pub fn can_write(&self, key: &[u8]) -> bool {
+        self.permission == Permission::ReadWrite && self.can_read(key)
+    }
+}
+
+/// `AccessControl` 保存网络前端接受的凭据：静态令牌，或者用户名和密码，每个凭据对应一个`Grant`。
+///
+/// 可以通过`add_token`和`add_user`构造，也可以通过`load`从文件读取。
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    tokens: HashMap<String, Grant>,
+    users: HashMap<String, (String, Grant)>,
+}
+
+impl AccessControl {
+    /// 添加一个静态令牌，已有的同名令牌会被替换。
+    pub fn add_token(&mut self, token: &str, grant: Grant) {
+        self.tokens.insert(token.to_string(), grant);
+    }
+
+    /// 添加一个用户，已有的同名用户会被替换。
+    pub fn add_user(&mut self, user: &str, password: &str, grant: Grant) {
+        self.users.insert(user.to_string(), (password.to_string(), grant));
+    }
+
+    /// 根据令牌查找权限，令牌无效时返回`None`。
+    ///
+    /// 不使用哈希表查找，而是以恒定时间逐个比较所有令牌且不提前结束，
+    /// 耗时与给定的令牌匹配到哪一个、匹配了多少字节无关。
+    pub fn authenticate_token(&self, token: &str) -> Option<&Grant> {
+        self.tokens.iter().fold(None, |found, (expected, grant)| {
+            match constant_time_eq(expected.as_bytes(), token.as_bytes()) {
+                true => Some(grant),
+                false => found,
+            }
+        })
+    }
+
+    /// 根据用户名和密码查找权限，用户不存在或密码错误时返回`None`。
+    pub fn authenticate_user(&self, user: &str, password: &str) -> Option<&Grant> {
+        let (expected, grant) = self.users.get(user)?;
+        constant_time_eq(expected.as_bytes(), password.as_bytes()).then_some(grant)
+    }
+
+    /// 从文件中读取凭据，每行一个：
+    ///
+    /// ```text
+    /// token <令牌> <ro|rw> [键前缀]
+    /// user <用户名> <密码> <ro|rw> [键前缀]
+    /// ```
+    ///
+    /// 空行和以`#`开头的行会被忽略；无法解析的行以`BitCaskError::CorruptedData`返回，并指出行号。
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, BitCaskError> {
+        let mut access = Self::default();
+        for (index, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match fields[..] {
+                ["token", token, permission, ref prefix @ ..] => {
+                    parse_grant(permission, prefix).map(|grant| access.add_token(token, grant))
+                }
+                ["user", user, password, permission, ref prefix @ ..] => {
+                    parse_grant(permission, prefix).map(|grant| access.add_user(user, password, grant))
+                }
+                _ => None,
+            };
+            if parsed.is_none() {
+                return Err(BitCaskError::CorruptedData(format!(
+                    "invalid credential on line {}",
+                    index + 1
+                )));
+            }
+        }
+        Ok(access)
+    }
+}
+
+/// 解析凭据文件中的权限和可选的键前缀。
+fn parse_grant(permission: &str, prefix: &[&str]) -> Option<Grant> {
+    let grant = match permission {
+        "ro" => Grant::read_only(),
+        "rw" => Grant::read_write(),
+        _ => return None,
+    };
+    match prefix {
+        [] => Some(grant),
+        [prefix] => Some(grant.with_prefix(prefix.as_bytes())),
+        _ => None,
+    }
+}
+
+/// 比较两个字节串，耗时只与长度有关，避免通过响应时间逐字节猜测密码。
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
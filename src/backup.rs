@@ -0,0 +1,131 @@
+use crate::error::BitCaskError;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// 增量备份的清单文件名，记录上一次备份包含的数据文件及其大小。
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// `BackupReport` 描述一次增量备份实际做了哪些工作。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackupReport {
+    /// 本次复制的数据文件数量。
+    pub copied_files: usize,
+    /// 与上一次备份相同而被跳过的数据文件数量。
+    pub skipped_files: usize,
+    /// 本次复制的字节数。
+    pub copied_bytes: u64,
+}
+
+/// `Manifest` 是清单文件在内存中的表示。
+///
+/// 清单的第一行是`source <数据目录>`，之后每一行是`<文件名> <字节数>`。
+/// 压缩会把存储切换到新的数据目录并重新分配文件ID，因此数据目录不同的清单会被整体视为失效。
+#[derive(Debug, Default)]
+struct Manifest {
+    source: PathBuf,
+    files: HashMap<String, u64>,
+}
+
+impl Manifest {
+    /// 从备份目录中读取清单，不存在或无法解析时返回一个空清单，也就是进行全量备份。
+    fn load(backup_dir: &Path) -> Self {
+        let content = match std::fs::read_to_string(backup_dir.join(MANIFEST_FILE)) {
+            Ok(content) => content,
+            Err(_) => return Self::default(),
+        };
+        let mut lines = content.lines();
+        let source = match lines.next().and_then(|line| line.strip_prefix("source ")) {
+            Some(source) => PathBuf::from(source),
+            None => return Self::default(),
+        };
+        let files = lines
+            .filter_map(|line| {
+                let (name, size) = line.rsplit_once(' ')?;
+                Some((name.to_string(), size.parse().ok()?))
+            })
+            .collect();
+        Self { source, files }
+    }
+
+    /// 先写入临时文件再重命名，保证清单要么是旧的，要么是完整的新清单。
+    fn store(&self, backup_dir: &Path) -> Result<(), BitCaskError> {
+        let tmp = backup_dir.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut file = std::fs::File::create(&tmp)?;
+        writeln!(file, "source {}", self.source.display())?;
+        let mut files: Vec<_> = self.files.iter().collect();
+        files.sort();
+        for (name, size) in files {
+            writeln!(file, "{} {}", name, size)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(tmp, backup_dir.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+}
+
+/// 将数据文件增量备份到`backup_dir`。
+///
+/// # 参数
+/// - `data_dir`: 存储当前的数据目录。
+/// - `files`: 当前所有数据文件的路径以及需要备份的字节数，活跃文件只备份已经写入的部分。
+///   文件在备份目录中的位置与其在数据目录中的相对位置相同。
+/// - `backup_dir`: 备份目录。
+///
+/// # 返回
+/// - `Result<BackupReport, BitCaskError>`: 本次备份的统计信息，或者复制过程中遇到的错误。
+///
+/// # 说明
+/// 与上一次备份的清单相比，文件名和大小都没有变化的文件会被跳过；
+/// 备份目录中已经不属于当前存储的旧数据文件会被删除，以免恢复时混入过期数据。
+pub(crate) fn backup_incremental(
+    data_dir: &Path,
+    files: Vec<(PathBuf, u64)>,
+    backup_dir: &Path,
+) -> Result<BackupReport, BitCaskError> {
+    std::fs::create_dir_all(backup_dir)?;
+    let mut previous = Manifest::load(backup_dir);
+    if previous.source != data_dir {
+        previous.files.clear();
+    }
+
+    let mut report = BackupReport::default();
+    let mut manifest = Manifest {
+        source: data_dir.to_path_buf(),
+        files: HashMap::new(),
+    };
+    for (path, size) in files {
+        let relative = path.strip_prefix(data_dir).unwrap_or(&path);
+        let name = relative.to_string_lossy().to_string();
+        if previous.files.remove(&name) == Some(size) {
+            report.skipped_files += 1;
+        } else {
+            copy_prefix(&path, &backup_dir.join(&name), size)?;
+            report.copied_files += 1;
+            report.copied_bytes += size;
+        }
+        manifest.files.insert(name, size);
+    }
+
+    // 删除不再属于当前存储的文件
+    for name in previous.files.keys() {
+        let stale = backup_dir.join(name);
+        if stale.exists() {
+            std::fs::remove_file(stale)?;
+        }
+    }
+    manifest.store(backup_dir)?;
+    Ok(report)
+}
+
+/// 将`src`的前`len`个字节复制到`dst`，并将结果刷新到磁盘。
+fn copy_prefix(src: &Path, dst: &Path, len: u64) -> Result<(), BitCaskError> {
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut reader = std::fs::File::open(src)?.take(len);
+    let mut writer = std::fs::File::create(dst)?;
+    std::io::copy(&mut reader, &mut writer)?;
+    writer.sync_all()?;
+    Ok(())
+}
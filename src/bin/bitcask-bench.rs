@@ -0,0 +1,249 @@
+//! YCSB风格的基准测试工具，报告吞吐量和延迟分位数。
+//!
+//! 用法: bitcask-bench [--workload <load|read-heavy|update-heavy|scan>] [--records N] [--operations N]
+//!                     [--key-size N] [--value-size N] [--threads N] [--scan-length N] [--data-dir PATH]
+//!                     [--index-backend <bytes|inline-keys|both>]
+//!
+//! 除`load`之外的负载会先（不计时地）写入`--records`个键，再由`--threads`个线程共执行`--operations`次操作，
+//! 操作的键在已写入的键中均匀随机选择：
+//! - `load`: 只写入新键；
+//! - `read-heavy`: 95%读取，5%更新（YCSB B）；
+//! - `update-heavy`: 50%读取，50%更新（YCSB A）；
+//! - `scan`: 95%从随机键开始的短范围扫描，5%更新（YCSB E）。
+//!
+//! `--index-backend`选择内存索引的实现（见`IndexBackendKind`），为`both`时依次以两种实现运行同一个负载，
+//! 便于比较；此时`--data-dir`下为每种实现各使用一个子目录。
+//!
+//! 未指定`--data-dir`时使用临时目录，结束后删除。
+
+use bitcask_engine_rs::bitcask::{BitCask, KVStorage, Key};
+use bitcask_engine_rs::options::{BitCaskOptions, IndexBackendKind};
+use rand::Rng;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Workload {
+    Load,
+    ReadHeavy,
+    UpdateHeavy,
+    Scan,
+}
+
+impl Workload {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "load" => Some(Workload::Load),
+            "read-heavy" => Some(Workload::ReadHeavy),
+            "update-heavy" => Some(Workload::UpdateHeavy),
+            "scan" => Some(Workload::Scan),
+            _ => None,
+        }
+    }
+
+    /// 读取（或扫描）操作所占的百分比。
+    fn read_percent(self) -> u32 {
+        match self {
+            Workload::Load => 0,
+            Workload::ReadHeavy | Workload::Scan => 95,
+            Workload::UpdateHeavy => 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    workload: Workload,
+    records: usize,
+    operations: usize,
+    key_size: usize,
+    value_size: usize,
+    threads: usize,
+    scan_length: usize,
+    data_dir: Option<PathBuf>,
+    index_backends: Vec<IndexBackendKind>,
+}
+
+impl Config {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut config = Config {
+            workload: Workload::ReadHeavy,
+            records: 100_000,
+            operations: 100_000,
+            key_size: 16,
+            value_size: 100,
+            threads: 4,
+            scan_length: 100,
+            data_dir: None,
+            index_backends: vec![IndexBackendKind::default()],
+        };
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| format!("missing value for {}", flag))?;
+            let number = || value.parse::<usize>().map_err(|_| format!("invalid value for {}: {}", flag, value));
+            match flag.as_str() {
+                "--workload" => {
+                    config.workload = Workload::parse(value).ok_or_else(|| format!("unknown workload {}", value))?
+                }
+                "--records" => config.records = number()?,
+                "--operations" => config.operations = number()?,
+                "--key-size" => config.key_size = number()?,
+                "--value-size" => config.value_size = number()?,
+                "--threads" => config.threads = number()?.max(1),
+                "--scan-length" => config.scan_length = number()?.max(1),
+                "--data-dir" => config.data_dir = Some(PathBuf::from(value)),
+                "--index-backend" => {
+                    config.index_backends = match value.as_str() {
+                        "bytes" => vec![IndexBackendKind::Bytes],
+                        "inline-keys" => vec![IndexBackendKind::InlineKeys],
+                        "both" => vec![IndexBackendKind::Bytes, IndexBackendKind::InlineKeys],
+                        _ => return Err(format!("unknown index backend {}", value)),
+                    }
+                }
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        if config.workload != Workload::Load && config.records == 0 {
+            return Err("--records must be positive".to_string());
+        }
+        Ok(config)
+    }
+
+    /// 第`index`个键：十进制序号左侧补零到`key_size`字节，序号更长时不截断。
+    fn key(&self, index: usize) -> Key {
+        format!("{:0width$}", index, width = self.key_size).into_bytes()
+    }
+}
+
+/// 一个线程的执行结果：每次操作的延迟（纳秒）。
+type Latencies = Vec<u64>;
+
+/// 在单个线程中执行`operations`次操作。
+fn run_thread(config: &Config, mut bitcask: BitCask, thread: usize, operations: usize) -> Latencies {
+    let mut rng = rand::thread_rng();
+    let mut value = vec![0; config.value_size];
+    rng.fill(&mut value[..]);
+    let mut latencies = Vec::with_capacity(operations);
+    for i in 0..operations {
+        let started = Instant::now();
+        if config.workload == Workload::Load {
+            let key = config.key(config.records + thread * operations + i);
+            bitcask.put(&key, &value).expect("put failed");
+        } else {
+            let key = config.key(rng.gen_range(0..config.records));
+            if rng.gen_range(0..100) >= config.workload.read_percent() {
+                // 每次更新改变值的一个字节，避免所有更新写入完全相同的值
+                if let Some(byte) = value.get_mut(i % config.value_size.max(1)) {
+                    *byte = byte.wrapping_add(1);
+                }
+                bitcask.put(&key, &value).expect("put failed");
+            } else if config.workload == Workload::Scan {
+                let scanned = bitcask.iter(key..).take(config.scan_length).count();
+                std::hint::black_box(scanned);
+            } else {
+                std::hint::black_box(bitcask.get(&key));
+            }
+        }
+        latencies.push(started.elapsed().as_nanos() as u64);
+    }
+    latencies
+}
+
+/// 返回已排序的延迟中的分位数。
+fn percentile(sorted: &[u64], percentile: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((sorted.len() as f64) * percentile / 100.0).ceil() as usize;
+    Duration::from_nanos(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+fn run(config: Config) -> Result<(), String> {
+    for &index_backend in &config.index_backends {
+        let data_dir = match (&config.data_dir, config.index_backends.len()) {
+            (Some(data_dir), 1) => Some(data_dir.clone()),
+            (Some(data_dir), _) => Some(data_dir.join(format!("{:?}", index_backend).to_lowercase())),
+            (None, _) => None,
+        };
+        run_backend(&config, index_backend, data_dir)?;
+    }
+    Ok(())
+}
+
+/// 以给定的内存索引实现运行一次负载。
+fn run_backend(config: &Config, index_backend: IndexBackendKind, data_dir: Option<PathBuf>) -> Result<(), String> {
+    let (data_dir, temporary) = match data_dir {
+        Some(data_dir) => (data_dir, false),
+        None => (std::env::temp_dir().join(format!("bitcask-bench-{}", std::process::id())), true),
+    };
+    let options = BitCaskOptions {
+        index_backend,
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(&data_dir, options).map_err(|e| e.to_string())?;
+    if config.workload != Workload::Load {
+        let started = Instant::now();
+        let mut value = vec![0; config.value_size];
+        rand::thread_rng().fill(&mut value[..]);
+        for index in 0..config.records {
+            bitcask.put(&config.key(index), &value).map_err(|e| e.to_string())?;
+        }
+        println!("loaded {} records in {:.3}s", config.records, started.elapsed().as_secs_f64());
+    }
+
+    let started = Instant::now();
+    let per_thread = config.operations / config.threads;
+    let mut latencies: Latencies = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..config.threads)
+            .map(|thread| {
+                // 余数分给第一个线程
+                let operations = per_thread + if thread == 0 { config.operations % config.threads } else { 0 };
+                let bitcask = bitcask.clone();
+                scope.spawn(move || run_thread(config, bitcask, thread, operations))
+            })
+            .collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    });
+    let elapsed = started.elapsed();
+    latencies.sort_unstable();
+
+    println!(
+        "workload={:?} index_backend={:?} threads={} operations={} key_size={} value_size={}",
+        config.workload,
+        index_backend,
+        config.threads,
+        latencies.len(),
+        config.key_size,
+        config.value_size
+    );
+    println!(
+        "throughput: {:.0} ops/s ({:.3}s)",
+        latencies.len() as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+        elapsed.as_secs_f64()
+    );
+    println!(
+        "latency: p50={:?} p95={:?} p99={:?} p99.9={:?} max={:?}",
+        percentile(&latencies, 50.0),
+        percentile(&latencies, 95.0),
+        percentile(&latencies, 99.0),
+        percentile(&latencies, 99.9),
+        percentile(&latencies, 100.0),
+    );
+    drop(bitcask);
+    if temporary {
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match Config::parse(&args).and_then(run) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("bitcask-bench: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! 以文本格式导出和导入存储中的键值对（见`dump`模块），离线压缩和升级已经关闭的存储，
+//! 以及从无法打开的损坏存储中紧急导出数据。
+//!
+//! 用法:
+//!   bitcask-cli <数据目录> dump [--format jsonl|csv] [--output 文件]
+//!   bitcask-cli <数据目录> load [--format jsonl|csv] [--input 文件]
+//!   bitcask-cli <数据目录> compact
+//!   bitcask-cli <数据目录> upgrade
+//!   bitcask-cli <数据目录> salvage --output 目录
+//!
+//! 默认格式为`jsonl`，默认从标准输入读取、向标准输出写入。`compact`见`BitCask::compact_offline`，
+//! `upgrade`见`BitCask::upgrade`，存储正在被其他进程使用时它们都会失败。
+//! `salvage`见`salvage::salvage`，把能够找回的记录写入输出目录中的新存储，并打印报告。
+
+use bitcask_engine_rs::bitcask::BitCask;
+use bitcask_engine_rs::dump::{dump, load, DumpFormat};
+use bitcask_engine_rs::options::BitCaskOptions;
+use bitcask_engine_rs::salvage::{salvage, SALVAGE_REPORT};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::process::ExitCode;
+
+const USAGE: &str = "usage:
+  bitcask-cli <data-dir> dump [--format jsonl|csv] [--output FILE]
+  bitcask-cli <data-dir> load [--format jsonl|csv] [--input FILE]
+  bitcask-cli <data-dir> compact
+  bitcask-cli <data-dir> upgrade
+  bitcask-cli <data-dir> salvage --output DIR";
+
+fn run(args: &[String]) -> Result<(), String> {
+    let [data_dir, command, flags @ ..] = args else {
+        return Err(USAGE.to_string());
+    };
+    let mut format = DumpFormat::Jsonl;
+    let mut path = None;
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        let value = flags.next().ok_or_else(|| format!("missing value for {}", flag))?;
+        match (command.as_str(), flag.as_str()) {
+            (_, "--format") => format = value.parse()?,
+            ("dump" | "salvage", "--output") | ("load", "--input") => path = Some(value),
+            _ => return Err(format!("unknown option {}\n{}", flag, USAGE)),
+        }
+    }
+    if command == "compact" {
+        let estimate = BitCask::compact_offline(data_dir, BitCaskOptions::default()).map_err(|e| e.to_string())?;
+        eprintln!(
+            "compacted {} files ({} bytes) into {} bytes, reclaimed {} bytes",
+            estimate.input_files,
+            estimate.input_bytes,
+            estimate.output_bytes,
+            estimate.reclaimable_bytes()
+        );
+        return Ok(());
+    }
+    if command == "upgrade" {
+        let v1_records = BitCask::upgrade(data_dir, BitCaskOptions::default()).map_err(|e| e.to_string())?;
+        match v1_records {
+            0 => eprintln!("already at record format version 2"),
+            _ => eprintln!("rewrote a store with {} version 1 records to version 2", v1_records),
+        }
+        return Ok(());
+    }
+    if command == "salvage" {
+        let out_dir = path.ok_or_else(|| format!("salvage needs --output\n{}", USAGE))?;
+        let report = salvage(data_dir, out_dir, BitCaskOptions::default()).map_err(|e| e.to_string())?;
+        print!("{}", report);
+        eprintln!(
+            "salvaged {} keys into {}, report written to {}",
+            report.keys,
+            out_dir,
+            SALVAGE_REPORT
+        );
+        return Ok(());
+    }
+    let mut bitcask = BitCask::new(data_dir).map_err(|e| e.to_string())?;
+    match command.as_str() {
+        "dump" => {
+            let count = match path {
+                Some(path) => {
+                    let file = File::create(path).map_err(|e| e.to_string())?;
+                    dump(&bitcask, BufWriter::new(file), format)
+                }
+                None => dump(&bitcask, BufWriter::new(std::io::stdout().lock()), format),
+            }
+            .map_err(|e| e.to_string())?;
+            eprintln!("dumped {} entries", count);
+        }
+        "load" => {
+            let count = match path {
+                Some(path) => {
+                    let file = File::open(path).map_err(|e| e.to_string())?;
+                    load(&mut bitcask, BufReader::new(file), format)
+                }
+                None => load(&mut bitcask, std::io::stdin().lock(), format),
+            }
+            .map_err(|e| e.to_string())?;
+            bitcask.sync().map_err(|e| e.to_string())?;
+            eprintln!("loaded {} entries", count);
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("bitcask-cli: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
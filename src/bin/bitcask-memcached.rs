@@ -0,0 +1,92 @@
+//! 以memcached文本协议提供基于BitCask的持久化缓存，见`memcached`模块。
+//!
+//! 用法: bitcask-memcached <数据目录> [--listen 地址] [--auth-file 文件]
+//!                          [--tls-cert 文件 --tls-key 文件 [--tls-client-ca 文件]]
+//!                          [--unix-socket 路径 [--unix-mode 八进制权限]]
+//!
+//! 默认监听`127.0.0.1:11211`，过期的键由后台线程定期清理。
+//! 指定`--unix-socket`时改为在Unix域套接字上监听，套接字文件的权限默认为`660`。
+//! 指定`--auth-file`时客户端需要先认证，凭据文件的格式见`AccessControl::load`。
+//! 指定`--tls-cert`和`--tls-key`时只接受TLS连接，再指定`--tls-client-ca`时要求客户端证书。
+
+use bitcask_engine_rs::auth::AccessControl;
+use bitcask_engine_rs::bitcask::BitCask;
+#[cfg(unix)]
+use bitcask_engine_rs::memcached::{bind_unix, serve_unix};
+use bitcask_engine_rs::memcached::{serve_with_options, ServerOptions};
+use bitcask_engine_rs::options::{BitCaskOptions, ExpirySweep};
+use bitcask_engine_rs::tls::TlsOptions;
+use std::net::TcpListener;
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: bitcask-memcached <data-dir> [--listen ADDR] [--auth-file FILE]
+                         [--tls-cert FILE --tls-key FILE [--tls-client-ca FILE]]
+                         [--unix-socket PATH [--unix-mode OCTAL]]";
+
+fn run(args: &[String]) -> Result<(), String> {
+    let [data_dir, flags @ ..] = args else {
+        return Err(USAGE.to_string());
+    };
+    let mut listen = "127.0.0.1:11211";
+    let mut server_options = ServerOptions::default();
+    let (mut tls_cert, mut tls_key, mut tls_client_ca) = (None, None, None);
+    let (mut unix_socket, mut unix_mode) = (None, 0o660);
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        let value = flags.next().ok_or_else(|| format!("missing value for {}", flag))?;
+        match flag.as_str() {
+            "--listen" => listen = value,
+            "--auth-file" => server_options.access = Some(AccessControl::load(value).map_err(|e| e.to_string())?),
+            "--tls-cert" => tls_cert = Some(value),
+            "--tls-key" => tls_key = Some(value),
+            "--tls-client-ca" => tls_client_ca = Some(value.into()),
+            "--unix-socket" => unix_socket = Some(value),
+            "--unix-mode" => {
+                unix_mode = u32::from_str_radix(value, 8).map_err(|_| format!("invalid value for {}: {}", flag, value))?
+            }
+            _ => return Err(format!("unknown option {}\n{}", flag, USAGE)),
+        }
+    }
+    server_options.tls = match (tls_cert, tls_key, tls_client_ca) {
+        (Some(cert_file), Some(key_file), client_ca_file) => Some(TlsOptions {
+            client_ca_file,
+            ..TlsOptions::new(cert_file, key_file)
+        }),
+        (None, None, None) => None,
+        _ => return Err("--tls-cert and --tls-key must be given together".to_string()),
+    };
+    let options = BitCaskOptions {
+        expiry_sweep: Some(ExpirySweep::default()),
+        ..BitCaskOptions::default()
+    };
+    let bitcask = BitCask::new_with_options(data_dir, options).map_err(|e| e.to_string())?;
+    if let Some(path) = unix_socket {
+        return serve_unix_socket(path, unix_mode, bitcask, server_options);
+    }
+    let listener = TcpListener::bind(listen).map_err(|e| e.to_string())?;
+    eprintln!("listening on {}", listen);
+    serve_with_options(listener, bitcask, server_options).map_err(|e| e.to_string())
+}
+
+#[cfg(unix)]
+fn serve_unix_socket(path: &str, mode: u32, bitcask: BitCask, server_options: ServerOptions) -> Result<(), String> {
+    let listener = bind_unix(path, mode).map_err(|e| e.to_string())?;
+    eprintln!("listening on {}", path);
+    serve_unix(listener, bitcask, server_options).map_err(|e| e.to_string())
+}
+
+#[cfg(not(unix))]
+fn serve_unix_socket(_: &str, _: u32, _: BitCask, _: ServerOptions) -> Result<(), String> {
+    Err("Unix sockets are not supported on this platform".to_string())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("bitcask-memcached: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,51 @@
+//! 在一个新的存储上重放操作记录文件，见`BitCaskOptions::trace_file`。
+//!
+//! 用法: bitcask-replay <操作记录文件> <数据目录> [--max-speed]
+//!
+//! 数据目录必须不存在或为空。默认按记录的时间间隔重放，`--max-speed`时尽可能快地重放。
+
+use bitcask_engine_rs::bitcask::BitCask;
+use bitcask_engine_rs::trace::{replay, ReplaySpeed};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let max_speed = args.iter().any(|arg| arg == "--max-speed");
+    let paths: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
+    let [trace_file, data_dir] = paths[..] else {
+        eprintln!("usage: bitcask-replay <trace-file> <data-dir> [--max-speed]");
+        return ExitCode::FAILURE;
+    };
+    let data_dir = PathBuf::from(data_dir);
+    if std::fs::read_dir(&data_dir).is_ok_and(|mut entries| entries.next().is_some()) {
+        eprintln!("data directory {} is not empty", data_dir.display());
+        return ExitCode::FAILURE;
+    }
+    let speed = match max_speed {
+        true => ReplaySpeed::Max,
+        false => ReplaySpeed::Original,
+    };
+    let res = BitCask::new(data_dir).and_then(|mut bitcask| {
+        let report = replay(trace_file, &mut bitcask, speed)?;
+        bitcask.sync()?;
+        Ok(report)
+    });
+    match res {
+        Ok(report) => {
+            let seconds = report.elapsed.as_secs_f64();
+            println!(
+                "replayed {} operations ({} failed) in {:.3}s, {:.0} ops/s",
+                report.operations,
+                report.failed,
+                seconds,
+                report.operations as f64 / seconds.max(f64::EPSILON),
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("replay failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -1,16 +1,43 @@
+use crate::audit::AuditLog;
+use crate::backup::BackupReport;
+use crate::clock::now_millis;
+use crate::compaction::CompactionEstimate;
+use crate::durability::{CommitAck, DurabilityTracker};
 use crate::error::BitCaskError;
-use crate::storage::{start_compaction, LogStorage};
+use crate::health::Health;
+use crate::history::KeyRecord;
+use crate::iter::Iter;
+use crate::lock::{KeyGuard, KeyLockTable};
+use crate::maintenance::{MaintenancePool, TaskPriority};
+use crate::merge::MergeReport;
+use crate::merkle::{MerkleTree, SyncEntry};
+use crate::options::{BitCaskOptions, CapAction, ExpirySweep, SyncPolicy, TunableOptions};
+use crate::snapshot::ReadSnapshot;
+use crate::stats::{RangeEstimate, Stats};
+use crate::tree::Tree;
+use crate::txn::ReadTxn;
+use crate::storage::{log_slow_op, op_span, record_op_span, start_compaction, LogStorage};
+use crate::value_ref::ValueRef;
+use arc_swap::ArcSwap;
+use std::ops::{Bound, RangeBounds};
 use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
 
 pub(crate) type FileId = usize;
 pub(crate) type ByteSize = u64;
 pub(crate) type ByteOffset = u64;
 pub type Key = Vec<u8>;
 pub type Value = Vec<u8>;
+pub type Metadata = Vec<u8>;
 
 /// 定义一个键值对存储的公共 trait，用于在键值存储系统中规范数据的读取、写入和删除操作。
 /// 实现该 trait 的类型还需要实现 Clone、Send，并且其生命周期为 'static，以确保数据可以在多线程环境中安全地发送和持久存储。
+///
+/// 实现者需要提供`get`、`put_with_option`、`delete`、`size`和`len`；`iter`、`contains_key`、`is_empty`和`flush`
+/// 都有默认实现，可以覆盖它们以提供遍历或者更高效的实现。`len`没有默认实现，因为默认的`iter`不支持遍历，
+/// 无法据此得到正确的数量。
 pub trait KVStorage: Clone + Send + 'static {
     /// 根据给定的键获取对应的值。
     /// # 参数
@@ -50,17 +77,74 @@ pub trait KVStorage: Clone + Send + 'static {
     /// # 返回值
     /// - `usize`: 表示存储系统中键值对的数量。
     fn size(&self) -> usize;
+
+    /// 按键的顺序遍历存储中所有未删除且未过期的键值对。
+    /// 默认实现不支持遍历，只产生一个`ErrorKind::Unsupported`的IO错误。
+    /// # 返回值
+    /// - `Box<dyn Iterator>`: 产生键值对的迭代器，读取失败时产生Err。
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<(Key, Value), BitCaskError>> + '_> {
+        let unsupported = std::io::Error::new(std::io::ErrorKind::Unsupported, "iteration is not supported");
+        Box::new(std::iter::once(Err(unsupported.into())))
+    }
+
+    /// 判断给定的键是否存在。
+    /// 默认实现通过`get`读取值，实现者可以只查找索引来避免读取值。
+    /// # 参数
+    /// - `key`: 一个指向 Key 类型的引用，表示要查找的键。
+    /// # 返回值
+    /// - `bool`: 键存在且未过期时返回 true。
+    fn contains_key(&self, key: &Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// 获取存储系统中未删除且未过期的键的数量。
+    /// 与`size`不同，结果不包括墓碑等内部条目。
+    /// # 返回值
+    /// - `usize`: 存在的键的数量。
+    fn len(&self) -> usize;
+
+    /// 判断存储系统中是否没有任何存在的键。
+    /// # 返回值
+    /// - `bool`: `len`为0时返回 true。
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 将目前为止的所有写入持久化。默认实现什么都不做，适合不需要显式持久化的存储。
+    /// # 返回值
+    /// - `Result<(), BitCaskError>`: 如果持久化成功，则返回Ok(()); 否则返回 Err 包裹的错误。
+    fn flush(&self) -> Result<(), BitCaskError> {
+        Ok(())
+    }
 }
 
 /// 定义一个名为PutOption的公开结构体，用于封装存储操作的选项。
 /// 结构体包含两个布尔类型字段：nx和xx，分别表示操作的条件。
 /// NX (not exist) for put operation
 /// XX (exist) for put operation
+/// 可选的ttl字段表示键的存活时间，过期后的键视为不存在，并会在压缩时被丢弃。
+/// 可选的expected_version字段要求键的当前版本号（见`BitCask::get_with_meta`）与之相同，
+/// 否则写入以`BitCaskError::VersionMismatch`失败。
+/// sync字段为true时，无论`BitCaskOptions::sync_policy`如何，写入返回前都会fsync，
+/// 适合少量需要完全持久化的关键写入，其余写入仍然使用放宽的策略。
+/// 可选的request_id字段是客户端为这次写入生成的唯一ID，随记录一起持久化；
+/// 在`BitCaskOptions::request_id_window`内再次收到相同ID的写入被视为重试，不再写入并直接返回成功。
+/// 可选的metadata字段是随值一起保存的用户元数据（最多`MAX_METADATA_SIZE`字节），例如内容类型、来源或者应用的版本标签，
+/// 通过`BitCask::get_with_metadata`读取，压缩后仍然保留。
+#[derive(Default)]
 pub struct PutOption {
     pub nx: bool,
     pub xx: bool,
+    pub ttl: Option<Duration>,
+    pub expected_version: Option<u64>,
+    pub sync: bool,
+    pub request_id: Option<u64>,
+    pub metadata: Option<Vec<u8>>,
 }
 
+/// 每次写入可以附加的用户元数据的最大字节数，见`PutOption::metadata`。
+pub const MAX_METADATA_SIZE: usize = 256;
+
 impl PutOption {
 
     /// 返回一个Option类型的None值，表示没有PutOption实例。
@@ -74,7 +158,7 @@ impl PutOption {
     pub fn nx() -> Option<Self> {
         Some(Self {
             nx: true,
-            xx: false,
+            ..Self::default()
         })
     }
 
@@ -82,16 +166,117 @@ impl PutOption {
     /// 这个方法用于明确需要使用xx条件的操作选项。
     pub fn xx() -> Option<Self> {
         Some(Self {
-            nx: false,
             xx: true,
+            ..Self::default()
         })
     }
+
+    /// 创建一个PutOption的实例，并设置键的存活时间，nx和xx字段都为false。
+    /// 这个方法用于写入在给定时间后自动过期的键。
+    pub fn ttl(ttl: Duration) -> Option<Self> {
+        Some(Self {
+            ttl: Some(ttl),
+            ..Self::default()
+        })
+    }
+
+    /// 创建一个PutOption的实例，只有当键的当前版本号等于给定版本号时才写入。
+    /// 这个方法用于实现比较并交换（CAS），而不需要重新发送旧值。
+    pub fn expected_version(version: u64) -> Option<Self> {
+        Some(Self {
+            expected_version: Some(version),
+            ..Self::default()
+        })
+    }
+
+    /// 创建一个PutOption的实例，sync为true时这次写入返回前一定会fsync。
+    /// 这个方法用于在放宽的fsync策略下单独为关键写入提供完全的持久性。
+    pub fn sync(sync: bool) -> Option<Self> {
+        Some(Self {
+            sync,
+            ..Self::default()
+        })
+    }
+
+    /// 创建一个PutOption的实例，带有客户端生成的请求ID。
+    /// 这个方法用于至少一次（at-least-once）投递的写入管道，重试的写入只会生效一次。
+    pub fn request_id(request_id: u64) -> Option<Self> {
+        Some(Self {
+            request_id: Some(request_id),
+            ..Self::default()
+        })
+    }
+
+    /// 创建一个PutOption的实例，随值一起保存给定的用户元数据。
+    /// 这个方法用于给值打上内容类型、来源或者版本等标签，而不必把它们编码进值本身。
+    pub fn metadata(metadata: Vec<u8>) -> Option<Self> {
+        Some(Self {
+            metadata: Some(metadata),
+            ..Self::default()
+        })
+    }
+}
+
+/// 一次写入的回执，由`BitCask::put_with_receipt`返回，可以用来建立外部索引、核对复制的位置或者实现恰好一次的交接。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteReceipt {
+    /// 记录所在的数据文件的ID。
+    pub file_id: FileId,
+    /// 记录在数据文件中的起始偏移量。压缩会重写记录，之后文件ID和偏移量不再指向这条记录。
+    pub offset: ByteOffset,
+    /// 写入的序号，与`CommitAck`使用的序号相同，在一次打开期间单调递增，重新打开后从头计数。
+    pub sequence: u64,
+    /// 写入时的混合逻辑时钟时间戳，见`ValueMeta::timestamp`。
+    pub timestamp: u64,
+}
+
+/// 带有元数据的值，由`BitCask::get_with_meta`返回。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueMeta {
+    /// 键对应的值。
+    pub value: Value,
+    /// 键的版本号，每次写入都会改变，可以作为`PutOption::expected_version`传入。
+    pub version: u64,
+    /// 写入时的混合逻辑时钟时间戳：高48位是Unix毫秒时间，低16位是逻辑计数器。
+    /// 同一存储中后写入的值时间戳更大，旧版本写入的值为0。
+    pub timestamp: u64,
+    /// 键的过期时间（Unix毫秒时间戳），为`None`表示永不过期。
+    pub expire_at: Option<u64>,
+    /// 写入时附加的用户元数据，见`PutOption::metadata`。
+    pub metadata: Option<Vec<u8>>,
+}
+
+/// `BitCask::put_all_if`在写入前检查的条件。已经删除或过期的键视为不存在。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    /// 键存在，并且当前的版本号等于给定的版本号，见`ValueMeta::version`。
+    Version(Key, u64),
+    /// 键存在，并且当前的值等于给定的值。
+    Value(Key, Value),
+    /// 键不存在。
+    Absent(Key),
+}
+
+/// `BitCask::put_all_if`原子地执行的一个写入。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchWrite {
+    /// 写入键值对，过期时间按`BitCaskOptions::default_ttl`计算。
+    Put(Key, Value),
+    /// 删除键。
+    Delete(Key),
 }
 
 #[derive(Clone)]
 // 定义一个BitCask结构体，用于管理存储引擎
+// 写入通过storage上的写锁串行化；读取只加载snapshot中最近一次写入后发布的不可变快照，不会被写入或压缩阻塞
 pub struct BitCask {
     pub(crate) storage: Arc<RwLock<LogStorage>>,
+    snapshot: Arc<ArcSwap<ReadSnapshot>>,
+    durability: Arc<DurabilityTracker>,
+    locks: Arc<KeyLockTable>,
+    audit: Option<Arc<AuditLog>>,
+    client_id: Option<Arc<str>>,
+    maintenance: Arc<MaintenancePool>,
 }
 
 impl BitCask {
@@ -99,25 +284,777 @@ impl BitCask {
     // 参数: data_dir - 存储数据的目录路径
     // 返回: Result<Self, BitCaskError> - 如果成功创建实例则返回Ok，否则返回Err
     pub fn new<T: Into<PathBuf>>(data_dir: T) -> Result<Self, BitCaskError> {
-        let storage = LogStorage::new(data_dir)?;
-        Ok(Self {
+        Self::new_with_options(data_dir, BitCaskOptions::default())
+    }
+
+    // 使用给定的配置选项创建一个新的BitCask实例
+    // 设置了BitCaskOptions::compact_on_open时，打开期间可能会先在原目录中压缩数据文件
+    // 参数: data_dir - 存储数据的目录路径
+    //        options - 存储的配置选项
+    // 返回: Result<Self, BitCaskError> - 如果成功创建实例则返回Ok，否则返回Err
+    pub fn new_with_options<T: Into<PathBuf>>(
+        data_dir: T,
+        options: BitCaskOptions,
+    ) -> Result<Self, BitCaskError> {
+        let audit = match options.audit.clone() {
+            Some(audit) => Some(Arc::new(AuditLog::open(audit)?)),
+            None => None,
+        };
+        let maintenance = Arc::new(MaintenancePool::new(options.maintenance_threads));
+        let storage = LogStorage::open(data_dir.into(), options)?;
+        let bitcask = Self {
+            snapshot: storage.snapshot_handle(),
+            durability: storage.durability_handle(),
             storage: Arc::new(RwLock::new(storage)),
-        })
+            locks: Arc::new(KeyLockTable::default()),
+            audit,
+            client_id: None,
+            maintenance,
+        };
+        bitcask.schedule_background_tasks();
+        Ok(bitcask)
+    }
+
+    // 离线压缩：打开数据目录中已经关闭的存储，在原目录中把所有数据文件合并为一个新文件，之后关闭存储
+    // 压缩期间持有数据目录的锁，与BitCaskOptions::compact_on_open相同，中途崩溃时剩余的文件仍然表示同一份数据
+    // 参数: data_dir - 存储的数据目录
+    //        options - 打开存储使用的配置选项，其中的compact_on_open被忽略
+    // 返回: Result<CompactionEstimate, BitCaskError> - 压缩之前的预估结果，数据目录被其他存储打开时返回DirectoryLocked
+    pub fn compact_offline<T: Into<PathBuf>>(
+        data_dir: T,
+        options: BitCaskOptions,
+    ) -> Result<CompactionEstimate, BitCaskError> {
+        LogStorage::compact_offline(data_dir.into(), options)
+    }
+
+    // 升级：打开数据目录中已经关闭的存储，通过离线压缩把第一版格式的记录重写为第二版格式（变长编码的标志和大小）
+    // 两种格式都可以直接读取，升级之后记录更小；与compact_offline一样，被覆盖、删除和过期的记录会被丢弃
+    // 参数: data_dir - 存储的数据目录
+    //        options - 打开存储使用的配置选项，其中的compact_on_open被忽略
+    // 返回: Result<usize, BitCaskError> - 升级之前第一版格式的记录数量，为0时数据目录没有被修改
+    pub fn upgrade<T: Into<PathBuf>>(data_dir: T, options: BitCaskOptions) -> Result<usize, BitCaskError> {
+        LogStorage::upgrade(data_dir.into(), options)
+    }
+
+    // 返回当前可以在运行时修改的配置选项
+    // 返回: TunableOptions - 可以修改后传给reconfigure
+    pub fn tunable_options(&self) -> TunableOptions {
+        TunableOptions::from(&self.storage.read().unwrap_or_else(PoisonError::into_inner).options)
+    }
+
+    // 在不重新打开存储的情况下修改运行时可调的配置选项，对共享同一个存储的所有句柄生效
+    // fsync、过期键清理和保存keydir的后台任务会按新的配置重新提交
+    // 参数: options - 新的配置选项，通常由tunable_options返回的值修改而来
+    // 返回: Result<(), BitCaskError> - 改为SyncPolicy::Always时fsync之前的写入失败则返回Err，配置不变
+    pub fn reconfigure(&mut self, options: TunableOptions) -> Result<(), BitCaskError> {
+        write_storage(&self.storage)?.reconfigure(options)?;
+        self.schedule_background_tasks();
+        Ok(())
+    }
+
+    // 按当前的配置向维护线程池提交fsync、过期键清理和保存keydir的后台任务，之前的任务会在发现配置变化后停止
+    fn schedule_background_tasks(&self) {
+        let storage = self.storage.read().unwrap_or_else(PoisonError::into_inner);
+        let epoch = storage.options_epoch;
+        if let SyncPolicy::Interval(interval) = storage.options.sync_policy {
+            schedule_sync(&self.maintenance, Arc::downgrade(&self.storage), interval, epoch);
+        }
+        if let Some(expiry_sweep) = storage.options.expiry_sweep.clone() {
+            schedule_sweep(&self.maintenance, Arc::downgrade(&self.storage), expiry_sweep, epoch);
+        }
+        if let Some(interval) = storage.options.keydir_interval {
+            schedule_keydir(&self.maintenance, Arc::downgrade(&self.storage), interval, epoch);
+        }
+    }
+
+    // 返回共享同一个存储、但以给定客户端ID记录审计日志的句柄，见BitCaskOptions::audit
+    // 参数: client_id - 写入审计记录的客户端ID，例如服务名或连接的对端地址
+    // 返回: BitCask - 新的句柄，原句柄的客户端ID不变
+    pub fn with_client_id(&self, client_id: &str) -> BitCask {
+        BitCask {
+            client_id: Some(Arc::from(client_id)),
+            ..self.clone()
+        }
+    }
+
+    // 在开启审计时追加一条审计记录
+    fn audit<T>(&self, op: &str, key: &[u8], size: usize, res: &Result<T, BitCaskError>, read: bool) {
+        if let Some(audit) = &self.audit {
+            audit.record(self.client_id.as_deref(), op, key, size as u64, res.is_ok(), read);
+        }
+    }
+
+    // 写入键值对，并返回一个在该写入真正fsync到磁盘后完成的提交确认
+    // 在放宽的fsync策略下，调用方可以只为需要的写入等待持久化，而不必让每次写入都fsync
+    // 参数: key - 要写入的键
+    //        value - 要写入的值
+    // 返回: Result<CommitAck, BitCaskError> - 写入成功后返回提交确认，通过CommitAck::wait等待持久化
+    pub fn put_with_ack(&mut self, key: &Key, value: &Value) -> Result<CommitAck, BitCaskError> {
+        let res = write_storage_capped(&self.storage).and_then(|mut storage| storage.put(key, value, PutOption::none()));
+        self.audit("put", key, value.len(), &res, false);
+        res?;
+        Ok(CommitAck::new(self.durability.last_written(), self.durability.clone()))
+    }
+
+    // 按给定的选项写入键值对，并返回写入的回执：记录所在的文件和偏移量、写入序号以及时间戳
+    // 带有请求ID的重试不会写入新的记录，此时回执描述的是键当前的记录
+    // 参数: key - 要写入的键
+    //        value - 要写入的值
+    //        option - 与put_with_option相同的写入选项
+    // 返回: Result<WriteReceipt, BitCaskError> - 写入成功后返回回执
+    pub fn put_with_receipt(
+        &mut self,
+        key: &Key,
+        value: &Value,
+        option: Option<PutOption>,
+    ) -> Result<WriteReceipt, BitCaskError> {
+        let res = write_storage_capped(&self.storage)
+            .and_then(|mut storage| storage.put_with_receipt(key, value, option));
+        self.audit("put", key, value.len(), &res, false);
+        res
+    }
+
+    // 批量写入按键严格升序排列的键值对，适合一次导入大量预先排好序的数据
+    // 条目成批顺序追加到日志中，比逐个调用put少得多的写入系统调用和快照发布
+    // 参数: pairs - 按键严格升序排列的键值对
+    // 返回: Result<(), BitCaskError> - 键没有严格升序时不写入任何数据并返回错误
+    pub fn put_many_sorted(&mut self, pairs: &[(Key, Value)]) -> Result<(), BitCaskError> {
+        let res = write_storage_capped(&self.storage).and_then(|mut storage| storage.put_many_sorted(pairs));
+        for (key, value) in pairs {
+            self.audit("put", key, value.len(), &res, false);
+        }
+        res
+    }
+
+    // 检查一组条件，所有条件都成立时原子地执行一批写入，用于需要同时协调多个键的场景，例如转移所有权或者分配租约
+    // 所有写入作为一个批次一次追加到日志中，读者要么看到全部写入，要么一个也看不到；写入批次时崩溃，重新打开时整个批次被丢弃
+    // 参数: conditions - 写入前检查的条件
+    //        writes - 按顺序执行的写入，同一个键被写入多次时最后一次生效
+    // 返回: Result<(), BitCaskError> - 第i个条件不成立时不写入任何数据并返回ConditionFailed(i)
+    pub fn put_all_if(&mut self, conditions: &[Condition], writes: &[BatchWrite]) -> Result<(), BitCaskError> {
+        let res = write_storage_capped(&self.storage).and_then(|mut storage| storage.put_all_if(conditions, writes));
+        for write in writes {
+            match write {
+                BatchWrite::Put(key, value) => self.audit("put", key, value.len(), &res, false),
+                BatchWrite::Delete(key) => self.audit("delete", key, 0, &res, false),
+            }
+        }
+        res
+    }
+
+    // 将目前为止的所有写入fsync到磁盘，并完成对应的提交确认
+    // 返回: Result<(), BitCaskError> - 如果fsync成功则返回Ok(()), 否则返回Err
+    pub fn sync(&self) -> Result<(), BitCaskError> {
+        read_storage(&self.storage)?.sync()
+    }
+
+    // 将内存索引保存到数据目录中的keydir提示文件，之后打开存储时从它重建内存索引，而不必扫描所有数据文件
+    // 保存之后追加的写入在打开时仍然会从数据文件中加载；数据文件被压缩替换后keydir不再使用
+    // 写入会在保存期间被阻塞，适合在关闭存储之前调用
+    // 返回: Result<(), BitCaskError> - 如果保存成功则返回Ok(()), 否则返回Err
+    pub fn save_keydir(&self) -> Result<(), BitCaskError> {
+        read_storage(&self.storage)?.save_keydir().map(|_| ())
+    }
+
+    // 封存当前的活跃文件并开始写入新的文件，例如在备份之前或者每天的固定时间调用，
+    // 之后被封存的文件不再变化，可以直接复制；封存时触发FileEvent::Sealed，并重新保存keydir，
+    // 这样打开存储和只读读者都不必再扫描被封存的文件
+    // 返回: Result<Option<usize>, BitCaskError> - 被封存的文件ID，活跃文件为空时不创建新文件并返回None
+    pub fn rotate_active_file(&self) -> Result<Option<usize>, BitCaskError> {
+        write_storage(&self.storage)?.rotate_active_file()
+    }
+
+    // 正常关闭存储：持久化所有写入，保存keydir，并写入正常关闭的标记
+    // 下一次打开时如果数据文件在关闭之后没有变化，直接信任关闭时保存的keydir，不再重新校验或扫描数据文件；
+    // 否则（例如崩溃、关闭之后又有写入）退回到普通的打开流程。标记在打开时被删除
+    // 存储的其他克隆在关闭之后不应再写入，否则下一次打开时标记不再匹配
+    // 返回: Result<(), BitCaskError> - 如果关闭成功则返回Ok(()), 否则返回Err
+    pub fn close(self) -> Result<(), BitCaskError> {
+        read_storage(&self.storage)?.close()
     }
 
     // 注意：此方法是一个阻塞调用，它将阻塞当前线程直到合并完成
     // 如果在异步上下文中使用此方法，你应该在一个阻塞工作线程中调用它
+    // 按BitCaskOptions::compaction_strategy选择要合并的封存文件，其余的文件原样复制到新目录
     // 参数: data_dir - 新的存储数据的目录路径
     // 返回: Result<(), BitCaskError> - 如果合并成功则返回Ok(()), 否则返回Err
     pub fn compact_to_new_dir<T: Into<PathBuf>>(&self, data_dir: T) -> Result<(), BitCaskError> {
-        let mut storage = self.storage.write().unwrap();
-        let data_dir: PathBuf = data_dir.into();
-        let immutable_files = storage.prepare_compaction()?;
+        let span = op_span("compaction");
+        let _entered = span.enter();
+        let started = Instant::now();
+        let res = self.compact_to_new_dir_inner(data_dir.into());
+        record_op_span(started.elapsed(), None, None, None);
+        res
+    }
+
+    // compact_to_new_dir的实际实现，每个步骤都在各自的操作span中执行
+    fn compact_to_new_dir_inner(&self, data_dir: PathBuf) -> Result<(), BitCaskError> {
+        let mut storage = write_storage(&self.storage)?;
+        let plan = op_span("compaction_prepare").in_scope(|| {
+            let started = Instant::now();
+            let plan = storage.prepare_compaction()?;
+            storage.log_slow_op("compaction_prepare", started.elapsed(), None, None, None);
+            Ok::<_, BitCaskError>(plan)
+        })?;
+        let options = storage.options.clone();
         drop(storage);
-        start_compaction(immutable_files.clone(), data_dir.clone())?;
-        let mut storage = self.storage.write().unwrap();
-        storage.finish_compaction(immutable_files, data_dir)
+        let merge = || {
+            op_span("compaction_merge").in_scope(|| {
+                let started = Instant::now();
+                let res = start_compaction(&plan, data_dir.clone(), &options);
+                log_slow_op(options.slow_op_threshold, "compaction_merge", started.elapsed(), None, None, Some(0));
+                res
+            })
+        };
+        // 压缩策略没有选择任何文件时只把数据文件复制到新目录；磁盘写满时释放保留文件，用腾出的空间重新压缩一次
+        if !plan.files.is_empty() {
+            match merge() {
+                Err(BitCaskError::DiskFull) if write_storage(&self.storage)?.release_reserve() => merge()?,
+                res => res?,
+            }
+        }
+        let mut storage = write_storage(&self.storage)?;
+        op_span("compaction_finish").in_scope(|| {
+            let started = Instant::now();
+            let res = storage.finish_compaction(plan.files, data_dir);
+            storage.log_slow_op("compaction_finish", started.elapsed(), None, None, None);
+            res
+        })
+    }
+
+    // 预估现在进行压缩的结果：输出大小、可回收的字节数以及保留和丢弃的记录数量，不会写入任何文件
+    // 需要扫描所有数据文件来统计记录数量，但不会读取内存索引中的值
+    // 返回: Result<CompactionEstimate, BitCaskError> - 压缩的预估结果
+    pub fn estimate_compaction(&self) -> Result<CompactionEstimate, BitCaskError> {
+        self.snapshot.load().estimate_compaction()
+    }
+
+    // 在指定目录中创建当前状态的时间点副本：已封存的文件以硬链接方式共享，活跃文件复制到当前的写入位置
+    // 副本可以通过BitCask::new打开，用于备份或派生测试环境
+    // 参数: checkpoint_dir - 副本所在的目录，必须为空或不存在
+    // 返回: Result<(), BitCaskError> - 如果创建成功则返回Ok(()), 否则返回Err
+    pub fn checkpoint<T: Into<PathBuf>>(&self, checkpoint_dir: T) -> Result<(), BitCaskError> {
+        read_storage(&self.storage)?.checkpoint(checkpoint_dir.into())
+    }
+
+    // 把当前状态导出为一个独立的检查点目录：所有数据被压缩为一个数据文件，并带有keydir和正常关闭的标记，
+    // 打开导出的目录时直接加载keydir而不必扫描数据文件，适合打包进容器镜像或分发到边缘节点作为只读数据使用
+    // 只在创建时间点副本期间持有存储的读锁，之后的压缩在导出目录中进行，不阻塞写入
+    // 参数: export_dir - 导出的目录，必须为空或不存在
+    // 返回: Result<(), BitCaskError> - 如果导出成功则返回Ok(()), 否则返回Err
+    pub fn export_checkpoint<T: Into<PathBuf>>(&self, export_dir: T) -> Result<(), BitCaskError> {
+        let export_dir = export_dir.into();
+        let options = {
+            let storage = read_storage(&self.storage)?;
+            storage.checkpoint(export_dir.clone())?;
+            storage.options.clone()
+        };
+        LogStorage::seal_export(export_dir, options)
+    }
+
+    // 将当前状态增量备份到指定目录，只复制自上一次备份以来新增或变化的数据文件，并在目录中维护一份清单
+    // 备份目录可以直接通过BitCask::new打开
+    // 参数: backup_dir - 备份目录，如果不存在会被创建
+    // 返回: Result<BackupReport, BitCaskError> - 本次备份复制和跳过的文件统计
+    pub fn backup_incremental<T: Into<PathBuf>>(&self, backup_dir: T) -> Result<BackupReport, BitCaskError> {
+        read_storage(&self.storage)?.backup_incremental(backup_dir.into())
+    }
+
+    // 根据给定的键获取值，与KVStorage::get不同，读取磁盘日志时的IO错误和数据损坏不会被当作键不存在
+    // 参数: key - 要查找的键
+    // 返回: Result<Option<Value>, BitCaskError> - 键不存在、已删除或已过期时返回Ok(None)，读取失败时返回Err
+    pub fn try_get(&self, key: &Key) -> Result<Option<Value>, BitCaskError> {
+        let res = self.snapshot.load().try_get(key);
+        let size = match &res {
+            Ok(Some(value)) => value.len(),
+            _ => 0,
+        };
+        self.audit("get", key, size, &res, true);
+        res
+    }
+
+    // 返回键的剩余存活时间，与Redis的PTTL相同，只查找索引，不读取值
+    // 参数: key - 要查找的键
+    // 返回: Option<Duration> - 键不存在、已删除、已过期或者没有设置过期时间时返回None，可以用contains_key区分后一种情况
+    pub fn ttl(&self, key: &Key) -> Option<Duration> {
+        let expire_at = self.snapshot.load().expire_at(key)?;
+        Some(Duration::from_millis(expire_at.saturating_sub(now_millis())))
+    }
+
+    // 返回键的过期时间，即写入时的PutOption::ttl加上写入时间
+    // 参数: key - 要查找的键
+    // 返回: Option<SystemTime> - 与ttl相同，键不存在或者没有设置过期时间时返回None
+    pub fn expires_at(&self, key: &Key) -> Option<SystemTime> {
+        let expire_at = self.snapshot.load().expire_at(key)?;
+        Some(UNIX_EPOCH + Duration::from_millis(expire_at))
+    }
+
+    // 批量读取多个键的值，适合分析类的批量访问：需要从数据文件读取的值按文件和偏移量排序，
+    // 同一文件中相邻的值合并为一次顺序读取，比逐个随机读取快得多；所有值都从同一个只读快照中读取
+    // 参数: keys - 要读取的键，可以重复
+    // 返回: Vec<Result<Option<Value>, BitCaskError>> - 与keys一一对应的结果，语义与try_get相同
+    pub fn get_entries(&self, keys: &[Key]) -> Vec<Result<Option<Value>, BitCaskError>> {
+        let results = self.snapshot.load().get_entries(keys);
+        for (key, res) in keys.iter().zip(&results) {
+            let size = match res {
+                Ok(Some(value)) => value.len(),
+                _ => 0,
+            };
+            self.audit("get", key, size, res, true);
+        }
+        results
     }
+
+    // 根据给定的键获取值及其版本号
+    // 参数: key - 要查找的键
+    // 返回: Result<Option<ValueMeta>, BitCaskError> - 如果键存在则返回Some，读取磁盘日志失败时返回Err
+    pub fn get_with_meta(&self, key: &Key) -> Result<Option<ValueMeta>, BitCaskError> {
+        let res = self.snapshot.load().get_with_meta(key);
+        let size = match &res {
+            Ok(Some(meta)) => meta.value.len(),
+            _ => 0,
+        };
+        self.audit("get", key, size, &res, true);
+        res
+    }
+
+    // 根据给定的键获取值及写入时附加的用户元数据（见PutOption::metadata）
+    // 参数: key - 要查找的键
+    // 返回: Result<Option<(Value, Option<Metadata>)>, BitCaskError> - 如果键存在则返回值和元数据，写入时没有附加元数据时元数据为None
+    pub fn get_with_metadata(&self, key: &Key) -> Result<Option<(Value, Option<Metadata>)>, BitCaskError> {
+        Ok(self.get_with_meta(key)?.map(|meta| (meta.value, meta.metadata)))
+    }
+
+    // 根据给定的键获取值，已封存数据文件中的未压缩值直接引用文件的内存映射，不会被复制
+    // 适合读取较大的值；第一次零拷贝读取某个封存文件时会为整个文件建立内存映射
+    // 参数: key - 要查找的键
+    // 返回: Result<Option<ValueRef>, BitCaskError> - 如果键存在则返回Some，读取磁盘日志失败时返回Err
+    pub fn get_ref(&self, key: &Key) -> Result<Option<ValueRef>, BitCaskError> {
+        let res = self.snapshot.load().get_ref(key);
+        let size = match &res {
+            Ok(Some(value)) => value.len(),
+            _ => 0,
+        };
+        self.audit("get", key, size, &res, true);
+        res
+    }
+
+    // 按键的顺序读取给定范围内的所有键值对，已删除的键会被跳过
+    // 所有键值对读取自同一个快照，不会看到扫描期间的写入，也不受同时进行的压缩影响
+    // 参数: range - 键的范围，例如 start..end 或 ..
+    // 返回: Result<Vec<(Key, Value)>, BitCaskError> - 按键升序排列的键值对
+    pub fn scan<R: RangeBounds<Key>>(&self, range: R) -> Result<Vec<(Key, Value)>, BitCaskError> {
+        self.snapshot.load().scan(range)
+    }
+
+    // 按键的顺序读取给定范围内满足filter的键值对，过滤在引擎内部进行，不满足的值不会被复制到返回结果中
+    // 与scan一样，所有键值对读取自同一个快照
+    // 参数: range - 键的范围，例如 start..end 或 ..
+    //        filter - 对每个未删除且未过期的键值对调用，返回true时保留
+    // 返回: Result<Vec<(Key, Value)>, BitCaskError> - 按键升序排列的满足条件的键值对
+    pub fn scan_filtered<R, F>(&self, range: R, filter: F) -> Result<Vec<(Key, Value)>, BitCaskError>
+    where
+        R: RangeBounds<Key>,
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        self.snapshot.load().scan_filtered(range, |_, _| true, filter)
+    }
+
+    // 与scan_filtered相同，但先用metadata_filter根据键和写入时附加的用户元数据（见PutOption::metadata）筛选，
+    // 元数据保存在索引中，没有通过的键不会读取值，适合按元数据中的类型或标签过滤
+    // 参数: range - 键的范围
+    //        metadata_filter - 对每个未删除且未过期的键调用，参数是键和元数据（没有元数据时为None），返回true时才读取值
+    //        filter - 对通过metadata_filter的键值对调用，返回true时保留
+    // 返回: Result<Vec<(Key, Value)>, BitCaskError> - 按键升序排列的满足两个条件的键值对
+    pub fn scan_filtered_by_metadata<R, M, F>(
+        &self,
+        range: R,
+        metadata_filter: M,
+        filter: F,
+    ) -> Result<Vec<(Key, Value)>, BitCaskError>
+    where
+        R: RangeBounds<Key>,
+        M: FnMut(&[u8], Option<&[u8]>) -> bool,
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        self.snapshot.load().scan_filtered(range, metadata_filter, filter)
+    }
+
+    // 开始一个只读事务：固定当前的快照和时刻，事务中的多次读取（get、scan、iter、count）看到同一个一致的视图，
+    // 不受之后的写入、删除、压缩和键的过期影响，即可重复读；事务不会阻塞写入
+    // 返回: ReadTxn - 只读事务，被丢弃时释放快照
+    pub fn read_txn(&self) -> ReadTxn {
+        ReadTxn::new(self.snapshot.load_full())
+    }
+
+    // 按键的顺序逐个遍历给定范围内的键值对，已删除和已过期的键会被跳过
+    // 迭代器在创建时固定当前的快照（内存索引版本和数据文件集合），迭代期间的写入、压缩和旧文件的删除
+    // 都不会改变迭代的结果或使读取的偏移量失效；值在遍历到时才从磁盘读取，适合遍历较大的范围
+    // 参数: range - 键的范围，例如 start..end 或 ..
+    // 返回: Iter - 按键升序产生键值对的迭代器，读取磁盘日志失败时产生Err
+    pub fn iter<R: RangeBounds<Key>>(&self, range: R) -> Iter {
+        Iter::new(self.snapshot.load_full(), range)
+    }
+
+    // 按键的顺序逐个遍历以给定前缀开头的键值对，快照语义与iter相同
+    // 参数: prefix - 键的前缀，为空时遍历所有键
+    // 返回: Iter - 按键升序产生键值对的迭代器
+    pub fn iter_prefix(&self, prefix: &[u8]) -> Iter {
+        Iter::new(self.snapshot.load_full(), prefix_range(prefix))
+    }
+
+    // 打开给定名字的树：树中的所有键自动加上树的前缀，树有自己的遍历、计数和清空操作，
+    // 同一个存储中的树共享数据文件并且互不重叠，适合多个组件共用一个存储；树不需要预先创建
+    // 参数: name - 树的名字，不能超过65535字节
+    // 返回: Result<Tree, BitCaskError> - 名字过长时返回Err
+    pub fn open_tree<N: AsRef<[u8]>>(&self, name: N) -> Result<Tree, BitCaskError> {
+        let name = name.as_ref();
+        if name.len() > u16::MAX as usize {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "tree name is too long").into());
+        }
+        Ok(Tree::new(self.clone(), name))
+    }
+
+    // 按键的顺序返回以给定前缀开头的所有未删除且未过期的键，不读取值
+    pub(crate) fn keys_with_prefix(&self, prefix: &[u8]) -> Vec<Key> {
+        self.snapshot.load().keys(prefix_range(prefix))
+    }
+
+    // 返回以给定前缀开头的未删除且未过期的键的数量，不读取值
+    pub(crate) fn count_prefix(&self, prefix: &[u8]) -> usize {
+        self.snapshot.load().count(prefix_range(prefix))
+    }
+
+    // 预热：按值在磁盘上的位置顺序读取给定键的值，使它们进入操作系统的页缓存，用于重启之后在接收请求之前预先加载热点数据
+    // 存储没有自己的值缓存，读取的值直接丢弃；内联在索引中的值已经在内存中，不需要预热
+    // 参数: keys - 要预热的键，不存在、已删除或已过期的键被跳过
+    // 返回: Result<usize, BitCaskError> - 从磁盘读取了值的键的数量，读取失败时返回Err
+    pub fn warm_up(&self, keys: &[Key]) -> Result<usize, BitCaskError> {
+        self.snapshot.load().warm_up(keys.iter().map(|key| key.as_slice()))
+    }
+
+    // 预热以给定前缀开头的所有键的值，见warm_up
+    // 参数: prefix - 键的前缀，为空时预热所有键
+    // 返回: Result<usize, BitCaskError> - 从磁盘读取了值的键的数量，读取失败时返回Err
+    pub fn warm_up_prefix(&self, prefix: &[u8]) -> Result<usize, BitCaskError> {
+        self.snapshot.load().warm_up_range(prefix_range(prefix))
+    }
+
+    // 估计给定键范围内未删除且未过期的键的数量和总字节数，只根据索引中记录的大小计算，不读取值，
+    // 可以用于在不扫描数据的情况下选择分片的切分点
+    // 开启稀疏索引时需要扫描数据文件块来补全不在内存索引中的键
+    // 参数: range - 键的范围，例如start..end或..
+    // 返回: RangeEstimate - 键的数量以及键和值的字节数
+    pub fn estimate_range_size<R: RangeBounds<Key>>(&self, range: R) -> RangeEstimate {
+        self.snapshot.load().estimate_range(range)
+    }
+
+    // 以无状态的游标分页遍历所有键，语义与Redis的SCAN类似，可用于网络前端实现SCAN命令
+    // 游标就是上一页的最后一个键，键按顺序返回，因此在并发写入下也不会重复返回同一个键，
+    // 遍历期间一直存在的键一定会被返回；遍历期间写入或删除的键可能返回也可能不返回
+    // 参数: cursor - 上一次调用返回的游标，为None时从第一个键开始
+    //        count - 本页最多返回的键数量，为0时按1处理
+    // 返回: (Vec<Key>, Option<Key>) - 本页的键，以及下一次调用使用的游标，为None表示遍历已经完成
+    pub fn scan_keys(&self, cursor: Option<&Key>, count: usize) -> (Vec<Key>, Option<Key>) {
+        self.snapshot.load().scan_keys(&[], cursor, count)
+    }
+
+    // 与scan_keys相同，但只遍历以给定前缀开头的键，游标仍然是带有前缀的完整的键
+    pub(crate) fn scan_keys_with_prefix(&self, prefix: &[u8], cursor: Option<&Key>, count: usize) -> (Vec<Key>, Option<Key>) {
+        self.snapshot.load().scan_keys(prefix, cursor, count)
+    }
+
+    // 按过期时间顺序返回最多limit个已经过期但尚未删除的键，用于后台清理过期键
+    // 内存中维护着按过期时间排序的索引，代价只与返回的键数量有关，不会扫描整个索引
+    // 参数: limit - 最多返回的键数量
+    // 返回: Vec<Key> - 过期时间最早的已过期键
+    pub fn expired_keys(&self, limit: usize) -> Vec<Key> {
+        self.snapshot.load().expired_keys(limit)
+    }
+
+    // 按过期时间顺序返回尚未过期、但会在给定时间之前过期的键，用于提前刷新即将过期的键或者观察过期情况
+    // 与expired_keys相同，只访问过期索引中落在这个时间范围内的部分
+    // 参数: before - 过期时间的上界（不含）
+    //        limit - 最多返回的键数量
+    // 返回: Vec<(Key, SystemTime)> - 过期时间最早的键及其过期时间
+    pub fn expiring_before(&self, before: SystemTime, limit: usize) -> Vec<(Key, SystemTime)> {
+        let before = before
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_millis() as u64);
+        self.snapshot
+            .load()
+            .expiring_keys(before, limit)
+            .into_iter()
+            .map(|(key, expire_at)| (key, UNIX_EPOCH + Duration::from_millis(expire_at)))
+            .collect()
+    }
+
+    // 软删除给定的键，键随即不可见，但删除前的值会保留到下一次压缩，在此之前可以用undelete恢复
+    // 参数: key - 要删除的键
+    // 返回: Result<(), BitCaskError> - 键不存在或已经过期时返回KeyNotFound
+    pub fn soft_delete(&mut self, key: &Key) -> Result<(), BitCaskError> {
+        let res = write_storage(&self.storage).and_then(|mut storage| storage.soft_delete(key));
+        self.audit("soft_delete", key, 0, &res, false);
+        res
+    }
+
+    // 恢复通过soft_delete删除的键，值和过期时间与删除前相同
+    // 参数: key - 要恢复的键
+    // 返回: Result<(), BitCaskError> - 键的最后一次修改不是软删除，或者软删除的值已经被压缩丢弃时返回KeyNotFound
+    pub fn undelete(&mut self, key: &Key) -> Result<(), BitCaskError> {
+        let res = write_storage_capped(&self.storage).and_then(|mut storage| storage.undelete(key));
+        self.audit("undelete", key, 0, &res, false);
+        res
+    }
+
+    // 把old的值原子地移动到new下，新键的写入和旧键的删除同时对读取可见，过期时间随值一起移动
+    // 参数: old - 原来的键
+    //        new - 新的键
+    //        nx - 为true时只在新键不存在时移动，类似PutOption::nx
+    // 返回: Result<(), BitCaskError> - 旧键不存在时返回KeyNotFound，nx为true且新键已存在时返回KeyExists
+    pub fn rename(&mut self, old: &Key, new: &Key, nx: bool) -> Result<(), BitCaskError> {
+        let res = write_storage_capped(&self.storage).and_then(|mut storage| storage.rename(old, new, nx));
+        self.audit("rename", old, 0, &res, false);
+        res
+    }
+
+    // 把src的值复制到dst下，src保持不变
+    // 参数: src - 源键
+    //        dst - 目标键
+    //        nx - 为true时只在目标键不存在时复制，否则覆盖目标键
+    // 返回: Result<(), BitCaskError> - 源键不存在时返回KeyNotFound，nx为true且目标键已存在时返回KeyExists
+    pub fn copy(&mut self, src: &Key, dst: &Key, nx: bool) -> Result<(), BitCaskError> {
+        let res = write_storage_capped(&self.storage).and_then(|mut storage| storage.copy(src, dst, nx));
+        self.audit("copy", dst, 0, &res, false);
+        res
+    }
+
+    // 为最多limit个已经过期的键写入墓碑，与BitCaskOptions::expiry_sweep配置的后台清理相同
+    // 参数: limit - 最多清理的键数量
+    // 返回: Result<usize, BitCaskError> - 实际清理的键数量，小于limit说明已经没有过期的键
+    pub fn sweep_expired(&mut self, limit: usize) -> Result<usize, BitCaskError> {
+        write_storage(&self.storage)?.sweep_expired(limit)
+    }
+
+    // 以最后写入者胜出（LWW）的方式导入另一个存储中的条目，用于协调在不同机器上写入的存储
+    // 每个键按两边最后一条记录（写入或删除）的混合逻辑时钟时间戳决定胜负，时间戳相同时按确定性的规则打破平局
+    // 参数: other_dir - 另一个存储的数据目录，合并期间不应被写入
+    // 返回: Result<MergeReport, BitCaskError> - 导入和跳过的键的统计
+    pub fn merge_from<T: Into<PathBuf>>(&mut self, other_dir: T) -> Result<MergeReport, BitCaskError> {
+        write_storage_capped(&self.storage)?.merge_from(other_dir.into())
+    }
+
+    // 扫描所有数据文件，返回给定键的所有记录（包括已被覆盖的记录和墓碑），用于排查值丢失等问题
+    // 该方法会读取全部数据文件，代价与存储大小成正比，只应在调试时使用
+    // 参数: key - 要查看的键
+    // 返回: Result<Vec<KeyRecord>, BitCaskError> - 按写入顺序排列的记录，最后一条是当前生效的记录
+    pub fn history(&self, key: &Key) -> Result<Vec<KeyRecord>, BitCaskError> {
+        self.snapshot.load().disk_log.history(key)
+    }
+
+    // 返回当前内容的默克尔树，叶子对应按键哈希划分的各个段，用于与其他副本比较差异
+    // 写入时增量维护叶子，调用代价只与段的数量有关
+    // 返回: MerkleTree - 可以逐层发送给另一个副本，通过MerkleTree::diff找出内容不同的段
+    pub fn merkle_tree(&self) -> MerkleTree {
+        self.snapshot.load().merkle_tree()
+    }
+
+    // 导出给定段中每个键的最后一条记录（包括删除），发送给另一个副本后由apply_sync_entries应用
+    // 参数: segments - 需要导出的段，通常是MerkleTree::diff的结果
+    // 返回: Result<Vec<SyncEntry>, BitCaskError> - 这些段中的记录
+    pub fn segment_entries(&self, segments: &[usize]) -> Result<Vec<SyncEntry>, BitCaskError> {
+        self.snapshot.load().sync_entries(Some(segments))
+    }
+
+    // 以最后写入者胜出的方式应用另一个副本导出的记录，规则与merge_from相同
+    // 参数: entries - 另一个副本通过segment_entries导出的记录
+    // 返回: Result<MergeReport, BitCaskError> - 导入和跳过的记录的统计
+    pub fn apply_sync_entries(&mut self, entries: Vec<SyncEntry>) -> Result<MergeReport, BitCaskError> {
+        write_storage_capped(&self.storage)?.apply_sync_entries(entries)
+    }
+
+    // 与另一个副本进行一轮反熵修复：比较两边的默克尔树，只交换哈希不同的段中的记录
+    // 压缩会丢弃墓碑，因此删除只有在两边都压缩之前完成同步才能可靠地传播
+    // 参数: other - 另一个副本
+    // 返回: Result<(MergeReport, MergeReport), BitCaskError> - 本地和另一个副本各自应用的记录统计
+    pub fn anti_entropy(&mut self, other: &mut BitCask) -> Result<(MergeReport, MergeReport), BitCaskError> {
+        let segments = self.merkle_tree().diff(&other.merkle_tree());
+        if segments.is_empty() {
+            return Ok((MergeReport::default(), MergeReport::default()));
+        }
+        let local_entries = self.segment_entries(&segments)?;
+        let remote_entries = other.segment_entries(&segments)?;
+        let local_report = self.apply_sync_entries(remote_entries)?;
+        let remote_report = other.apply_sync_entries(local_entries)?;
+        Ok((local_report, remote_report))
+    }
+
+    // 获取给定键的应用层排他锁，用于协调多步更新；返回的守卫被丢弃时释放锁
+    // 该锁是建议性的，只在同样调用lock_key的调用方之间互斥，不会阻塞普通的读写
+    // 参数: key - 要锁定的键
+    //        timeout - 键已被锁定时最多等待的时间
+    // 返回: Result<KeyGuard, BitCaskError> - 超时未获得锁时返回BitCaskError::LockTimeout
+    pub fn lock_key(&self, key: &Key, timeout: Duration) -> Result<KeyGuard, BitCaskError> {
+        self.locks.lock(key, timeout)
+    }
+
+    // 检查存储的健康状态，可用于服务的就绪探针
+    // 返回: Result<Health, BitCaskError> - 健康检查报告，通过Health::is_healthy判断是否健康
+    pub fn health(&self) -> Result<Health, BitCaskError> {
+        read_storage(&self.storage)?.health()
+    }
+
+    // 退出IO看门狗的只读降级模式（见StallAction::ReadOnly），通常在排除了存储设备的问题之后调用
+    // 之后的写入再次超过期限时会重新进入降级模式
+    // 返回: Result<bool, BitCaskError> - 之前是否处于降级模式
+    pub fn resume_writes(&self) -> Result<bool, BitCaskError> {
+        Ok(read_storage(&self.storage)?.resume_writes())
+    }
+
+    // 返回运行时统计信息，例如索引条目数量、数据文件数量和临时缓冲区的分配次数
+    // 返回: Stats - 基于当前只读快照计算，不会阻塞写入
+    pub fn stats(&self) -> Stats {
+        self.snapshot.load().stats()
+    }
+}
+
+// 返回以prefix开头的所有键组成的范围：下界是前缀本身，上界是第一个大于所有这些键的键
+// 去掉末尾的0xFF之后把最后一个字节加一；前缀全部由0xFF组成时没有上界
+pub(crate) fn prefix_range(prefix: &[u8]) -> (Bound<Key>, Bound<Key>) {
+    let mut upper = prefix.to_vec();
+    while upper.last() == Some(&u8::MAX) {
+        upper.pop();
+    }
+    let upper = match upper.last_mut() {
+        Some(last) => {
+            *last += 1;
+            Bound::Excluded(upper)
+        }
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix.to_vec()), upper)
+}
+
+// 获取存储的读锁，锁已经中毒时见poisoned
+fn read_storage(storage: &RwLock<LogStorage>) -> Result<RwLockReadGuard<'_, LogStorage>, BitCaskError> {
+    storage.read().map_err(|_| poisoned())
+}
+
+// 获取存储的写锁，锁已经中毒时见poisoned
+fn write_storage(storage: &RwLock<LogStorage>) -> Result<RwLockWriteGuard<'_, LogStorage>, BitCaskError> {
+    storage.write().map_err(|_| poisoned())
+}
+
+// 数据文件的总大小超过BitCaskOptions::disk_usage_cap时写入在两次检查之间等待的时间
+const WRITE_STALL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// 获取写入使用的存储写锁：数据文件的总大小超过BitCaskOptions::disk_usage_cap时按配置立即失败，
+// 或者在不持有锁的情况下等待压缩回收空间；删除、压缩等回收空间的操作直接使用write_storage
+fn write_storage_capped(storage: &RwLock<LogStorage>) -> Result<RwLockWriteGuard<'_, LogStorage>, BitCaskError> {
+    let started = Instant::now();
+    let mut stalled = false;
+    loop {
+        let guard = write_storage(storage)?;
+        let max_stall = match guard.disk_usage_exceeded() {
+            None => {
+                if stalled {
+                    guard.record_write_stall(started.elapsed());
+                }
+                return Ok(guard);
+            }
+            Some(CapAction::Fail) => Some(Duration::ZERO),
+            Some(CapAction::Block(max_stall)) => max_stall,
+        };
+        if max_stall.is_some_and(|max_stall| started.elapsed() >= max_stall) {
+            guard.record_write_stall(started.elapsed());
+            return Err(BitCaskError::WriteStalled);
+        }
+        if !stalled {
+            warn!("disk usage exceeds the configured cap, stalling writes until compaction frees space");
+            stalled = true;
+        }
+        drop(guard);
+        std::thread::sleep(WRITE_STALL_POLL_INTERVAL);
+    }
+}
+
+// 之前的操作在持有写锁时panic使锁中毒：panic的操作可能只完成了一部分，例如记录已经追加但索引没有更新，
+// 因此不清除中毒状态，之后所有需要存储锁的调用都返回BitCaskError::Internal，而不是让调用方panic，
+// 也不在可能不一致的状态上继续写入或保存keydir；读取继续使用panic之前发布的快照。
+// 丢弃所有句柄后重新打开存储，会从磁盘重建状态
+fn poisoned() -> BitCaskError {
+    error!("a previous operation panicked while holding the storage lock, reopen the store");
+    BitCaskError::Internal("a previous operation panicked while holding the storage lock, reopen the store".to_string())
+}
+
+// 提交按固定间隔fsync的后台任务，所有BitCask句柄被丢弃或配置被修改后任务自动停止
+fn schedule_sync(pool: &MaintenancePool, storage: Weak<RwLock<LogStorage>>, interval: Duration, epoch: u64) {
+    pool.schedule("sync", TaskPriority::High, interval, move || {
+        let storage = storage.upgrade()?;
+        let storage = match read_storage(&storage) {
+            Ok(storage) => storage,
+            Err(e) => {
+                error!("Error while syncing disk log: {:?}", e);
+                return Some(interval);
+            }
+        };
+        if storage.options_epoch != epoch {
+            return None;
+        }
+        if let Err(e) = storage.sync() {
+            error!("Error while syncing disk log: {:?}", e);
+        }
+        Some(interval)
+    });
+}
+
+// 提交定期清理过期键的后台任务，所有BitCask句柄被丢弃或配置被修改后任务自动停止
+// 每次执行只清理一批，清理了一整批时立即再次执行；批之间释放写锁，写入和优先级更高的任务可以穿插进行
+fn schedule_sweep(pool: &MaintenancePool, storage: Weak<RwLock<LogStorage>>, expiry_sweep: ExpirySweep, epoch: u64) {
+    let batch_size = expiry_sweep.batch_size.max(1);
+    let interval = expiry_sweep.interval;
+    pool.schedule("expiry sweep", TaskPriority::Normal, interval, move || {
+        let storage = storage.upgrade()?;
+        let mut storage = match write_storage(&storage) {
+            Ok(storage) => storage,
+            Err(e) => {
+                error!("Error while sweeping expired keys: {:?}", e);
+                return Some(interval);
+            }
+        };
+        if storage.options_epoch != epoch {
+            return None;
+        }
+        match storage.sweep_expired(batch_size) {
+            Ok(swept) if swept == batch_size => Some(Duration::ZERO),
+            Ok(_) => Some(interval),
+            Err(e) => {
+                error!("Error while sweeping expired keys: {:?}", e);
+                Some(interval)
+            }
+        }
+    });
+}
+
+// 提交定期保存keydir的后台任务，所有BitCask句柄被丢弃或配置被修改后任务自动停止
+fn schedule_keydir(pool: &MaintenancePool, storage: Weak<RwLock<LogStorage>>, interval: Duration, epoch: u64) {
+    pool.schedule("keydir", TaskPriority::Low, interval, move || {
+        let storage = storage.upgrade()?;
+        let storage = match read_storage(&storage) {
+            Ok(storage) => storage,
+            Err(e) => {
+                error!("Error while saving keydir: {:?}", e);
+                return Some(interval);
+            }
+        };
+        if storage.options_epoch != epoch {
+            return None;
+        }
+        if let Err(e) = storage.save_keydir() {
+            error!("Error while saving keydir: {:?}", e);
+        }
+        Some(interval)
+    });
 }
 
 // 实现KVStorage trait
@@ -126,7 +1063,16 @@ impl KVStorage for BitCask {
     // 参数: key - 要查找的键
     // 返回: Option<Value> - 如果键存在则返回Some(value)，否则返回None
     fn get(&self, key: &Key) -> Option<Value> {
-        self.storage.read().unwrap().get(key)
+        let res = self.snapshot.load().try_get(key);
+        let size = match &res {
+            Ok(Some(value)) => value.len(),
+            _ => 0,
+        };
+        self.audit("get", key, size, &res, true);
+        res.unwrap_or_else(|e| {
+            error!("Error while getting value from disk log: {:?}", e);
+            None
+        })
     }
 
     // 带选项地将键值对放入存储中
@@ -135,19 +1081,48 @@ impl KVStorage for BitCask {
     //        option - 放入选项
     // 返回: Result<(), BitCaskError> - 如果放入成功则返回Ok(()), 否则返回Err
     fn put_with_option(&mut self, key: &Key, value: &Value, option: Option<PutOption>) -> Result<(), BitCaskError> {
-        self.storage.write().unwrap().put(key, value, option)
+        let res = write_storage_capped(&self.storage).and_then(|mut storage| storage.put(key, value, option));
+        self.audit("put", key, value.len(), &res, false);
+        res
     }
 
     // 删除给定的键
     // 参数: key - 要删除的键
     // 返回: Result<(), BitCaskError> - 如果删除成功则返回Ok(()), 否则返回Err
     fn delete(&mut self, key: &Key) -> Result<(), BitCaskError> {
-        self.storage.write().unwrap().delete(key)
+        let res = write_storage(&self.storage).and_then(|mut storage| storage.delete(key));
+        self.audit("delete", key, 0, &res, false);
+        res
     }
 
     // 获取存储的大小
     // 返回: usize - 存储的大小
     fn size(&self) -> usize {
-        self.storage.read().unwrap().size()
+        self.snapshot.load().size()
+    }
+
+    // 按键的顺序遍历所有键值对，与iter_prefix(&[])相同
+    // 返回: Box<dyn Iterator> - 按键升序产生键值对的迭代器
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<(Key, Value), BitCaskError>> + '_> {
+        Box::new(self.iter_prefix(&[]))
+    }
+
+    // 判断给定的键是否存在，只查找索引，不读取值
+    // 参数: key - 要查找的键
+    // 返回: bool - 键存在且未过期时返回true
+    fn contains_key(&self, key: &Key) -> bool {
+        self.snapshot.load().contains_key(key)
+    }
+
+    // 获取未删除且未过期的键的数量，只统计索引，不读取值
+    // 返回: usize - 存在的键的数量
+    fn len(&self) -> usize {
+        self.snapshot.load().count(..)
+    }
+
+    // 将目前为止的所有写入fsync到磁盘，与sync相同
+    // 返回: Result<(), BitCaskError> - 如果fsync成功则返回Ok(()), 否则返回Err
+    fn flush(&self) -> Result<(), BitCaskError> {
+        self.sync()
     }
 }
\ No newline at end of file
@@ -0,0 +1,107 @@
+use crate::bitcask::{ByteSize, Value};
+use crate::error::BitCaskError;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// 存放大值的子目录名。
+pub(crate) const BLOB_DIR: &str = "blobs";
+
+/// 大值文件的扩展名。
+const BLOB_EXT: &str = "blob";
+
+/// 大值的唯一标识符，同时也是文件名。
+pub(crate) type BlobId = u64;
+
+/// `BlobStorage` 结构体管理数据目录下存放大值的独立文件。
+///
+/// 超过阈值的值会被写入`blobs/<blob_id>.blob`，日志中只记录键和指向该文件的指针，
+/// 这样压缩时只需要链接仍然被引用的大值文件，而不需要反复重写它们。
+#[derive(Clone)]
+pub(crate) struct BlobStorage {
+    /// 大值文件所在的目录。
+    dir: PathBuf,
+    /// 下一个可用的大值ID。
+    next_id: BlobId,
+}
+
+impl BlobStorage {
+    /// 打开数据目录下的大值目录，并根据已有文件确定下一个可用的ID。
+    ///
+    /// # 参数
+    /// - `data_dir`: 数据目录的路径。
+    pub(crate) fn open(data_dir: &Path) -> Result<Self, BitCaskError> {
+        let dir = data_dir.join(BLOB_DIR);
+        let next_id = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let path = entry.path();
+                    if path.extension()? != BLOB_EXT {
+                        return None;
+                    }
+                    path.file_stem()?.to_str()?.parse::<BlobId>().ok()
+                })
+                .max()
+                .map_or(0, |max_id| max_id + 1),
+            Err(_) => 0,
+        };
+        Ok(Self { dir, next_id })
+    }
+
+    /// 返回大值文件的路径。
+    pub(crate) fn path(&self, blob_id: BlobId) -> PathBuf {
+        let mut path = self.dir.join(blob_id.to_string());
+        path.set_extension(BLOB_EXT);
+        path
+    }
+
+    /// 将值写入一个新的大值文件，并返回其ID。
+    ///
+    /// # 说明
+    /// 值先写入临时文件并刷新到磁盘，再重命名为正式文件，
+    /// 因此日志中的指针一定指向完整的文件。
+    pub(crate) fn write(&mut self, value: &Value) -> Result<BlobId, BitCaskError> {
+        std::fs::create_dir_all(&self.dir)?;
+        let blob_id = self.next_id;
+        self.next_id += 1;
+        let path = self.path(blob_id);
+        let tmp = path.with_extension("tmp");
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(value)?;
+        file.sync_all()?;
+        std::fs::rename(tmp, path)?;
+        Ok(blob_id)
+    }
+
+    /// 读取大值文件的内容。
+    ///
+    /// # 错误
+    /// 如果文件的大小与日志中记录的不一致，返回`CorruptedData`。
+    pub(crate) fn read(&self, blob_id: BlobId, value_size: ByteSize) -> Result<Value, BitCaskError> {
+        let value = std::fs::read(self.path(blob_id))?;
+        if value.len() as ByteSize != value_size {
+            return Err(BitCaskError::CorruptedData(format!(
+                "blob {} has {} bytes, expected {}",
+                blob_id,
+                value.len(),
+                value_size
+            )));
+        }
+        Ok(value)
+    }
+
+    /// 将大值文件链接到另一个数据目录（跨文件系统时退化为复制），用于压缩、检查点和备份。
+    pub(crate) fn link_to(&self, blob_id: BlobId, data_dir: &Path) -> Result<(), BitCaskError> {
+        let source = self.path(blob_id);
+        let target_dir = data_dir.join(BLOB_DIR);
+        std::fs::create_dir_all(&target_dir)?;
+        let target = target_dir.join(source.file_name().unwrap());
+        if target.exists() {
+            return Ok(());
+        }
+        if std::fs::hard_link(&source, &target).is_err() {
+            std::fs::copy(&source, &target)?;
+        }
+        Ok(())
+    }
+}
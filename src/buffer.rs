@@ -0,0 +1,43 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 线程本地缓冲区使用后保留的最大容量，超过时释放，避免一次写入大值后长期占用内存。
+const MAX_RETAINED_CAPACITY: usize = 1024 * 1024; // 1MB
+
+thread_local! {
+    /// 当前线程复用的临时缓冲区，用于编码写入的条目和读取需要解压的值。
+    static SCRATCH: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// 使用临时缓冲区时需要分配（或扩容）内存的次数，所有存储共享。
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+/// 使用临时缓冲区时直接复用已有内存的次数，所有存储共享。
+static REUSES: AtomicU64 = AtomicU64::new(0);
+
+/// 使用当前线程的临时缓冲区执行`f`，缓冲区在调用前被清空。
+///
+/// 热路径上的编码和读取因此不必每次都分配新的`Vec`。嵌套调用时内层使用新分配的缓冲区。
+pub(crate) fn with_scratch<R>(f: impl FnOnce(&mut Vec<u8>) -> R) -> R {
+    SCRATCH.with(|scratch| match scratch.try_borrow_mut() {
+        Ok(mut buf) => {
+            buf.clear();
+            let capacity = buf.capacity();
+            let res = f(&mut buf);
+            let counter = if buf.capacity() > capacity { &ALLOCATIONS } else { &REUSES };
+            counter.fetch_add(1, Ordering::Relaxed);
+            if buf.capacity() > MAX_RETAINED_CAPACITY {
+                *buf = Vec::new();
+            }
+            res
+        }
+        Err(_) => {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            f(&mut Vec::new())
+        }
+    })
+}
+
+/// 返回临时缓冲区的分配次数和复用次数。
+pub(crate) fn buffer_stats() -> (u64, u64) {
+    (ALLOCATIONS.load(Ordering::Relaxed), REUSES.load(Ordering::Relaxed))
+}
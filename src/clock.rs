@@ -0,0 +1,49 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 返回当前的Unix时间戳，以毫秒为单位。
+///
+/// 系统时间早于Unix纪元时返回0。
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// 混合逻辑时钟时间戳中逻辑计数器所占的位数。
+const LOGICAL_BITS: u32 = 16;
+
+/// 返回Unix毫秒时间`millis`对应的最小的混合逻辑时钟时间戳，用于按墙上时间比较时间戳。
+pub(crate) fn timestamp_at(millis: u64) -> u64 {
+    millis << LOGICAL_BITS
+}
+
+/// `HybridClock` 是一个混合逻辑时钟（HLC），为每次写入生成单调递增的时间戳。
+///
+/// 时间戳的高48位是Unix毫秒时间，低16位是同一毫秒内（或物理时钟回拨时）的逻辑计数器。
+/// 与单纯的墙上时钟相比，即使物理时钟回拨，时间戳也不会倒退；
+/// 时间戳随条目一起持久化，使不同节点上并发的写入可以被确定性地排序。
+pub(crate) struct HybridClock {
+    /// 最近一次生成或观察到的时间戳。
+    last: u64,
+}
+
+impl HybridClock {
+    /// 创建一个不早于`last`的时钟。
+    pub(crate) fn new(last: u64) -> Self {
+        Self { last }
+    }
+
+    /// 生成一个新的时间戳，保证大于之前生成或观察到的所有时间戳。
+    pub(crate) fn now(&mut self) -> u64 {
+        let physical = timestamp_at(now_millis());
+        self.last = physical.max(self.last + 1);
+        self.last
+    }
+
+    /// 观察一个来自其他存储的时间戳，之后生成的时间戳都会大于它。
+    pub(crate) fn observe(&mut self, timestamp: u64) {
+        self.last = self.last.max(timestamp);
+    }
+}
+
@@ -0,0 +1,111 @@
+use crate::stats::FileStats;
+use std::fmt;
+use std::ops::Range;
+
+/// `CompactionEstimate` 是`BitCask::estimate_compaction`返回的压缩预估结果。
+///
+/// 预估基于调用时刻的内存索引计算，不会读取值或写入任何文件，
+/// 可以据此判断是否值得安排一次压缩。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// 参与压缩的数据文件数量（压缩前活跃文件会被封存，因此包括当前所有数据文件）。
+    pub input_files: usize,
+    /// 参与压缩的数据文件的总字节数。
+    pub input_bytes: u64,
+    /// 参与压缩的数据文件中的记录数量，包括已被覆盖的记录和墓碑。
+    pub input_entries: usize,
+    /// 压缩后保留的记录数量，即未删除且未过期的键的数量。
+    pub output_entries: usize,
+    /// 压缩后新数据文件的预计字节数。
+    pub output_bytes: u64,
+}
+
+impl CompactionEstimate {
+    /// 压缩预计可以回收的字节数。
+    pub fn reclaimable_bytes(&self) -> u64 {
+        self.input_bytes.saturating_sub(self.output_bytes)
+    }
+
+    /// 压缩预计丢弃的记录数量（已被覆盖的记录、墓碑和过期的键）。
+    pub fn dropped_entries(&self) -> usize {
+        self.input_entries.saturating_sub(self.output_entries)
+    }
+}
+
+/// `CompactionStrategy` 决定`BitCask::compact_to_new_dir`合并哪些已经封存的数据文件，见`BitCaskOptions::compaction_strategy`。
+///
+/// 策略只能选择按文件ID连续的一段文件，合并的结果占据这段文件原来的位置，更新的文件中的记录仍然覆盖它，
+/// 它仍然覆盖更旧的文件中的记录。选择的文件不包括最旧的文件时，更旧的文件中可能还有被覆盖的值，
+/// 因此墓碑、过期的键以及被过滤器或保留期限丢弃的键都以墓碑的形式保留在合并的结果中。
+pub trait CompactionStrategy: fmt::Debug + Send + Sync {
+    /// 从封存的数据文件中选出这次要合并的文件。
+    ///
+    /// `files`按文件ID升序（从旧到新）排列，返回其中要合并的范围；返回空范围时不合并任何文件，
+    /// 压缩只把数据文件复制到新的目录。
+    fn select(&self, files: &[FileStats]) -> Range<usize>;
+}
+
+/// `MergeAll` 每次把所有封存的数据文件合并为一个文件，是默认的压缩策略。
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MergeAll;
+
+impl CompactionStrategy for MergeAll {
+    fn select(&self, files: &[FileStats]) -> Range<usize> {
+        0..files.len()
+    }
+}
+
+/// `SizeTiered` 只合并一段大小相近且碎片较多的文件，大的存储不必在每次压缩时重写所有数据。
+///
+/// 在所有满足条件的连续文件中选择可回收字节数（文件大小减去存活记录的大小）最多的一段；
+/// 合并之后的文件比原来的文件更大，之后会与其他同样大小的文件一起合并，没有满足条件的文件时不合并。
+#[derive(Debug, Clone)]
+pub struct SizeTiered {
+    /// 一次至少合并的文件数量。
+    pub min_files: usize,
+    /// 一次最多合并的文件数量。
+    pub max_files: usize,
+    /// 一段文件中最大的文件与最小的文件的字节数之比不能超过这个值。
+    pub size_ratio: f64,
+    /// 一段文件中可回收的字节数占总字节数的比例至少为这个值。
+    pub min_garbage_ratio: f64,
+}
+
+impl Default for SizeTiered {
+    /// 每次合并2到10个大小相差不超过一倍、至少有20%可回收空间的文件。
+    fn default() -> Self {
+        Self {
+            min_files: 2,
+            max_files: 10,
+            size_ratio: 2.0,
+            min_garbage_ratio: 0.2,
+        }
+    }
+}
+
+impl CompactionStrategy for SizeTiered {
+    fn select(&self, files: &[FileStats]) -> Range<usize> {
+        let mut best: Option<(u64, Range<usize>)> = None;
+        for start in 0..files.len() {
+            let (mut smallest, mut largest) = (u64::MAX, 0);
+            let (mut bytes, mut garbage) = (0, 0);
+            for (end, file) in files.iter().enumerate().skip(start).take(self.max_files) {
+                // 空文件按1字节计算，避免比例没有意义
+                smallest = smallest.min(file.bytes.max(1));
+                largest = largest.max(file.bytes.max(1));
+                if largest as f64 > smallest as f64 * self.size_ratio {
+                    break;
+                }
+                bytes += file.bytes;
+                garbage += file.bytes.saturating_sub(file.live_bytes);
+                if end + 1 - start < self.min_files || (garbage as f64) < bytes as f64 * self.min_garbage_ratio {
+                    continue;
+                }
+                if best.as_ref().is_none_or(|(best_garbage, _)| garbage > *best_garbage) {
+                    best = Some((garbage, start..end + 1));
+                }
+            }
+        }
+        best.map_or(0..0, |(_, range)| range)
+    }
+}
@@ -0,0 +1,166 @@
+use crate::bitcask::{Key, Value};
+use crate::error::BitCaskError;
+use crate::log_entry::DiskLogEntry;
+use crate::options::DictionaryCompression;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{trace, warn};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+
+/// 字典文件与数据文件存放在同一个目录中。
+pub(crate) const DICTIONARY_FILE: &str = "dictionary.zstd";
+
+/// 训练好的zstd字典，分别预处理成压缩和解压使用的形式。
+struct Dictionary {
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
+}
+
+impl Dictionary {
+    /// 根据字典的原始字节和压缩级别构建字典。
+    fn new(raw: &[u8], level: i32) -> Self {
+        Self {
+            encoder: EncoderDictionary::copy(raw, level),
+            decoder: DecoderDictionary::copy(raw),
+        }
+    }
+}
+
+/// `DictionaryCompressor` 负责对小值进行zstd字典压缩。
+///
+/// 单条记录很小时，逐条压缩几乎没有效果，因此先从写入的值中采样，
+/// 采样足够后训练一个字典并保存到数据目录，之后的小值都使用该字典压缩。
+/// 只要数据目录中存在字典文件，即使没有开启压缩选项也能读取压缩过的值。
+pub(crate) struct DictionaryCompressor {
+    /// 字典压缩的配置，为`None`时不压缩新的值。
+    options: Option<DictionaryCompression>,
+    /// 已经训练好的字典，与只读快照中的解压器共享。
+    dictionary: Option<Arc<Dictionary>>,
+    /// 训练字典前收集的样本。
+    samples: Vec<Value>,
+    /// 字典文件所在的数据目录。
+    data_dir: PathBuf,
+}
+
+impl DictionaryCompressor {
+    /// 为数据目录创建压缩器，如果目录中已经存在字典文件则加载它。
+    ///
+    /// # 参数
+    /// - `data_dir`: 数据目录的路径。
+    /// - `options`: 字典压缩的配置，为`None`时只用于解压已有的值。
+    pub(crate) fn open(
+        data_dir: &Path,
+        options: Option<DictionaryCompression>,
+    ) -> Result<Self, BitCaskError> {
+        let path = data_dir.join(DICTIONARY_FILE);
+        let dictionary = if path.exists() {
+            let raw = std::fs::read(&path)?;
+            let level = options.as_ref().map(|options| options.level).unwrap_or(0);
+            Some(Arc::new(Dictionary::new(&raw, level)))
+        } else {
+            None
+        };
+        Ok(Self {
+            options,
+            dictionary,
+            samples: Vec::new(),
+            data_dir: data_dir.to_path_buf(),
+        })
+    }
+
+    /// 如果数据目录中存在字典文件，返回它的路径。
+    pub(crate) fn dictionary_path(&self) -> Option<PathBuf> {
+        self.dictionary
+            .as_ref()
+            .map(|_| self.data_dir.join(DICTIONARY_FILE))
+    }
+
+    /// 为键值对构造磁盘日志条目，必要时对值进行字典压缩。
+    ///
+    /// # 说明
+    /// 只有不超过`max_value_size`的值才会被压缩，并且压缩后必须比原值更小，否则按原样存储。
+    /// 字典尚未训练时，值会被采样，样本足够时训练字典并写入数据目录。
+    pub(crate) fn encode(&mut self, key: &Key, value: &Value) -> Result<DiskLogEntry, BitCaskError> {
+        let raw_entry = || DiskLogEntry::new_entry(key.clone(), value.clone());
+        let options = match &self.options {
+            Some(options) if value.len() <= options.max_value_size => options.clone(),
+            _ => return Ok(raw_entry()),
+        };
+
+        let dictionary = match &self.dictionary {
+            Some(dictionary) => dictionary,
+            None => {
+                self.samples.push(value.clone());
+                if self.samples.len() >= options.training_samples {
+                    self.train(&options)?;
+                }
+                return Ok(raw_entry());
+            }
+        };
+
+        let mut encoder =
+            zstd::stream::write::Encoder::with_prepared_dictionary(Vec::new(), &dictionary.encoder)?;
+        encoder.write_all(value)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() >= value.len() {
+            return Ok(raw_entry());
+        }
+        let mut entry = DiskLogEntry::new_entry(key.clone(), compressed);
+        entry.compressed = true;
+        Ok(entry)
+    }
+
+    /// 返回使用当前字典的解压器，用于发布只读快照。
+    pub(crate) fn decoder(&self) -> DictionaryDecoder {
+        DictionaryDecoder {
+            dictionary: self.dictionary.clone(),
+        }
+    }
+
+    /// 使用收集到的样本训练字典，并以先写临时文件再重命名的方式保存到数据目录。
+    ///
+    /// 样本不足以训练字典时只记录日志并丢弃样本，之后重新采样。
+    fn train(&mut self, options: &DictionaryCompression) -> Result<(), BitCaskError> {
+        let samples = std::mem::take(&mut self.samples);
+        let raw = match zstd::dict::from_samples(&samples, options.dictionary_size) {
+            Ok(raw) => raw,
+            Err(e) => {
+                warn!("Failed to train compression dictionary: {:?}", e);
+                return Ok(());
+            }
+        };
+        let tmp = self.data_dir.join(format!("{}.tmp", DICTIONARY_FILE));
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(&raw)?;
+        file.sync_all()?;
+        std::fs::rename(tmp, self.data_dir.join(DICTIONARY_FILE))?;
+        trace!("Trained a {} bytes compression dictionary", raw.len());
+        self.dictionary = Some(Arc::new(Dictionary::new(&raw, options.level)));
+        Ok(())
+    }
+}
+
+/// `DictionaryDecoder` 持有某一时刻的压缩字典，只用于解压，可以在不持有存储锁的情况下使用。
+#[derive(Clone)]
+pub(crate) struct DictionaryDecoder {
+    /// 解压使用的字典，为`None`表示数据目录中还没有字典。
+    dictionary: Option<Arc<Dictionary>>,
+}
+
+impl DictionaryDecoder {
+    /// 解压经过字典压缩的值。
+    ///
+    /// # 错误
+    /// 如果数据目录中没有字典文件或者解压失败，返回`CorruptedData`。
+    pub(crate) fn decode(&self, stored: &[u8]) -> Result<Value, BitCaskError> {
+        let dictionary = self.dictionary.as_ref().ok_or_else(|| {
+            BitCaskError::CorruptedData("compressed value without dictionary".to_string())
+        })?;
+        let mut value = Vec::new();
+        zstd::stream::read::Decoder::with_prepared_dictionary(stored, &dictionary.decoder)
+            .and_then(|mut decoder| decoder.read_to_end(&mut value))
+            .map_err(|e| BitCaskError::CorruptedData(format!("invalid compressed value: {}", e)))?;
+        Ok(value)
+    }
+}
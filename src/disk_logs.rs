@@ -1,18 +1,34 @@
-use crate::bitcask::{FileId, Key, Value};
+use crate::bitcask::{ByteOffset, FileId, Key, Value};
 use crate::error::BitCaskError;
-use crate::log_entry::DiskLogEntry;
-use crate::log_file::DiskLogFile;
+use crate::log_entry::{DiskLogEntry, RecordFormat};
+use crate::history::KeyRecord;
+use crate::keydir::{self, Keydir};
+use crate::log_file::{sync_dir, temp_path, DiskLogFile};
 use crate::memory_index::{MemIndexEntry, MemIndexStorage};
-use std::ffi::OsStr;
-use std::io::{BufReader, Read, Seek, SeekFrom};
-use std::path::PathBuf;
-use tracing::trace;
+use crate::options::{FileEvent, FileHook, FileNaming};
+use crate::value_ref::ValueRef;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::watchdog::Watchdog;
+use tracing::{error, trace};
+
+/// 批量追加时每次写入的最大字节数。
+const BULK_WRITE_SIZE: u64 = 1024 * 1024; // 1MB
+
+/// 批量读取时，同一文件中两个值之间的间隔不超过该字节数就合并为一次读取。
+const MAX_READ_GAP: u64 = 4 * 1024; // 4KB
+
+/// 批量读取时一次合并读取的最大字节数。
+const MAX_READ_SPAN: u64 = 1024 * 1024; // 1MB
 
 /// `DiskLogFileStorage` 结构体用于管理磁盘日志。
 /// 它主要负责维护一组日志文件（DiskLogFile）以及与日志文件相关的元数据。
 pub(crate) struct DiskLogFileStorage {
-    /// 日志文件的集合，每个日志文件可能包含多个日志条目。
-    files: Vec<DiskLogFile>,
+    /// 日志文件的集合，按文件ID升序排列，每个日志文件可能包含多个日志条目。
+    /// 文件以`Arc`共享，只读快照可以在不持有存储锁的情况下读取它们。
+    files: Vec<Arc<DiskLogFile>>,
 
     /// 日志文件所在的目录路径。
     data_dir: PathBuf,
@@ -20,8 +36,23 @@ pub(crate) struct DiskLogFileStorage {
     /// 当前日志文件的大小，用于跟踪何时需要切换到新的日志文件。
     current_file_size: u64,
 
+    /// 已经封存的文件的总大小，加上`current_file_size`就是所有数据文件的总大小。
+    sealed_size: u64,
+
     /// 标识日志是否为不可变状态。一旦日志被标记为不可变，不能再向其写入日志条目。
     immutable: bool,
+
+    /// 数据文件的命名方式。
+    naming: FileNaming,
+
+    /// 文件被封存时调用的钩子，见`BitCaskOptions::file_hook`。
+    file_hook: Option<FileHook>,
+    /// 监视追加和fsync的看门狗，见`BitCaskOptions::io_watchdog`。
+    watchdog: Option<Arc<Watchdog>>,
+    /// 创建新的数据文件之后是否fsync数据目录，见`BitCaskOptions::sync_directory`。
+    sync_directory: bool,
+    /// 活跃文件的大小上限，超过后切换到新的文件，见`BitCaskOptions::max_file_size`。
+    max_file_size: u64,
 }
 
 impl DiskLogFileStorage {
@@ -29,6 +60,7 @@ impl DiskLogFileStorage {
     ///
     /// # 参数
     /// - `immutable_files`: 一个包含不可变文件路径的向量。
+    /// - `naming`: 数据文件的命名方式。
     /// - `mem_index`: 一个指向内存索引的可变引用，用于更新内存中的索引信息。
     ///
     /// # 返回
@@ -39,10 +71,11 @@ impl DiskLogFileStorage {
     /// 最后，使用这些文件、数据目录以及一些初始化标志（如当前文件大小为0和设置不可变为true）来创建并返回一个`Self`实例。
     pub(crate) fn immutable_initialization(
         immutable_files: Vec<PathBuf>,
+        naming: FileNaming,
         mem_index: &mut MemIndexStorage,
     ) -> Result<Self, BitCaskError> {
         // 将不可变文件转换为磁盘日志文件格式，并更新内存索引
-        let files = Self::to_disk_log_files(immutable_files, mem_index)?;
+        let files = Self::to_disk_log_files(immutable_files, &naming, mem_index, &HashMap::new())?;
 
         // 获取数据目录路径
         let data_dir = files.first().unwrap().path.parent().unwrap().to_path_buf();
@@ -52,7 +85,13 @@ impl DiskLogFileStorage {
             files,
             data_dir,
             current_file_size: 0,
+            sealed_size: 0,
             immutable: true,
+            naming,
+            file_hook: None,
+            watchdog: None,
+            sync_directory: false,
+            max_file_size: DiskLogFile::MAX_FILE_SIZE,
         })
     }
 
@@ -60,6 +99,7 @@ impl DiskLogFileStorage {
     ///
     /// # 参数
     /// - `data_dir`: 数据目录的路径，可以转换为`PathBuf`。
+    /// - `naming`: 数据文件的命名方式。
     ///
     /// # 返回值
     /// 返回`Result`类型，包含`Self`（当前实例）或者`BitCaskError`（如果创建过程中发生错误）。
@@ -67,15 +107,22 @@ impl DiskLogFileStorage {
     /// # 说明
     /// 此函数用于初始化一个新的日志文件管理器，它将在指定的数据目录中创建一个文件ID为0的日志文件。
     /// 这个管理器用来处理日志文件的创建、追踪当前文件的大小，并确保文件的不可变性。
-    fn new<T: Into<PathBuf> + Clone>(data_dir: T) -> Result<Self, BitCaskError> {
+    fn new<T: Into<PathBuf> + Clone>(data_dir: T, naming: FileNaming) -> Result<Self, BitCaskError> {
         // 将数据目录路径转换为PathBuf类型，以便于文件操作。
         let data_dir_path_buf: PathBuf = data_dir.clone().into();
         // 创建一个新的日志文件管理器实例，包含一个文件ID为0的日志文件。
+        let file = DiskLogFile::new(data_dir, 0, &naming)?;
         Ok(Self {
-            files: vec![DiskLogFile::new(data_dir, 0)?],
+            current_file_size: file.data_start(),
+            files: vec![Arc::new(file)],
             data_dir: data_dir_path_buf,
-            current_file_size: 0,
+            sealed_size: 0,
             immutable: false,
+            naming,
+            file_hook: None,
+            watchdog: None,
+            sync_directory: false,
+            max_file_size: DiskLogFile::MAX_FILE_SIZE,
         })
     }
 
@@ -84,6 +131,7 @@ impl DiskLogFileStorage {
     ///
     /// # 参数
     /// - `data_dir`: 数据目录的路径，用于查找所有日志文件。
+    /// - `naming`: 数据文件的命名方式，不符合命名方式的文件会被忽略。
     /// - `mem_index`: 内存索引的引用，用于存储日志文件的内容。
     ///
     /// # 返回
@@ -93,38 +141,92 @@ impl DiskLogFileStorage {
     /// 如果数据目录不存在或无法读取，或者当前文件大小无法获取，则返回`BitCarkError`。
     pub(crate) fn from_disk<T: Into<PathBuf>>(
         data_dir: T,
+        naming: FileNaming,
         mem_index: &mut MemIndexStorage,
     ) -> Result<Self, BitCaskError> {
         let data_dir: PathBuf = data_dir.into();
 
-        // 读取数据目录下的所有文件，过滤出日志文件，并转换为`DiskLogFile`对象。
-        let files = std::fs::read_dir(&data_dir)?
-            .filter_map(|path| {
-                path.ok().map(|path| path.path()).filter(|path| {
-                    path.is_file() && path.extension() == Some(OsStr::new(DiskLogFile::EXT))
-                })
-            })
+        // 读取数据目录下的所有文件，过滤出符合命名方式的日志文件，并转换为`DiskLogFile`对象。
+        let paths = std::fs::read_dir(&data_dir)?
+            .filter_map(|path| path.ok().map(|path| path.path()).filter(|path| path.is_file()))
+            .collect::<Vec<_>>();
+        // 创建数据文件或压缩时崩溃留下的临时文件不包含任何已确认的写入，直接删除
+        for path in &paths {
+            if path.extension() == Some("tmp".as_ref()) && naming.file_id(&path.with_extension("")).is_some() {
+                trace!("removing leftover temporary file: {:?}", path);
+                std::fs::remove_file(path)?;
+            }
+        }
+        let files: Vec<PathBuf> = paths
+            .into_iter()
+            .filter(|path| naming.file_id(path).is_some())
             .collect();
-        let files = Self::to_disk_log_files(files, mem_index)?;
+        let loaded = Self::load_keydir(&data_dir, &files, &naming, mem_index)?;
+        let files = Self::to_disk_log_files(files, &naming, mem_index, &loaded)?;
 
         // 如果没有找到日志文件，则从头开始创建新的实例。
         if files.is_empty() {
             trace!("No disk log files found, starting from scratch");
-            return Self::new(data_dir);
+            return Self::new(data_dir, naming);
         }
 
         // 获取最后一个日志文件的大小，作为当前文件大小。
         let current_file_size = files.last().unwrap().file.metadata()?.len();
+        let mut sealed_size = 0;
+        for disk_log_file in &files[..files.len() - 1] {
+            sealed_size += disk_log_file.file.metadata()?.len();
+        }
 
         // 创建实例并返回。
         Ok(Self {
             files,
             data_dir,
             current_file_size,
+            sealed_size,
             immutable: false,
+            naming,
+            file_hook: None,
+            watchdog: None,
+            sync_directory: false,
+            max_file_size: DiskLogFile::MAX_FILE_SIZE,
         })
     }
 
+    /// 如果数据目录中的keydir仍然描述这些数据文件，把它加载到内存索引中，
+    /// 返回每个已经加载的文件需要从哪个偏移量继续加载以及之前的记录数量；否则不加载并返回空的映射。
+    ///
+    /// keydir只保存完整的索引，开启稀疏索引时不使用。上一次正常关闭并且之后数据文件没有变化时，
+    /// 关闭时保存的keydir直接被信任，不再重新校验，见`BitCask::close`。
+    fn load_keydir(
+        data_dir: &Path,
+        files: &[PathBuf],
+        naming: &FileNaming,
+        mem_index: &mut MemIndexStorage,
+    ) -> Result<HashMap<FileId, (ByteOffset, u64)>, BitCaskError> {
+        let clean_shutdown = keydir::take_clean_shutdown(data_dir)?;
+        if mem_index.sparse_interval().is_some() {
+            return Ok(HashMap::new());
+        }
+        let current = files
+            .iter()
+            .filter_map(|path| naming.file_id(path).map(|file_id| (file_id, path)))
+            .map(|(file_id, path)| Ok((file_id, std::fs::metadata(path)?.len())))
+            .collect::<Result<Vec<_>, BitCaskError>>()?;
+        let last_file = current.iter().max_by_key(|(file_id, _)| *file_id).copied();
+        let trusted_checksum = clean_shutdown
+            .filter(|marker| Some(marker.last_file) == last_file)
+            .map(|marker| marker.keydir_checksum);
+        let Some(keydir) = Keydir::open(data_dir, trusted_checksum)? else {
+            return Ok(HashMap::new());
+        };
+        let Some(loaded) = keydir.resume_offsets(&current) else {
+            trace!("ignoring keydir that does not match the data files in {:?}", data_dir);
+            return Ok(HashMap::new());
+        };
+        keydir.load_into(mem_index)?;
+        Ok(loaded)
+    }
+
     /**
      * 获取当前文件和文件ID
      *
@@ -132,29 +234,33 @@ impl DiskLogFileStorage {
      * 它首先找到已经打开的最后一个文件，然后返回该文件和它的ID
      *
      * # 返回值
-     * - `&mut DiskLogFile`: 当前正在使用的日志文件，为可变借用
+     * - `&DiskLogFile`: 当前正在使用的日志文件
      * - `FileId`: 当前文件的ID
      */
-    fn current_file(&mut self) -> (&mut DiskLogFile, FileId) {
+    fn current_file(&self) -> (&DiskLogFile, FileId) {
         // the last file is always open for appending
-        let disk_log_file = self.files.last_mut().unwrap();
+        let disk_log_file = self.files.last().unwrap();
         let file_id = disk_log_file.file_id;
         (disk_log_file, file_id)
     }
 
-    /// 根据文件ID获取磁盘日志文件的引用
-    ///
-    /// # 参数
-    /// - `file_id`: 文件ID，类型为`FileId`，用于标识特定的文件
-    ///
-    /// # 返回值
-    /// 返回一个指向`DiskLogFile`类型的引用，该引用指向由`file_id`指定的文件
-    ///
-    /// # 说明
-    /// 本方法通过将`file_id`转换为`usize`类型，从`self.files`集合中查找并获取对应的文件引用
-    /// 使用`unwrap`方法处理查找结果，这意味着如果文件ID无效或文件不存在于集合中，程序将panic
-    fn get_file(&self, file_id: FileId) -> &DiskLogFile {
-        self.files.get(file_id as usize).unwrap()
+    /// 返回所有数据文件的总字节数，不需要访问文件系统，见`BitCaskOptions::disk_usage_cap`。
+    pub(crate) fn disk_usage(&self) -> u64 {
+        self.sealed_size + self.current_file_size
+    }
+
+    /// 返回当前活跃的日志文件的ID。
+    pub(crate) fn active_file_id(&self) -> FileId {
+        self.files.last().unwrap().file_id
+    }
+
+    /// 检查当前活跃的日志文件是否可以以追加模式打开，用于健康检查。
+    pub(crate) fn active_file_writable(&self) -> bool {
+        let disk_log_file = self.files.last().unwrap();
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&disk_log_file.path)
+            .is_ok()
     }
 
     /// 根据内存索引项获取磁盘中的值
@@ -165,61 +271,85 @@ impl DiskLogFileStorage {
     /// # 返回
     /// - `Result<Value, BitCaskError>`: 返回一个结果，包含请求的值或操作中遇到的错误
     pub(crate) fn get(&self, mem_index_entry: &MemIndexEntry) -> Result<Value, BitCaskError> {
-        // 解构内存索引项以获取值的偏移量、大小和文件ID
-        let MemIndexEntry {
-            value_offset,
-            value_size,
-            file_id,
-        } = mem_index_entry;
-
-        // 根据文件ID获取对应的磁盘日志文件
-        let disk_log_file = self.get_file(*file_id);
-
-        // 创建一个具有指定容量的缓冲读取器，以提高读取性能
-        let mut buffered_reader =
-            BufReader::with_capacity(*value_size as usize, &disk_log_file.file);
-
-        // 将读取器定位到值的开始偏移量位置
-        buffered_reader.seek(SeekFrom::Start(*value_offset))?;
-
-        // 创建一个具有值大小的缓冲区，用于读取值
-        let mut buf = vec![0u8; *value_size as usize];
+        read_value(&self.files, mem_index_entry)
+    }
 
-        // 从缓冲读取器中精确读取值到缓冲区
-        buffered_reader.read_exact(buf.as_mut())?;
+    /// 将活跃文件中已经写入的数据通过fsync持久化到磁盘。
+    pub(crate) fn sync(&self) -> Result<(), BitCaskError> {
+        let (disk_log_file, file_id) = self.current_file();
+        self.watched("fsync", file_id, || Ok(disk_log_file.file.sync_data()?))
+    }
 
-        // 将读取到的缓冲区转换为值对象并返回
-        Ok(Value::from(buf))
+    /// 返回当前所有数据文件的只读视图，用于发布只读快照。
+    pub(crate) fn reader(&self) -> DiskLogReader {
+        DiskLogReader {
+            files: self.files.clone(),
+        }
     }
 
-    /// 向内存索引中插入键值对
+    /// 向磁盘日志中追加一个键值对条目
     ///
     /// # 参数
-    /// - `key`: 键的引用
-    /// - `value`: 值的引用
+    /// - `entry`: 已经构造好的条目，值可能经过了字典压缩
     ///
     /// # 返回
-    /// 返回结果类型`Result`，在成功插入后包含`MemIndexEntry`类型的条目信息，否则包含`BitCaskError`类型的错误信息
-    ///
-    /// # 说明
-    /// 此函数通过克隆键和值，并创建一个新的`DiskLogEntry`条目，将其追加到内存索引中
-    pub(crate) fn put(&mut self, key: &Key, value: &Value) -> Result<MemIndexEntry, BitCaskError> {
-        self.append_log_entry(DiskLogEntry::new_entry(key.clone(), value.clone()))
+    /// 返回结果类型`Result`，在成功追加后包含`MemIndexEntry`类型的条目信息，否则包含`BitCaskError`类型的错误信息
+    pub(crate) fn put_entry(&mut self, entry: DiskLogEntry) -> Result<MemIndexEntry, BitCaskError> {
+        self.append_log_entry(entry)
     }
 
-    /// 从内存索引中删除指定键对应的条目
-    ///
-    /// # 参数
-    /// - `key`: 需要删除的条目在内存索引中的键
-    ///
-    /// # 返回
-    /// - `Result<MemIndexEntry, BitCaskError>`: 如果删除成功，返回被删除的条目；否则返回错误
-    ///
-    /// # 说明
-    /// 此函数通过向磁盘日志添加一个表示删除操作的条目来标记对应键的条目为已删除状态
-    /// 它并不直接从内存索引中移除条目，而是通过添加一个删除标记（tombstone）来实现逻辑删除
-    pub(crate) fn delete(&mut self, key: &Key) -> Result<MemIndexEntry, BitCaskError> {
-        self.append_log_entry(DiskLogEntry::new_tombstone(key.clone()))
+    /// 按顺序批量追加日志条目，返回对应的内存索引条目。
+    ///
+    /// 条目按批次编码后一次写入，每批不超过`BULK_WRITE_SIZE`字节，也不会跨越文件切换，
+    /// 因此批量导入时每个条目不再需要一次单独的写入系统调用。
+    pub(crate) fn put_entries(&mut self, entries: Vec<DiskLogEntry>) -> Result<Vec<MemIndexEntry>, BitCaskError> {
+        let mut index_entries = Vec::with_capacity(entries.len());
+        let mut batch = Vec::new();
+        let mut batch_size = 0;
+        for entry in entries {
+            batch_size += entry.total_byte_size();
+            batch.push(entry);
+            if batch_size >= BULK_WRITE_SIZE || self.current_file_size + batch_size > self.max_file_size {
+                self.append_batch(&mut batch, &mut index_entries)?;
+                batch_size = 0;
+            }
+        }
+        if !batch.is_empty() {
+            self.append_batch(&mut batch, &mut index_entries)?;
+        }
+        Ok(index_entries)
+    }
+
+    /// 把所有条目一次写入当前日志文件，不按`BULK_WRITE_SIZE`拆分，也不会跨越文件切换，
+    /// 用于需要原子提交的批次，见`DiskLogEntry::set_batch_remaining`。
+    pub(crate) fn put_atomic(&mut self, mut entries: Vec<DiskLogEntry>) -> Result<Vec<MemIndexEntry>, BitCaskError> {
+        let mut index_entries = Vec::with_capacity(entries.len());
+        self.append_batch(&mut entries, &mut index_entries)?;
+        Ok(index_entries)
+    }
+
+    /// 将一批条目一次写入当前日志文件，并把它们的内存索引条目追加到`index_entries`中。
+    fn append_batch(
+        &mut self,
+        batch: &mut Vec<DiskLogEntry>,
+        index_entries: &mut Vec<MemIndexEntry>,
+    ) -> Result<(), BitCaskError> {
+        if self.immutable {
+            panic!("Cannot append to an immutable disk log");
+        }
+        let (disk_log_file, file_id) = self.current_file();
+        let start = self.current_file_size;
+        let value_offsets = self
+            .watched("append", file_id, || disk_log_file.append_entries(batch))
+            .inspect_err(|e| roll_back_stalled(disk_log_file, start, batch.len() as u64, e))?;
+        for (entry, value_offset) in batch.drain(..).zip(value_offsets) {
+            self.current_file_size += entry.total_byte_size();
+            index_entries.push(MemIndexEntry::from_log_entry(file_id, value_offset, &entry));
+        }
+        if self.current_file_size > self.max_file_size {
+            self.check_file_size()?;
+        }
+        Ok(())
     }
 
     /// 向当前磁盘日志文件中追加新的日志条目。
@@ -246,22 +376,21 @@ impl DiskLogFileStorage {
         let (disk_log_file, file_id) = self.current_file();
 
         // 将新的日志条目追加到磁盘日志文件中，并获取该条目的偏移量。
-        let value_offset = disk_log_file.append_new_entry(entry.clone())?;
+        let start = self.current_file_size;
+        let value_offset = self
+            .watched("append", file_id, || disk_log_file.append_new_entry(entry.clone()))
+            .inspect_err(|e| roll_back_stalled(disk_log_file, start, 1, e))?;
 
         // 更新当前文件大小。
         self.current_file_size += entry.total_byte_size();
 
         // 检查当前文件大小是否超过最大文件大小，如果超过，则创建一个新的文件。
-        if self.current_file_size > DiskLogFile::MAX_FILE_SIZE {
+        if self.current_file_size > self.max_file_size {
             self.check_file_size()?;
         }
 
         // 返回内存索引条目，包含文件ID、值偏移量和值大小。
-        Ok(MemIndexEntry {
-            file_id,
-            value_offset,
-            value_size: entry.value_byte_size(),
-        })
+        Ok(MemIndexEntry::from_log_entry(file_id, value_offset, &entry))
     }
 
     /// 检查当前日志文件的大小
@@ -275,11 +404,11 @@ impl DiskLogFileStorage {
         // 获取当前正在使用的日志文件和文件ID
         let (disk_log_file, file_id) = self.current_file();
         // 通过文件ID获取文件对象
-        let file = &mut disk_log_file.file;
+        let file = &disk_log_file.file;
         // 获取文件的元数据，包括文件大小等信息
         let file_size = file.metadata()?.len();
         // 检查文件大小是否超过了最大文件大小限制
-        if file_size > DiskLogFile::MAX_FILE_SIZE {
+        if file_size > self.max_file_size {
             // 如果文件过大，记录日志并创建新文件
             trace!(
                 "Disk log file {} exceeds max file size, creating a new file",
@@ -314,20 +443,130 @@ impl DiskLogFileStorage {
 
     /// 当用户调用`compact_to_new_dir`或库函数`check_file_size`时被调用，负责创建一个新的日志文件。
     pub(crate) fn create_new_file(&mut self) -> Result<(), BitCaskError> {
+        // 封存之前先把当前文件持久化，之后只需要同步活跃文件
+        self.sync()?;
+
         // 获取当前最后一个文件的ID，为新文件生成递增的ID。
         let last_file_id = self.files.last().unwrap().file_id;
         let new_file_id = last_file_id + 1;
 
         // 基于新的文件ID创建一个新的日志文件实例。
-        let new_file = DiskLogFile::new(&self.data_dir, new_file_id)?;
+        let new_file = DiskLogFile::new(&self.data_dir, new_file_id, &self.naming)?;
+        if self.sync_directory {
+            sync_dir(&self.data_dir)?;
+        }
+
+        // 将新的日志文件实例添加到文件集合中，新文件从头部之后开始写入。
+        self.sealed_size += self.current_file_size;
+        self.current_file_size = new_file.data_start();
+        self.files.push(Arc::new(new_file));
 
-        // 将新的日志文件实例添加到文件集合中。
-        self.files.push(new_file);
+        // 之前的活跃文件已经被封存
+        if let Some(hook) = &self.file_hook {
+            let sealed = &self.files[self.files.len() - 2];
+            hook.fire(FileEvent::Sealed(sealed.path.clone()));
+        }
 
         // 表示新文件创建成功，无错误返回。
         Ok(())
     }
 
+    /// 设置监视追加和fsync的看门狗。
+    pub(crate) fn set_watchdog(&mut self, watchdog: Option<Arc<Watchdog>>) {
+        self.watchdog = watchdog;
+    }
+
+    /// 在看门狗（如果开启）的监视下对数据文件`file_id`执行一次IO操作，见`BitCaskOptions::io_watchdog`。
+    fn watched<T>(
+        &self,
+        op: &'static str,
+        file_id: FileId,
+        f: impl FnOnce() -> Result<T, BitCaskError>,
+    ) -> Result<T, BitCaskError> {
+        match &self.watchdog {
+            Some(watchdog) => watchdog.run(op, file_id, f),
+            None => f(),
+        }
+    }
+
+    /// 设置文件被封存时调用的钩子。
+    pub(crate) fn set_file_hook(&mut self, file_hook: Option<FileHook>) {
+        self.file_hook = file_hook;
+    }
+
+    /// 设置创建新的数据文件之后是否fsync数据目录。
+    pub(crate) fn set_sync_directory(&mut self, sync_directory: bool) {
+        self.sync_directory = sync_directory;
+    }
+
+    /// 设置活跃文件的大小上限。
+    pub(crate) fn set_max_file_size(&mut self, max_file_size: u64) {
+        self.max_file_size = max_file_size;
+    }
+
+    /// 返回活跃文件中是否还没有任何记录。
+    pub(crate) fn active_file_is_empty(&self) -> bool {
+        self.current_file_size == self.files.last().unwrap().data_start()
+    }
+
+    /// 返回所有数据文件的ID以及其中已经写入的字节数，用于保存keydir。
+    pub(crate) fn file_lengths(&self) -> Result<Vec<(FileId, u64)>, BitCaskError> {
+        self.files
+            .iter()
+            .map(|disk_log_file| Ok((disk_log_file.file_id, disk_log_file.file.metadata()?.len())))
+            .collect()
+    }
+
+    /// 返回每个数据文件的ID、长度和记录数量，用于保存keydir。
+    pub(crate) fn file_records(&self) -> Result<Vec<(FileId, u64, u64)>, BitCaskError> {
+        file_records(&self.files)
+    }
+
+    /// 返回每个数据文件的ID、记录占用的字节数和记录数量，用于统计信息和选择压缩的文件。
+    pub(crate) fn file_usage(&self) -> Result<Vec<(FileId, u64, u64)>, BitCaskError> {
+        file_usage(&self.files)
+    }
+
+    /// 返回所有数据文件的路径以及其中已经写入的字节数，活跃文件使用当前记录的写入位置。
+    pub(crate) fn file_sizes(&self) -> Result<Vec<(PathBuf, u64)>, BitCaskError> {
+        let (active_file, sealed_files) = self.files.split_last().unwrap();
+        let mut sizes = sealed_files
+            .iter()
+            .map(|disk_log_file| Ok((disk_log_file.path.clone(), disk_log_file.file.metadata()?.len())))
+            .collect::<Result<Vec<_>, BitCaskError>>()?;
+        sizes.push((active_file.path.clone(), self.current_file_size));
+        Ok(sizes)
+    }
+
+    /// 在`checkpoint_dir`中创建当前状态的一份自包含副本。
+    ///
+    /// # 参数
+    /// - `checkpoint_dir`: 副本所在的目录，需要已经存在。
+    ///
+    /// # 返回
+    /// - `Result<(), BitCaskError>`: 表示操作结果，如果操作成功则返回 `Ok(())`，否则返回错误 `BitCaskError`
+    ///
+    /// # 说明
+    /// 已封存的文件不会再被修改，因此直接硬链接到新目录（跨文件系统时退化为复制）；
+    /// 活跃文件只复制到当前记录的写入位置，调用方需要保证期间没有新的写入。
+    pub(crate) fn checkpoint(&self, checkpoint_dir: &Path) -> Result<(), BitCaskError> {
+        let (active_file, sealed_files) = self.files.split_last().unwrap();
+        for disk_log_file in sealed_files {
+            let target = checkpoint_dir.join(disk_log_file.path.file_name().unwrap());
+            if std::fs::hard_link(&disk_log_file.path, &target).is_err() {
+                std::fs::copy(&disk_log_file.path, &target)?;
+            }
+        }
+
+        // 活跃文件只复制已经记录的部分
+        let target = checkpoint_dir.join(active_file.path.file_name().unwrap());
+        let mut reader = std::fs::File::open(&active_file.path)?.take(self.current_file_size);
+        let mut target_file = std::fs::File::create(target)?;
+        std::io::copy(&mut reader, &mut target_file)?;
+        target_file.sync_all()?;
+        Ok(())
+    }
+
     /// 将文件复制到新目录，同时排除不可变文件
     ///
     /// # 参数
@@ -338,7 +577,8 @@ impl DiskLogFileStorage {
     /// - `Result<(), BitCaskError>`: 表示操作结果，如果操作成功则返回 `Ok(())`，否则返回错误 `BitCaskError`
     ///
     /// # 说明
-    /// 此函数首先从 `self.files` 中过滤出不在 `immutable_files` 列表中的文件，然后将这些可变文件复制到新的日志文件目录中
+    /// 此函数首先从 `self.files` 中过滤出不在 `immutable_files` 列表中的文件，然后将这些可变文件复制到新的日志文件目录中。
+    /// 每个文件先复制为临时文件并刷新到磁盘，再重命名为正式文件
     pub(crate) fn copy_files_to_new_dir(
         &self,
         immutable_files: Vec<PathBuf>,
@@ -358,7 +598,10 @@ impl DiskLogFileStorage {
             let mut new_file = new_log_file_path.clone();
             new_file.push(file.file_name().unwrap());
             // 执行文件复制操作
-            std::fs::copy(file, new_file)?;
+            let tmp = temp_path(&new_file);
+            std::fs::copy(file, &tmp)?;
+            std::fs::File::open(&tmp)?.sync_all()?;
+            std::fs::rename(tmp, new_file)?;
         }
 
         // 返回操作成功
@@ -369,6 +612,7 @@ impl DiskLogFileStorage {
     ///
     /// # 参数
     /// - `files`: 一个包含文件路径的向量
+    /// - `naming`: 数据文件的命名方式，用于从文件名中解析文件ID
     /// - `mem_index`: 一个内存索引存储的引用，用于与磁盘日志文件交互
     ///
     /// # 返回
@@ -378,30 +622,295 @@ impl DiskLogFileStorage {
     /// 如果文件ID解析失败，或者磁盘日志文件打开失败，或者文件排序失败，则返回错误
     pub(crate) fn to_disk_log_files(
         files: Vec<PathBuf>,
+        naming: &FileNaming,
         mem_index: &mut MemIndexStorage,
-    ) -> Result<Vec<DiskLogFile>, BitCaskError> {
-        // 过滤并映射文件路径，解析文件ID，并尝试打开每个文件作为磁盘日志文件
+        loaded: &HashMap<FileId, (ByteOffset, u64)>,
+    ) -> Result<Vec<Arc<DiskLogFile>>, BitCaskError> {
+        // 过滤并映射文件路径，解析文件ID，按文件ID排序，后写入的记录才能覆盖先写入的记录
         let mut files = files
             .into_iter()
-            .filter_map(|path| {
-                path.file_stem()
-                    .and_then(|file_stem| file_stem.to_str())
-                    .and_then(|file_stem| file_stem.parse::<FileId>().ok())
-                    .map(|file_id| (file_id, path))
-            })
+            .filter_map(|path| naming.file_id(&path).map(|file_id| (file_id, path)))
+            .collect::<Vec<_>>();
+        files.sort_by_key(|(file_id, _)| *file_id);
+
+        // 打开每个文件并填充内存索引，稀疏索引模式下最后一个文件会继续被写入，仍然完整索引
+        let last_file_id = files.last().map(|(file_id, _)| *file_id);
+        files
+            .into_iter()
             .map(|(file_id, path)| {
-                DiskLogFile::open(file_id, path, mem_index)
-                    .map(|disk_log_file| (file_id, disk_log_file))
+                let disk_log_file = Arc::new(DiskLogFile::open(file_id, path)?);
+                let records = match (mem_index.sparse_interval(), loaded.get(&file_id)) {
+                    // 已经通过keydir加载的文件只需要加载之后追加的条目
+                    (_, Some((start, records))) => {
+                        let (loaded, committed) = disk_log_file.populate_mem_index_from(mem_index, *start)?;
+                        disk_log_file.truncate_uncommitted(committed)?;
+                        records + loaded
+                    }
+                    (Some(interval), None) if Some(file_id) != last_file_id => {
+                        disk_log_file.populate_sparse_index(mem_index, interval)?
+                    }
+                    _ => {
+                        let (records, committed) = disk_log_file.populate_mem_index(mem_index)?;
+                        disk_log_file.truncate_uncommitted(committed)?;
+                        records
+                    }
+                };
+                disk_log_file.add_records(records);
+                Ok(disk_log_file)
             })
-            .collect::<Result<Vec<(FileId, DiskLogFile)>, BitCaskError>>()?;
+            .collect()
+    }
+}
 
-        // 按文件ID排序磁盘日志文件
-        files.sort_by_key(|(file_id, _)| *file_id);
+/// `DiskLogReader` 是某一时刻所有数据文件的只读视图。
+///
+/// 它只持有文件的共享引用，因此可以放入只读快照中，在不持有存储锁的情况下读取值。
+#[derive(Clone)]
+pub(crate) struct DiskLogReader {
+    /// 按文件ID升序排列的数据文件。
+    files: Vec<Arc<DiskLogFile>>,
+}
 
-        // 转换元组向量为磁盘日志文件向量并返回
-        Ok(files
-            .into_iter()
-            .map(|(_, disk_log_file)| disk_log_file)
-            .collect())
+impl DiskLogReader {
+    /// 根据按文件ID升序排列的数据文件创建只读视图，用于只读打开的存储，见`reader::ReadOnlyBitCask`。
+    pub(crate) fn new(files: Vec<Arc<DiskLogFile>>) -> Self {
+        DiskLogReader { files }
+    }
+
+    /// 根据内存索引项读取磁盘中的值。
+    pub(crate) fn get(&self, mem_index_entry: &MemIndexEntry) -> Result<Value, BitCaskError> {
+        read_value(&self.files, mem_index_entry)
+    }
+
+    /// 读取多个内存索引项的值，调用方需要先按文件ID和偏移量排序，返回的结果与索引项一一对应。
+    ///
+    /// 同一文件中相邻的值（间隔不超过`MAX_READ_GAP`）合并为一次顺序读取，每个值仍然单独校验；
+    /// 合并读取失败时退回到逐个读取，使每个值得到自己的错误。
+    pub(crate) fn get_sorted(&self, entries: &[&MemIndexEntry]) -> Vec<Result<Value, BitCaskError>> {
+        let mut values = Vec::with_capacity(entries.len());
+        let mut start = 0;
+        while start < entries.len() {
+            let first = entries[start];
+            let span_start = first.value_offset;
+            let mut span_end = first.value_offset + first.value_size;
+            let mut end = start + 1;
+            while let Some(next) = entries.get(end) {
+                let next_end = span_end.max(next.value_offset + next.value_size);
+                if next.file_id != first.file_id
+                    || next.value_offset > span_end + MAX_READ_GAP
+                    || next_end - span_start > MAX_READ_SPAN
+                {
+                    break;
+                }
+                span_end = next_end;
+                end += 1;
+            }
+            let group = &entries[start..end];
+            let disk_log_file = find_file(&self.files, first.file_id);
+            match disk_log_file.read_at(span_start, span_end - span_start) {
+                Ok(span) => values.extend(group.iter().map(|mem_index_entry| {
+                    let offset = (mem_index_entry.value_offset - span_start) as usize;
+                    let value = &span[offset..offset + mem_index_entry.value_size as usize];
+                    verify_checksum(disk_log_file, mem_index_entry, value)?;
+                    Ok(value.to_vec())
+                })),
+                Err(_) => values.extend(group.iter().map(|mem_index_entry| read_value(&self.files, mem_index_entry))),
+            }
+            start = end;
+        }
+        values
+    }
+
+    /// 根据内存索引项将磁盘中的值读取到`buf`中，用于读取后还需要处理（例如解压）的值。
+    pub(crate) fn get_into(&self, mem_index_entry: &MemIndexEntry, buf: &mut Vec<u8>) -> Result<(), BitCaskError> {
+        let disk_log_file = find_file(&self.files, mem_index_entry.file_id);
+        disk_log_file.read_at_into(mem_index_entry.value_offset, mem_index_entry.value_size, buf)?;
+        verify_checksum(disk_log_file, mem_index_entry, buf)
+    }
+
+    /// 读取内存索引项对应的完整记录，校验校验和，并检查记录的大小和标志与索引项是否一致，
+    /// 用于`BitCaskOptions::paranoid_checks`。
+    ///
+    /// 引用条目和大值指针条目校验的是引用记录或指针记录本身，它们指向的值不在这条记录中。
+    pub(crate) fn read_verified(&self, mem_index_entry: &MemIndexEntry) -> Result<DiskLogEntry, BitCaskError> {
+        let disk_log_file = find_file(&self.files, mem_index_entry.file_id);
+        let entry = disk_log_file.read_entry_at(mem_index_entry.record_offset)?;
+        let target = match (entry.blob_target(), entry.reference_target()) {
+            (Some((blob_id, value_size)), _) => (blob_id, value_size, false),
+            (None, Some(target)) => target,
+            (None, None) => (
+                mem_index_entry.record_offset + entry.value_byte_offset(),
+                entry.value_byte_size(),
+                entry.compressed,
+            ),
+        };
+        let expected = (
+            mem_index_entry.value_offset,
+            mem_index_entry.value_size,
+            mem_index_entry.compressed,
+        );
+        if entry.is_tombstone() != mem_index_entry.is_tombstone()
+            || entry.soft_deleted != mem_index_entry.soft_deleted
+            || entry.blob != mem_index_entry.blob
+            || entry.expire_at != mem_index_entry.expire_at
+            || entry.metadata != mem_index_entry.metadata
+            || target != expected
+        {
+            return Err(BitCaskError::CorruptedData(format!(
+                "record at {} in data file {:?} does not match the index",
+                mem_index_entry.record_offset, disk_log_file.path
+            )));
+        }
+        Ok(entry)
+    }
+
+    /// 根据内存索引项返回磁盘中的值，已封存文件中的值直接引用文件的内存映射，不会复制。
+    ///
+    /// 快照中的最后一个文件可能仍在被追加，它的值仍然按位置读取到新的缓冲区中。
+    pub(crate) fn get_ref(&self, mem_index_entry: &MemIndexEntry) -> Result<ValueRef, BitCaskError> {
+        let disk_log_file = find_file(&self.files, mem_index_entry.file_id);
+        let sealed = self
+            .files
+            .last()
+            .is_some_and(|active_file| active_file.file_id != disk_log_file.file_id);
+        if !sealed {
+            let value = disk_log_file.read_at(mem_index_entry.value_offset, mem_index_entry.value_size)?;
+            verify_checksum(disk_log_file, mem_index_entry, &value)?;
+            return Ok(ValueRef::owned(value));
+        }
+        let mmap = disk_log_file.mmap()?;
+        let start = mem_index_entry.value_offset as usize;
+        let end = start + mem_index_entry.value_size as usize;
+        if end > mmap.len() {
+            return Err(BitCaskError::CorruptedData(format!(
+                "value at {}..{} is outside of data file {:?}",
+                start, end, disk_log_file.path
+            )));
+        }
+        verify_checksum(disk_log_file, mem_index_entry, &mmap[start..end])?;
+        Ok(ValueRef::mapped(mmap, start..end))
+    }
+
+    /// 返回数据文件的数量。
+    pub(crate) fn file_count(&self) -> usize {
+        self.files.len()
+    }
+
+    /// 返回每个数据文件的ID、记录占用的字节数和记录数量，记录数量不需要扫描文件。
+    pub(crate) fn file_usage(&self) -> Result<Vec<(FileId, u64, u64)>, BitCaskError> {
+        file_usage(&self.files)
+    }
+
+    /// 返回数据文件的数量、记录占用的总字节数和记录数量，记录数量需要扫描所有文件。
+    pub(crate) fn file_stats(&self) -> Result<(usize, u64, usize), BitCaskError> {
+        let mut bytes = 0;
+        let mut entries = 0;
+        for disk_log_file in &self.files {
+            bytes += disk_log_file.record_bytes()?;
+            disk_log_file.for_each_entry(|_, _| entries += 1)?;
+        }
+        Ok((self.files.len(), bytes, entries))
+    }
+
+    /// 返回所有数据文件中第一版格式的记录数量，见`BitCask::upgrade`。
+    pub(crate) fn v1_record_count(&self) -> Result<usize, BitCaskError> {
+        let mut count = 0;
+        for disk_log_file in &self.files {
+            disk_log_file.for_each_entry(|_, entry| {
+                if entry.format != RecordFormat::V2 {
+                    count += 1;
+                }
+            })?;
+        }
+        Ok(count)
     }
+
+    /// 按写入顺序扫描所有数据文件，返回给定键的所有记录，包括已经被覆盖的记录和墓碑。
+    pub(crate) fn history(&self, key: &Key) -> Result<Vec<KeyRecord>, BitCaskError> {
+        let mut records = Vec::new();
+        for disk_log_file in &self.files {
+            disk_log_file.for_each_entry(|offset, entry| {
+                if &entry.key != key {
+                    return;
+                }
+                records.push(KeyRecord {
+                    file_id: disk_log_file.file_id,
+                    offset,
+                    size: entry.total_byte_size(),
+                    timestamp: entry.timestamp,
+                    expire_at: entry.expire_at,
+                    tombstone: entry.is_tombstone(),
+                    compressed: entry.compressed,
+                    reference: entry.reference,
+                    blob: entry.blob,
+                });
+            })?;
+        }
+        Ok(records)
+    }
+}
+
+/// 在按文件ID升序排列的文件中查找内存索引项所在的文件并读取值。
+///
+/// 压缩后文件ID不一定连续，因此按文件ID二分查找，而不是按位置索引。
+/// 如果文件ID不存在，说明内存索引与数据文件不一致，程序将panic。
+fn read_value(files: &[Arc<DiskLogFile>], mem_index_entry: &MemIndexEntry) -> Result<Value, BitCaskError> {
+    let MemIndexEntry {
+        value_offset,
+        value_size,
+        file_id,
+        ..
+    } = mem_index_entry;
+    let disk_log_file = find_file(files, *file_id);
+    let value = disk_log_file.read_at(*value_offset, *value_size)?;
+    verify_checksum(disk_log_file, mem_index_entry, &value)?;
+    Ok(value)
+}
+
+/// 检查从数据文件中读取的值与内存索引项记录的校验和是否一致，没有校验和的索引项不检查。
+fn verify_checksum(disk_log_file: &DiskLogFile, mem_index_entry: &MemIndexEntry, value: &[u8]) -> Result<(), BitCaskError> {
+    match mem_index_entry.check_sum {
+        Some(check_sum) if check_sum != crc32c::crc32c(value) => Err(BitCaskError::CorruptedData(format!(
+            "checksum mismatch for the value at {} in data file {:?}",
+            mem_index_entry.value_offset, disk_log_file.path
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// 在按文件ID升序排列的文件中二分查找给定ID的文件，找不到时panic。
+fn find_file(files: &[Arc<DiskLogFile>], file_id: FileId) -> &DiskLogFile {
+    let position = files
+        .binary_search_by_key(&file_id, |disk_log_file| disk_log_file.file_id)
+        .unwrap();
+    &files[position]
+}
+
+/// 追加已经完成但超过了看门狗的期限并且需要失败时，撤销这次追加，见`StallAction::Fail`。
+fn roll_back_stalled(disk_log_file: &DiskLogFile, start: ByteOffset, records: u64, e: &BitCaskError) {
+    if !matches!(e, BitCaskError::IoStalled(_)) {
+        return;
+    }
+    if let Err(truncate_error) = disk_log_file.roll_back(start, records) {
+        error!("Error while rolling back a stalled write to {:?}: {:?}", disk_log_file.path, truncate_error);
+    }
+}
+
+/// 返回每个文件的ID、长度和记录数量。
+fn file_records(files: &[Arc<DiskLogFile>]) -> Result<Vec<(FileId, u64, u64)>, BitCaskError> {
+    files
+        .iter()
+        .map(|disk_log_file| {
+            let len = disk_log_file.file.metadata()?.len();
+            Ok((disk_log_file.file_id, len, disk_log_file.record_count()))
+        })
+        .collect()
+}
+
+/// 返回每个文件的ID、记录占用的字节数（不包括文件头部）和记录数量。
+fn file_usage(files: &[Arc<DiskLogFile>]) -> Result<Vec<(FileId, u64, u64)>, BitCaskError> {
+    files
+        .iter()
+        .map(|disk_log_file| Ok((disk_log_file.file_id, disk_log_file.record_bytes()?, disk_log_file.record_count())))
+        .collect()
 }
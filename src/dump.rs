@@ -0,0 +1,94 @@
+use crate::bitcask::{BitCask, KVStorage, Key, Value};
+use crate::error::BitCaskError;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+/// `DumpFormat` 是`dump`和`load`使用的文本格式，键和值都以标准base64编码，因此可以包含任意字节。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// 每行一个JSON对象：`{"key":"<base64>","value":"<base64>"}`。
+    Jsonl,
+    /// 第一行是表头`key,value`，之后每行一个`<base64>,<base64>`。
+    Csv,
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "jsonl" => Ok(DumpFormat::Jsonl),
+            "csv" => Ok(DumpFormat::Csv),
+            _ => Err(format!("unknown dump format {}", name)),
+        }
+    }
+}
+
+/// CSV格式的表头。
+const CSV_HEADER: &str = "key,value";
+
+/// 按键的顺序把所有未删除且未过期的键值对写入`writer`，返回写入的条目数量。
+///
+/// 数据来自调用时固定的快照（见`BitCask::iter`），导出期间的写入不会出现在结果中。
+pub fn dump<W: Write>(bitcask: &BitCask, mut writer: W, format: DumpFormat) -> Result<usize, BitCaskError> {
+    if format == DumpFormat::Csv {
+        writeln!(writer, "{}", CSV_HEADER)?;
+    }
+    let mut count = 0;
+    for pair in bitcask.iter(..) {
+        let (key, value) = pair?;
+        let (key, value) = (STANDARD.encode(key), STANDARD.encode(value));
+        match format {
+            DumpFormat::Jsonl => writeln!(writer, r#"{{"key":"{}","value":"{}"}}"#, key, value)?,
+            DumpFormat::Csv => writeln!(writer, "{},{}", key, value)?,
+        }
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// 从`reader`中逐行读取`dump`导出的键值对并写入存储，返回写入的条目数量。
+///
+/// 空行会被忽略；无法解析的行以`BitCaskError::CorruptedData`返回，并指出行号，之前的行已经被写入。
+pub fn load<R: BufRead>(bitcask: &mut BitCask, reader: R, format: DumpFormat) -> Result<usize, BitCaskError> {
+    let mut count = 0;
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || (format == DumpFormat::Csv && index == 0 && line == CSV_HEADER) {
+            continue;
+        }
+        let (key, value) = parse_line(line, format).ok_or_else(|| {
+            BitCaskError::CorruptedData(format!("invalid {:?} record on line {}", format, index + 1))
+        })?;
+        bitcask.put(&key, &value)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// 解析一行中的键和值。
+fn parse_line(line: &str, format: DumpFormat) -> Option<(Key, Value)> {
+    let (key, value) = match format {
+        DumpFormat::Jsonl => (json_field(line, "key")?, json_field(line, "value")?),
+        DumpFormat::Csv => line.split_once(',')?,
+    };
+    Some((STANDARD.decode(key.trim()).ok()?, STANDARD.decode(value.trim()).ok()?))
+}
+
+/// 在只包含字符串字段的单层JSON对象中查找字段的值。
+///
+/// base64编码的字符串不包含需要转义的字符，因此不需要完整的JSON解析器。
+fn json_field<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let body = line.strip_prefix('{')?.strip_suffix('}')?;
+    body.split(',').find_map(|field| {
+        let (field_name, field_value) = field.split_once(':')?;
+        if field_name.trim() != format!("\"{}\"", name) {
+            return None;
+        }
+        field_value.trim().strip_prefix('"')?.strip_suffix('"')
+    })
+}
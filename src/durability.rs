@@ -0,0 +1,81 @@
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// `DurabilityTracker` 记录已经写入日志的条目数量以及其中已经通过fsync持久化的数量。
+///
+/// 每次写入都会得到一个递增的序号，fsync之后所有不大于当时序号的写入都被视为已持久化，
+/// 等待中的`CommitAck`会被唤醒。
+#[derive(Default)]
+pub(crate) struct DurabilityTracker {
+    /// `(已写入的序号, 已持久化的序号)`。
+    state: Mutex<(u64, u64)>,
+    /// 有新的写入被持久化时通知等待的线程。
+    synced: Condvar,
+}
+
+impl DurabilityTracker {
+    /// 记录一次写入，返回它的序号。
+    pub(crate) fn record_write(&self) -> u64 {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.0 += 1;
+        state.0
+    }
+
+    /// 返回最近一次写入的序号。
+    pub(crate) fn last_written(&self) -> u64 {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).0
+    }
+
+    /// 将不大于`seq`的写入标记为已持久化，并唤醒等待的线程。
+    pub(crate) fn mark_synced(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if seq > state.1 {
+            state.1 = seq;
+            self.synced.notify_all();
+        }
+    }
+}
+
+/// `CommitAck` 是`BitCask::put_with_ack`返回的提交确认句柄，
+/// 当对应的写入真正通过fsync持久化到磁盘后完成。
+///
+/// 在`SyncPolicy::Always`下，句柄返回时已经完成；在其他策略下，
+/// 它会在下一次后台或手动（`BitCask::sync`）fsync之后完成。
+pub struct CommitAck {
+    seq: u64,
+    tracker: Arc<DurabilityTracker>,
+}
+
+impl CommitAck {
+    /// 为序号为`seq`的写入创建确认句柄。
+    pub(crate) fn new(seq: u64, tracker: Arc<DurabilityTracker>) -> Self {
+        Self { seq, tracker }
+    }
+
+    /// 返回写入是否已经持久化，不会阻塞。
+    pub fn is_synced(&self) -> bool {
+        self.tracker.state.lock().unwrap_or_else(PoisonError::into_inner).1 >= self.seq
+    }
+
+    /// 阻塞直到写入被持久化。
+    pub fn wait(&self) {
+        let mut state = self.tracker.state.lock().unwrap_or_else(PoisonError::into_inner);
+        while state.1 < self.seq {
+            state = self.tracker.synced.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// 阻塞直到写入被持久化或者超时，返回写入是否已经持久化。
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.tracker.state.lock().unwrap_or_else(PoisonError::into_inner);
+        while state.1 < self.seq {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            state = self.tracker.synced.wait_timeout(state, remaining).unwrap_or_else(PoisonError::into_inner).0;
+        }
+        true
+    }
+}
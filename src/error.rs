@@ -18,4 +18,47 @@ pub enum BitCaskError {
     /// 当查询一个不存在的键时抛出的错误
     #[error("Key does not exist")]
     KeyNotFound,
+    /// 当写入时期望的版本号与键的当前版本号不一致时抛出的错误
+    #[error("Key version does not match the expected version")]
+    VersionMismatch,
+    /// 当在超时之前无法获得键的应用层锁时抛出的错误
+    #[error("Timed out waiting for the key lock")]
+    LockTimeout,
+    /// 追加数据文件时磁盘空间不足，写入了一部分的记录已经被回滚
+    #[error("No space left on device")]
+    DiskFull,
+    /// 存储因为磁盘空间不足（见`BitCaskOptions::read_only_on_disk_full`）或者IO超过看门狗的期限
+    /// （见`StallAction::ReadOnly`）进入了只读模式
+    #[error("Storage is read-only until disk space is freed")]
+    ReadOnly,
+    /// 数据目录正在被压缩，或者需要排他地使用数据目录时它已经被其他句柄（可能在另一个进程中）打开
+    #[error("Data directory is locked by another store")]
+    DirectoryLocked,
+    /// 存储处于追加模式（见`BitCaskOptions::append_only`），键已经写入后不能再被覆盖或删除
+    #[error("Key is read-only in append-only mode")]
+    ReadOnlyKey,
+    /// 写入时附加的用户元数据超过了`MAX_METADATA_SIZE`字节，{0}是元数据的实际大小
+    #[error("Metadata of {0} bytes exceeds the limit")]
+    MetadataTooLarge(usize),
+    /// 持有存储的线程已经结束（被关闭或者执行命令时panic），见`actor::BitCaskActor`
+    #[error("Storage actor has stopped")]
+    ActorStopped,
+    /// 存储内部的不变量被破坏，例如之前的操作在持有存储的锁时panic，{0}是具体的描述
+    #[error("Internal error: {0}")]
+    Internal(String),
+    /// 追加或fsync超过了IO看门狗的期限，见`BitCaskOptions::io_watchdog`，{0}是超时的操作和耗时
+    #[error("IO operation exceeded the watchdog deadline: {0}")]
+    IoStalled(String),
+    /// 通过`actor::Handle`发送的操作没有在`BitCaskOptions::op_timeout`之内完成，操作本身仍然可能在之后完成
+    #[error("Operation did not complete within the timeout")]
+    Timeout,
+    /// 数据文件的总大小超过了`BitCaskOptions::disk_usage_cap`，写入被拒绝或者阻塞超时
+    #[error("Write stalled because disk usage exceeds the configured cap")]
+    WriteStalled,
+    /// `BitCask::put_all_if`的条件不成立，没有写入任何数据，{0}是第一个不成立的条件的下标
+    #[error("Condition {0} of the conditional batch does not hold")]
+    ConditionFailed(usize),
+    /// 按`keyenc::KeyDecoder`读取的类型无法解码键的剩余部分，{0}是具体的原因
+    #[error("Invalid key encoding: {0}")]
+    InvalidKeyEncoding(String),
 }
\ No newline at end of file
@@ -0,0 +1,209 @@
+//! C语言接口层，用于从C/C++/Go(cgo)等语言中嵌入BitCask存储引擎。
+//!
+//! 对应的头文件位于仓库的`include/bitcask.h`。函数通过返回值表示结果：`0`表示成功，`1`表示键不存在，
+//! `-1`表示发生错误。每个函数体都在`catch_unwind`中执行，内部的panic不会越过`extern "C"`边界，
+//! 而是被记录下来并作为`-1`（或空指针）返回。
+
+use crate::bitcask::{BitCask, KVStorage};
+use std::ffi::{c_char, c_int, CStr};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use tracing::error;
+
+/// 操作成功。
+pub const BITCASK_OK: c_int = 0;
+/// 查询的键不存在。
+pub const BITCASK_NOT_FOUND: c_int = 1;
+/// 操作失败，例如参数无效或IO错误。
+pub const BITCASK_ERROR: c_int = -1;
+
+/// 将C传入的指针和长度转换为字节向量，空指针且长度为0时视为空切片。
+///
+/// # Safety
+/// `data`必须指向至少`len`字节的有效内存，或者为空指针且`len`为0。
+unsafe fn to_bytes(data: *const u8, len: usize) -> Option<Vec<u8>> {
+    if data.is_null() {
+        return if len == 0 { Some(Vec::new()) } else { None };
+    }
+    Some(std::slice::from_raw_parts(data, len).to_vec())
+}
+
+/// 执行一个C接口函数的函数体，发生panic时记录日志并返回`on_panic`，避免panic越过`extern "C"`边界。
+fn guard<T>(on_panic: T, body: impl FnOnce() -> T) -> T {
+    catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|_| {
+        error!("Panic caught at the ffi boundary");
+        on_panic
+    })
+}
+
+/// 打开（或创建）位于`path`的BitCask存储。
+///
+/// # 参数
+/// - `path`: 以NUL结尾的UTF-8数据目录路径。
+///
+/// # 返回
+/// 成功时返回存储句柄，失败时返回空指针。句柄必须通过`bitcask_close`释放。
+///
+/// # Safety
+/// `path`必须是有效的、以NUL结尾的C字符串。
+#[no_mangle]
+pub unsafe extern "C" fn bitcask_open(path: *const c_char) -> *mut BitCask {
+    guard(ptr::null_mut(), || {
+        if path.is_null() {
+            return ptr::null_mut();
+        }
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(path) => path,
+            Err(_) => return ptr::null_mut(),
+        };
+        match BitCask::new(path) {
+            Ok(bitcask) => Box::into_raw(Box::new(bitcask)),
+            Err(e) => {
+                error!("Error while opening bitcask through ffi: {:?}", e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// 根据键读取值。
+///
+/// 找到值时，`*value`指向一块新分配的内存，`*value_len`为其长度，调用方需要用`bitcask_free_value`释放。
+///
+/// # 返回
+/// `BITCASK_OK`、`BITCASK_NOT_FOUND`或`BITCASK_ERROR`；读取磁盘时的IO错误和数据损坏返回`BITCASK_ERROR`，
+/// 而不是`BITCASK_NOT_FOUND`。
+///
+/// # Safety
+/// `db`必须是`bitcask_open`返回的有效句柄，`key`必须指向至少`key_len`字节的内存，
+/// `value`和`value_len`必须是有效的可写指针。
+#[no_mangle]
+pub unsafe extern "C" fn bitcask_get(
+    db: *mut BitCask,
+    key: *const u8,
+    key_len: usize,
+    value: *mut *mut u8,
+    value_len: *mut usize,
+) -> c_int {
+    guard(BITCASK_ERROR, || {
+        if db.is_null() || value.is_null() || value_len.is_null() {
+            return BITCASK_ERROR;
+        }
+        let key = match to_bytes(key, key_len) {
+            Some(key) => key,
+            None => return BITCASK_ERROR,
+        };
+        *value = ptr::null_mut();
+        *value_len = 0;
+        match (*db).try_get(&key) {
+            Ok(Some(found)) => {
+                let found = found.into_boxed_slice();
+                *value_len = found.len();
+                *value = Box::into_raw(found) as *mut u8;
+                BITCASK_OK
+            }
+            Ok(None) => BITCASK_NOT_FOUND,
+            Err(e) => {
+                error!("Error while getting through ffi: {:?}", e);
+                BITCASK_ERROR
+            }
+        }
+    })
+}
+
+/// 写入一个键值对。
+///
+/// # 返回
+/// `BITCASK_OK`或`BITCASK_ERROR`。
+///
+/// # Safety
+/// `db`必须是`bitcask_open`返回的有效句柄，`key`和`value`必须分别指向至少`key_len`和`value_len`字节的内存。
+#[no_mangle]
+pub unsafe extern "C" fn bitcask_put(
+    db: *mut BitCask,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> c_int {
+    guard(BITCASK_ERROR, || {
+        if db.is_null() {
+            return BITCASK_ERROR;
+        }
+        let (key, value) = match (to_bytes(key, key_len), to_bytes(value, value_len)) {
+            (Some(key), Some(value)) => (key, value),
+            _ => return BITCASK_ERROR,
+        };
+        match (*db).put(&key, &value) {
+            Ok(()) => BITCASK_OK,
+            Err(e) => {
+                error!("Error while putting through ffi: {:?}", e);
+                BITCASK_ERROR
+            }
+        }
+    })
+}
+
+/// 删除一个键。
+///
+/// # 返回
+/// `BITCASK_OK`或`BITCASK_ERROR`。
+///
+/// # Safety
+/// `db`必须是`bitcask_open`返回的有效句柄，`key`必须指向至少`key_len`字节的内存。
+#[no_mangle]
+pub unsafe extern "C" fn bitcask_delete(db: *mut BitCask, key: *const u8, key_len: usize) -> c_int {
+    guard(BITCASK_ERROR, || {
+        if db.is_null() {
+            return BITCASK_ERROR;
+        }
+        let key = match to_bytes(key, key_len) {
+            Some(key) => key,
+            None => return BITCASK_ERROR,
+        };
+        match (*db).delete(&key) {
+            Ok(()) => BITCASK_OK,
+            Err(e) => {
+                error!("Error while deleting through ffi: {:?}", e);
+                BITCASK_ERROR
+            }
+        }
+    })
+}
+
+/// 释放`bitcask_get`返回的值。
+///
+/// # Safety
+/// `value`和`value_len`必须来自同一次成功的`bitcask_get`调用，且只能释放一次。
+#[no_mangle]
+pub unsafe extern "C" fn bitcask_free_value(value: *mut u8, value_len: usize) {
+    guard((), || {
+        if value.is_null() {
+            return;
+        }
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(value, value_len)));
+    })
+}
+
+/// 正常关闭存储并释放句柄，与`BitCask::close`相同：持久化所有写入，保存keydir，并写入正常关闭的标记。
+///
+/// # 返回
+/// `BITCASK_OK`或`BITCASK_ERROR`。无论关闭是否成功，句柄都会被释放；空指针返回`BITCASK_OK`。
+///
+/// # Safety
+/// `db`必须是`bitcask_open`返回的句柄或空指针，且关闭后不能再使用。
+#[no_mangle]
+pub unsafe extern "C" fn bitcask_close(db: *mut BitCask) -> c_int {
+    guard(BITCASK_ERROR, || {
+        if db.is_null() {
+            return BITCASK_OK;
+        }
+        match Box::from_raw(db).close() {
+            Ok(()) => BITCASK_OK,
+            Err(e) => {
+                error!("Error while closing bitcask through ffi: {:?}", e);
+                BITCASK_ERROR
+            }
+        }
+    })
+}
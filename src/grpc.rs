@@ -0,0 +1,242 @@
+use crate::bitcask::{prefix_range, BitCask, Key};
+use crate::error::BitCaskError;
+use crate::iter::Iter;
+use std::ops::Bound;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::codegen::{http, Body, BoxFuture, Bytes, Context, Poll, Service, StdError};
+use tonic::{Request, Response, Status};
+use tonic_prost::ProstCodec;
+
+/// gRPC服务的名字，与`proto/bitcask.proto`中的`package bitcask; service BitCask`对应。
+pub const SERVICE_NAME: &str = "bitcask.BitCask";
+
+/// `Scan`方法的路径。
+const SCAN_PATH: &str = "/bitcask.BitCask/Scan";
+
+/// `ScanRequest::batch_size`为0时每条响应包含的键值对数量。
+const DEFAULT_BATCH_SIZE: usize = 100;
+
+/// `ScanRequest::batch_size`允许的最大值。
+const MAX_BATCH_SIZE: usize = 10_000;
+
+/// 一条响应中键和值的总字节数达到该值时提前结束这一批，使响应远小于gRPC默认的4 MB消息上限。
+const MAX_BATCH_BYTES: usize = 1024 * 1024;
+
+/// 每个流中已经读出但还没有发送的响应的最大数量，见`BitCaskGrpc`。
+const STREAM_BUFFER: usize = 4;
+
+/// `Scan`的请求。同时给出前缀和范围时遍历它们的交集。
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ScanRequest {
+    /// 只返回以该前缀开头的键，为空时不限制。
+    #[prost(bytes = "vec", tag = "1")]
+    pub prefix: Vec<u8>,
+    /// 键的下界（包含），为空时不限制。
+    #[prost(bytes = "vec", tag = "2")]
+    pub start: Vec<u8>,
+    /// 键的上界（不包含），为空时不限制。
+    #[prost(bytes = "vec", tag = "3")]
+    pub end: Vec<u8>,
+    /// 每条响应最多包含的键值对数量，为0时使用默认值100，不能超过10000。
+    #[prost(uint32, tag = "4")]
+    pub batch_size: u32,
+}
+
+/// 一个键值对。
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct KeyValue {
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+}
+
+/// `Scan`的一条响应，包含按键升序排列的一批键值对。
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ScanResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub pairs: Vec<KeyValue>,
+}
+
+/// `BitCaskGrpc` 是存储的gRPC服务，通过`tonic::transport::Server::add_service`挂载，接口见`proto/bitcask.proto`。
+///
+/// 服务端流式的`Scan`按批返回一个前缀或范围内的所有键值对，客户端不需要一次接收整个结果：
+/// - 迭代器在收到请求时创建，固定了当时的快照（见`BitCask::iter`），整个流都读取同一个快照，
+///   之后的写入、删除和压缩不会影响结果；
+/// - 键值对在阻塞线程池中逐批读取，最多缓冲4批。客户端读取得慢时HTTP/2的流量控制让发送停下，
+///   缓冲区满之后读取也随之暂停，因此每个流占用的内存有上限；暂停期间流占用阻塞线程池中的一个线程；
+/// - 客户端取消或断开后读取在下一批停止。
+///
+/// 读取失败时流以`INTERNAL`状态结束，之前发送的批次仍然有效。
+#[derive(Clone)]
+pub struct BitCaskGrpc {
+    bitcask: BitCask,
+}
+
+impl BitCaskGrpc {
+    pub fn new(bitcask: BitCask) -> Self {
+        Self { bitcask }
+    }
+
+    /// 创建`Scan`的响应流，并在阻塞线程池中开始读取。
+    fn scan(&self, request: ScanRequest) -> Result<ReceiverStream<Result<ScanResponse, Status>>, Status> {
+        let batch_size = match request.batch_size as usize {
+            0 => DEFAULT_BATCH_SIZE,
+            batch_size if batch_size > MAX_BATCH_SIZE => {
+                return Err(Status::invalid_argument(format!("batch_size must not exceed {}", MAX_BATCH_SIZE)));
+            }
+            batch_size => batch_size,
+        };
+        // 迭代器在这里创建，此时固定快照
+        let iter = scan_range(&request).map(|range| self.bitcask.iter(range));
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn_blocking(move || send_batches(iter, batch_size, sender));
+        Ok(ReceiverStream::new(receiver))
+    }
+}
+
+/// 返回前缀与`[start, end)`的交集，交集为空时返回`None`。
+fn scan_range(request: &ScanRequest) -> Option<(Bound<Key>, Bound<Key>)> {
+    let (_, upper) = prefix_range(&request.prefix);
+    let lower = request.prefix.clone().max(request.start.clone());
+    let upper = match (upper, request.end.is_empty()) {
+        (upper, true) => upper,
+        (Bound::Excluded(upper), false) => Bound::Excluded(upper.min(request.end.clone())),
+        (_, false) => Bound::Excluded(request.end.clone()),
+    };
+    match &upper {
+        Bound::Excluded(upper) if lower >= *upper => None,
+        _ => Some((Bound::Included(lower), upper)),
+    }
+}
+
+/// 按批读取键值对并发送，接收端被丢弃（客户端取消或断开）时停止。
+fn send_batches(iter: Option<Iter>, batch_size: usize, sender: mpsc::Sender<Result<ScanResponse, Status>>) {
+    let Some(iter) = iter else {
+        return;
+    };
+    let mut pairs = Vec::new();
+    let mut bytes = 0;
+    for pair in iter {
+        let (key, value) = match pair {
+            Ok(pair) => pair,
+            Err(e) => {
+                let _ = sender.blocking_send(Err(scan_error(e)));
+                return;
+            }
+        };
+        bytes += key.len() + value.len();
+        pairs.push(KeyValue { key, value });
+        if pairs.len() < batch_size && bytes < MAX_BATCH_BYTES {
+            continue;
+        }
+        // 缓冲区已满时在这里等待客户端读取
+        if sender.blocking_send(Ok(ScanResponse { pairs: std::mem::take(&mut pairs) })).is_err() {
+            return;
+        }
+        bytes = 0;
+    }
+    if !pairs.is_empty() {
+        let _ = sender.blocking_send(Ok(ScanResponse { pairs }));
+    }
+}
+
+fn scan_error(e: BitCaskError) -> Status {
+    Status::internal(format!("failed to read from the store: {}", e))
+}
+
+/// `Scan`方法，供`tonic::server::Grpc`调用。
+struct ScanSvc(BitCaskGrpc);
+
+impl tonic::server::ServerStreamingService<ScanRequest> for ScanSvc {
+    type Response = ScanResponse;
+    type ResponseStream = ReceiverStream<Result<ScanResponse, Status>>;
+    type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+    fn call(&mut self, request: Request<ScanRequest>) -> Self::Future {
+        let res = self.0.scan(request.into_inner()).map(Response::new);
+        Box::pin(std::future::ready(res))
+    }
+}
+
+/// 与`tonic-build`生成的服务相同：按路径分发请求，未知的方法返回`UNIMPLEMENTED`。
+impl<B> Service<http::Request<B>> for BitCaskGrpc
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        match req.uri().path() {
+            SCAN_PATH => {
+                let method = ScanSvc(self.clone());
+                Box::pin(async move {
+                    let mut grpc = tonic::server::Grpc::new(ProstCodec::default());
+                    Ok(grpc.server_streaming(method, req).await)
+                })
+            }
+            _ => Box::pin(async move {
+                let mut response = http::Response::new(tonic::body::Body::default());
+                let headers = response.headers_mut();
+                headers.insert(Status::GRPC_STATUS, (tonic::Code::Unimplemented as i32).into());
+                headers.insert(http::header::CONTENT_TYPE, tonic::metadata::GRPC_CONTENT_TYPE);
+                Ok(response)
+            }),
+        }
+    }
+}
+
+impl tonic::server::NamedService for BitCaskGrpc {
+    const NAME: &'static str = SERVICE_NAME;
+}
+
+/// `ScanClient` 是`BitCaskGrpc`的客户端。
+#[derive(Debug, Clone)]
+pub struct ScanClient<T> {
+    inner: tonic::client::Grpc<T>,
+}
+
+impl ScanClient<tonic::transport::Channel> {
+    /// 连接到给定的地址，例如`http://127.0.0.1:50051`。
+    pub async fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+    where
+        D: TryInto<tonic::transport::Endpoint>,
+        D::Error: Into<StdError>,
+    {
+        let channel = tonic::transport::Endpoint::new(dst)?.connect().await?;
+        Ok(Self::new(channel))
+    }
+}
+
+impl<T> ScanClient<T>
+where
+    T: tonic::client::GrpcService<tonic::body::Body>,
+    T::Error: Into<StdError>,
+    T::ResponseBody: Body<Data = Bytes> + Send + 'static,
+    <T::ResponseBody as Body>::Error: Into<StdError> + Send,
+{
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner: tonic::client::Grpc::new(inner),
+        }
+    }
+
+    /// 调用`Scan`，返回按批产生键值对的流。
+    pub async fn scan(&mut self, request: ScanRequest) -> Result<tonic::codec::Streaming<ScanResponse>, Status> {
+        self.inner
+            .ready()
+            .await
+            .map_err(|e| Status::unknown(format!("Service was not ready: {}", e.into())))?;
+        let path = http::uri::PathAndQuery::from_static(SCAN_PATH);
+        let response = self.inner.server_streaming(Request::new(request), path, ProstCodec::default()).await?;
+        Ok(response.into_inner())
+    }
+}
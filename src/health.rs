@@ -0,0 +1,81 @@
+use crate::error::BitCaskError;
+use std::collections::VecDeque;
+use std::sync::{Mutex, PoisonError};
+
+/// `Health` 结构体是`BitCask::health()`返回的健康检查报告，
+/// 可以直接用于服务的就绪探针（readiness probe）。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Health {
+    /// 当前活跃的日志文件是否可以以追加模式打开。
+    pub active_file_writable: bool,
+    /// 数据目录所在磁盘的可用空间，以字节为单位。
+    pub available_space: u64,
+    /// 配置的最小可用空间阈值，以字节为单位。
+    pub min_available_space: u64,
+    /// 参与统计的最近操作数量。
+    pub recent_ops: usize,
+    /// 最近的操作中发生IO错误的数量。
+    pub recent_io_errors: usize,
+    /// 存储是否因为磁盘空间不足进入了只读模式，见`BitCaskOptions::read_only_on_disk_full`。
+    pub read_only: bool,
+    /// 打开存储以来超过IO看门狗期限的追加和fsync的数量，见`BitCaskOptions::io_watchdog`。
+    pub io_stalls: u64,
+    /// 存储是否因为IO超过期限进入了只读的降级模式，见`StallAction::ReadOnly`。
+    pub degraded: bool,
+}
+
+impl Health {
+    /// 判断存储是否健康。
+    ///
+    /// 只有当活跃文件可写、不在只读或降级模式、可用空间不低于阈值并且最近的操作没有IO错误时才返回`true`。
+    pub fn is_healthy(&self) -> bool {
+        self.active_file_writable
+            && !self.read_only
+            && !self.degraded
+            && self.available_space >= self.min_available_space
+            && self.recent_io_errors == 0
+    }
+}
+
+/// `OpHistory` 记录最近N次操作是否发生了IO错误，用于健康检查。
+///
+/// 由于`get`只持有存储的读锁，这里使用`Mutex`提供内部可变性。
+pub(crate) struct OpHistory {
+    /// 最多记录的操作数量。
+    capacity: usize,
+    /// 最近的操作结果，`true`表示该操作发生了IO错误。
+    outcomes: Mutex<VecDeque<bool>>,
+}
+
+impl OpHistory {
+    /// 创建一个最多记录`capacity`次操作的历史记录。
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            outcomes: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// 记录一次操作的结果。只有IO错误（包括磁盘空间不足和超过看门狗期限）会被视为失败，例如`KeyExists`这类业务错误不会影响健康状态。
+    pub(crate) fn record<T>(&self, res: &Result<T, BitCaskError>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let io_error = matches!(
+            res,
+            Err(BitCaskError::IoError(_) | BitCaskError::DiskFull | BitCaskError::IoStalled(_))
+        );
+        let mut outcomes = self.outcomes.lock().unwrap_or_else(PoisonError::into_inner);
+        if outcomes.len() == self.capacity {
+            outcomes.pop_front();
+        }
+        outcomes.push_back(io_error);
+    }
+
+    /// 返回记录的操作数量以及其中发生IO错误的数量。
+    pub(crate) fn summary(&self) -> (usize, usize) {
+        let outcomes = self.outcomes.lock().unwrap_or_else(PoisonError::into_inner);
+        let errors = outcomes.iter().filter(|io_error| **io_error).count();
+        (outcomes.len(), errors)
+    }
+}
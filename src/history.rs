@@ -0,0 +1,22 @@
+/// `KeyRecord` 是`BitCask::history`返回的某个键在数据文件中的一条记录。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRecord {
+    /// 记录所在的数据文件ID。
+    pub file_id: usize,
+    /// 记录在文件中的起始偏移量。
+    pub offset: u64,
+    /// 记录在文件中占用的字节数，包括头部和键。
+    pub size: u64,
+    /// 记录的混合逻辑时钟时间戳，旧版本写入的记录没有时间戳。
+    pub timestamp: Option<u64>,
+    /// 记录的过期时间（Unix毫秒时间戳）。
+    pub expire_at: Option<u64>,
+    /// 记录是否为删除键的墓碑。
+    pub tombstone: bool,
+    /// 记录的值是否经过了字典压缩。
+    pub compressed: bool,
+    /// 记录是否为指向相同值的引用（去重）。
+    pub reference: bool,
+    /// 记录是否为指向独立大值文件的指针。
+    pub blob: bool,
+}
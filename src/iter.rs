@@ -0,0 +1,85 @@
+use crate::bitcask::{Key, Value};
+use crate::clock::now_millis;
+use crate::error::BitCaskError;
+use crate::memory_index::{MemIndexEntry, MemIndexStorage};
+use crate::snapshot::ReadSnapshot;
+use std::collections::VecDeque;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+/// 每次从内存索引中取出的条目数量。
+const BATCH_SIZE: usize = 64;
+
+/// `Iter` 是`BitCask::iter`返回的按键顺序遍历键值对的迭代器。
+///
+/// 迭代器在创建时固定一个读快照：快照中的内存索引版本和数据文件集合在迭代器存在期间保持不变，
+/// 之后的写入、删除、压缩以及压缩后对旧文件的删除都不会影响迭代的结果，
+/// 读到的偏移量也不会因为文件被替换而失效（快照持有已打开的文件句柄）。
+/// 是否过期按创建迭代器的时刻判断。
+///
+/// 迭代器持有旧文件的句柄，长时间不释放会推迟旧文件所占磁盘空间的回收。
+pub struct Iter {
+    /// 创建迭代器时的读快照。
+    snapshot: Arc<ReadSnapshot>,
+    /// 快照中包含所有键的索引，稀疏索引模式下在创建迭代器时扫描一次数据文件得到。
+    index: MemIndexStorage,
+    /// 创建迭代器的时刻，用于判断键是否过期。
+    now: u64,
+    /// 尚未遍历部分的下界。
+    lower: Bound<Key>,
+    /// 遍历范围的上界。
+    upper: Bound<Key>,
+    /// 已经从内存索引中取出但尚未返回的条目。
+    pending: VecDeque<(Key, MemIndexEntry)>,
+    /// 内存索引中的范围是否已经遍历完毕。
+    exhausted: bool,
+}
+
+impl Iter {
+    /// 在给定的快照上创建遍历`range`的迭代器。
+    pub(crate) fn new<R: RangeBounds<Key>>(snapshot: Arc<ReadSnapshot>, range: R) -> Self {
+        Self::at(snapshot, range, now_millis())
+    }
+
+    /// 与`new`相同，但按给定的时刻（Unix毫秒时间戳）判断键是否过期，见`ReadTxn`。
+    pub(crate) fn at<R: RangeBounds<Key>>(snapshot: Arc<ReadSnapshot>, range: R, now: u64) -> Self {
+        Iter {
+            index: snapshot.full_index().into_owned(),
+            snapshot,
+            now,
+            lower: range.start_bound().cloned(),
+            upper: range.end_bound().cloned(),
+            pending: VecDeque::new(),
+            exhausted: false,
+        }
+    }
+
+    /// 从内存索引中取出下一批未删除且未过期的条目。
+    fn refill(&mut self) {
+        let now = self.now;
+        let batch: Vec<(Key, MemIndexEntry)> = self
+            .index
+            .range((self.lower.clone(), self.upper.clone()))
+            .filter(|(_, mem_index_entry)| mem_index_entry.is_live(now))
+            .take(BATCH_SIZE)
+            .map(|(key, mem_index_entry)| (key.to_vec(), mem_index_entry.clone()))
+            .collect();
+        self.exhausted = batch.len() < BATCH_SIZE;
+        if let Some((key, _)) = batch.last() {
+            self.lower = Bound::Excluded(key.clone());
+        }
+        self.pending.extend(batch);
+    }
+}
+
+impl Iterator for Iter {
+    type Item = Result<(Key, Value), BitCaskError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pending.is_empty() && !self.exhausted {
+            self.refill();
+        }
+        let (key, mem_index_entry) = self.pending.pop_front()?;
+        Some(self.snapshot.read_value(&mem_index_entry).map(|value| (key, value)))
+    }
+}
@@ -0,0 +1,319 @@
+use crate::bitcask::{ByteOffset, FileId};
+use crate::error::BitCaskError;
+use crate::log_file::temp_path;
+use crate::memory_index::{MemIndexEntry, MemIndexStorage};
+use crate::trace::{encode_bytes, PayloadCursor};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use tracing::trace;
+
+/// 数据目录中保存内存索引的提示文件（hint file）的文件名。
+pub(crate) const KEYDIR_FILE: &str = "keydir";
+
+/// 正常关闭时写入的标记文件名，见`write_clean_shutdown`。
+pub(crate) const CLEAN_SHUTDOWN_FILE: &str = "clean-shutdown";
+
+const KEYDIR_MAGIC: &[u8; 4] = b"BCKD";
+const KEYDIR_VERSION: u32 = 1;
+
+/// 加载时最后一条记录是墓碑的键，只保存删除时间戳。
+const ENTRY_REMOVED: u8 = 1;
+const ENTRY_COMPRESSED: u8 = 1 << 1;
+const ENTRY_BLOB: u8 = 1 << 2;
+const ENTRY_EXPIRY: u8 = 1 << 3;
+const ENTRY_INLINE: u8 = 1 << 4;
+const ENTRY_CHECKSUM: u8 = 1 << 5;
+/// 软删除的墓碑，与未删除的键一样保存值的位置。
+const ENTRY_SOFT_DELETED: u8 = 1 << 6;
+const ENTRY_METADATA: u8 = 1 << 7;
+
+/// 把内存索引保存到数据目录中的keydir文件，之后打开存储时可以从它重建索引而不必扫描数据文件。
+///
+/// keydir只是加快打开的提示文件：打开时它的条目被解码到通常的内存索引中，查找从不直接读取这个文件，
+/// keydir缺失、损坏或者过期时存储仍然可以从数据文件重建索引。
+///
+/// 文件格式为：
+///
+/// ```text
+/// "BCKD" | 版本 | 文件数量 | (文件ID | 文件长度 | 记录数量)* | 条目数量 | 条目* | 请求ID数量 | (请求ID | 时间戳)* | CRC32C
+/// ```
+///
+/// 每个条目为`标志 | 键 | 时间戳`，未删除的键和软删除的键之后是`文件ID | 偏移量 | 值大小 | 记录偏移量`，
+/// 以及可选的过期时间、校验和、内联值与用户元数据。`files`是保存时每个数据文件的长度和记录数量，
+/// 索引必须恰好反映这些字节。
+/// 文件先写入临时文件再原子地重命名，保存过程中崩溃不会留下不完整的keydir。返回文件末尾的校验和。
+pub(crate) fn save(data_dir: &Path, files: &[(FileId, u64, u64)], mem_index: &MemIndexStorage) -> Result<u32, BitCaskError> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(KEYDIR_MAGIC);
+    buf.extend_from_slice(&KEYDIR_VERSION.to_le_bytes());
+    buf.extend_from_slice(&(files.len() as u32).to_le_bytes());
+    for (file_id, len, records) in files {
+        buf.extend_from_slice(&(*file_id as u64).to_le_bytes());
+        buf.extend_from_slice(&len.to_le_bytes());
+        buf.extend_from_slice(&records.to_le_bytes());
+    }
+    let count_position = buf.len();
+    buf.extend_from_slice(&0u64.to_le_bytes());
+    let mut count = 0u64;
+    for (key, entry) in mem_index.range(..) {
+        // 运行时删除的键以墓碑条目留在索引中，重新打开时它们与加载时遇到的墓碑一样只保留删除时间，
+        // 软删除的键还需要保留值的位置
+        if entry.is_tombstone() && !entry.soft_deleted {
+            encode_removed(&mut buf, key, entry.timestamp);
+        } else {
+            encode_entry(&mut buf, key, entry);
+        }
+        count += 1;
+    }
+    for (key, timestamp) in mem_index.removed() {
+        encode_removed(&mut buf, key, timestamp);
+        count += 1;
+    }
+    buf[count_position..count_position + 8].copy_from_slice(&count.to_le_bytes());
+    let request_ids: Vec<(u64, u64)> = mem_index.request_ids().collect();
+    buf.extend_from_slice(&(request_ids.len() as u64).to_le_bytes());
+    for (request_id, timestamp) in request_ids {
+        buf.extend_from_slice(&request_id.to_le_bytes());
+        buf.extend_from_slice(&timestamp.to_le_bytes());
+    }
+    let checksum = crc32c::crc32c(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    write_atomically(&data_dir.join(KEYDIR_FILE), &buf)?;
+    Ok(checksum)
+}
+
+/// 先写入临时文件并刷新到磁盘，再原子地重命名为`path`。
+fn write_atomically(path: &Path, buf: &[u8]) -> Result<(), BitCaskError> {
+    let tmp = temp_path(path);
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(buf)?;
+    file.sync_all()?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// `CleanShutdown` 是正常关闭时写入的标记：关闭时最后一个数据文件的ID和长度，以及同时保存的keydir的校验和。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CleanShutdown {
+    pub(crate) last_file: (FileId, u64),
+    pub(crate) keydir_checksum: u32,
+}
+
+/// 正常关闭时在保存keydir之后写入标记文件。
+pub(crate) fn write_clean_shutdown(data_dir: &Path, marker: CleanShutdown) -> Result<(), BitCaskError> {
+    let mut buf = Vec::with_capacity(24);
+    buf.extend_from_slice(&(marker.last_file.0 as u64).to_le_bytes());
+    buf.extend_from_slice(&marker.last_file.1.to_le_bytes());
+    buf.extend_from_slice(&marker.keydir_checksum.to_le_bytes());
+    buf.extend_from_slice(&crc32c::crc32c(&buf).to_le_bytes());
+    write_atomically(&data_dir.join(CLEAN_SHUTDOWN_FILE), &buf)
+}
+
+/// 读取并删除正常关闭的标记，没有标记或者标记损坏时返回`None`。
+///
+/// 标记在打开时就被删除，之后的写入或崩溃不会让下一次打开误以为存储是正常关闭的。
+pub(crate) fn take_clean_shutdown(data_dir: &Path) -> Result<Option<CleanShutdown>, BitCaskError> {
+    let path = data_dir.join(CLEAN_SHUTDOWN_FILE);
+    let buf = match std::fs::read(&path) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    std::fs::remove_file(&path)?;
+    let Some((body, checksum)) = buf.split_last_chunk::<4>() else {
+        return Ok(None);
+    };
+    if body.len() != 20 || crc32c::crc32c(body) != u32::from_le_bytes(*checksum) {
+        trace!("ignoring corrupted clean shutdown marker: {:?}", path);
+        return Ok(None);
+    }
+    let mut cursor = PayloadCursor::new(body);
+    Ok(Some(CleanShutdown {
+        last_file: (cursor.u64()? as FileId, cursor.u64()?),
+        keydir_checksum: cursor.u32()?,
+    }))
+}
+
+/// 写入一个未删除或者软删除的键。
+fn encode_entry(buf: &mut Vec<u8>, key: &[u8], entry: &MemIndexEntry) {
+    let mut flags = 0;
+    for (set, flag) in [
+        (entry.compressed, ENTRY_COMPRESSED),
+        (entry.blob, ENTRY_BLOB),
+        (entry.expire_at.is_some(), ENTRY_EXPIRY),
+        (entry.inline_value.is_some(), ENTRY_INLINE),
+        (entry.check_sum.is_some(), ENTRY_CHECKSUM),
+        (entry.soft_deleted, ENTRY_SOFT_DELETED),
+        (entry.metadata.is_some(), ENTRY_METADATA),
+    ] {
+        if set {
+            flags |= flag;
+        }
+    }
+    buf.push(flags);
+    encode_bytes(buf, key);
+    buf.extend_from_slice(&entry.timestamp.to_le_bytes());
+    buf.extend_from_slice(&(entry.file_id as u64).to_le_bytes());
+    buf.extend_from_slice(&entry.value_offset.to_le_bytes());
+    buf.extend_from_slice(&entry.value_size.to_le_bytes());
+    buf.extend_from_slice(&entry.record_offset.to_le_bytes());
+    if let Some(expire_at) = entry.expire_at {
+        buf.extend_from_slice(&expire_at.to_le_bytes());
+    }
+    if let Some(check_sum) = entry.check_sum {
+        buf.extend_from_slice(&check_sum.to_le_bytes());
+    }
+    if let Some(inline_value) = &entry.inline_value {
+        encode_bytes(buf, inline_value);
+    }
+    if let Some(metadata) = &entry.metadata {
+        encode_bytes(buf, metadata);
+    }
+}
+
+/// 写入一个已删除的键。
+fn encode_removed(buf: &mut Vec<u8>, key: &[u8], timestamp: u64) {
+    buf.push(ENTRY_REMOVED);
+    encode_bytes(buf, key);
+    buf.extend_from_slice(&timestamp.to_le_bytes());
+}
+
+/// `Keydir` 是读入内存的keydir文件，见`save`。
+pub(crate) struct Keydir {
+    buf: Vec<u8>,
+    /// 保存时每个数据文件的ID、长度和记录数量。
+    files: Vec<(FileId, u64, u64)>,
+    /// 条目数量。
+    count: u64,
+    /// 第一个条目在文件中的位置。
+    entries_start: usize,
+}
+
+impl Keydir {
+    /// 打开数据目录中的keydir文件，文件不存在、校验和不匹配或版本不支持时返回`None`。
+    ///
+    /// `trusted_checksum`来自正常关闭的标记：keydir末尾的校验和与它相同时，说明keydir就是关闭时完整写入的那一份，
+    /// 不再重新计算整个文件的校验和。
+    pub(crate) fn open(data_dir: &Path, trusted_checksum: Option<u32>) -> Result<Option<Self>, BitCaskError> {
+        let path = data_dir.join(KEYDIR_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let buf = std::fs::read(&path)?;
+        let Some((body, checksum)) = buf.split_last_chunk::<4>() else {
+            trace!("ignoring truncated keydir: {:?}", path);
+            return Ok(None);
+        };
+        let checksum = u32::from_le_bytes(*checksum);
+        if trusted_checksum != Some(checksum) && crc32c::crc32c(body) != checksum {
+            trace!("ignoring keydir with a checksum mismatch: {:?}", path);
+            return Ok(None);
+        }
+        let mut cursor = PayloadCursor::new(body);
+        if cursor.take(4)? != KEYDIR_MAGIC || cursor.u32()? != KEYDIR_VERSION {
+            trace!("ignoring keydir with an unsupported format: {:?}", path);
+            return Ok(None);
+        }
+        let files = (0..cursor.u32()?)
+            .map(|_| Ok((cursor.u64()? as FileId, cursor.u64()?, cursor.u64()?)))
+            .collect::<Result<Vec<_>, BitCaskError>>()?;
+        let count = cursor.u64()?;
+        let entries_start = body.len() - cursor.remaining();
+        Ok(Some(Self {
+            buf,
+            files,
+            count,
+            entries_start,
+        }))
+    }
+
+    /// 检查keydir是否仍然描述这些数据文件，返回每个已经被keydir覆盖的文件需要从哪个偏移量继续加载，
+    /// 以及该偏移量之前的记录数量。
+    ///
+    /// 保存之后只有最后一个文件可能被继续追加，之后也可能创建了ID更大的新文件；
+    /// 其他情况（例如压缩替换了文件）说明keydir已经过期，返回`None`。
+    pub(crate) fn resume_offsets(&self, current: &[(FileId, u64)]) -> Option<HashMap<FileId, (ByteOffset, u64)>> {
+        let last_saved = self.files.last().map(|(file_id, _, _)| *file_id);
+        let current: HashMap<FileId, u64> = current.iter().copied().collect();
+        for (file_id, len, _) in &self.files {
+            let current_len = *current.get(file_id)?;
+            let appended = Some(*file_id) == last_saved && current_len > *len;
+            if current_len != *len && !appended {
+                return None;
+            }
+        }
+        let newer_files_only = current
+            .keys()
+            .all(|file_id| self.files.iter().any(|(saved, _, _)| saved == file_id) || Some(*file_id) > last_saved);
+        newer_files_only.then(|| {
+            self.files
+                .iter()
+                .map(|(file_id, len, records)| (*file_id, (*len, *records)))
+                .collect()
+        })
+    }
+
+    /// 把keydir中的所有条目和请求ID加载到内存索引中。
+    pub(crate) fn load_into(&self, mem_index: &mut MemIndexStorage) -> Result<(), BitCaskError> {
+        let body = &self.buf[..self.buf.len() - 4];
+        let mut cursor = PayloadCursor::new(&body[self.entries_start..]);
+        for _ in 0..self.count {
+            let flags = cursor.u8()?;
+            let key = cursor.bytes()?;
+            let timestamp = cursor.u64()?;
+            if flags & ENTRY_REMOVED != 0 {
+                mem_index.delete(&key, timestamp);
+                continue;
+            }
+            let file_id = cursor.u64()? as FileId;
+            let value_offset = cursor.u64()?;
+            let value_size = cursor.u64()?;
+            let record_offset = cursor.u64()?;
+            let expire_at = match flags & ENTRY_EXPIRY {
+                0 => None,
+                _ => Some(cursor.u64()?),
+            };
+            let check_sum = match flags & ENTRY_CHECKSUM {
+                0 => None,
+                _ => Some(cursor.u32()?),
+            };
+            let inline_value = match flags & ENTRY_INLINE {
+                0 => None,
+                _ => Some(cursor.bytes()?),
+            };
+            let metadata = match flags & ENTRY_METADATA {
+                0 => None,
+                _ => Some(cursor.bytes()?),
+            };
+            // 保存之后内联阈值可能被调小
+            let inline_value = inline_value
+                .filter(|value| mem_index.inline_value_threshold().is_some_and(|threshold| value.len() <= threshold));
+            let entry = MemIndexEntry {
+                file_id,
+                value_offset,
+                value_size,
+                compressed: flags & ENTRY_COMPRESSED != 0,
+                blob: flags & ENTRY_BLOB != 0,
+                inline_value,
+                expire_at,
+                version: 0,
+                timestamp,
+                tombstone: flags & ENTRY_SOFT_DELETED != 0,
+                record_offset,
+                check_sum,
+                soft_deleted: flags & ENTRY_SOFT_DELETED != 0,
+                metadata,
+            };
+            mem_index.put(key, entry);
+        }
+        for _ in 0..cursor.u64()? {
+            let request_id = cursor.u64()?;
+            mem_index.record_request_id(request_id, cursor.u64()?);
+        }
+        if !cursor.is_empty() {
+            return Err(BitCaskError::CorruptedData("trailing bytes in keydir".to_string()));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! 组合键的保序编码：编码后的键按字节比较的顺序与各个部分依次比较的顺序相同，
+//! 因此可以直接用`BitCask::iter`和`BitCask::iter_prefix`按组合键的范围扫描。
+//!
+//! 整数和时间戳编码为定长的大端字节；字节串和字符串编码为变长的部分：其中的`0x00`被转义为`0x00 0xFF`，
+//! 末尾加上`0x00 0x01`作为结束标记，因此较短的字节串排在以它开头的较长字节串之前，
+//! 并且每个部分都是自定界的。只包含前几个部分的编码是包含更多部分的编码的前缀，可以作为`iter_prefix`的前缀。
+
+use crate::bitcask::Key;
+use crate::error::BitCaskError;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 字节串中的`0x00`和结束标记的第一个字节。
+const ESCAPE: u8 = 0x00;
+/// `0x00 0xFF`表示字节串中的一个`0x00`。
+const ESCAPED_ZERO: u8 = 0xFF;
+/// `0x00 0x01`表示字节串结束。
+const TERMINATOR: u8 = 0x01;
+
+/// `KeyEncoder` 按顺序把组合键的各个部分编码为一个保序的键，
+/// 例如`KeyEncoder::new().u64(tenant).str("orders").timestamp(created).finish()`。
+#[derive(Debug, Clone, Default)]
+pub struct KeyEncoder {
+    buf: Vec<u8>,
+}
+
+impl KeyEncoder {
+    /// 创建一个空的编码器。
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 追加一个无符号整数，编码为8字节的大端字节。
+    pub fn u64(mut self, value: u64) -> Self {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    /// 追加一个有符号整数，翻转符号位后编码为8字节的大端字节，负数排在正数之前。
+    pub fn i64(self, value: i64) -> Self {
+        self.u64((value as u64) ^ (1 << 63))
+    }
+
+    /// 追加一个时间戳，编码为自Unix纪元以来的纳秒数（8字节大端），纪元之前的时间按纪元处理。
+    pub fn timestamp(self, time: SystemTime) -> Self {
+        let nanos = time.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_nanos());
+        self.u64(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+
+    /// 追加一个字符串，与`bytes`的编码相同。
+    pub fn str(self, value: &str) -> Self {
+        self.bytes(value.as_bytes())
+    }
+
+    /// 追加一个字节串，转义其中的`0x00`并加上结束标记。
+    pub fn bytes(mut self, value: &[u8]) -> Self {
+        for &byte in value {
+            self.buf.push(byte);
+            if byte == ESCAPE {
+                self.buf.push(ESCAPED_ZERO);
+            }
+        }
+        self.buf.extend_from_slice(&[ESCAPE, TERMINATOR]);
+        self
+    }
+
+    /// 返回编码后的键。
+    pub fn finish(self) -> Key {
+        self.buf
+    }
+}
+
+/// `KeyDecoder` 按编码时的顺序读取`KeyEncoder`编码的键的各个部分。
+///
+/// 读取的类型必须与编码时相同，键的剩余部分不足或格式不对时返回`BitCaskError::InvalidKeyEncoding`。
+#[derive(Debug, Clone)]
+pub struct KeyDecoder<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> KeyDecoder<'a> {
+    /// 从键的开头开始读取。
+    pub fn new(key: &'a [u8]) -> Self {
+        Self { rest: key }
+    }
+
+    /// 读取一个无符号整数，见`KeyEncoder::u64`。
+    pub fn u64(&mut self) -> Result<u64, BitCaskError> {
+        let Some((bytes, rest)) = self.rest.split_first_chunk::<8>() else {
+            return Err(BitCaskError::InvalidKeyEncoding(format!(
+                "expected 8 bytes for an integer, {} left",
+                self.rest.len()
+            )));
+        };
+        self.rest = rest;
+        Ok(u64::from_be_bytes(*bytes))
+    }
+
+    /// 读取一个有符号整数，见`KeyEncoder::i64`。
+    pub fn i64(&mut self) -> Result<i64, BitCaskError> {
+        Ok((self.u64()? ^ (1 << 63)) as i64)
+    }
+
+    /// 读取一个时间戳，见`KeyEncoder::timestamp`。
+    pub fn timestamp(&mut self) -> Result<SystemTime, BitCaskError> {
+        Ok(UNIX_EPOCH + Duration::from_nanos(self.u64()?))
+    }
+
+    /// 读取一个字符串，见`KeyEncoder::str`，不是UTF-8时返回错误。
+    pub fn str(&mut self) -> Result<String, BitCaskError> {
+        String::from_utf8(self.bytes()?)
+            .map_err(|e| BitCaskError::InvalidKeyEncoding(format!("string is not valid UTF-8: {}", e)))
+    }
+
+    /// 读取一个字节串，见`KeyEncoder::bytes`。
+    pub fn bytes(&mut self) -> Result<Vec<u8>, BitCaskError> {
+        let mut value = Vec::new();
+        let mut bytes = self.rest.iter().enumerate();
+        while let Some((_, &byte)) = bytes.next() {
+            if byte != ESCAPE {
+                value.push(byte);
+                continue;
+            }
+            match bytes.next() {
+                Some((_, &ESCAPED_ZERO)) => value.push(ESCAPE),
+                Some((end, &TERMINATOR)) => {
+                    self.rest = &self.rest[end + 1..];
+                    return Ok(value);
+                }
+                Some((position, byte)) => {
+                    return Err(BitCaskError::InvalidKeyEncoding(format!(
+                        "unexpected byte {:#04x} after 0x00 at {}",
+                        byte, position
+                    )))
+                }
+                None => break,
+            }
+        }
+        Err(BitCaskError::InvalidKeyEncoding("unterminated byte string".to_string()))
+    }
+
+    /// 是否已经读完了整个键。
+    pub fn is_empty(&self) -> bool {
+        self.rest.is_empty()
+    }
+}
@@ -0,0 +1,86 @@
+use crate::bitcask::{BitCask, KVStorage, Key};
+use crate::error::BitCaskError;
+use ::kvdb::{DBKey, DBKeyValue, DBOp, DBTransaction, DBValue, KeyValueDB};
+use std::io;
+
+/// 列在存储中的键前缀：4字节大端序的列号。
+const COLUMN_PREFIX_LEN: usize = 4;
+
+/// 把存储作为`kvdb::KeyValueDB`使用，面向该trait编写的应用（例如基于parity-common的项目）可以直接换用本引擎。
+///
+/// 列映射为键前缀：列`col`中的键`key`在存储中的键是`col`的4字节大端序表示加上`key`，
+/// 因此同一个存储可以同时承载任意数量的列，列与列之间按列号顺序排列。
+/// `write`按顺序执行事务中的操作，每个操作单独写入，事务在崩溃时不保证原子性，
+/// 读取方也可能看到只执行了一部分的事务；`DeletePrefix`先遍历前缀下的键再逐个删除。
+/// 克隆的句柄共享同一个存储，写入时使用句柄的克隆，因此可以通过`&self`写入。
+impl KeyValueDB for BitCask {
+    fn get(&self, col: u32, key: &[u8]) -> io::Result<Option<DBValue>> {
+        self.get_with_meta(&column_key(col, key))
+            .map(|meta| meta.map(|meta| meta.value))
+            .map_err(into_io_error)
+    }
+
+    fn get_by_prefix(&self, col: u32, prefix: &[u8]) -> io::Result<Option<DBValue>> {
+        self.iter_prefix(&column_key(col, prefix))
+            .next()
+            .transpose()
+            .map(|pair| pair.map(|(_, value)| value))
+            .map_err(into_io_error)
+    }
+
+    fn write(&self, transaction: DBTransaction) -> io::Result<()> {
+        let mut bitcask = self.clone();
+        for op in transaction.ops {
+            let res = match op {
+                DBOp::Insert { col, key, value } => bitcask.put(&column_key(col, &key), &value),
+                DBOp::Delete { col, key } => bitcask.delete(&column_key(col, &key)),
+                DBOp::DeletePrefix { col, prefix } => delete_prefix(&mut bitcask, &column_key(col, &prefix)),
+            };
+            res.map_err(into_io_error)?;
+        }
+        Ok(())
+    }
+
+    fn iter<'a>(&'a self, col: u32) -> Box<dyn Iterator<Item = io::Result<DBKeyValue>> + 'a> {
+        self.iter_with_prefix(col, &[])
+    }
+
+    fn iter_with_prefix<'a>(
+        &'a self,
+        col: u32,
+        prefix: &'a [u8],
+    ) -> Box<dyn Iterator<Item = io::Result<DBKeyValue>> + 'a> {
+        Box::new(self.iter_prefix(&column_key(col, prefix)).map(|pair| {
+            pair.map(|(key, value)| (DBKey::from_slice(&key[COLUMN_PREFIX_LEN..]), value))
+                .map_err(into_io_error)
+        }))
+    }
+}
+
+/// 返回列中的键在存储中的键。
+fn column_key(col: u32, key: &[u8]) -> Key {
+    let mut column_key = Vec::with_capacity(COLUMN_PREFIX_LEN + key.len());
+    column_key.extend_from_slice(&col.to_be_bytes());
+    column_key.extend_from_slice(key);
+    column_key
+}
+
+/// 删除以`prefix`开头的所有键。
+fn delete_prefix(bitcask: &mut BitCask, prefix: &[u8]) -> Result<(), BitCaskError> {
+    let keys = bitcask
+        .iter_prefix(prefix)
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<Vec<Key>, BitCaskError>>()?;
+    for key in keys {
+        bitcask.delete(&key)?;
+    }
+    Ok(())
+}
+
+/// 把存储的错误转换为`kvdb`使用的IO错误，IO错误原样返回。
+fn into_io_error(e: BitCaskError) -> io::Error {
+    match e {
+        BitCaskError::IoError(e) => e,
+        e => io::Error::other(e),
+    }
+}
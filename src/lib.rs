@@ -1,7 +1,49 @@
+pub mod actor;
+pub mod audit;
+pub mod auth;
+pub mod backup;
 pub mod bitcask;
+pub mod compaction;
+pub mod dump;
+pub mod durability;
 pub mod error;
+pub mod ffi;
+pub mod grpc;
+pub mod health;
+pub mod history;
+pub mod iter;
+pub mod keyenc;
+pub mod kvdb;
+pub mod lock;
+pub mod memcached;
+pub mod merge;
+pub mod merkle;
+pub mod options;
+pub mod reader;
+pub mod resp;
+pub mod salvage;
+pub mod server;
+pub mod service;
+pub mod shadow;
+pub mod sled;
+pub mod stats;
+pub mod tls;
+pub mod trace;
+pub mod tree;
+pub mod txn;
+pub mod value_ref;
+mod blob;
+mod buffer;
+mod clock;
+mod compression;
 mod disk_logs;
+mod keydir;
 mod log_entry;
 mod log_file;
+mod maintenance;
 mod memory_index;
+mod reserve;
+mod snapshot;
+mod sparse_index;
 mod storage;
+mod watchdog;
@@ -0,0 +1,121 @@
+use crate::bitcask::Key;
+use crate::error::BitCaskError;
+use crate::options::FileNaming;
+use fs2::FileExt;
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// `DirLock` 是数据目录上的文件锁，锁文件见`FileNaming::lock_file_name`。
+///
+/// 打开的存储持有共享锁，同一个数据目录可以被多个句柄同时打开；离线压缩和打开时的压缩会在原目录中替换数据文件，
+/// 需要排他锁，因此只能在没有其他句柄打开存储时进行。锁由操作系统在文件关闭时释放，进程崩溃不会留下需要手动清理的锁。
+pub(crate) struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    /// 以共享模式锁定数据目录，目录正在被压缩时立即返回`BitCaskError::DirectoryLocked`，不会等待。
+    pub(crate) fn shared(data_dir: &Path, naming: &FileNaming) -> Result<Self, BitCaskError> {
+        let file = Self::open(data_dir, naming)?;
+        contended(FileExt::try_lock_shared(&file))?;
+        Ok(Self { file })
+    }
+
+    /// 以排他模式锁定数据目录，目录已经被打开或者正在被压缩时立即返回`BitCaskError::DirectoryLocked`。
+    pub(crate) fn exclusive(data_dir: &Path, naming: &FileNaming) -> Result<Self, BitCaskError> {
+        let file = Self::open(data_dir, naming)?;
+        contended(FileExt::try_lock_exclusive(&file))?;
+        Ok(Self { file })
+    }
+
+    /// 尝试把共享锁升级为排他锁，其他句柄也持有共享锁时返回`false`，锁保持不变。
+    pub(crate) fn try_upgrade(&self) -> Result<bool, BitCaskError> {
+        match contended(FileExt::try_lock_exclusive(&self.file)) {
+            Ok(()) => Ok(true),
+            Err(BitCaskError::DirectoryLocked) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 把排他锁降级为共享锁。
+    pub(crate) fn downgrade(&self) -> Result<(), BitCaskError> {
+        Ok(FileExt::lock_shared(&self.file)?)
+    }
+
+    /// 打开（必要时创建）锁文件。
+    fn open(data_dir: &Path, naming: &FileNaming) -> Result<File, BitCaskError> {
+        Ok(std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(data_dir.join(naming.lock_file_name()))?)
+    }
+}
+
+/// 把锁已经被占用的错误转换为`BitCaskError::DirectoryLocked`。
+fn contended(res: std::io::Result<()>) -> Result<(), BitCaskError> {
+    match res {
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => Err(BitCaskError::DirectoryLocked),
+        res => Ok(res?),
+    }
+}
+
+/// `KeyLockTable` 记录当前被应用层锁定的键，由同一个存储的所有`BitCask`句柄共享。
+///
+/// 这些锁是建议性的：它们只在调用`BitCask::lock_key`的调用方之间互斥，
+/// 不会阻止其他没有加锁的读写操作。
+#[derive(Default)]
+pub(crate) struct KeyLockTable {
+    /// 当前被锁定的键。
+    locked: Mutex<HashSet<Key>>,
+    /// 有键被释放时通知等待的线程。
+    released: Condvar,
+}
+
+impl KeyLockTable {
+    /// 锁定给定的键，如果键已经被锁定，最多等待`timeout`。
+    ///
+    /// # 返回
+    /// - `Ok(KeyGuard)`: 获得锁，守卫被丢弃时释放锁。
+    /// - `Err(BitCaskError::LockTimeout)`: 在超时之前没有获得锁。
+    pub(crate) fn lock(self: &Arc<Self>, key: &Key, timeout: Duration) -> Result<KeyGuard, BitCaskError> {
+        let deadline = Instant::now() + timeout;
+        let mut locked = self.locked.lock().unwrap_or_else(PoisonError::into_inner);
+        while locked.contains(key) {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(BitCaskError::LockTimeout);
+            }
+            locked = self.released.wait_timeout(locked, remaining).unwrap_or_else(PoisonError::into_inner).0;
+        }
+        locked.insert(key.clone());
+        Ok(KeyGuard {
+            table: self.clone(),
+            key: key.clone(),
+        })
+    }
+}
+
+/// `KeyGuard` 是`BitCask::lock_key`返回的键锁守卫，被丢弃时自动释放锁。
+pub struct KeyGuard {
+    table: Arc<KeyLockTable>,
+    key: Key,
+}
+
+impl KeyGuard {
+    /// 返回被锁定的键。
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+}
+
+impl Drop for KeyGuard {
+    /// 释放锁并唤醒等待的线程。
+    fn drop(&mut self) {
+        self.table.locked.lock().unwrap_or_else(PoisonError::into_inner).remove(&self.key);
+        self.table.released.notify_all();
+    }
+}
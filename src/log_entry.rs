@@ -1,17 +1,74 @@
-use crate::bitcask::{ByteOffset, ByteSize, Key, Value};
+use crate::bitcask::{ByteOffset, ByteSize, Key, Value, MAX_METADATA_SIZE};
+use crate::blob::BlobId;
 use crate::error::BitCaskError;
 use crc::{Crc, CRC_32_CKSUM};
-use std::io::{Read, Write};
+use std::io::Write;
 
 const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
 
-/// Any object that is readable can be deserialized
+/// 记录标志：删除标记。有了该标志，值为空的记录不再被视为删除标记（第一版格式中值大小为0的记录是删除标记）。
+const RECORD_TOMBSTONE: u8 = 1 << 0;
+/// 记录标志：值经过了字典压缩。
+const RECORD_COMPRESSED: u8 = 1 << 1;
+/// 记录标志：值经过了加密，保留给以后的版本，当前版本遇到该标志时拒绝读取。
+const RECORD_ENCRYPTED: u8 = 1 << 2;
+/// 记录标志：键和值之前有8字节的过期时间。
+const RECORD_EXPIRY: u8 = 1 << 3;
+/// 记录标志：（过期时间之后）有8字节的混合逻辑时钟时间戳。
+const RECORD_TIMESTAMP: u8 = 1 << 4;
+/// 记录标志：引用条目，见`DiskLogEntry::new_reference`。
+const RECORD_REFERENCE: u8 = 1 << 5;
+/// 记录标志：大值指针条目，见`DiskLogEntry::new_blob_pointer`。
+const RECORD_BLOB: u8 = 1 << 6;
+/// 记录标志：（时间戳之后）有8字节的客户端请求ID，见`PutOption::request_id`。
+const RECORD_REQUEST_ID: u8 = 1 << 7;
+/// 记录标志：软删除的墓碑，见`DiskLogEntry::soft_deleted`。
+const RECORD_SOFT_DELETED: u64 = 1 << 8;
+/// 记录标志：（请求ID之后）有变长编码的长度和随后的用户元数据。
+const RECORD_METADATA: u64 = 1 << 9;
+/// 记录标志：（元数据之后）有变长编码的长度和随后的扩展区，见`DiskLogEntry::extensions`。
+const RECORD_EXTENSIONS: u64 = 1 << 10;
+/// 所有已知的记录标志，带有其他标志的记录由更新的版本写入，拒绝读取。
+/// 以后新增的可选字段应该写入扩展区，而不是新增记录标志，这样旧版本仍然可以读取记录。
+const RECORD_V2_FLAGS: u64 = 0xff | RECORD_SOFT_DELETED | RECORD_METADATA | RECORD_EXTENSIONS;
+
+/// 扩展类型的最低位表示关键扩展：不认识它的读取方必须拒绝记录，不认识的非关键扩展被跳过。
+const EXTENSION_CRITICAL: u64 = 1;
+
+/// 非关键扩展：原子批量写入中的一条记录，数据是变长编码的整数，表示同一批次中在这条记录之后还有几条记录，
+/// 见`DiskLogEntry::set_batch_remaining`。不认识它的旧版本把批次中的记录当作普通记录读取。
+const EXTENSION_BATCH: u64 = 8;
+
+/// 第二版格式的记录在校验和之后的第一个字节。第一版格式在这个位置是8字节键大小的最高字节，总是为0。
+const RECORD_VERSION_2: u8 = 2;
+
+/// 记录在数据文件中的格式。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RecordFormat {
+    /// 第一版格式，即最初的记录布局：校验和、8字节的键大小和值大小、键和值，没有任何标志，值大小为0的记录是删除标记。
+    /// 只会从旧的数据文件中读取，不会再被写入。
+    V1,
+    /// 第二版格式：版本字节，之后是变长编码的记录标志、键大小和值大小。
+    V2,
+}
+
+/// Any object that can be parsed from a borrowed byte slice can be deserialized
 pub(crate) trait Deserialize {
-    fn deserialize<T: Read>(buf: &mut T) -> Result<Self, BitCaskError>
+    fn deserialize(buf: &[u8]) -> Result<Decoded<Self>, BitCaskError>
     where
         Self: Sized;
 }
 
+/// 从字节切片反序列化的结果。
+#[derive(Debug)]
+pub(crate) enum Decoded<T> {
+    /// 切片开头的完整对象和它占用的字节数。
+    Complete(T, usize),
+    /// 切片不包含完整的对象，至少需要从切片开头算起的这么多字节才能继续解析。
+    /// 大小字段损坏时这个值可能非常大，调用方应该先与剩余的数据量比较，再读取更多的数据。
+    Incomplete(u64),
+}
+
 /// Any object that is writable can be serialized to
 pub(crate) trait Serialize {
     fn serialize<T: Write>(&self, buf: &mut T) -> Result<(), BitCaskError>;
@@ -30,6 +87,31 @@ pub(crate) struct DiskLogEntry {
     pub(crate) key: Key,
     /// 日志条目的值，如果为 None，则表示该条目为删除标记。
     pub(crate) value: Option<Value>, // None 表示一个删除标记
+    /// 值是否经过了字典压缩，压缩时存储的是压缩后的字节。
+    pub(crate) compressed: bool,
+    /// 是否为去重模式下的引用条目，引用条目的值只记录共享记录的位置，见`new_reference`。
+    pub(crate) reference: bool,
+    /// 是否为大值指针条目，条目的值只记录大值文件的ID和大小，见`new_blob_pointer`。
+    pub(crate) blob: bool,
+    /// 键的过期时间（Unix毫秒时间戳），为`None`表示永不过期。
+    pub(crate) expire_at: Option<u64>,
+    /// 写入时的混合逻辑时钟时间戳，见`clock::HybridClock`；旧版本写入的条目没有时间戳。
+    pub(crate) timestamp: Option<u64>,
+    /// 写入时客户端提供的请求ID，用于识别重试的写入，见`PutOption::request_id`。
+    pub(crate) request_id: Option<u64>,
+    /// 是否为软删除的墓碑。软删除的墓碑保留了删除前存储的值（可能经过压缩），
+    /// 在下一次压缩之前可以通过`undelete`恢复，见`BitCask::soft_delete`。
+    pub(crate) soft_deleted: bool,
+    /// 写入时附加的用户元数据，最多`MAX_METADATA_SIZE`字节，见`PutOption::metadata`。
+    pub(crate) metadata: Option<Vec<u8>>,
+    /// 第二版格式记录中原样保存的扩展区，由若干个`类型 | 长度 | 数据`组成（类型和长度是变长编码的整数）。
+    /// 当前版本只写入批次扩展，读取时跳过不认识的非关键扩展，并保留原始字节使记录重新写入时大小不变。
+    pub(crate) extensions: Option<Vec<u8>>,
+    /// 校验和是否为CRC32C，新写入的条目都使用CRC32C，旧版本写入的条目使用CRC_32_CKSUM。
+    pub(crate) crc32c: bool,
+    /// 记录的格式。新写入的条目都使用第二版格式；第一版格式的条目只会从旧的数据文件中读取，
+    /// 写入路径总是构造新的条目，不会重新写入它们。`BitCask::upgrade`把整个存储重写为第二版格式。
+    pub(crate) format: RecordFormat,
 }
 
 impl DiskLogEntry {
@@ -47,11 +129,89 @@ impl DiskLogEntry {
     /// 校验和用于后续的数据完整性检查，确保数据未被意外修改。
     /// 键和值则直接存储在条目中，以便于快速访问和操作。
     pub(crate) fn new_entry(key: Key, value: Value) -> Self {
-        let check_sum = CRC32.checksum(&value);
+        let check_sum = crc32c::crc32c(&value);
         Self {
             check_sum,
             key,
             value: Some(value),
+            compressed: false,
+            reference: false,
+            blob: false,
+            expire_at: None,
+            timestamp: None,
+            request_id: None,
+            soft_deleted: false,
+            metadata: None,
+            extensions: None,
+            crc32c: true,
+            format: RecordFormat::V2,
+        }
+    }
+
+    /// 创建一个引用条目，用于在去重模式下让键指向同一文件中已经存在的相同值。
+    ///
+    /// # 参数
+    /// - `key`: 条目的键。
+    /// - `value_offset`: 共享记录的值在文件中的偏移量。
+    /// - `value_size`: 共享记录的值的字节大小。
+    /// - `compressed`: 共享记录的值是否经过了字典压缩。
+    ///
+    /// # 说明
+    /// 引用条目的值依次为偏移量（8字节）、大小（8字节）和压缩标记（1字节）。
+    pub(crate) fn new_reference(
+        key: Key,
+        value_offset: ByteOffset,
+        value_size: ByteSize,
+        compressed: bool,
+    ) -> Self {
+        let mut value = Vec::with_capacity(17);
+        value.extend_from_slice(&value_offset.to_be_bytes());
+        value.extend_from_slice(&value_size.to_be_bytes());
+        value.push(compressed as u8);
+        let mut entry = Self::new_entry(key, value);
+        entry.reference = true;
+        entry
+    }
+
+    /// 创建一个大值指针条目，真实的值存放在独立的大值文件中。
+    ///
+    /// # 参数
+    /// - `key`: 条目的键。
+    /// - `blob_id`: 大值文件的ID。
+    /// - `value_size`: 大值的字节大小。
+    ///
+    /// # 说明
+    /// 指针条目的值依次为大值文件ID（8字节）和大小（8字节）。
+    pub(crate) fn new_blob_pointer(key: Key, blob_id: BlobId, value_size: ByteSize) -> Self {
+        let mut value = Vec::with_capacity(16);
+        value.extend_from_slice(&blob_id.to_be_bytes());
+        value.extend_from_slice(&value_size.to_be_bytes());
+        let mut entry = Self::new_entry(key, value);
+        entry.blob = true;
+        entry
+    }
+
+    /// 如果当前条目是大值指针条目，返回大值文件的ID和大值的大小。
+    pub(crate) fn blob_target(&self) -> Option<(BlobId, ByteSize)> {
+        match &self.value {
+            Some(value) if self.blob && value.len() == 16 => {
+                let blob_id = BlobId::from_be_bytes(value[0..8].try_into().unwrap());
+                let value_size = ByteSize::from_be_bytes(value[8..16].try_into().unwrap());
+                Some((blob_id, value_size))
+            }
+            _ => None,
+        }
+    }
+
+    /// 如果当前条目是引用条目，返回共享记录的值的偏移量、大小和压缩标记。
+    pub(crate) fn reference_target(&self) -> Option<(ByteOffset, ByteSize, bool)> {
+        match &self.value {
+            Some(value) if self.reference && value.len() == 17 => {
+                let value_offset = ByteOffset::from_be_bytes(value[0..8].try_into().unwrap());
+                let value_size = ByteSize::from_be_bytes(value[8..16].try_into().unwrap());
+                Some((value_offset, value_size, value[16] != 0))
+            }
+            _ => None,
         }
     }
 
@@ -73,26 +233,74 @@ impl DiskLogEntry {
             check_sum,
             key,
             value: None,
+            compressed: false,
+            reference: false,
+            blob: false,
+            expire_at: None,
+            timestamp: None,
+            request_id: None,
+            soft_deleted: false,
+            metadata: None,
+            extensions: None,
+            crc32c: true,
+            format: RecordFormat::V2,
         }
     }
     
+    /// 把第二版格式的记录标记为原子批量写入中的一条，`remaining`是同一批次中在它之后写入的记录数量，
+    /// 批次的最后一条记录为0。加载时只有完整出现的批次才会生效，见`DiskLogFile::for_each_committed_entry_in`。
+    pub(crate) fn set_batch_remaining(&mut self, remaining: u64) {
+        debug_assert_eq!(self.format, RecordFormat::V2);
+        let mut data = Vec::new();
+        let extensions = self.extensions.get_or_insert_with(Vec::new);
+        write_varint(&mut data, remaining)
+            .and_then(|()| write_varint(extensions, EXTENSION_BATCH))
+            .and_then(|()| write_varint(extensions, data.len() as u64))
+            .expect("writing to a Vec cannot fail");
+        extensions.extend_from_slice(&data);
+    }
+
+    /// 如果记录属于原子批量写入，返回同一批次中在它之后还有几条记录，见`set_batch_remaining`。
+    pub(crate) fn batch_remaining(&self) -> Option<u64> {
+        let extensions = self.extensions.as_deref()?;
+        let mut reader = SliceReader::new(extensions);
+        while reader.pos < extensions.len() {
+            let extension_type = reader.varint().ok()?;
+            let len = reader.varint().ok()?;
+            let data = reader.take(len).ok()?;
+            if extension_type == EXTENSION_BATCH {
+                return SliceReader::new(data).varint().ok();
+            }
+        }
+        None
+    }
+
     /// 检查当前对象是否为“墓碑”对象。
     ///
     /// “墓碑”对象表示一个已删除或不再存在的实体。该方法通过检查`value`字段是否为`None`来判断对象是否为“墓碑”对象。
-    /// 如果`value`为`None`，则返回`true`，表示对象是“墓碑”对象；否则返回`false`。
+    /// 如果`value`为`None`，则返回`true`，表示对象是“墓碑”对象；软删除的墓碑保留了值，同样返回`true`。
     pub(crate) fn is_tombstone(&self) -> bool {
-        self.value.is_none()
+        self.value.is_none() || self.soft_deleted
     }
 
     /// 检查数据包是否有效。
     ///
-    /// 有效性通过检查数据包的校验和与CRC32校验和是否相等来确定。
+    /// 有效性通过检查数据包的校验和与按条目记录的算法（CRC32C或CRC_32_CKSUM）计算的校验和是否相等来确定。
     /// 如果数据包的值存在，则进行校验和比较；如果值不存在（为None），则认为数据包有效。
     fn is_valid(&self) -> bool {
-        if let Some(value) = &self.value {
-            self.check_sum == CRC32.checksum(value)
-        } else {
-            true
+        match &self.value {
+            Some(value) if self.crc32c => self.check_sum == crc32c::crc32c(value),
+            Some(value) => self.check_sum == CRC32.checksum(value),
+            None => true,
+        }
+    }
+
+    /// 返回值的CRC32C校验和，墓碑返回`None`。旧版本写入的条目的校验和不是CRC32C，需要重新计算。
+    pub(crate) fn value_crc32c(&self) -> Option<u32> {
+        match &self.value {
+            Some(_) if self.crc32c => Some(self.check_sum),
+            Some(value) => Some(crc32c::crc32c(value)),
+            None => None,
         }
     }
 
@@ -108,6 +316,54 @@ impl DiskLogEntry {
         4
     }
 
+    /// 键大小和值大小字段的字节大小，第一版格式是固定的16字节，第二版格式是两个变长编码的整数
+    fn sizes_byte_size(&self) -> ByteSize {
+        match self.format {
+            RecordFormat::V1 => Self::size_byte_len() * 2,
+            RecordFormat::V2 => varint_len(self.key_byte_size()) + varint_len(self.value_byte_size()),
+        }
+    }
+
+    /// 大小字段之外的头部字段（版本、记录标志、过期时间、时间戳、请求ID和元数据）的字节大小，不存在的字段不占用空间。
+    /// 第一版格式没有这些字段。
+    fn extension_byte_size(&self) -> ByteSize {
+        if self.format == RecordFormat::V1 {
+            return 0;
+        }
+        let flags_size = 1 + varint_len(self.flags());
+        let optional_fields = [self.expire_at, self.timestamp, self.request_id]
+            .iter()
+            .filter(|field| field.is_some())
+            .count();
+        let metadata_size = self.metadata.as_ref().map_or(0, |metadata| {
+            let len = metadata.len() as ByteSize;
+            varint_len(len) + len
+        });
+        let extensions_size = self
+            .extensions
+            .as_ref()
+            .map_or(0, |extensions| varint_len(extensions.len() as u64) + extensions.len() as ByteSize);
+        flags_size + optional_fields as ByteSize * 8 + metadata_size + extensions_size
+    }
+
+    /// 返回条目的记录标志。
+    fn flags(&self) -> u64 {
+        [
+            (self.is_tombstone(), RECORD_TOMBSTONE as u64),
+            (self.compressed, RECORD_COMPRESSED as u64),
+            (self.expire_at.is_some(), RECORD_EXPIRY as u64),
+            (self.timestamp.is_some(), RECORD_TIMESTAMP as u64),
+            (self.reference, RECORD_REFERENCE as u64),
+            (self.blob, RECORD_BLOB as u64),
+            (self.request_id.is_some(), RECORD_REQUEST_ID as u64),
+            (self.soft_deleted, RECORD_SOFT_DELETED),
+            (self.metadata.is_some(), RECORD_METADATA),
+            (self.extensions.is_some(), RECORD_EXTENSIONS),
+        ]
+        .into_iter()
+        .fold(0, |flags, (set, flag)| if set { flags | flag } else { flags })
+    }
+
     /// 获取密钥的字节大小
     ///
     /// # 返回
@@ -142,7 +398,10 @@ impl DiskLogEntry {
     /// # 返回值
     /// - 返回值是`ByteOffset`类型，表示值在存储中的字节偏移量。
     pub(crate) fn value_byte_offset(&self) -> ByteOffset {
-        Self::check_sum_byte_size() + Self::size_byte_len() * 2 + self.key_byte_size()
+        Self::check_sum_byte_size()
+            + self.sizes_byte_size()
+            + self.extension_byte_size()
+            + self.key_byte_size()
     }
     
     /// 计算对象的总字节大小
@@ -156,8 +415,10 @@ impl DiskLogEntry {
     pub(crate) fn total_byte_size(&self) -> ByteSize {
         // 计算校验和的字节大小
         Self::check_sum_byte_size()
-        // 计算大小字节的长度，并乘以2，因为通常包含两个部分
-        + Self::size_byte_len() * 2
+        // 计算键大小和值大小字段的长度
+        + self.sizes_byte_size()
+        // 计算记录标志、过期时间和时间戳等字段的字节大小
+        + self.extension_byte_size()
         // 计算键的字节大小
         + self.key_byte_size()
         // 计算值的字节大小
@@ -165,15 +426,36 @@ impl DiskLogEntry {
     }
 }
 
-/// Disk layout
-///  - Checksum (4 bytes long)
-///  - Size of key in bytes (8 bytes long)
+/// Disk layout, version 2
+///  - Checksum (4 bytes long, CRC32C of the value)
+///  - Version (1 byte, always 2)
+///  - Flags (varint: tombstone, compressed, encrypted, expiry, timestamp, reference, blob, request id,
+///    soft delete, metadata and extensions bits, from the lowest bit up; the encrypted bit is reserved and rejected
+///    when reading, as are unknown bits)
+///  - Size of key in bytes (varint)
+///  - Size of value in bytes (varint)
+///  - Expiry as unix milliseconds (8 bytes long, only if the expiry flag is set)
+///  - Hybrid logical clock timestamp (8 bytes long, only if the timestamp flag is set)
+///  - Client request id (8 bytes long, only if the request id flag is set)
+///  - User metadata (a varint length followed by the metadata, only if the metadata flag is set)
+///  - Extensions (a varint length followed by that many bytes of `type | length | data` entries, type and
+///    length being varints, only if the extensions flag is set). Readers skip extensions they do not know
+///    unless the lowest bit of the type marks them as critical
+///  - Key
+///  - Value (empty for a tombstone, the deleted value for a soft-deleted tombstone)
+///
+/// Varints are LEB128: 7 bits per byte, lowest bits first, the highest bit marks that more bytes follow.
+///
+/// Disk layout, version 1 (the original layout, read but never written)
+///  - Checksum (4 bytes long, CRC_32_CKSUM of the value)
+///  - Size of key in bytes (8 bytes long, so the byte after the checksum is always 0)
 ///  - Size of value in bytes (8 bytes long)
 ///  - Key
 ///  - Value (if tombstone, then value is None, and value size is 0)
 impl Serialize for DiskLogEntry {
     /// 序列化方法，用于将当前的DiskLogEntry实例写入到一个可写入的缓冲区中。
-    /// 该方法会首先写入校验和，然后是键和值的大小，最后是键和值本身。
+    /// 该方法会首先写入校验和，然后是版本、记录标志、键和值的大小以及可选的字段，最后是键和值本身。
+    /// 总是写入第二版格式，第一版格式的条目不会被重新写入，见`DiskLogEntry::format`。
     ///
     /// # 参数
     /// - `buf`: 一个可写入的缓冲区，实现了Write trait。
@@ -190,18 +472,48 @@ impl Serialize for DiskLogEntry {
             check_sum,
             key,
             value,
+            expire_at,
+            timestamp,
+            request_id,
+            metadata,
+            extensions,
+            crc32c,
+            format,
+            ..
         } = self;
+        debug_assert!(*format == RecordFormat::V2 && *crc32c, "only version 2 records are written");
 
         // 写入校验和。校验和用于确保数据的完整性。
         buf.write_all(&check_sum.to_be_bytes())?;
 
-        // 计算键和值的大小，准备写入。
-        let key_size = self.key_byte_size();
-        let value_size = self.value_byte_size();
+        // 依次写入版本、记录标志和键值的大小，都是变长编码。
+        buf.write_all(&[RECORD_VERSION_2])?;
+        write_varint(buf, self.flags())?;
+        write_varint(buf, self.key_byte_size())?;
+        write_varint(buf, self.value_byte_size())?;
 
-        // 写入键和值的大小。这允许在读取时知道键和值分别占用多少字节。
-        buf.write_all(&key_size.to_be_bytes())?;
-        buf.write_all(&value_size.to_be_bytes())?;
+        // 如果有过期时间，紧跟在大小之后写入。
+        if let Some(expire_at) = expire_at {
+            buf.write_all(&expire_at.to_be_bytes())?;
+        }
+        // 如果有时间戳，紧跟在过期时间之后写入。
+        if let Some(timestamp) = timestamp {
+            buf.write_all(&timestamp.to_be_bytes())?;
+        }
+        // 如果有请求ID，紧跟在时间戳之后写入。
+        if let Some(request_id) = request_id {
+            buf.write_all(&request_id.to_be_bytes())?;
+        }
+        // 如果有元数据，紧跟在请求ID之后写入长度和元数据。
+        if let Some(metadata) = metadata {
+            write_varint(buf, metadata.len() as u64)?;
+            buf.write_all(metadata)?;
+        }
+        // 如果有扩展区，紧跟在元数据之后写入长度和原始字节。
+        if let Some(extensions) = extensions {
+            write_varint(buf, extensions.len() as u64)?;
+            buf.write_all(extensions)?;
+        }
 
         // 写入键。键是必须的，因此直接写入。
         buf.write_all(key.as_ref())?;
@@ -217,42 +529,126 @@ impl Serialize for DiskLogEntry {
 }
 
 impl Deserialize for DiskLogEntry {
-    
-    /// 从可读取的缓冲区中反序列化数据
+
+    /// 从字节切片的开头反序列化一条记录
     ///
     /// # 参数
-    /// - `buf`: 一个可读取的缓冲区，用于读取数据
+    /// - `buf`: 以记录开头的字节切片，可以是从文件读取的缓冲区，也可以是内存映射的一段
     ///
     /// # 返回值
-    /// - `Result<Self, BitCaskError>`: 表示反序列化结果的`Result`类型，
-    ///   成功时返回反序列化的`Self`实例，失败时返回`BitCaskError`错误
-    fn deserialize<T: Read>(buf: &mut T) -> Result<Self, BitCaskError> {
-        
+    /// - `Result<Decoded<Self>, BitCaskError>`: 成功时返回记录和它占用的字节数；
+    ///   切片不包含完整的记录时返回还需要的字节数，失败时返回`BitCaskError`错误
+    ///
+    /// 每个长度字段在使用前都与切片中剩余的字节数比较，因此损坏的大小字段不会导致按损坏的大小分配内存。
+    fn deserialize(buf: &[u8]) -> Result<Decoded<Self>, BitCaskError> {
+        let mut reader = SliceReader::new(buf);
+        match Self::parse(&mut reader) {
+            Ok(entry) => Ok(Decoded::Complete(entry, reader.pos)),
+            Err(ParseError::Incomplete(needed)) => Ok(Decoded::Incomplete(needed)),
+            Err(ParseError::Invalid(e)) => Err(e),
+        }
+    }
+}
+
+impl DiskLogEntry {
+    /// 按顺序解析记录的各个字段，见`Deserialize::deserialize`。
+    fn parse(reader: &mut SliceReader) -> Result<Self, ParseError> {
+
         // 4字节用于存储校验和
-        let mut check_sum_buf = [0u8; Self::check_sum_byte_size() as usize];
-        buf.read_exact(&mut check_sum_buf)?;
-        let check_sum = u32::from_be_bytes(check_sum_buf);
-
-        // 8字节用于存储大小
-        let mut size_buf = [0u8; Self::size_byte_len() as usize];
-        buf.read_exact(&mut size_buf)?;
-        let key_size = ByteSize::from_be_bytes(size_buf);
-
-        buf.read_exact(&mut size_buf)?;
-        let value_size = ByteSize::from_be_bytes(size_buf);
-
-        // 读取key
-        let mut key_buf = vec![0 as u8; key_size as usize];
-        buf.read_exact(&mut key_buf)?;
-        let key = key_buf;
-
-        // 如果是墓碑（tombstone），则value为None
-        let value = if value_size > 0 {
-            let mut value_buf = vec![0 as u8; value_size as usize];
-            buf.read_exact(&mut value_buf)?;
-            Some(value_buf)
-        } else {
-            None
+        let check_sum = u32::from_be_bytes(reader.array()?);
+
+        // 校验和之后的第一个字节区分格式：第二版格式的版本字节，或者第一版格式中键大小的最高字节
+        let [version] = reader.array()?;
+        let (format, crc32c, key_size, value_size, flags) = match version {
+            RECORD_VERSION_2 => {
+                let flags = reader.varint()?;
+                if flags & !RECORD_V2_FLAGS != 0 {
+                    return Err(ParseError::corrupted(format!(
+                        "record uses unsupported flags {:#b}",
+                        flags
+                    )));
+                }
+                let key_size = reader.varint()?;
+                let value_size = reader.varint()?;
+                (RecordFormat::V2, true, key_size, value_size, flags)
+            }
+            0 => {
+                // 第一版格式：8字节用于存储大小，键大小的最高字节就是上面读取的0；没有记录标志，值大小为0的记录是删除标记
+                let mut size_buf = [0u8; Self::size_byte_len() as usize];
+                size_buf[1..].copy_from_slice(reader.take(Self::size_byte_len() - 1)?);
+                let key_size = ByteSize::from_be_bytes(size_buf);
+                let value_size = ByteSize::from_be_bytes(reader.array()?);
+                let flags = match value_size {
+                    0 => RECORD_TOMBSTONE as u64,
+                    _ => 0,
+                };
+                (RecordFormat::V1, false, key_size, value_size, flags)
+            }
+            version => {
+                return Err(ParseError::corrupted(format!(
+                    "record uses unsupported version {}",
+                    version
+                )));
+            }
+        };
+        if flags & RECORD_ENCRYPTED as u64 != 0 {
+            return Err(ParseError::corrupted(format!(
+                "record uses unsupported flags {:#010b}",
+                flags
+            )));
+        }
+        let compressed = flags & RECORD_COMPRESSED as u64 != 0;
+        let reference = flags & RECORD_REFERENCE as u64 != 0;
+        let blob = flags & RECORD_BLOB as u64 != 0;
+        let has_expiry = flags & RECORD_EXPIRY as u64 != 0;
+        let has_timestamp = flags & RECORD_TIMESTAMP as u64 != 0;
+        let tombstone = flags & RECORD_TOMBSTONE as u64 != 0;
+        let soft_deleted = flags & RECORD_SOFT_DELETED != 0;
+
+        // 读取过期时间和时间戳（如果有）
+        let mut read_u64 = |present: bool| -> Result<Option<u64>, ParseError> {
+            match present {
+                true => Ok(Some(u64::from_be_bytes(reader.array()?))),
+                false => Ok(None),
+            }
+        };
+        let expire_at = read_u64(has_expiry)?;
+        let timestamp = read_u64(has_timestamp)?;
+        let request_id = read_u64(flags & RECORD_REQUEST_ID as u64 != 0)?;
+        let metadata = match flags & RECORD_METADATA != 0 {
+            true => {
+                let len = reader.varint()?;
+                // 写入时元数据不会超过`MAX_METADATA_SIZE`字节，更大的长度只能来自损坏的数据
+                if len > MAX_METADATA_SIZE as u64 {
+                    return Err(ParseError::corrupted(format!("record metadata of {} bytes is too large", len)));
+                }
+                Some(reader.take(len)?.to_vec())
+            }
+            false => None,
+        };
+        let extensions = match flags & RECORD_EXTENSIONS != 0 {
+            true => {
+                let len = reader.varint()?;
+                let extensions = reader.take(len)?;
+                check_extensions(extensions, "record")?;
+                Some(extensions.to_vec())
+            }
+            false => None,
+        };
+
+        // 如果是墓碑（tombstone），则value为None，软删除的墓碑保留了值
+        if soft_deleted && !tombstone {
+            return Err(ParseError::corrupted("soft delete without a tombstone".to_string()));
+        }
+        if tombstone && !soft_deleted && value_size != 0 {
+            return Err(ParseError::corrupted("tombstone with a value".to_string()));
+        }
+
+        // 读取key，然后读取值（如果有）
+        let key = reader.take(key_size)?.to_vec();
+        let value = match !tombstone || soft_deleted {
+            true => Some(reader.take(value_size)?.to_vec()),
+            false => None,
         };
 
         // 构建DiskLogEntry实例
@@ -260,13 +656,133 @@ impl Deserialize for DiskLogEntry {
             check_sum,
             key,
             value,
+            compressed,
+            reference,
+            blob,
+            expire_at,
+            timestamp,
+            request_id,
+            soft_deleted,
+            metadata,
+            extensions,
+            crc32c,
+            format,
         };
 
         // 验证校验和
         if entry.is_valid() {
             Ok(entry)
         } else {
-            Err(BitCaskError::CorruptedData("invalid checksum".to_string()))
+            Err(ParseError::corrupted("invalid checksum".to_string()))
         }
     }
 }
+
+/// 解析记录失败的原因。
+enum ParseError {
+    /// 切片在记录结束之前就结束了，至少需要从切片开头算起的这么多字节。
+    Incomplete(u64),
+    /// 记录已损坏或者由不支持的版本写入。
+    Invalid(BitCaskError),
+}
+
+impl ParseError {
+    fn corrupted(message: String) -> Self {
+        Self::Invalid(BitCaskError::CorruptedData(message))
+    }
+}
+
+impl From<BitCaskError> for ParseError {
+    fn from(e: BitCaskError) -> Self {
+        Self::Invalid(e)
+    }
+}
+
+/// `SliceReader` 在字节切片上按顺序读取记录的字段，每次读取前检查剩余的字节数，不会复制数据。
+struct SliceReader<'a> {
+    buf: &'a [u8],
+    /// 已经读取的字节数。
+    pos: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// 读取接下来的`len`个字节，剩余的字节不足时返回还需要的字节数。
+    fn take(&mut self, len: u64) -> Result<&'a [u8], ParseError> {
+        let rest = &self.buf[self.pos..];
+        if len > rest.len() as u64 {
+            return Err(ParseError::Incomplete((self.pos as u64).saturating_add(len)));
+        }
+        self.pos += len as usize;
+        Ok(&rest[..len as usize])
+    }
+
+    /// 读取接下来的`N`个字节。
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ParseError> {
+        Ok(self.take(N as u64)?.try_into().unwrap())
+    }
+
+    /// 读取LEB128变长编码的整数，超过10字节或者超出u64范围时视为数据损坏。
+    fn varint(&mut self) -> Result<u64, ParseError> {
+        let mut n = 0u64;
+        for i in 0..10 {
+            let [byte] = self.array()?;
+            let bits = (byte & 0x7f) as u64;
+            if i == 9 && bits > 1 {
+                break;
+            }
+            n |= bits << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(ParseError::corrupted("invalid varint".to_string()))
+    }
+}
+
+/// 检查扩展区中的每个扩展都是完整的，并且没有不认识的关键扩展。
+///
+/// 记录和数据文件头部的扩展区使用相同的格式，`owner`是错误信息中扩展区的所属，见`log_file::read_file_header`。
+pub(crate) fn check_extensions(extensions: &[u8], owner: &str) -> Result<(), BitCaskError> {
+    let truncated = || BitCaskError::CorruptedData(format!("truncated {} extension", owner));
+    let mut reader = SliceReader::new(extensions);
+    while reader.pos < extensions.len() {
+        let extension_type = reader.varint().map_err(|_| truncated())?;
+        let len = reader.varint().map_err(|_| truncated())?;
+        if extension_type & EXTENSION_CRITICAL != 0 {
+            return Err(BitCaskError::CorruptedData(format!(
+                "{} uses unsupported extension {}",
+                owner, extension_type
+            )));
+        }
+        reader.take(len).map_err(|_| truncated())?;
+    }
+    Ok(())
+}
+
+/// 按LEB128写入变长编码的整数。
+fn write_varint<T: Write>(buf: &mut T, mut n: u64) -> Result<(), BitCaskError> {
+    let mut bytes = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            bytes[len] = byte;
+            len += 1;
+            break;
+        }
+        bytes[len] = byte | 0x80;
+        len += 1;
+    }
+    buf.write_all(&bytes[..len])?;
+    Ok(())
+}
+
+/// 整数按LEB128编码后的字节大小。
+fn varint_len(n: u64) -> ByteSize {
+    (64 - n.max(1).leading_zeros() as ByteSize).div_ceil(7)
+}
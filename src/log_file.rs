@@ -1,10 +1,17 @@
 use crate::bitcask::FileId;
 use crate::error::BitCaskError;
-use crate::log_entry::{Deserialize, DiskLogEntry, Serialize};
+use crate::log_entry::{check_extensions, Decoded, Deserialize, DiskLogEntry, Serialize};
 use crate::memory_index::{MemIndexStorage, MemIndexEntry};
-use std::io::{BufReader, Seek, SeekFrom, Write};
-use std::path::PathBuf;
-use tracing::trace;
+use crate::buffer::with_scratch;
+use crate::options::FileNaming;
+use crate::sparse_index::{SparseBlock, SparseFile};
+use crate::bitcask::{ByteOffset, ByteSize, Value};
+use std::io::{Read, Write};
+use memmap2::Mmap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use tracing::{error, trace, warn};
 
 /// `DiskLogFile` 结构体代表一个磁盘上的日志文件。
 /// 它包含了文件的唯一标识符、文件路径和文件对象。
@@ -13,14 +20,19 @@ use tracing::trace;
 /// - `file_id`: 文件的唯一标识符，用于在文件之间进行区分。
 /// - `path`: 文件在磁盘上的路径，用于定位文件。
 /// - `file`: 文件的句柄，用于对文件进行读写操作。
+/// - `mmap`: 封存后的文件第一次被零拷贝读取时建立的内存映射。
+/// - `records`: 文件中的记录数量（包括墓碑和被覆盖的记录）。
+/// - `data_start`: 第一条记录的起始偏移量，即文件头部的大小，见`read_file_header`。
 pub(crate) struct DiskLogFile { // DataFile
     pub(crate) file_id: FileId,
     pub(crate) path: PathBuf,
     pub(crate) file: std::fs::File,
+    mmap: OnceLock<Arc<Mmap>>,
+    records: AtomicU64,
+    data_start: ByteOffset,
 }
 
 impl DiskLogFile {
-    pub(crate) const EXT: &'static str = "bitcask";
     pub(crate) const MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024; // 1GB
 
     /// 创建一个新的文件用于写入
@@ -28,47 +40,82 @@ impl DiskLogFile {
     /// # 参数
     /// - `data_dir`: 数据目录的路径，可以转换为 `PathBuf`
     /// - `file_id`: 文件的唯一标识符，类型为 `FileId`
+    /// - `naming`: 数据文件的命名方式
     ///
     /// # 返回
     /// - `Result<Self, BitCaskError>`: 返回一个结果，其中 Ok 包含一个文件对象 `Self`，Err 包含一个错误对象 `BitCaskError`
     ///
     /// # 说明
-    /// 该函数根据给定的数据目录和文件 ID 构建文件路径，并创建一个新的文件用于写入
+    /// 该函数根据给定的数据目录和文件 ID 构建文件路径，并创建一个新的文件用于写入。
+    /// 文件先以`.tmp`后缀创建并刷新到磁盘，再原子地重命名为正式文件，
+    /// 因此创建过程中崩溃不会留下含义不明的数据文件。
     pub(crate) fn new<T: Into<PathBuf>>(
         data_dir: T,
         file_id: FileId,
+        naming: &FileNaming,
     ) -> Result<Self, BitCaskError> {
         
         // 将数据目录转换为 PathBuf 对象
         let mut path: PathBuf = data_dir.into();
         
-        // 将文件 ID 添加到路径中
-        path.push(file_id.to_string());
-        
-        // 设置文件扩展名
-        path.set_extension(Self::EXT);
+        // 按命名方式将文件名添加到路径中
+        path.push(naming.file_name(file_id));
+
+        // 先创建临时文件并持久化，再重命名为正式文件
+        if !path.exists() {
+            let tmp = temp_path(&path);
+            let mut file = std::fs::File::create(&tmp)?;
+            write_file_header(&mut file)?;
+            file.sync_all()?;
+            std::fs::rename(tmp, &path)?;
+        }
         
-        // 使用 OpenOptions 创建、读取和追加模式打开文件
+        // 使用 OpenOptions 以读取和追加模式打开文件
         let file = std::fs::OpenOptions::new()
-            .create(true)
             .read(true)
             .append(true)
             .open(&path)?;
         
         // 返回 Ok 包含一个文件对象，其中包含文件 ID、路径和文件描述符
-        Ok(Self {
-            file_id,
-            path,
-            file,
-        })
+        Self::with_file(file_id, path, file)
     }
 
-    // 打开一个现有文件以进行读取
-    pub(crate) fn open(
+    /// 在临时路径`<文件名>.tmp`上创建一个新的文件用于写入，用于压缩的输出。
+    ///
+    /// 写完后需要调用`persist`将其重命名为正式文件，在此之前崩溃只会留下会被忽略的临时文件。
+    pub(crate) fn new_temp<T: Into<PathBuf>>(
+        data_dir: T,
         file_id: FileId,
-        path: PathBuf,
-        mem_index: &mut MemIndexStorage,
+        naming: &FileNaming,
     ) -> Result<Self, BitCaskError> {
+        let path = temp_path(&data_dir.into().join(naming.file_name(file_id)));
+        // 清除之前崩溃时留下的临时文件
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        write_file_header(&mut file)?;
+        Self::with_file(file_id, path, file)
+    }
+
+    /// 将`new_temp`创建的临时文件刷新到磁盘并原子地重命名为正式文件，返回正式文件的路径。
+    pub(crate) fn persist(self) -> Result<PathBuf, BitCaskError> {
+        let Self { path, file, .. } = self;
+        file.sync_all()?;
+        drop(file);
+        let target = path.with_extension("");
+        std::fs::rename(path, &target)?;
+        Ok(target)
+    }
+
+    // 打开一个现有文件以进行读取
+    ///
+    /// 打开后需要通过`populate_mem_index`或`populate_sparse_index`把文件中的记录加入内存索引。
+    pub(crate) fn open(file_id: FileId, path: PathBuf) -> Result<Self, BitCaskError> {
         
         // 这里所有的文件都以追加模式打开，但除了最后一个文件外，我们实际上并不追加任何内容
         trace!("opening disk log file: {:?}", path);
@@ -80,17 +127,37 @@ impl DiskLogFile {
             .open(&path)?;
         
         // 使用给定的文件ID、路径和文件对象来创建一个新的FileLog实例
-        let file = Self {
+        Self::with_file(file_id, path, file)
+    }
+
+    /// 以只读方式打开一个现有文件，用于只读打开的存储，写入者可能仍在另一个进程中追加这个文件。
+    pub(crate) fn open_read_only(file_id: FileId, path: PathBuf) -> Result<Self, BitCaskError> {
+        trace!("opening disk log file read-only: {:?}", path);
+        let file = std::fs::File::open(&path)?;
+        Self::with_file(file_id, path, file)
+    }
+
+    /// 用已经打开的文件创建实例，读取文件头部得到第一条记录的位置。
+    fn with_file(file_id: FileId, path: PathBuf, file: std::fs::File) -> Result<Self, BitCaskError> {
+        let data_start = read_file_header(&file).map_err(|e| match e {
+            BitCaskError::CorruptedData(message) => {
+                BitCaskError::CorruptedData(format!("{} in {:?}", message, path))
+            }
+            e => e,
+        })?;
+        Ok(Self {
             file_id,
             path,
             file,
-        };
-        
-        // 用内存索引填充文件，以便于快速查找文件中的数据
-        file.populate_mem_index(mem_index)?;
-        
-        // 返回成功的结果
-        Ok(file)
+            mmap: OnceLock::new(),
+            records: AtomicU64::new(0),
+            data_start,
+        })
+    }
+
+    /// 返回文件中第一条记录的起始偏移量。没有头部的旧文件从0开始，见`read_file_header`。
+    pub(crate) fn data_start(&self) -> ByteOffset {
+        self.data_start
     }
 
     /// 从磁盘日志文件中加载数据到内存索引中。
@@ -102,58 +169,185 @@ impl DiskLogFile {
     /// - `mem_index`: 一个可变引用，指向内存索引结构，该结构用于存储条目的键和其在磁盘文件中的位置信息。
     ///
     /// # 返回
-    /// - `Result<(), BitCaskError>`: 表示操作结果，如果成功则返回 `Ok(())`，否则返回包含错误信息的 `Err`。
+    /// - `Result<(u64, ByteOffset), BitCaskError>`: 表示操作结果，如果成功则返回加载的记录数量，
+    ///   以及最后一条生效的记录的结束位置（见`for_each_committed_entry_in`），否则返回包含错误信息的 `Err`。
     ///
     /// # 错误
     /// - 如果文件元数据获取失败，或者文件读取操作中发生错误，将返回 `BitCaskError`。
-    fn populate_mem_index(&self, mem_index: &mut MemIndexStorage) -> Result<(), BitCaskError> {
-       
-        // 获取文件的大小，用于确定读取的终点。
+    pub(crate) fn populate_mem_index(&self, mem_index: &mut MemIndexStorage) -> Result<(u64, ByteOffset), BitCaskError> {
+        self.populate_mem_index_from(mem_index, self.data_start)
+    }
+
+    /// 与`populate_mem_index`相同，但只加载从`start`开始的条目，用于加载keydir保存之后追加的条目。
+    pub(crate) fn populate_mem_index_from(
+        &self,
+        mem_index: &mut MemIndexStorage,
+        start: ByteOffset,
+    ) -> Result<(u64, ByteOffset), BitCaskError> {
         let file_size = self.file.metadata()?.len();
-        
-        // 创建一个缓冲读取器，用于高效读取文件内容。
-        let mut buffered_reader = BufReader::new(&self.file);
-       
-        // 初始化读取位置指针。
-        let mut cursor = 0u64;
-        
-        // 将文件读取位置设置到开始位置。
-        buffered_reader.seek(SeekFrom::Start(cursor))?;
-
-        // 循环读取文件中的条目，直到文件末尾。
-        loop {
-            
-            // 如果读取位置超过文件大小，则停止读取。
-            if cursor >= file_size {
-                break;
-            }
-            
-            // 读取并反序列化一个条目。
-            let entry: DiskLogEntry = DiskLogEntry::deserialize(&mut buffered_reader)?;
-            
-            // 计算条目总大小，用于更新读取位置。
-            let entry_size = entry.total_byte_size();
-            
+        let mut records = 0;
+        let committed = self.for_each_committed_entry_in(start, file_size, |cursor, entry| {
+            records += 1;
+            record_request_id(mem_index, &entry);
             // 如果条目是墓碑（表示删除操作），则不在内存索引中存储。
-            if entry.is_tombstone() {
-                mem_index.delete(&entry.key);
+            // 稀疏索引模式下墓碑需要留在索引中，否则查找时会读到更早的文件中被删除的值。
+            // 软删除的墓碑保留了值，需要留在索引中以便恢复。
+            if entry.is_tombstone() && !entry.soft_deleted && mem_index.sparse_interval().is_none() {
+                mem_index.delete(&entry.key, entry.timestamp.unwrap_or(0));
             } else {
-                // 创建一个内存索引条目，包含文件ID，值的偏移量和大小。
-                let mem_log_entry = MemIndexEntry {
-                    file_id: self.file_id,
-                    value_offset: cursor + entry.value_byte_offset(),
-                    value_size: entry.value_byte_size(),
-                };
-                // 将条目添加到内存索引中。
-                mem_index.put(entry.key, mem_log_entry);
+                self.index_entry(mem_index, cursor, entry);
             }
-            // 更新读取位置，指向下一个条目开始处。
-            cursor += entry_size;
+        })?;
+        Ok((records, committed))
+    }
+
+    /// 以稀疏索引模式加载文件，见`BitCaskOptions::sparse_index`。
+    ///
+    /// 每`interval`条记录中只有第一条被加入内存索引，其余的记录按块记录在`SparseFile`中。
+    /// 墓碑以及已经在索引中的键的记录总是被加入索引，因此索引中的条目总是键的最后一条记录，
+    /// 不在索引中的键的所有记录都可以按文件从新到旧在块中找到。返回文件中的记录数量。
+    pub(crate) fn populate_sparse_index(
+        self: &Arc<Self>,
+        mem_index: &mut MemIndexStorage,
+        interval: usize,
+    ) -> Result<u64, BitCaskError> {
+        let interval = interval.max(1);
+        let mut blocks: Vec<SparseBlock> = Vec::new();
+        let mut records = 0;
+        let file_size = self.file.metadata()?.len();
+        self.for_each_committed_entry_in(self.data_start, file_size, |cursor, entry| {
+            let sampled = records % interval == 0;
+            records += 1;
+            if sampled {
+                blocks.push(SparseBlock::new(cursor, interval));
+            }
+            blocks.last_mut().unwrap().add(&entry.key, cursor + entry.total_byte_size());
+            record_request_id(mem_index, &entry);
+            if sampled || entry.is_tombstone() || mem_index.get(&entry.key).is_some() {
+                self.index_entry(mem_index, cursor, entry);
+            } else {
+                mem_index.observe_timestamp(entry.timestamp.unwrap_or(0));
+            }
+        })?;
+        mem_index.add_sparse_file(SparseFile::new(self.clone(), blocks));
+        Ok(records as u64)
+    }
+
+    /// 打开存储时截掉文件末尾没有完整写入的原子批次，`committed`是`populate_mem_index`返回的最后一条生效的记录的结束位置，
+    /// 之后的追加因此不会接在被丢弃的记录之后。
+    pub(crate) fn truncate_uncommitted(&self, committed: ByteOffset) -> Result<(), BitCaskError> {
+        let file_size = self.file.metadata()?.len();
+        if committed < file_size {
+            warn!("Truncating {:?} from {} to {} bytes to drop an incomplete batch", self.path, file_size, committed);
+            self.truncate(committed)?;
+            self.file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// 返回文件中的记录占用的字节数，即文件的长度减去头部的大小。
+    pub(crate) fn record_bytes(&self) -> Result<ByteSize, BitCaskError> {
+        Ok(self.file.metadata()?.len().saturating_sub(self.data_start))
+    }
+
+    /// 返回文件中的记录数量，包括墓碑和已经被覆盖的记录。
+    pub(crate) fn record_count(&self) -> u64 {
+        self.records.load(Ordering::Relaxed)
+    }
+
+    /// 在记录数量中加上打开文件时加载的记录，见`DiskLogFileStorage::to_disk_log_files`。
+    pub(crate) fn add_records(&self, records: u64) {
+        self.records.fetch_add(records, Ordering::Relaxed);
+    }
+
+    /// 把文件中起始于`cursor`的记录加入内存索引。
+    fn index_entry(&self, mem_index: &mut MemIndexStorage, cursor: ByteOffset, entry: DiskLogEntry) {
+        // 创建一个内存索引条目，包含文件ID，值的偏移量和大小。
+        let mut mem_log_entry = MemIndexEntry::from_log_entry(
+            self.file_id,
+            cursor + entry.value_byte_offset(),
+            &entry,
+        );
+        // 足够小的值直接内联到索引项中
+        mem_log_entry.inline_value = mem_index.inline_candidate(&entry);
+        // 将条目添加到内存索引中。
+        mem_index.put(entry.key, mem_log_entry);
+    }
+
+    /// 按写入顺序遍历文件中的所有条目（包括墓碑）。
+    ///
+    /// # 参数
+    /// - `f`: 对每个条目调用的函数，参数是条目在文件中的起始偏移量和条目本身。
+    ///
+    /// # 错误
+    /// - 如果文件元数据获取失败，或者文件读取操作中发生错误，将返回 `BitCaskError`。
+    pub(crate) fn for_each_entry<F: FnMut(ByteOffset, DiskLogEntry)>(&self, f: F) -> Result<(), BitCaskError> {
+       
+        // 获取文件的大小，用于确定读取的终点。
+        let file_size = self.file.metadata()?.len();
+        self.for_each_entry_in(self.data_start, file_size, f)
+    }
+
+    /// 按写入顺序遍历文件中从`start`开始、在`end`之前结束的条目，`start`必须是某个条目的起始偏移量。
+    pub(crate) fn for_each_entry_in<F: FnMut(ByteOffset, DiskLogEntry)>(
+        &self,
+        start: ByteOffset,
+        end: ByteOffset,
+        mut f: F,
+    ) -> Result<(), BitCaskError> {
+        // 按位置读取，不会移动共享的文件游标，
+        // 因此遍历活跃文件时不会与写入者或其他读者互相干扰。
+        let mut reader = EntryReader::new(self, start, end);
+
+        // 循环读取文件中的条目，直到范围的末尾。
+        while let Some((cursor, entry)) = reader.next_entry()? {
+            f(cursor, entry);
         }
         // 所有操作完成，返回Ok(())。
         Ok(())
     }
 
+    /// 与`for_each_entry_in`相同，但跳过没有完整写入的原子批次，返回最后一条生效的记录的结束位置。
+    ///
+    /// 批次中的记录（见`DiskLogEntry::set_batch_remaining`）先被暂存，读到批次的最后一条记录时才一起交给`f`。
+    /// 批次被其他记录打断或者到范围的末尾还没有结束，说明写入批次时发生了崩溃，暂存的记录被丢弃。
+    pub(crate) fn for_each_committed_entry_in<F: FnMut(ByteOffset, DiskLogEntry)>(
+        &self,
+        start: ByteOffset,
+        end: ByteOffset,
+        mut f: F,
+    ) -> Result<ByteOffset, BitCaskError> {
+        let mut reader = EntryReader::new(self, start, end);
+        let mut batch: Vec<(ByteOffset, DiskLogEntry)> = Vec::new();
+        // 暂存的最后一条记录之后批次中还有几条记录
+        let mut pending = 0;
+        let mut committed = start;
+        while let Some((cursor, entry)) = reader.next_entry()? {
+            let remaining = entry.batch_remaining();
+            if !batch.is_empty() && remaining.is_none_or(|remaining| remaining + 1 != pending) {
+                warn!("Ignoring {} records of an incomplete batch at offset {} of {:?}", batch.len(), batch[0].0, self.path);
+                batch.clear();
+            }
+            let entry_end = cursor + entry.total_byte_size();
+            match remaining {
+                None => f(cursor, entry),
+                Some(remaining) => {
+                    batch.push((cursor, entry));
+                    pending = remaining;
+                    if remaining > 0 {
+                        continue;
+                    }
+                    batch.drain(..).for_each(|(cursor, entry)| f(cursor, entry));
+                }
+            }
+            committed = entry_end;
+        }
+        if let Some((offset, _)) = batch.first() {
+            warn!("Ignoring {} records of an incomplete batch at offset {} of {:?}", batch.len(), offset, self.path);
+        }
+        Ok(committed)
+    }
+
     /// 向日志文件中追加新的日志条目
     ///
     /// # 参数
@@ -167,11 +361,329 @@ impl DiskLogFile {
     /// 此函数负责将一个新的日志条目追加到日志文件的末尾，并确保更改持久化到磁盘。
     /// 它首先计算出日志条目在文件中的位置（偏移量），然后将日志条目序列化到文件中，
     /// 最后刷新文件缓冲区以确保更改持久化。这个过程保证了日志条目的原子写入和持久化。
-    pub(crate) fn append_new_entry(&mut self, entry: DiskLogEntry) -> Result<u64, BitCaskError> {
-        let file = &mut self.file;
-        let value_offset = file.seek(SeekFrom::End(0))? + entry.value_byte_offset();
-        entry.serialize(file)?;
-        file.flush()?; // 确保持久性
-        Ok(value_offset)
+    pub(crate) fn append_new_entry(&self, entry: DiskLogEntry) -> Result<u64, BitCaskError> {
+        let start = self.end_offset()?;
+        // 先在复用的缓冲区中编码整个条目，再一次性写入文件
+        with_scratch(|buf| {
+            entry.serialize(buf)?;
+            self.append_bytes(start, buf)
+        })?;
+        self.add_records(1);
+        Ok(start + entry.value_byte_offset())
+    }
+
+    /// 将多个日志条目一次性追加到日志文件的末尾，返回每个条目的值在文件中的偏移量。
+    ///
+    /// 所有条目先在复用的缓冲区中编码，再通过一次写入追加到文件，用于批量导入。
+    pub(crate) fn append_entries(&self, entries: &[DiskLogEntry]) -> Result<Vec<u64>, BitCaskError> {
+        let start = self.end_offset()?;
+        let mut cursor = start;
+        let mut value_offsets = Vec::with_capacity(entries.len());
+        with_scratch(|buf| {
+            for entry in entries {
+                value_offsets.push(cursor + entry.value_byte_offset());
+                cursor += entry.total_byte_size();
+                entry.serialize(buf)?;
+            }
+            self.append_bytes(start, buf)
+        })?;
+        self.add_records(entries.len() as u64);
+        Ok(value_offsets)
+    }
+
+    /// 返回追加的下一条记录的起始偏移量，即文件当前的长度。
+    ///
+    /// 不使用`seek(SeekFrom::End(0))`：windows上的按位置读取会移动文件共享的游标（见`PositionalReader`），
+    /// 追加不能依赖游标的位置。文件以追加模式打开，写入总是落在文件末尾；追加只在持有存储的写锁时进行，
+    /// 读取长度和写入之间不会有其他的追加，因此这里的长度就是写入的位置。
+    fn end_offset(&self) -> Result<ByteOffset, BitCaskError> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// 撤销已经成功追加的`records`条记录，把文件截断回追加之前的长度`start`，
+    /// 用于超过IO看门狗期限的追加，见`StallAction::Fail`。
+    pub(crate) fn roll_back(&self, start: ByteOffset, records: u64) -> Result<(), BitCaskError> {
+        self.truncate(start)?;
+        self.records.fetch_sub(records, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// 把已经编码的记录追加到文件末尾，`start`是写入前的文件长度。
+    ///
+    /// 写入失败时把文件截断回`start`，之后的追加不会接在写入了一部分的记录之后；
+    /// 磁盘空间不足时返回`BitCaskError::DiskFull`。
+    fn append_bytes(&self, start: ByteOffset, buf: &[u8]) -> Result<(), BitCaskError> {
+        let mut file = &self.file;
+        let Err(e) = file.write_all(buf).and_then(|()| file.flush()) else {
+            return Ok(());
+        };
+        if let Err(truncate_error) = self.truncate(start) {
+            error!("Error while rolling back a partial write to {:?}: {:?}", self.path, truncate_error);
+        }
+        match e.kind() {
+            std::io::ErrorKind::StorageFull => Err(BitCaskError::DiskFull),
+            _ => Err(e.into()),
+        }
+    }
+
+    /// 把文件截断到`len`字节，用于打开时丢弃不完整的批次以及撤销失败或超时的追加。
+    ///
+    /// 这些路径只作用于没有内存映射的文件：打开时文件还没有被发布给读者；撤销追加只发生在活跃文件上，
+    /// 而`DiskLogFileStorage::get_ref`只映射快照中不是最后一个的（已经封存的）文件，活跃文件总是快照中的最后一个文件。
+    /// 截断被映射的文件会让引用映射的`ValueRef`在访问时触发SIGBUS，因此文件已经被映射时拒绝截断并返回错误。
+    fn truncate(&self, len: ByteOffset) -> Result<(), BitCaskError> {
+        if self.mmap.get().is_some() {
+            return Err(BitCaskError::Internal(format!(
+                "refusing to truncate memory-mapped data file {:?}",
+                self.path
+            )));
+        }
+        self.file.set_len(len)?;
+        Ok(())
     }
+
+    /// 从文件的给定偏移量读取指定大小的值。
+    ///
+    /// 使用按位置读取而不是移动共享的文件游标，因此多个读者可以同时读取同一个文件，
+    /// 也不会受到写入者追加数据的影响。
+    pub(crate) fn read_at(&self, offset: ByteOffset, size: ByteSize) -> Result<Value, BitCaskError> {
+        let mut buf = Vec::new();
+        self.read_at_into(offset, size, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// 从文件的给定偏移量读取指定大小的值到`buf`中，`buf`原有的内容会被覆盖。
+    ///
+    /// 调用方可以复用`buf`，避免每次读取都分配新的内存。
+    pub(crate) fn read_at_into(&self, offset: ByteOffset, size: ByteSize, buf: &mut Vec<u8>) -> Result<(), BitCaskError> {
+        buf.clear();
+        buf.resize(size as usize, 0);
+        PositionalReader::new(&self.file, offset).read_exact(buf)?;
+        Ok(())
+    }
+
+    /// 从文件的给定偏移量读取一条完整的记录，校验和不匹配时返回`BitCaskError::CorruptedData`。
+    pub(crate) fn read_entry_at(&self, offset: ByteOffset) -> Result<DiskLogEntry, BitCaskError> {
+        let file_size = self.file.metadata()?.len();
+        match EntryReader::new(self, offset, file_size).next_entry()? {
+            Some((_, entry)) => Ok(entry),
+            None => Err(BitCaskError::CorruptedData(format!(
+                "no record at offset {} of {:?}, the file has {} bytes",
+                offset, self.path, file_size
+            ))),
+        }
+    }
+
+    /// 返回整个文件的只读内存映射，第一次调用时建立映射，之后的调用共享同一个映射。
+    ///
+    /// 只能对已经封存的文件调用：映射建立之后追加的数据不在映射的范围内。
+    pub(crate) fn mmap(&self) -> Result<Arc<Mmap>, BitCaskError> {
+        if let Some(mmap) = self.mmap.get() {
+            return Ok(mmap.clone());
+        }
+        // SAFETY: 封存的文件不会再被写入；截断文件只通过`truncate`，它只作用于打开时还没有发布的文件和活跃文件，
+        // 并且在文件已经被映射时拒绝截断，因此映射的范围内的数据不会消失；压缩只会创建新的文件，
+        // 映射持有文件的引用，文件被删除后映射仍然有效
+        let mmap = Arc::new(unsafe { Mmap::map(&self.file)? });
+        Ok(self.mmap.get_or_init(|| mmap).clone())
+    }
+}
+
+/// 加载时记录带有请求ID的写入，见`PutOption::request_id`。
+fn record_request_id(mem_index: &mut MemIndexStorage, entry: &DiskLogEntry) {
+    if let (Some(request_id), Some(timestamp)) = (entry.request_id, entry.timestamp) {
+        mem_index.record_request_id(request_id, timestamp);
+    }
+}
+
+/// 数据文件头部的魔数，位于没有头部的文件中第一条记录的校验和的位置。
+const FILE_MAGIC: [u8; 4] = *b"BCSK";
+/// 紧跟魔数的标记字节，位于记录的版本字节的位置。第一版格式的记录在这里是8字节键大小的最高字节，总是为0，
+/// 第二版格式的记录在这里是2，因此加入头部之前写入的文件不会被误认为有头部。
+const FILE_HEADER_MARKER: u8 = 0xff;
+/// 当前的数据文件格式版本。只有旧版本无法正确读取的改动才增加版本号，
+/// 旧版本可以忽略的信息写入头部的扩展区。
+const FILE_VERSION: u8 = 1;
+/// 头部中扩展区之前的固定部分的大小：魔数、标记、版本和2字节的扩展区大小。
+pub(crate) const FILE_HEADER_SIZE: ByteSize = 8;
+
+/// 写入数据文件的头部，只在创建新文件时调用。
+///
+/// File layout
+///  - Magic (4 bytes, `BCSK`)
+///  - Marker (1 byte, always 0xff)
+///  - File format version (1 byte)
+///  - Extension section size (2 bytes, big endian)
+///  - Extension section (`type | length | data` entries, the same format as the record extensions,
+///    see `DiskLogEntry::extensions`; unknown non-critical extensions are skipped)
+///  - Records
+///
+/// 当前版本不写入任何扩展，扩展区为空。
+fn write_file_header<T: Write>(file: &mut T) -> Result<(), BitCaskError> {
+    let mut header = [0u8; FILE_HEADER_SIZE as usize];
+    header[..4].copy_from_slice(&FILE_MAGIC);
+    header[4] = FILE_HEADER_MARKER;
+    header[5] = FILE_VERSION;
+    file.write_all(&header)?;
+    Ok(())
+}
+
+/// 读取数据文件的头部，返回第一条记录的起始偏移量。没有头部的文件（加入头部之前写入的文件）返回0。
+///
+/// 不认识的格式版本、截断的头部和不认识的关键扩展视为数据损坏，不认识的非关键扩展被跳过。
+fn read_file_header(file: &std::fs::File) -> Result<ByteOffset, BitCaskError> {
+    let file_size = file.metadata()?.len();
+    let mut prefix = vec![0u8; file_size.min(FILE_HEADER_SIZE) as usize];
+    PositionalReader::new(file, 0).read_exact(&mut prefix)?;
+    let Some(extension_size) = parse_file_header(&prefix)? else {
+        return Ok(0);
+    };
+    let data_start = FILE_HEADER_SIZE + extension_size;
+    if data_start > file_size {
+        return Err(BitCaskError::CorruptedData("truncated data file header".to_string()));
+    }
+    let mut extensions = vec![0u8; extension_size as usize];
+    PositionalReader::new(file, FILE_HEADER_SIZE).read_exact(&mut extensions)?;
+    check_extensions(&extensions, "data file header")?;
+    Ok(data_start)
+}
+
+/// 解析数据文件开头的字节，返回头部中扩展区的大小，开头不是头部时返回`None`。
+fn parse_file_header(prefix: &[u8]) -> Result<Option<ByteSize>, BitCaskError> {
+    if prefix.len() <= 4 || prefix[..4] != FILE_MAGIC || prefix[4] != FILE_HEADER_MARKER {
+        return Ok(None);
+    }
+    if prefix.len() < FILE_HEADER_SIZE as usize {
+        return Err(BitCaskError::CorruptedData("truncated data file header".to_string()));
+    }
+    if prefix[5] != FILE_VERSION {
+        return Err(BitCaskError::CorruptedData(format!(
+            "data file uses unsupported format version {}",
+            prefix[5]
+        )));
+    }
+    Ok(Some(u16::from_be_bytes([prefix[6], prefix[7]]) as ByteSize))
+}
+
+/// 返回字节切片形式的数据文件中第一条记录的起始偏移量，头部无法解析时返回0，用于抢救损坏的文件，见`salvage`。
+pub(crate) fn data_start_of(buf: &[u8]) -> ByteOffset {
+    let prefix = &buf[..buf.len().min(FILE_HEADER_SIZE as usize)];
+    match parse_file_header(prefix) {
+        Ok(Some(extension_size)) => (FILE_HEADER_SIZE + extension_size).min(buf.len() as u64),
+        _ => 0,
+    }
+}
+
+/// 读取记录时每次至少从文件读取的字节数，与标准库`BufReader`的默认缓冲区大小相同。
+const READ_AHEAD_SIZE: u64 = 8 * 1024;
+
+/// `EntryReader` 按写入顺序读取文件中一段范围内的记录。
+///
+/// 文件的数据按块读入缓冲区，再从缓冲区的字节切片中解析记录，见`Deserialize`。
+/// 记录的大小超出范围的末尾时直接报告记录损坏，因此损坏的大小字段不会导致按它分配内存。
+struct EntryReader<'a> {
+    file: &'a DiskLogFile,
+    reader: PositionalReader<'a>,
+    /// 从`cursor`开始已经读入但还没有解析的数据。
+    buf: Vec<u8>,
+    /// `buf`中已经解析过的字节数。
+    consumed: usize,
+    /// 下一条记录的起始偏移量。
+    cursor: ByteOffset,
+    end: ByteOffset,
+}
+
+impl<'a> EntryReader<'a> {
+    /// 创建一个读取`start`到`end`之间的记录的读取器，`start`必须是某个记录的起始偏移量。
+    fn new(file: &'a DiskLogFile, start: ByteOffset, end: ByteOffset) -> Self {
+        Self {
+            file,
+            reader: PositionalReader::new(&file.file, start),
+            buf: Vec::new(),
+            consumed: 0,
+            cursor: start,
+            end,
+        }
+    }
+
+    /// 读取下一条记录，返回记录的起始偏移量和记录本身，到达范围的末尾时返回`None`。
+    fn next_entry(&mut self) -> Result<Option<(ByteOffset, DiskLogEntry)>, BitCaskError> {
+        while self.cursor < self.end {
+            match DiskLogEntry::deserialize(&self.buf[self.consumed..])? {
+                Decoded::Complete(entry, len) => {
+                    let cursor = self.cursor;
+                    self.cursor += len as u64;
+                    self.consumed += len;
+                    return Ok(Some((cursor, entry)));
+                }
+                Decoded::Incomplete(needed) => {
+                    // 记录被截断，或者大小字段已经损坏（例如声称值有几个GB）
+                    let remaining = self.end - self.cursor;
+                    if needed > remaining {
+                        return Err(BitCaskError::CorruptedData(format!(
+                            "record at offset {} of {:?} needs {} bytes, only {} remain",
+                            self.cursor, self.file.path, needed, remaining
+                        )));
+                    }
+                    // 丢弃已经解析的数据，再读入至少`needed`字节
+                    self.buf.drain(..self.consumed);
+                    self.consumed = 0;
+                    let filled = self.buf.len();
+                    self.buf.resize(needed.max(READ_AHEAD_SIZE).min(remaining) as usize, 0);
+                    self.reader.read_exact(&mut self.buf[filled..])?;
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// `PositionalReader` 从文件的给定偏移量开始按位置读取（unix上的pread，windows上的`seek_read`），自己维护读取位置。
+///
+/// 每次读取都给出偏移量，多个读者可以同时通过同一个`File`读取，不会读到彼此的位置。
+/// 在unix上读取不会移动文件共享的游标；windows上的`seek_read`会移动游标，
+/// 因此同一个`File`上的其他操作不能依赖游标的位置，追加的位置见`DiskLogFile::end_offset`。
+struct PositionalReader<'a> {
+    file: &'a std::fs::File,
+    offset: u64,
+}
+
+impl<'a> PositionalReader<'a> {
+    /// 创建一个从`offset`开始读取的读取器。
+    fn new(file: &'a std::fs::File, offset: u64) -> Self {
+        Self { file, offset }
+    }
+}
+
+impl Read for PositionalReader<'_> {
+    /// 从当前位置读取数据并向后移动读取位置。
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        #[cfg(unix)]
+        let n = {
+            use std::os::unix::fs::FileExt;
+            self.file.read_at(buf, self.offset)?
+        };
+        #[cfg(windows)]
+        let n = {
+            use std::os::windows::fs::FileExt;
+            self.file.seek_read(buf, self.offset)?
+        };
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// fsync目录，使其中文件的创建、重命名和删除在断电后仍然有效，见`BitCaskOptions::sync_directory`。
+/// 只在unix上执行，其他平台不支持打开目录，什么都不做。
+pub(crate) fn sync_dir(dir: &Path) -> Result<(), BitCaskError> {
+    #[cfg(unix)]
+    std::fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// 返回数据文件对应的临时文件路径，即在文件名后追加`.tmp`。
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap().to_os_string();
+    file_name.push(".tmp");
+    path.with_file_name(file_name)
 }
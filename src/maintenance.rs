@@ -0,0 +1,154 @@
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+
+/// 后台任务的优先级。多个任务同时到期时先执行优先级高的任务。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum TaskPriority {
+    /// 可以推迟的任务，例如保存keydir。
+    Low,
+    /// 例如清理过期键。
+    Normal,
+    /// 影响持久性的任务，例如按间隔fsync。
+    High,
+}
+
+/// `MaintenancePool` 是一个存储的所有后台维护任务共享的线程池，见`BitCaskOptions::maintenance_threads`。
+///
+/// 任务是周期性的：每次执行后返回下一次执行的延迟，返回`None`时不再执行。线程在第一次提交任务时才创建，
+/// 数量不超过配置的线程数；线程池被丢弃（所有`BitCask`句柄都被丢弃）后线程在当前任务完成后退出。
+pub(crate) struct MaintenancePool {
+    shared: Arc<Shared>,
+    threads: usize,
+}
+
+/// 线程池和工作线程共享的状态。
+struct Shared {
+    state: Mutex<PoolState>,
+    /// 有新任务或者线程池被丢弃时唤醒等待的工作线程。
+    wakeup: Condvar,
+}
+
+struct PoolState {
+    /// 等待执行的任务，正在执行的任务不在其中。任务数量很少，因此按顺序查找下一个任务。
+    tasks: Vec<Task>,
+    /// 已经创建的工作线程数量。
+    workers: usize,
+    /// 线程池是否已经被丢弃。
+    shutdown: bool,
+}
+
+/// 一个周期性的后台任务。
+struct Task {
+    name: &'static str,
+    priority: TaskPriority,
+    /// 下一次执行的时间。
+    due: Instant,
+    run: Box<dyn FnMut() -> Option<Duration> + Send>,
+}
+
+impl MaintenancePool {
+    /// 创建最多使用`threads`个线程的线程池，至少使用一个线程。
+    pub(crate) fn new(threads: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(PoolState {
+                    tasks: Vec::new(),
+                    workers: 0,
+                    shutdown: false,
+                }),
+                wakeup: Condvar::new(),
+            }),
+            threads: threads.max(1),
+        }
+    }
+
+    /// 提交一个在`delay`之后第一次执行的周期性任务。
+    ///
+    /// `run`返回下一次执行的延迟，返回`None`或者panic时任务被移除。
+    pub(crate) fn schedule(
+        &self,
+        name: &'static str,
+        priority: TaskPriority,
+        delay: Duration,
+        run: impl FnMut() -> Option<Duration> + Send + 'static,
+    ) {
+        let mut state = self.shared.state();
+        state.tasks.push(Task {
+            name,
+            priority,
+            due: Instant::now() + delay,
+            run: Box::new(run),
+        });
+        if state.workers < self.threads.min(state.tasks.len()) {
+            let shared = self.shared.clone();
+            let spawned = std::thread::Builder::new()
+                .name(format!("bitcask-maintenance-{}", state.workers))
+                .spawn(move || worker_loop(shared));
+            match spawned {
+                Ok(_) => state.workers += 1,
+                Err(e) if state.workers == 0 => error!("Failed to start a maintenance thread, {} does not run: {:?}", name, e),
+                Err(e) => warn!("Failed to start another maintenance thread: {:?}", e),
+            }
+        }
+        drop(state);
+        // 新任务可能比等待中的线程要等的任务更早到期
+        self.shared.wakeup.notify_one();
+    }
+}
+
+impl Drop for MaintenancePool {
+    /// 通知工作线程退出，不等待正在执行的任务完成。
+    fn drop(&mut self) {
+        self.shared.state().shutdown = true;
+        self.shared.wakeup.notify_all();
+    }
+}
+
+impl Shared {
+    /// 获取状态的锁。持有锁时不会执行任务，也不会panic，因此忽略中毒。
+    fn state(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// 工作线程：执行已经到期的任务中优先级最高的一个，同一优先级中先执行到期最早的；没有到期的任务时等到最早的任务到期。
+fn worker_loop(shared: Arc<Shared>) {
+    let mut state = shared.state();
+    loop {
+        if state.shutdown {
+            return;
+        }
+        let now = Instant::now();
+        let next = state
+            .tasks
+            .iter()
+            .enumerate()
+            .filter(|(_, task)| task.due <= now)
+            .max_by_key(|(_, task)| (task.priority, std::cmp::Reverse(task.due)))
+            .map(|(index, _)| index);
+        let Some(index) = next else {
+            let earliest = state.tasks.iter().map(|task| task.due).min();
+            state = match earliest {
+                Some(due) => {
+                    let timeout = due.saturating_duration_since(now);
+                    shared.wakeup.wait_timeout(state, timeout).unwrap_or_else(PoisonError::into_inner).0
+                }
+                None => shared.wakeup.wait(state).unwrap_or_else(PoisonError::into_inner),
+            };
+            continue;
+        };
+        let mut task = state.tasks.swap_remove(index);
+        drop(state);
+        let next_run = catch_unwind(AssertUnwindSafe(|| (task.run)())).unwrap_or_else(|_| {
+            error!("Maintenance task {} panicked and will not run again", task.name);
+            None
+        });
+        state = shared.state();
+        if let Some(delay) = next_run {
+            task.due = Instant::now() + delay;
+            state.tasks.push(task);
+        }
+    }
+}
@@ -0,0 +1,538 @@
+use crate::auth::{AccessControl, Grant};
+use crate::bitcask::{BitCask, KVStorage, Key, PutOption, Value};
+use crate::clock::now_millis;
+use crate::error::BitCaskError;
+use crate::tls::TlsOptions;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::warn;
+
+/// memcached协议允许的最大键长度。
+const MAX_KEY_LEN: usize = 250;
+
+/// 不超过该秒数的过期时间是相对时间，更大的值是Unix时间戳（30天）。
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+/// 存储在值前面的客户端标志的字节数。
+const FLAGS_LEN: usize = 4;
+
+/// 认证`set`的数据块（凭据）允许的最大字节数。认证之前的客户端不可信，超过时直接关闭连接。
+const MAX_CREDENTIAL_LEN: usize = 1024;
+
+/// 存储命令允许的最大数据块字节数，与memcached的默认值相同（1 MB）。
+const MAX_ITEM_SIZE: usize = 1024 * 1024;
+
+/// 在`listener`上提供memcached文本协议服务，每个连接使用一个线程，直到接受连接失败才返回。
+///
+/// 支持`get`/`gets`、`set`/`add`/`replace`、`delete`、`incr`/`decr`、`version`和`quit`，
+/// 过期时间的语义与memcached相同。客户端标志以4字节大端整数的形式存储在值的前面，
+/// 因此提供服务的存储应只通过memcached协议访问。开启审计日志时，每个连接以对端地址作为客户端ID。
+pub fn serve(listener: TcpListener, bitcask: BitCask) -> std::io::Result<()> {
+    serve_with_options(listener, bitcask, ServerOptions::default())
+}
+
+/// 与`serve`相同，但每个连接都需要先认证，之后的请求受凭据的权限限制，见`handle_authenticated_connection`。
+pub fn serve_with_access(listener: TcpListener, bitcask: BitCask, access: AccessControl) -> std::io::Result<()> {
+    let options = ServerOptions {
+        access: Some(access),
+        ..ServerOptions::default()
+    };
+    serve_with_options(listener, bitcask, options)
+}
+
+/// `ServerOptions` 配置memcached服务的认证和传输加密。
+#[derive(Debug, Clone, Default)]
+pub struct ServerOptions {
+    /// 连接需要认证时接受的凭据，见`handle_authenticated_connection`。为`None`时（默认）不需要认证。
+    pub access: Option<AccessControl>,
+    /// 开启后所有连接都必须使用TLS，可以通过`TlsOptions::client_ca_file`要求客户端证书。
+    /// 为`None`时（默认）使用明文。
+    pub tls: Option<TlsOptions>,
+}
+
+/// 与`serve`相同，但按`options`要求认证或使用TLS；证书无法读取时立即返回错误。
+///
+/// TLS握手在连接的线程中进行，握手失败只会关闭该连接。
+pub fn serve_with_options(listener: TcpListener, bitcask: BitCask, options: ServerOptions) -> std::io::Result<()> {
+    let settings = ConnectionSettings::new(options)?;
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let bitcask = match stream.peer_addr() {
+            Ok(peer) => bitcask.with_client_id(&peer.to_string()),
+            Err(_) => bitcask.clone(),
+        };
+        spawn_connection(stream, bitcask, settings.clone());
+    }
+    Ok(())
+}
+
+/// 与`serve_with_options`相同，但在Unix域套接字上提供服务，适合同一台机器上的低延迟访问，不需要开放TCP端口。
+///
+/// 套接字通常由`bind_unix`创建。开启审计日志时，连接的客户端ID为`unix`。
+#[cfg(unix)]
+pub fn serve_unix(listener: UnixListener, bitcask: BitCask, options: ServerOptions) -> std::io::Result<()> {
+    let settings = ConnectionSettings::new(options)?;
+    let bitcask = bitcask.with_client_id("unix");
+    for stream in listener.incoming() {
+        spawn_connection(stream?, bitcask.clone(), settings.clone());
+    }
+    Ok(())
+}
+
+/// 在`path`上创建Unix域套接字，并把套接字文件的权限设置为`mode`（例如`0o660`只允许所有者和同组用户连接）。
+///
+/// 上一次运行遗留的套接字文件会被删除；如果该套接字仍有服务在监听，返回`AddrInUse`错误。
+/// 套接字文件在绑定之后才设置权限，因此目录本身应只允许可信的用户访问。
+#[cfg(unix)]
+pub fn bind_unix<P: AsRef<Path>>(path: P, mode: u32) -> std::io::Result<UnixListener> {
+    let path = path.as_ref();
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        if UnixStream::connect(path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("{:?} is already being served", path),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// 所有连接共享的TLS配置和凭据。
+#[derive(Clone)]
+pub(crate) struct ConnectionSettings {
+    tls: Option<Arc<ServerConfig>>,
+    access: Option<Arc<AccessControl>>,
+}
+
+impl ConnectionSettings {
+    /// 读取TLS证书，证书无法读取时返回错误。
+    pub(crate) fn new(options: ServerOptions) -> std::io::Result<Self> {
+        let tls = match &options.tls {
+            Some(tls) => Some(tls.server_config().map_err(std::io::Error::other)?),
+            None => None,
+        };
+        Ok(Self {
+            tls,
+            access: options.access.map(Arc::new),
+        })
+    }
+}
+
+/// 在新线程中处理一个连接，开启TLS时先完成握手。
+pub(crate) fn spawn_connection<S: Read + Write + Send + 'static>(
+    stream: S,
+    bitcask: BitCask,
+    settings: ConnectionSettings,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        let ConnectionSettings { tls, access } = settings;
+        let res = match tls {
+            Some(tls) => ServerConnection::new(tls).map_err(std::io::Error::other).and_then(|conn| {
+                let stream = BufReader::new(StreamOwned::new(conn, stream));
+                run_connection(Duplex(stream), bitcask, access.as_deref())
+            }),
+            None => run_connection(Duplex(BufReader::new(stream)), bitcask, access.as_deref()),
+        };
+        if let Err(e) = res {
+            warn!("memcached connection closed with error: {:?}", e);
+        }
+    })
+}
+
+/// 由分开的读取端和写入端组成的连接。
+struct Split<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: Read, W> Read for Split<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R: BufRead, W> BufRead for Split<R, W> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount)
+    }
+}
+
+impl<R, W: Write> Write for Split<R, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// 同一个双向流（套接字或TLS流）组成的连接，读取经过缓冲，写入直接写到底层的流。
+struct Duplex<S>(BufReader<S>);
+
+impl<S: Read> Read for Duplex<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<S: Read> BufRead for Duplex<S> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.0.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.0.consume(amount)
+    }
+}
+
+impl<S: Write> Write for Duplex<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.get_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.get_mut().flush()
+    }
+}
+
+/// 处理一个连接上的所有请求，直到对方关闭连接或发送`quit`。
+pub fn handle_connection<R: BufRead, W: Write>(reader: R, writer: W, bitcask: BitCask) -> std::io::Result<()> {
+    run_connection(Split { reader, writer }, bitcask, None)
+}
+
+/// 处理一个需要认证的连接上的所有请求。
+///
+/// 认证沿用memcached文本协议的约定：客户端发送一个`set`，数据为`<用户名> <密码>`或者一个令牌，
+/// 键、标志和过期时间会被忽略；认证成功返回`STORED`，认证失败时返回`CLIENT_ERROR authentication failure`并关闭连接，
+/// 数据超过1 KiB时不读取数据，同样返回认证失败并关闭连接。
+/// 认证之前除`version`和`quit`之外的命令都返回`CLIENT_ERROR unauthenticated`；
+/// 认证之后访问凭据权限之外的键返回`CLIENT_ERROR access denied`。
+pub fn handle_authenticated_connection<R: BufRead, W: Write>(
+    reader: R,
+    writer: W,
+    bitcask: BitCask,
+    access: &AccessControl,
+) -> std::io::Result<()> {
+    run_connection(Split { reader, writer }, bitcask, Some(access))
+}
+
+fn run_connection<C: BufRead + Write>(
+    mut conn: C,
+    mut bitcask: BitCask,
+    access: Option<&AccessControl>,
+) -> std::io::Result<()> {
+    // 不需要认证时连接可以读写所有键
+    let mut grant = match access {
+        Some(_) => None,
+        None => Some(Grant::read_write()),
+    };
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if conn.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        let tokens: Vec<&[u8]> = line
+            .strip_suffix(b"\n")
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .unwrap_or(&line)
+            .split(|byte| *byte == b' ')
+            .filter(|token| !token.is_empty())
+            .collect();
+        let Some((command, args)) = tokens.split_first() else {
+            conn.write_all(b"ERROR\r\n")?;
+            continue;
+        };
+        let noreply = args.last() == Some(&&b"noreply"[..]);
+        let response = match (*command, &grant, access) {
+            (b"version", _, _) => format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes(),
+            (b"quit", _, _) => return conn.flush(),
+            (b"set", None, Some(access)) => {
+                let Some(request) = StorageRequest::parse(args) else {
+                    conn.write_all(b"CLIENT_ERROR bad command line format\r\n")?;
+                    continue;
+                };
+                if request.bytes > MAX_CREDENTIAL_LEN {
+                    conn.write_all(b"CLIENT_ERROR authentication failure\r\n")?;
+                    return conn.flush();
+                }
+                let Some(data) = read_data(&mut conn, request.bytes)? else {
+                    conn.write_all(b"CLIENT_ERROR bad data chunk\r\n")?;
+                    continue;
+                };
+                grant = authenticate(access, &data).cloned();
+                if grant.is_none() {
+                    // 认证失败后关闭连接，客户端不能在同一个连接上反复猜测凭据
+                    conn.write_all(b"CLIENT_ERROR authentication failure\r\n")?;
+                    return conn.flush();
+                }
+                b"STORED\r\n".to_vec()
+            }
+            (_, None, _) => b"CLIENT_ERROR unauthenticated\r\n".to_vec(),
+            (b"get" | b"gets", Some(grant), _) => match args.iter().all(|key| grant.can_read(key)) {
+                true => get(&bitcask, args),
+                false => ACCESS_DENIED.to_vec(),
+            },
+            (b"set" | b"add" | b"replace", Some(grant), _) => {
+                let Some(request) = StorageRequest::parse(args) else {
+                    conn.write_all(b"CLIENT_ERROR bad command line format\r\n")?;
+                    continue;
+                };
+                if request.bytes > MAX_ITEM_SIZE {
+                    // 与memcached相同：丢弃数据块，连接可以继续使用
+                    discard_data(&mut conn, request.bytes)?;
+                    conn.write_all(b"SERVER_ERROR object too large for cache\r\n")?;
+                    continue;
+                }
+                let Some(data) = read_data(&mut conn, request.bytes)? else {
+                    conn.write_all(b"CLIENT_ERROR bad data chunk\r\n")?;
+                    continue;
+                };
+                match grant.can_write(&request.key) {
+                    true => store(&mut bitcask, command, request, data),
+                    false => ACCESS_DENIED.to_vec(),
+                }
+            }
+            (b"delete" | b"incr" | b"decr", Some(grant), _)
+                if args.first().is_some_and(|key| !grant.can_write(key)) =>
+            {
+                ACCESS_DENIED.to_vec()
+            }
+            (b"delete", _, _) => delete(&mut bitcask, args),
+            (b"incr" | b"decr", _, _) => incr(&mut bitcask, args, *command == b"incr"),
+            _ => b"ERROR\r\n".to_vec(),
+        };
+        if !noreply {
+            conn.write_all(&response)?;
+        }
+        conn.flush()?;
+    }
+}
+
+/// 访问凭据权限之外的键时的响应。
+const ACCESS_DENIED: &[u8] = b"CLIENT_ERROR access denied\r\n";
+
+/// 读取存储命令的数据块和结尾的`\r\n`，数据块没有以`\r\n`结尾时返回`None`。
+fn read_data<R: BufRead>(reader: &mut R, bytes: usize) -> std::io::Result<Option<Vec<u8>>> {
+    let Some(len) = bytes.checked_add(2) else {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "data chunk too large"));
+    };
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data)?;
+    if !data.ends_with(b"\r\n") {
+        return Ok(None);
+    }
+    data.truncate(bytes);
+    Ok(Some(data))
+}
+
+/// 丢弃存储命令的数据块和结尾的`\r\n`，不为数据块分配内存。数据块不完整时返回`UnexpectedEof`错误。
+fn discard_data<R: BufRead>(reader: &mut R, bytes: usize) -> std::io::Result<()> {
+    let len = (bytes as u64).saturating_add(2);
+    match std::io::copy(&mut reader.take(len), &mut std::io::sink())? == len {
+        true => Ok(()),
+        false => Err(std::io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+/// 以`<用户名> <密码>`或者令牌认证，失败时返回`None`。
+fn authenticate<'a>(access: &'a AccessControl, data: &[u8]) -> Option<&'a Grant> {
+    let credential = std::str::from_utf8(data).ok()?.trim();
+    match credential.split_once(' ') {
+        Some((user, password)) => access.authenticate_user(user, password),
+        None => access.authenticate_token(credential),
+    }
+}
+
+/// `set`/`add`/`replace`命令行中的参数。
+struct StorageRequest {
+    key: Key,
+    flags: u32,
+    exptime: i64,
+    bytes: usize,
+}
+
+impl StorageRequest {
+    /// 解析`<key> <flags> <exptime> <bytes> [noreply]`。
+    fn parse(args: &[&[u8]]) -> Option<Self> {
+        let ([key, flags, exptime, bytes] | [key, flags, exptime, bytes, b"noreply"]) = args else {
+            return None;
+        };
+        Some(Self {
+            key: valid_key(key)?,
+            flags: parse_number(flags)?,
+            exptime: parse_number(exptime)?,
+            bytes: parse_number(bytes)?,
+        })
+    }
+}
+
+/// 检查键的长度，合法时返回键。
+fn valid_key(key: &[u8]) -> Option<Key> {
+    (key.len() <= MAX_KEY_LEN).then(|| key.to_vec())
+}
+
+/// 解析十进制数字参数。
+fn parse_number<T: std::str::FromStr>(token: &[u8]) -> Option<T> {
+    std::str::from_utf8(token).ok()?.parse().ok()
+}
+
+/// 把memcached的过期时间转换为存活时间：`Ok(None)`表示永不过期，`Err(())`表示已经过期。
+fn ttl_of(exptime: i64) -> Result<Option<Duration>, ()> {
+    let ttl_millis = match exptime {
+        0 => return Ok(None),
+        exptime if exptime < 0 => return Err(()),
+        exptime if exptime <= MAX_RELATIVE_EXPTIME => exptime * 1000,
+        exptime => exptime.saturating_mul(1000).saturating_sub(now_millis() as i64),
+    };
+    match ttl_millis > 0 {
+        true => Ok(Some(Duration::from_millis(ttl_millis as u64))),
+        false => Err(()),
+    }
+}
+
+/// 把客户端标志和数据编码为存储的值。
+fn encode_item(flags: u32, data: &[u8]) -> Value {
+    let mut value = Vec::with_capacity(FLAGS_LEN + data.len());
+    value.extend_from_slice(&flags.to_be_bytes());
+    value.extend_from_slice(data);
+    value
+}
+
+/// 把存储的值拆分为客户端标志和数据，不是通过memcached写入的短值视为标志为0。
+fn decode_item(value: &[u8]) -> (u32, &[u8]) {
+    match value.split_first_chunk::<FLAGS_LEN>() {
+        Some((flags, data)) => (u32::from_be_bytes(*flags), data),
+        None => (0, value),
+    }
+}
+
+fn get(bitcask: &BitCask, keys: &[&[u8]]) -> Vec<u8> {
+    let mut response = Vec::new();
+    for key in keys {
+        if let Some(value) = bitcask.get(&key.to_vec()) {
+            let (flags, data) = decode_item(&value);
+            response.extend_from_slice(b"VALUE ");
+            response.extend_from_slice(key);
+            response.extend_from_slice(format!(" {} {}\r\n", flags, data.len()).as_bytes());
+            response.extend_from_slice(data);
+            response.extend_from_slice(b"\r\n");
+        }
+    }
+    response.extend_from_slice(b"END\r\n");
+    response
+}
+
+fn store(bitcask: &mut BitCask, command: &[u8], request: StorageRequest, data: Vec<u8>) -> Vec<u8> {
+    let value = encode_item(request.flags, &data);
+    let Ok(ttl) = ttl_of(request.exptime) else {
+        // 已经过期的写入相当于删除，但add和replace仍然要满足各自的条件
+        let exists = bitcask.get(&request.key).is_some();
+        let stored = match command {
+            b"add" => !exists,
+            b"replace" => exists,
+            _ => true,
+        };
+        if !stored {
+            return b"NOT_STORED\r\n".to_vec();
+        }
+        return match bitcask.delete(&request.key) {
+            Ok(()) => b"STORED\r\n".to_vec(),
+            Err(e) => server_error(e),
+        };
+    };
+    let option = PutOption {
+        nx: command == b"add",
+        xx: command == b"replace",
+        ttl,
+        ..PutOption::default()
+    };
+    match bitcask.put_with_option(&request.key, &value, Some(option)) {
+        Ok(()) => b"STORED\r\n".to_vec(),
+        Err(BitCaskError::KeyExists | BitCaskError::KeyNotFound) => b"NOT_STORED\r\n".to_vec(),
+        Err(e) => server_error(e),
+    }
+}
+
+fn delete(bitcask: &mut BitCask, args: &[&[u8]]) -> Vec<u8> {
+    let key = match args {
+        [key] | [key, b"noreply"] => valid_key(key),
+        _ => None,
+    };
+    let Some(key) = key else {
+        return b"CLIENT_ERROR bad command line format\r\n".to_vec();
+    };
+    if bitcask.get(&key).is_none() {
+        return b"NOT_FOUND\r\n".to_vec();
+    }
+    match bitcask.delete(&key) {
+        Ok(()) => b"DELETED\r\n".to_vec(),
+        Err(e) => server_error(e),
+    }
+}
+
+/// `incr`和`decr`：以版本号做比较并交换，并发的增减不会丢失更新；过期时间和标志保持不变。
+fn incr(bitcask: &mut BitCask, args: &[&[u8]], increment: bool) -> Vec<u8> {
+    let (Some(key), Some(delta)) = (
+        args.first().and_then(|key| valid_key(key)),
+        args.get(1).and_then(|delta| parse_number::<u64>(delta)),
+    ) else {
+        return b"CLIENT_ERROR invalid numeric delta argument\r\n".to_vec();
+    };
+    loop {
+        let meta = match bitcask.get_with_meta(&key) {
+            Ok(Some(meta)) => meta,
+            Ok(None) => return b"NOT_FOUND\r\n".to_vec(),
+            Err(e) => return server_error(e),
+        };
+        let (flags, data) = decode_item(&meta.value);
+        let Some(current) = parse_number::<u64>(data) else {
+            return b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n".to_vec();
+        };
+        // 与memcached相同：incr在64位溢出时回绕，decr最小为0
+        let updated = match increment {
+            true => current.wrapping_add(delta),
+            false => current.saturating_sub(delta),
+        };
+        let ttl = match meta.expire_at {
+            Some(expire_at) => match expire_at.checked_sub(now_millis()).filter(|ttl| *ttl > 0) {
+                Some(ttl) => Some(Duration::from_millis(ttl)),
+                None => return b"NOT_FOUND\r\n".to_vec(),
+            },
+            None => None,
+        };
+        let option = PutOption {
+            ttl,
+            expected_version: Some(meta.version),
+            ..PutOption::default()
+        };
+        let value = encode_item(flags, updated.to_string().as_bytes());
+        match bitcask.put_with_option(&key, &value, Some(option)) {
+            Ok(()) => return format!("{}\r\n", updated).into_bytes(),
+            Err(BitCaskError::VersionMismatch) => continue,
+            Err(e) => return server_error(e),
+        }
+    }
+}
+
+fn server_error(e: BitCaskError) -> Vec<u8> {
+    format!("SERVER_ERROR {}\r\n", e).into_bytes()
+}
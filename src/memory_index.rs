@@ -1,5 +1,214 @@
-use crate::bitcask::{ByteOffset, ByteSize, FileId, Key};
-use std::collections::btree_map::{BTreeMap, IntoIter};
+use crate::bitcask::{ByteOffset, ByteSize, FileId, Key, Value};
+use crate::log_entry::DiskLogEntry;
+use crate::error::BitCaskError;
+use crate::merkle::{entry_digest, segment_of, MerkleTree, MERKLE_SEGMENTS};
+use crate::options::IndexBackendKind;
+use crate::sparse_index::SparseFile;
+use crate::stats::SizeHistogram;
+use im::{HashMap, OrdMap, OrdSet, Vector};
+use std::borrow::{Borrow, Cow};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::{Bound, Deref, RangeBounds};
+
+/// 能够内联存储在`IndexKey`中的最大键长度，使`IndexKey`与`Vec<u8>`一样只占24字节。
+const INLINE_KEY_LEN: usize = 22;
+const _: () = assert!(std::mem::size_of::<IndexKey>() == std::mem::size_of::<Vec<u8>>());
+
+/// `IndexKey` 是内存索引中使用的键，不超过`INLINE_KEY_LEN`字节的短键直接存储在结构体中。
+///
+/// 与`Vec<u8>`相比，短键不需要单独的堆分配，比较键时也不必跟随指针访问另一块内存；
+/// 大多数工作负载的键都很短，这显著减少了索引的内存占用和分配次数。
+/// 两种表示按字节序比较，排序与`Key`完全一致。
+#[derive(Clone)]
+pub(crate) enum IndexKey {
+    /// 内联存储的短键，只有前`len`个字节有效。
+    Inline { len: u8, bytes: [u8; INLINE_KEY_LEN] },
+    /// 存放在堆上的长键。
+    Heap(Box<[u8]>),
+}
+
+impl IndexKey {
+    /// 根据键的字节构造索引键，短键会被内联。
+    pub(crate) fn new(key: &[u8]) -> Self {
+        if key.len() <= INLINE_KEY_LEN {
+            let mut bytes = [0; INLINE_KEY_LEN];
+            bytes[..key.len()].copy_from_slice(key);
+            IndexKey::Inline {
+                len: key.len() as u8,
+                bytes,
+            }
+        } else {
+            IndexKey::Heap(key.into())
+        }
+    }
+}
+
+impl Deref for IndexKey {
+    type Target = [u8];
+
+    /// 返回键的字节。
+    fn deref(&self) -> &[u8] {
+        match self {
+            IndexKey::Inline { len, bytes } => &bytes[..*len as usize],
+            IndexKey::Heap(bytes) => bytes,
+        }
+    }
+}
+
+impl Borrow<[u8]> for IndexKey {
+    fn borrow(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq for IndexKey {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for IndexKey {}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl Hash for IndexKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl fmt::Debug for IndexKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl From<&[u8]> for IndexKey {
+    fn from(key: &[u8]) -> Self {
+        IndexKey::new(key)
+    }
+}
+
+impl From<IndexKey> for Key {
+    fn from(key: IndexKey) -> Self {
+        match key {
+            IndexKey::Inline { .. } => key.to_vec(),
+            IndexKey::Heap(bytes) => bytes.into_vec(),
+        }
+    }
+}
+
+/// `IndexBackend` 是内存索引中从键到索引项的有序映射，由`BitCaskOptions::index_backend`选择实现。
+///
+/// 实现必须按字节序排列键，并且克隆的代价与映射的大小无关：每次写入后发布只读快照时都会克隆索引。
+pub(crate) trait IndexBackend: fmt::Debug + Send + Sync {
+    /// 查找键的索引项。
+    fn get(&self, key: &[u8]) -> Option<&MemIndexEntry>;
+    /// 查找键的索引项并返回可变引用。
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut MemIndexEntry>;
+    /// 插入索引项，返回键之前的索引项。
+    fn insert(&mut self, key: &[u8], entry: MemIndexEntry) -> Option<MemIndexEntry>;
+    /// 把按键严格升序排列的一批索引项一次合并进映射，批次中的索引项覆盖已有的索引项，
+    /// 按批次的顺序返回每个键之前的索引项。
+    fn merge_sorted(&mut self, entries: Vec<(Key, MemIndexEntry)>) -> Vec<Option<MemIndexEntry>>;
+    /// 删除键，返回键之前的索引项。
+    fn remove(&mut self, key: &[u8]) -> Option<MemIndexEntry>;
+    /// 按键的顺序遍历范围内的索引项。
+    fn range(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> Box<dyn Iterator<Item = (&[u8], &MemIndexEntry)> + '_>;
+    /// 返回索引项的数量。
+    fn len(&self) -> usize;
+    /// 按键的顺序取出所有索引项。
+    fn into_entries(self: Box<Self>) -> Box<dyn Iterator<Item = (Key, MemIndexEntry)>>;
+    /// 克隆映射，用于克隆索引。
+    fn clone_box(&self) -> Box<dyn IndexBackend>;
+}
+
+impl Clone for Box<dyn IndexBackend> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// 两种实现都是以不同类型的键（`Key`或`IndexKey`）组织的有序持久化映射。
+impl<K> IndexBackend for OrdMap<K, MemIndexEntry>
+where
+    K: Ord + Clone + Borrow<[u8]> + for<'a> From<&'a [u8]> + Into<Key> + fmt::Debug + Send + Sync + 'static,
+{
+    fn get(&self, key: &[u8]) -> Option<&MemIndexEntry> {
+        OrdMap::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut MemIndexEntry> {
+        OrdMap::get_mut(self, key)
+    }
+
+    fn insert(&mut self, key: &[u8], entry: MemIndexEntry) -> Option<MemIndexEntry> {
+        OrdMap::insert(self, K::from(key), entry)
+    }
+
+    fn merge_sorted(&mut self, entries: Vec<(Key, MemIndexEntry)>) -> Vec<Option<MemIndexEntry>> {
+        let old = entries
+            .iter()
+            .map(|(key, _)| OrdMap::get(self, key.as_slice()).cloned())
+            .collect();
+        // 先由有序的批次构建一个映射，再把较小的一方并入较大的一方；
+        // 不使用`OrdMap::union`，它在两者大小不同时不保证哪一方的值胜出
+        let mut batch: OrdMap<K, MemIndexEntry> =
+            entries.into_iter().map(|(key, entry)| (K::from(key.as_slice()), entry)).collect();
+        if batch.len() >= OrdMap::len(self) {
+            for (key, entry) in std::mem::take(self) {
+                batch.entry(key).or_insert(entry);
+            }
+            *self = batch;
+        } else {
+            for (key, entry) in batch {
+                OrdMap::insert(self, key, entry);
+            }
+        }
+        old
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<MemIndexEntry> {
+        OrdMap::remove(self, key)
+    }
+
+    fn range(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> Box<dyn Iterator<Item = (&[u8], &MemIndexEntry)> + '_> {
+        Box::new(OrdMap::range::<_, [u8]>(self, bounds).map(|(key, entry)| (key.borrow(), entry)))
+    }
+
+    fn len(&self) -> usize {
+        OrdMap::len(self)
+    }
+
+    fn into_entries(self: Box<Self>) -> Box<dyn Iterator<Item = (Key, MemIndexEntry)>> {
+        Box::new((*self).into_iter().map(|(key, entry)| (key.into(), entry)))
+    }
+
+    fn clone_box(&self) -> Box<dyn IndexBackend> {
+        Box::new(self.clone())
+    }
+}
+
+/// 创建所选实现的空映射。
+fn new_backend(backend: IndexBackendKind) -> Box<dyn IndexBackend> {
+    match backend {
+        IndexBackendKind::Bytes => Box::new(OrdMap::<Key, MemIndexEntry>::new()),
+        IndexBackendKind::InlineKeys => Box::new(OrdMap::<IndexKey, MemIndexEntry>::new()),
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 /// 内存索引项结构体
@@ -13,43 +222,304 @@ pub(crate) struct MemIndexEntry {
     pub(crate) value_offset: ByteOffset,
     /// 值的大小，表示数据在内存中占用的字节数
     pub(crate) value_size: ByteSize,
+    /// 值是否经过了字典压缩，读取后需要解压
+    pub(crate) compressed: bool,
+    /// 值是否存放在独立的大值文件中，此时`value_offset`是大值文件的ID
+    pub(crate) blob: bool,
+    /// 内联在索引中的值（与磁盘上存储的字节相同，可能经过压缩），读取时无需访问磁盘
+    pub(crate) inline_value: Option<Value>,
+    /// 键的过期时间（Unix毫秒时间戳），为`None`表示永不过期
+    pub(crate) expire_at: Option<u64>,
+    /// 键的版本号，由内存索引在每次写入时分配，单调递增
+    pub(crate) version: u64,
+    /// 写入时的混合逻辑时钟时间戳，旧版本写入的条目为0
+    pub(crate) timestamp: u64,
+    /// 条目是否为墓碑。空值的`value_size`同样为0，因此不能根据值的大小判断
+    pub(crate) tombstone: bool,
+    /// 键的最后一条记录在文件中的起始偏移量，引用条目和大值指针条目是引用记录或指针记录本身的位置
+    pub(crate) record_offset: ByteOffset,
+    /// 磁盘上存储的值（可能经过压缩）的CRC32C校验和，从数据文件读取值时用于校验。
+    /// 大值指针条目和从磁盘加载的引用条目指向的值不在自己的记录中，没有校验和
+    pub(crate) check_sum: Option<u32>,
+    /// 是否为软删除的墓碑，此时`value_offset`和`value_size`指向墓碑中保留的值，见`DiskLogEntry::soft_deleted`
+    pub(crate) soft_deleted: bool,
+    /// 写入时附加的用户元数据，见`PutOption::metadata`
+    pub(crate) metadata: Option<Vec<u8>>,
 }
 
 impl MemIndexEntry {
+    /// 根据刚写入或读取的磁盘日志条目构造内存索引项。
+    ///
+    /// # 参数
+    /// - `file_id`: 条目所在的文件ID。
+    /// - `value_offset`: 条目的值在文件中的偏移量。
+    /// - `entry`: 磁盘日志条目，如果是引用条目，索引项会直接指向被引用的共享记录；
+    ///   如果是大值指针条目，索引项指向大值文件。
+    pub(crate) fn from_log_entry(file_id: FileId, value_offset: ByteOffset, entry: &DiskLogEntry) -> Self {
+        let record_offset = value_offset - entry.value_byte_offset();
+        if let Some((blob_id, value_size)) = entry.blob_target() {
+            return Self {
+                file_id,
+                value_offset: blob_id,
+                value_size,
+                compressed: false,
+                blob: true,
+                inline_value: None,
+                expire_at: entry.expire_at,
+                version: 0,
+                timestamp: entry.timestamp.unwrap_or(0),
+                tombstone: false,
+                record_offset,
+                check_sum: None,
+                soft_deleted: false,
+                metadata: entry.metadata.clone(),
+            };
+        }
+        match entry.reference_target() {
+            Some((value_offset, value_size, compressed)) => Self {
+                file_id,
+                value_offset,
+                value_size,
+                compressed,
+                blob: false,
+                inline_value: None,
+                expire_at: entry.expire_at,
+                version: 0,
+                timestamp: entry.timestamp.unwrap_or(0),
+                tombstone: false,
+                record_offset,
+                check_sum: None,
+                soft_deleted: false,
+                metadata: entry.metadata.clone(),
+            },
+            None => Self {
+                file_id,
+                value_offset,
+                value_size: entry.value_byte_size(),
+                compressed: entry.compressed,
+                blob: false,
+                inline_value: None,
+                expire_at: entry.expire_at,
+                version: 0,
+                timestamp: entry.timestamp.unwrap_or(0),
+                tombstone: entry.is_tombstone(),
+                record_offset,
+                check_sum: entry.value_crc32c(),
+                soft_deleted: entry.soft_deleted,
+                metadata: entry.metadata.clone(),
+            },
+        }
+    }
+
     /// 检查当前条目是否为墓碑条目。
     ///
     /// 墓碑条目用于标记一个条目已被删除。在某些数据库或存储系统中，当一个条目被删除后，
-    /// 其位置可能仍需要被保留或标记，以避免数据的混乱或冲突。这个方法通过检查条目的值
-    /// 大小是否为0来判断条目是否为墓碑条目。如果值大小为0，则表示该条目是一个墓碑条目。
+    /// 其位置可能仍需要被保留或标记，以避免数据的混乱或冲突。墓碑由日志记录的标志决定，
+    /// 值大小为0的条目也可能是一个空值。
     ///
     /// # 返回
     /// * `bool` - 如果当前条目是墓碑条目，则返回`true`；否则返回`false`。
     pub(crate) fn is_tombstone(&self) -> bool {
-        self.value_size == 0
+        self.tombstone
+    }
+
+    /// 返回键的最后一条记录在数据文件中占用的字节数。
+    ///
+    /// 值在记录的末尾，因此普通记录的大小就是值的结束位置减去记录的起始位置；引用条目指向在它之前写入的共享记录，
+    /// 大值指针条目指向大值文件，它们按`estimate_compaction`的写法重新编码引用记录或指针记录计算大小。
+    pub(crate) fn record_byte_size(&self, key: &[u8]) -> u64 {
+        if !self.blob && self.value_offset > self.record_offset {
+            return self.value_offset + self.value_size - self.record_offset;
+        }
+        let mut record = match self.blob {
+            true => DiskLogEntry::new_blob_pointer(key.to_vec(), self.value_offset, self.value_size),
+            false => DiskLogEntry::new_reference(key.to_vec(), self.value_offset, self.value_size, self.compressed),
+        };
+        record.expire_at = self.expire_at;
+        record.timestamp = Some(self.timestamp).filter(|timestamp| *timestamp != 0);
+        record.metadata = self.metadata.clone();
+        record.total_byte_size()
+    }
+
+    /// 检查条目在给定时间（Unix毫秒时间戳）是否已经过期。
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expire_at, Some(expire_at) if expire_at <= now)
+    }
+
+    /// 检查条目在给定时间是否仍然有效，即既不是墓碑也没有过期。
+    pub(crate) fn is_live(&self, now: u64) -> bool {
+        !self.is_tombstone() && !self.is_expired(now)
     }
 }
 
 /// 内存索引结构体，用于高效地在内存中索引和检索数据。
-/// 使用BTreeMap来存储键值对，以保持键的有序性，从而提高查找效率。
+/// 使用有序的持久化映射来存储键值对，以保持键的有序性，从而提高查找效率。
+/// 持久化映射在克隆时共享结构，克隆的代价与索引大小无关，因此每次写入后都可以廉价地发布只读快照。
 ///
 /// # Fields
-/// - `map`: BTreeMap<Key, MemIndexEntry> 类型，用于存储索引项。
-///   `Key` 是索引的键，`MemIndexEntry` 是每个键对应的索引项，包含键对应的值以及相关元数据。
+/// - `map`: 用于存储索引项的有序映射，实现由`IndexBackendKind`选择，见`IndexBackend`。
+///   `MemIndexEntry` 是每个键对应的索引项，包含键对应的值以及相关元数据。
 #[derive(Debug, Clone)]
 pub(crate) struct MemIndexStorage {
-    map: BTreeMap<Key, MemIndexEntry>,
+    map: Box<dyn IndexBackend>,
+    /// `map`的实现，重建索引时使用相同的实现。
+    backend: IndexBackendKind,
+    /// 不超过该字节数的值会被内联到索引项中，为`None`时不内联。
+    inline_value_threshold: Option<usize>,
+    /// 按过期时间排序的带有过期时间的键，用于在O(k)时间内找到已经过期的键。
+    expiry: OrdSet<(u64, IndexKey)>,
+    /// 最近一次分配的版本号。
+    sequence: u64,
+    /// 所有插入过的条目中最大的时间戳，用于在打开存储后让时钟继续单调递增。
+    max_timestamp: u64,
+    /// 加载时遇到的、最后一条记录是墓碑的键及其删除时间戳。
+    /// 这些键不在`map`中，但按最后写入者胜出的规则同步时仍然需要它们的删除时间。
+    removed: OrdMap<IndexKey, u64>,
+    /// 默克尔树的叶子，每个叶子是对应段中所有键最后一条记录摘要的异或。
+    merkle_leaves: Vector<u64>,
+    /// 未删除的键的大小分布，随写入增量维护。
+    key_sizes: SizeHistogram,
+    /// 未删除的键的值的大小分布，随写入增量维护。
+    value_sizes: SizeHistogram,
+    /// 每个数据文件中未删除的键的最后一条记录的数量和字节数，随写入增量维护。
+    file_live: OrdMap<FileId, (u64, u64)>,
+    /// 稀疏索引的间隔，为`None`时所有记录都加入索引，见`BitCaskOptions::sparse_index`。
+    sparse_interval: Option<usize>,
+    /// 以稀疏索引模式加载的数据文件，按文件ID升序排列。
+    sparse: Vector<SparseFile>,
+    /// 带有请求ID的写入的请求ID及其时间戳，用于识别重试的写入，见`PutOption::request_id`。
+    request_ids: HashMap<u64, u64>,
+    /// 按时间戳排序的`request_ids`，用于丢弃超出时间窗口的请求ID。
+    request_id_times: OrdSet<(u64, u64)>,
 }
 
 impl MemIndexStorage {
-    /// 创建一个新的、空的`BTreeMap`实例。
+    /// 创建一个新的、空的内存索引实例。
     ///
     /// ## Returns
-    /// 返回一个新的`Self`类型实例，其中`map`字段是一个空的`BTreeMap`。
+    /// 返回一个新的`Self`类型实例，其中`map`字段是一个空的`OrdMap`。
     pub(crate) fn new() -> Self {
         Self {
-            map: BTreeMap::new(),
+            map: new_backend(IndexBackendKind::default()),
+            backend: IndexBackendKind::default(),
+            inline_value_threshold: None,
+            expiry: OrdSet::new(),
+            sequence: 0,
+            max_timestamp: 0,
+            removed: OrdMap::new(),
+            merkle_leaves: Vector::from(vec![0; MERKLE_SEGMENTS]),
+            key_sizes: SizeHistogram::default(),
+            value_sizes: SizeHistogram::default(),
+            file_live: OrdMap::new(),
+            sparse_interval: None,
+            sparse: Vector::new(),
+            request_ids: HashMap::new(),
+            request_id_times: OrdSet::new(),
+        }
+    }
+
+    /// 创建一个空的内存索引，不超过`inline_value_threshold`字节的值会被内联到索引项中。
+    pub(crate) fn with_inline_value_threshold(inline_value_threshold: Option<usize>) -> Self {
+        Self {
+            map: new_backend(IndexBackendKind::default()),
+            backend: IndexBackendKind::default(),
+            inline_value_threshold,
+            expiry: OrdSet::new(),
+            sequence: 0,
+            max_timestamp: 0,
+            removed: OrdMap::new(),
+            merkle_leaves: Vector::from(vec![0; MERKLE_SEGMENTS]),
+            key_sizes: SizeHistogram::default(),
+            value_sizes: SizeHistogram::default(),
+            file_live: OrdMap::new(),
+            sparse_interval: None,
+            sparse: Vector::new(),
+            request_ids: HashMap::new(),
+            request_id_times: OrdSet::new(),
         }
     }
+
+    /// 返回内联值的阈值，见`BitCaskOptions::inline_value_threshold`。
+    pub(crate) fn inline_value_threshold(&self) -> Option<usize> {
+        self.inline_value_threshold
+    }
+
+    /// 使用给定实现的映射存储索引项，需要在插入任何条目之前调用。
+    pub(crate) fn with_backend(mut self, backend: IndexBackendKind) -> Self {
+        self.map = new_backend(backend);
+        self.backend = backend;
+        self
+    }
+
+    /// 开启稀疏索引：之后加载的数据文件（最后一个文件除外）每`interval`条记录只索引一条。
+    pub(crate) fn with_sparse_interval(mut self, sparse_interval: Option<usize>) -> Self {
+        self.sparse_interval = sparse_interval;
+        self
+    }
+
+    /// 返回稀疏索引的间隔，未开启稀疏索引时返回`None`。
+    pub(crate) fn sparse_interval(&self) -> Option<usize> {
+        self.sparse_interval
+    }
+
+    /// 加入一个以稀疏索引模式加载的数据文件，文件需要按文件ID升序加入。
+    pub(crate) fn add_sparse_file(&mut self, file: SparseFile) {
+        self.sparse.push_back(file);
+    }
+
+    /// 查找键的最后一条记录（包括墓碑）。
+    ///
+    /// 键不在索引中时，按文件从新到旧在稀疏加载的文件中查找，找到的索引项版本号为0。
+    pub(crate) fn lookup(&self, key: &[u8]) -> Result<Option<Cow<'_, MemIndexEntry>>, BitCaskError> {
+        if let Some(entry) = self.map.get(key) {
+            return Ok(Some(Cow::Borrowed(entry)));
+        }
+        for file in self.sparse.iter().rev() {
+            if let Some(entry) = file.find(key)? {
+                return Ok(Some(Cow::Owned(entry)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// 返回包含所有键的索引，用于范围扫描、统计等需要遍历所有键的操作。
+    ///
+    /// 没有稀疏加载的文件时直接返回自身；否则重新完整地扫描这些文件，再加入之后写入的条目，
+    /// 代价与这些文件的大小成正比。
+    pub(crate) fn materialize(&self) -> Result<Cow<'_, MemIndexStorage>, BitCaskError> {
+        if self.sparse.is_empty() {
+            return Ok(Cow::Borrowed(self));
+        }
+        let mut full =
+            MemIndexStorage::with_inline_value_threshold(self.inline_value_threshold).with_backend(self.backend);
+        for file in self.sparse.iter() {
+            file.file().populate_mem_index(&mut full)?;
+        }
+        // 索引中来自稀疏文件的条目与文件内容相同，其余的条目都比稀疏文件中的记录更新
+        let sparse_file_ids: HashSet<FileId> = self.sparse.iter().map(|file| file.file_id()).collect();
+        for (key, entry) in self.map.range((Bound::Unbounded, Bound::Unbounded)) {
+            if !sparse_file_ids.contains(&entry.file_id) {
+                full.put(key.to_vec(), entry.clone());
+            }
+        }
+        full.inherit_request_ids(self);
+        Ok(Cow::Owned(full))
+    }
+
+    /// 如果条目的值足够小，返回需要内联到索引项中的值。
+    ///
+    /// 引用条目和大值指针条目的值只是位置信息，不会被内联。
+    pub(crate) fn inline_candidate(&self, entry: &DiskLogEntry) -> Option<Value> {
+        let threshold = self.inline_value_threshold?;
+        if entry.reference || entry.blob {
+            return None;
+        }
+        entry
+            .value
+            .as_ref()
+            .filter(|value| value.len() <= threshold)
+            .cloned()
+    }
     /// 根据给定的键获取内存索引项的引用。
     ///
     /// ## 参数
@@ -59,7 +529,7 @@ impl MemIndexStorage {
     /// - `Option<&MemIndexEntry>`: 如果找到键，则返回其对应内存索引项的引用；否则返回`None`。
     ///
     /// 此方法提供了一种通过键访问内存索引项的简便方式，主要用于在内存中快速查找数据。
-    pub(crate) fn get(&self, key: &Key) -> Option<&MemIndexEntry> {
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&MemIndexEntry> {
         self.map.get(key)
     }
     /// 将给定的键值对插入到内存索引中。
@@ -70,19 +540,244 @@ impl MemIndexStorage {
     ///
     /// # 返回值
     /// 如果插入的键已存在于索引中，则返回该键之前的条目；否则，返回 `None`。
-    pub(crate) fn put(&mut self, key: Key, entry: MemIndexEntry) -> Option<MemIndexEntry> {
-        self.map.insert(key, entry)
+    ///
+    /// 插入的条目会被分配一个新的版本号。
+    pub(crate) fn put(&mut self, key: Key, mut entry: MemIndexEntry) -> Option<MemIndexEntry> {
+        let key = IndexKey::new(&key);
+        self.sequence += 1;
+        entry.version = self.sequence;
+        self.max_timestamp = self.max_timestamp.max(entry.timestamp);
+        self.toggle_digest(&key, entry.timestamp, !entry.is_tombstone());
+        if let Some(timestamp) = self.removed.remove(&*key) {
+            self.toggle_digest(&key, timestamp, false);
+        }
+        if let Some(expire_at) = entry.expire_at {
+            self.expiry.insert((expire_at, key.clone()));
+        }
+        let new_expire_at = entry.expire_at;
+        self.record_sizes(&key, &entry, true);
+        let old = self.map.insert(&key, entry);
+        if let Some(old) = &old {
+            self.toggle_digest(&key, old.timestamp, !old.is_tombstone());
+            self.record_sizes(&key, old, false);
+        }
+        // 旧条目的过期时间不再有效，需要从过期索引中移除
+        if let Some(old_expire_at) = old.as_ref().and_then(|old| old.expire_at) {
+            if Some(old_expire_at) != new_expire_at {
+                self.expiry.remove(&(old_expire_at, key));
+            }
+        }
+        old
+    }
+    /// 插入一批按键严格升序排列的条目，结果与按顺序逐个调用`put`相同，用于`LogStorage::put_many_sorted`。
+    ///
+    /// 键映射通过`IndexBackend::merge_sorted`一次合并；版本号、最大时间戳、过期索引和每个文件的统计
+    /// 也在整个批次上计算之后各更新一次，而不是每个键一次。
+    pub(crate) fn put_sorted(&mut self, entries: Vec<(Key, MemIndexEntry)>) {
+        let mut expiry = OrdSet::new();
+        let mut file_live: std::collections::HashMap<FileId, (u64, u64)> = std::collections::HashMap::new();
+        let entries: Vec<(Key, MemIndexEntry)> = entries
+            .into_iter()
+            .enumerate()
+            .map(|(index, (key, mut entry))| {
+                entry.version = self.sequence + 1 + index as u64;
+                (key, entry)
+            })
+            .collect();
+        self.sequence += entries.len() as u64;
+        self.max_timestamp = entries.iter().map(|(_, entry)| entry.timestamp).fold(self.max_timestamp, u64::max);
+        for (key, entry) in &entries {
+            self.toggle_digest(key, entry.timestamp, !entry.is_tombstone());
+            if let Some(timestamp) = self.removed.remove(key.as_slice()) {
+                self.toggle_digest(key, timestamp, false);
+            }
+            if let Some(expire_at) = entry.expire_at {
+                expiry.insert((expire_at, IndexKey::new(key)));
+            }
+            if !entry.is_tombstone() {
+                self.key_sizes.record(key.len() as u64);
+                self.value_sizes.record(entry.value_size);
+                let live = file_live.entry(entry.file_id).or_default();
+                live.0 += 1;
+                live.1 += entry.record_byte_size(key);
+            }
+        }
+        let new_expiry: Vec<Option<u64>> = entries.iter().map(|(_, entry)| entry.expire_at).collect();
+        let keys: Vec<Key> = entries.iter().map(|(key, _)| key.clone()).collect();
+        let old = self.map.merge_sorted(entries);
+        for ((key, old), new_expire_at) in keys.iter().zip(old).zip(new_expiry) {
+            let Some(old) = old else {
+                continue;
+            };
+            self.toggle_digest(key, old.timestamp, !old.is_tombstone());
+            self.record_sizes(key, &old, false);
+            if let Some(old_expire_at) = old.expire_at.filter(|expire_at| Some(*expire_at) != new_expire_at) {
+                self.expiry.remove(&(old_expire_at, IndexKey::new(key)));
+            }
+        }
+        self.expiry = std::mem::take(&mut self.expiry).union(expiry);
+        for (file_id, (count, bytes)) in file_live {
+            let live = self.file_live.entry(file_id).or_default();
+            live.0 += count;
+            live.1 += bytes;
+        }
     }
-    /// 从内存索引中删除与给定键关联的条目。
+    /// 从内存索引中删除与给定键关联的条目，并记录删除的时间戳。加载时遇到墓碑会调用此方法。
     ///
     /// # 参数
     /// - `key`: 要删除的条目在内存索引中的唯一键。
+    /// - `timestamp`: 墓碑的时间戳。
     ///
     /// # 返回值
     /// - `Option<MemIndexEntry>`: 如果成功删除了条目，则返回 Some(被删除的条目)；
     ///   如果没有找到与给定键关联的条目，则返回 None。
-    pub(crate) fn delete(&mut self, key: &Key) -> Option<MemIndexEntry> {
-        self.map.remove(key)
+    pub(crate) fn delete(&mut self, key: &Key, timestamp: u64) -> Option<MemIndexEntry> {
+        self.max_timestamp = self.max_timestamp.max(timestamp);
+        let key = IndexKey::new(key);
+        let old = self.map.remove(&key);
+        if let Some(old) = &old {
+            self.toggle_digest(&key, old.timestamp, !old.is_tombstone());
+            self.record_sizes(&key, old, false);
+            if let Some(expire_at) = old.expire_at {
+                self.expiry.remove(&(expire_at, key.clone()));
+            }
+        }
+        if let Some(previous) = self.removed.insert(key.clone(), timestamp) {
+            self.toggle_digest(&key, previous, false);
+        }
+        self.toggle_digest(&key, timestamp, false);
+        old
+    }
+    /// 返回加载时被删除的键的删除时间戳。
+    pub(crate) fn removed_timestamp(&self, key: &[u8]) -> Option<u64> {
+        self.removed.get(key).copied()
+    }
+    /// 遍历加载时被删除的键及其删除时间戳。
+    pub(crate) fn removed(&self) -> impl Iterator<Item = (&[u8], u64)> {
+        self.removed.iter().map(|(key, timestamp)| (&**key, *timestamp))
+    }
+    /// 在键所在段的叶子中加入或去掉一条记录的摘要（异或是自身的逆操作）。
+    fn toggle_digest(&mut self, key: &[u8], timestamp: u64, live: bool) {
+        self.merkle_leaves[segment_of(key)] ^= entry_digest(key, timestamp, live);
+    }
+    /// 根据当前的叶子构建默克尔树。
+    pub(crate) fn merkle_tree(&self) -> MerkleTree {
+        MerkleTree::from_leaves(self.merkle_leaves.iter().copied().collect())
+    }
+    /// 按过期时间顺序返回在给定时间（Unix毫秒时间戳）之前已经过期的键，最多返回`limit`个。
+    ///
+    /// 过期索引按过期时间排序，因此只需访问已经过期的k个键，而不必扫描整个索引。
+    pub(crate) fn expired_keys(&self, now: u64, limit: usize) -> Vec<Key> {
+        self.expiry
+            .iter()
+            .take_while(|(expire_at, _)| *expire_at <= now)
+            .take(limit)
+            .map(|(_, key)| key.to_vec())
+            .collect()
+    }
+    /// 按过期时间顺序返回在`now`之后、`before`之前（不含）过期的键及其过期时间（Unix毫秒时间戳），最多返回`limit`个。
+    ///
+    /// 与`expired_keys`相同，只访问过期索引中落在这个时间范围内的部分。
+    pub(crate) fn expiring_keys(&self, now: u64, before: u64, limit: usize) -> Vec<(Key, u64)> {
+        if before <= now.saturating_add(1) {
+            return Vec::new();
+        }
+        let start = (now + 1, IndexKey::new(&[]));
+        let end = (before, IndexKey::new(&[]));
+        self.expiry
+            .range((Bound::Included(start), Bound::Excluded(end)))
+            .take(limit)
+            .map(|(expire_at, key)| (key.to_vec(), *expire_at))
+            .collect()
+    }
+    /// 按键的顺序遍历给定范围内的索引项。
+    ///
+    /// # 参数
+    /// - `range`: 键的范围，例如`start..end`或`..`。
+    ///
+    /// # 返回
+    /// 一个按键升序返回`(&[u8], &MemIndexEntry)`的迭代器，其中可能包含墓碑条目。
+    pub(crate) fn range<R: RangeBounds<Key>>(&self, range: R) -> impl Iterator<Item = (&[u8], &MemIndexEntry)> {
+        let bounds: (Bound<&[u8]>, Bound<&[u8]>) = (
+            range.start_bound().map(|key| key.as_slice()),
+            range.end_bound().map(|key| key.as_slice()),
+        );
+        self.map.range(bounds)
+    }
+    /// 记录一条没有加入索引的记录的时间戳，用于稀疏索引模式下让时钟越过所有已经写入的记录。
+    pub(crate) fn observe_timestamp(&mut self, timestamp: u64) {
+        self.max_timestamp = self.max_timestamp.max(timestamp);
+    }
+    /// 返回所有插入过的条目中最大的时间戳。
+    pub(crate) fn max_timestamp(&self) -> u64 {
+        self.max_timestamp
+    }
+    /// 在大小分布和所在文件的存活统计中加入或去掉一个条目，墓碑不计入。
+    fn record_sizes(&mut self, key: &[u8], entry: &MemIndexEntry, added: bool) {
+        if entry.is_tombstone() {
+            return;
+        }
+        let record_size = entry.record_byte_size(key);
+        let live = self.file_live.entry(entry.file_id).or_default();
+        if added {
+            self.key_sizes.record(key.len() as u64);
+            self.value_sizes.record(entry.value_size);
+            live.0 += 1;
+            live.1 += record_size;
+        } else {
+            self.key_sizes.remove(key.len() as u64);
+            self.value_sizes.remove(entry.value_size);
+            live.0 = live.0.saturating_sub(1);
+            live.1 = live.1.saturating_sub(record_size);
+        }
+    }
+    /// 返回给定数据文件中未删除的键的最后一条记录的数量和字节数，已过期但尚未被清理的键仍然计算在内。
+    pub(crate) fn file_live(&self, file_id: FileId) -> (u64, u64) {
+        self.file_live.get(&file_id).copied().unwrap_or_default()
+    }
+    /// 返回未删除的键和值的大小分布。
+    pub(crate) fn size_histograms(&self) -> (SizeHistogram, SizeHistogram) {
+        (self.key_sizes.clone(), self.value_sizes.clone())
+    }
+    /// 记录一次带有请求ID的写入，同一个请求ID只保留最后一次写入的时间戳。
+    pub(crate) fn record_request_id(&mut self, request_id: u64, timestamp: u64) {
+        if let Some(previous) = self.request_ids.insert(request_id, timestamp) {
+            self.request_id_times.remove(&(previous, request_id));
+        }
+        self.request_id_times.insert((timestamp, request_id));
+    }
+    /// 请求ID是否已经被记录，超出时间窗口的请求ID需要先通过`prune_request_ids`丢弃。
+    pub(crate) fn has_request_id(&self, request_id: u64) -> bool {
+        self.request_ids.contains_key(&request_id)
+    }
+    /// 丢弃时间戳早于`before`的请求ID。
+    pub(crate) fn prune_request_ids(&mut self, before: u64) {
+        while let Some(&(timestamp, request_id)) = self.request_id_times.get_min() {
+            if timestamp >= before {
+                break;
+            }
+            self.request_id_times.remove(&(timestamp, request_id));
+            self.request_ids.remove(&request_id);
+        }
+    }
+    /// 返回所有记录的请求ID及其时间戳，按时间戳排序。
+    pub(crate) fn request_ids(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.request_id_times.iter().map(|(timestamp, request_id)| (*request_id, *timestamp))
+    }
+    /// 从另一个索引中继承请求ID，用于压缩后重建索引：压缩后的文件不再保存请求ID。
+    pub(crate) fn inherit_request_ids(&mut self, previous: &MemIndexStorage) {
+        for (request_id, timestamp) in previous.request_ids() {
+            self.record_request_id(request_id, timestamp);
+        }
+    }
+    /// 从另一个内容相同的索引中继承版本号，用于压缩后重建索引时保持版本号不变。
+    pub(crate) fn inherit_versions(&mut self, previous: &MemIndexStorage) {
+        for (key, previous_entry) in previous.map.range((Bound::Unbounded, Bound::Unbounded)) {
+            if let Some(entry) = self.map.get_mut(key) {
+                entry.version = previous_entry.version;
+            }
+        }
+        self.sequence = self.sequence.max(previous.sequence);
     }
     /// 获取集合的当前大小。
     ///
@@ -103,7 +798,7 @@ impl MemIndexStorage {
 /// 该结构体的主要用途是在内存中直接迭代索引项，而不是操作具体的存储数据。
 /// 这在实现数据库、缓存或其他需要高效内存访问的数据结构时非常有用。
 pub(crate) struct MemIndexIterator {
-    inner: IntoIter<Key, MemIndexEntry>,
+    inner: Box<dyn Iterator<Item = (Key, MemIndexEntry)>>,
 }
 
 impl IntoIterator for MemIndexStorage {
@@ -119,7 +814,7 @@ impl IntoIterator for MemIndexStorage {
     ///
     fn into_iter(self) -> Self::IntoIter {
         // 将内部map结构转换为迭代器
-        let inner = self.map.into_iter();
+        let inner = self.map.into_entries();
         // 构造并返回一个MemIndexIterator实例，该实例包裹了内部map的迭代器
         MemIndexIterator { inner }
     }
@@ -137,5 +832,3 @@ impl Iterator for MemIndexIterator {
         self.inner.next()
     }
 }
-
-
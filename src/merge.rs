@@ -0,0 +1,10 @@
+/// `MergeReport` 结构体是`BitCask::merge_from`的结果统计。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    /// 从另一个存储导入的写入数量。
+    pub imported: usize,
+    /// 从另一个存储导入的删除数量。
+    pub deleted: usize,
+    /// 因为本地的记录更新而被忽略的键数量。
+    pub skipped: usize,
+}
@@ -0,0 +1,103 @@
+use crate::bitcask::{Key, Value};
+use crc::{Crc, CRC_64_ECMA_182};
+
+const CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_ECMA_182);
+
+/// 键空间按键的哈希被划分成的段数，也就是默克尔树的叶子数量，必须是2的幂。
+pub const MERKLE_SEGMENTS: usize = 1024;
+
+/// 返回键所在的段。
+///
+/// 使用与平台和Rust版本无关的CRC64，不同机器上的存储对同一个键总是得到相同的段。
+pub fn segment_of(key: &[u8]) -> usize {
+    (CRC64.checksum(key) as usize) & (MERKLE_SEGMENTS - 1)
+}
+
+/// 计算键最后一条记录的摘要，叶子的哈希是段内所有键摘要的异或。
+///
+/// 异或与顺序无关，写入时可以增量地去掉旧记录的摘要并加入新记录的摘要。
+pub(crate) fn entry_digest(key: &[u8], timestamp: u64, live: bool) -> u64 {
+    let mut digest = CRC64.digest();
+    digest.update(key);
+    digest.update(&timestamp.to_be_bytes());
+    digest.update(&[live as u8]);
+    digest.finalize()
+}
+
+/// `MerkleTree` 是某一时刻存储内容的默克尔树，由`BitCask::merkle_tree`返回。
+///
+/// 叶子对应键空间的各个段，内部节点是两个子节点哈希的组合。两个副本先交换根哈希，
+/// 不同时再逐层交换子节点，最终只需要交换哈希不同的段中的条目（见`BitCask::anti_entropy`）。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    /// 从叶子到根的各层节点，`levels[0]`是叶子，最后一层只有根节点。
+    levels: Vec<Vec<u64>>,
+}
+
+impl MerkleTree {
+    /// 根据叶子的哈希构建默克尔树。
+    pub(crate) fn from_leaves(leaves: Vec<u64>) -> Self {
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let parents = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|children| {
+                    let mut digest = CRC64.digest();
+                    for child in children {
+                        digest.update(&child.to_be_bytes());
+                    }
+                    digest.finalize()
+                })
+                .collect();
+            levels.push(parents);
+        }
+        Self { levels }
+    }
+
+    /// 返回根节点的哈希，两个根哈希相同的副本内容一致。
+    pub fn root(&self) -> u64 {
+        self.levels.last().unwrap()[0]
+    }
+
+    /// 返回第`level`层（0为叶子）的所有节点哈希，可以逐层发送给另一个副本比较。
+    pub fn level(&self, level: usize) -> &[u64] {
+        &self.levels[level]
+    }
+
+    /// 返回树的层数（包括叶子和根）。
+    pub fn depth(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// 从根开始逐层比较两棵树，返回哈希不同的段，只会访问哈希不同的子树。
+    pub fn diff(&self, other: &MerkleTree) -> Vec<usize> {
+        if self.root() == other.root() {
+            return Vec::new();
+        }
+        let mut differing = vec![0];
+        for level in (0..self.depth() - 1).rev() {
+            differing = differing
+                .into_iter()
+                .flat_map(|parent| [parent * 2, parent * 2 + 1])
+                .filter(|&node| node < self.levels[level].len())
+                .filter(|&node| self.levels[level][node] != other.levels[level][node])
+                .collect();
+        }
+        differing
+    }
+}
+
+/// `SyncEntry` 是副本之间交换的一个键的最后一条记录。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncEntry {
+    /// 键。
+    pub key: Key,
+    /// 记录的混合逻辑时钟时间戳，用于按最后写入者胜出的规则解决冲突。
+    pub timestamp: u64,
+    /// 键的值，为`None`表示该记录是删除。
+    pub value: Option<Value>,
+    /// 键的过期时间（Unix毫秒时间戳）。
+    pub expire_at: Option<u64>,
+}
@@ -0,0 +1,561 @@
+use crate::audit::AuditOptions;
+use crate::bitcask::{FileId, Value};
+use crate::compaction::{CompactionStrategy, MergeAll};
+use crate::error::BitCaskError;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// `BitCaskOptions` 结构体用于配置BitCask存储引擎的行为。
+///
+/// 所有字段都是公开的，可以基于`BitCaskOptions::default()`修改需要的字段后
+/// 通过`BitCask::new_with_options`打开存储。
+#[derive(Debug, Clone)]
+pub struct BitCaskOptions {
+    /// 慢操作阈值。get/put/delete以及压缩的各个步骤耗时超过该值时，
+    /// 会以warn级别记录一条日志，包含键大小、值大小和文件ID。
+    /// 为`None`时（默认）不记录慢操作日志。
+    pub slow_op_threshold: Option<Duration>,
+    /// 健康检查要求的数据目录所在磁盘的最小可用空间，以字节为单位。
+    pub min_available_space: u64,
+    /// 健康检查统计IO错误时考虑的最近操作数量。
+    pub health_window: usize,
+    /// 小值的zstd字典压缩配置，为`None`时（默认）不压缩新写入的值。
+    pub dictionary_compression: Option<DictionaryCompression>,
+    /// 是否开启值去重。开启后，与活跃文件中已有记录相同的值只存储一次，
+    /// 新的键只写入一个指向共享记录的引用条目。默认关闭。
+    pub dedup: bool,
+    /// 大值阈值。超过该字节数的值会被写入数据目录下`blobs`子目录中的独立文件，
+    /// 日志中只记录指向它的指针，压缩时不会重写这些值。为`None`时（默认）不使用大值文件。
+    pub blob_threshold: Option<usize>,
+    /// 内联值阈值。不超过该字节数的值会同时保存在内存索引中，读取时无需访问磁盘，
+    /// 写入仍然会追加到日志中以保证持久性。为`None`时（默认）不内联。
+    pub inline_value_threshold: Option<usize>,
+    /// 内存索引保存键的数据结构，默认为`IndexBackendKind::Bytes`。
+    pub index_backend: IndexBackendKind,
+    /// 写入的fsync策略，默认为`SyncPolicy::Manual`。
+    pub sync_policy: SyncPolicy,
+    /// 创建、重命名或删除数据文件之后是否fsync所在的目录。在ext4和XFS等文件系统上，
+    /// 只有fsync了目录，新文件的目录项才会持久化，否则断电后刚轮转出的活跃文件或者刚切换的压缩结果可能消失。
+    /// 与`sync_policy`无关：文件轮转和压缩不频繁，目录的fsync不影响写入的吞吐量。默认开启。
+    pub sync_directory: bool,
+    /// 活跃数据文件的大小上限，以字节为单位。一次写入使活跃文件超过上限后，之后的写入追加到新的数据文件中。
+    /// 压缩的输出文件不受限制。默认为1GB。
+    pub max_file_size: u64,
+    /// 数据文件的命名方式，默认为`<文件ID>.bitcask`。
+    pub file_naming: FileNaming,
+    /// 后台清理过期键的配置，为`None`时（默认）不在后台清理，
+    /// 过期的键在读取时被视为不存在，直到压缩时才被丢弃。
+    pub expiry_sweep: Option<ExpirySweep>,
+    /// 操作记录文件的路径。设置后，每次修改操作在执行前都会连同时间戳追加到该文件中，
+    /// 之后可以通过`trace::replay`在新的存储上重放，用于重现问题或以真实负载做基准测试。
+    /// 合并和反熵导入的记录不会被记录。为`None`时（默认）不记录。
+    pub trace_file: Option<PathBuf>,
+    /// 审计日志的配置，开启后每次写入和删除（可选地包括读取）都会向单独的审计文件追加一条记录，
+    /// 记录中的客户端ID来自`BitCask::with_client_id`。为`None`时（默认）不审计。
+    pub audit: Option<AuditOptions>,
+    /// 打开存储时压缩数据文件的配置，为`None`时（默认）打开时不压缩。
+    pub compact_on_open: Option<CompactOnOpen>,
+    /// 稀疏索引的配置，为`None`时（默认）所有键都保存在内存索引中。
+    pub sparse_index: Option<SparseIndex>,
+    /// 是否开启严格校验。默认情况下从数据文件读取的值会按内存索引中保存的校验和校验；
+    /// 开启后每次读取还会读取整条记录，检查记录头中的大小和标志与内存索引是否一致，
+    /// 不一致时返回`BitCaskError::CorruptedData`，而不是返回错误的字节。内联在内存索引中的值不受影响。默认关闭。
+    pub paranoid_checks: bool,
+    /// 追加数据文件时磁盘空间不足是否进入只读模式。写入失败时写入了一部分的记录总会被回滚并返回
+    /// `BitCaskError::DiskFull`；开启后之后的写入直接返回`BitCaskError::ReadOnly`，
+    /// 直到可用空间恢复到`min_available_space`以上。读取不受影响。默认关闭。
+    pub read_only_on_disk_full: bool,
+    /// 保留空间的字节数。设置后打开存储时在数据目录中创建一个预先分配了磁盘块的`reserve`文件，
+    /// 追加时第一次遇到磁盘空间不足就删除它，腾出的空间用于写入墓碑并完成一次回收空间的压缩；
+    /// 压缩完成后在新的数据目录中重新预留。为`None`时（默认）不预留。
+    pub reserved_space: Option<u64>,
+    /// 识别重试写入的时间窗口。带有`PutOption::request_id`的写入在该时间内再次出现相同的请求ID时
+    /// 不再写入并直接返回成功，更早的请求ID会被丢弃。默认为10分钟。
+    pub request_id_window: Duration,
+    /// 数据保留期限。设置后压缩会丢弃写入时间（混合逻辑时钟时间戳中的物理时间）早于保留期限的记录，
+    /// 即使它们没有被删除或过期，适合遥测数据或者有法规要求的数据生命周期。保留只在压缩时生效，
+    /// 压缩之前超过期限的键仍然可以读取；旧版本写入的没有时间戳的记录总是被保留。为`None`时（默认）永久保留。
+    pub retention: Option<Duration>,
+    /// 数据文件被封存或者被压缩取代时调用的钩子，可以用来把文件上传到对象存储，或者按自己的保留策略删除它们。
+    /// 为`None`时（默认）不调用。
+    pub file_hook: Option<FileHook>,
+    /// 追加模式（WORM）。开启后键只能写入一次，覆盖、删除、重命名和同步中的覆盖都返回`BitCaskError::ReadOnlyKey`，
+    /// 过期的键不会被清理，压缩也不再丢弃过期的键或超过保留期限的记录，存储可以作为防篡改的审计日志使用。
+    /// 开启之前已经被覆盖或删除的记录仍然会在压缩时被丢弃。默认为`false`。
+    pub append_only: bool,
+    /// 压缩时对每个保留下来的键调用的过滤器，可以丢弃键或者替换它的值，用于实现应用自己的垃圾回收，
+    /// 例如丢弃已经删除的租户的所有记录，而不必自己扫描整个存储。追加模式下不调用。为`None`时（默认）不过滤。
+    pub compaction_filter: Option<CompactionFilter>,
+    /// 是否校验压缩的输出。开启后合并完成、切换到新文件之前会重新打开输出文件，校验每条记录的校验和，
+    /// 并检查重建的索引与合并时写入的键、值、过期时间和元数据完全一致；不一致时删除输出文件并返回
+    /// `BitCaskError::CorruptedData`，存储继续使用原来的文件。需要多读一遍输出文件。默认关闭。
+    pub verify_compaction: bool,
+    /// `BitCask::compact_to_new_dir`选择合并哪些数据文件的策略，默认为`MergeAll`，每次合并所有封存的文件；
+    /// `SizeTiered`只合并一段大小相近且碎片较多的文件。打开时压缩、离线压缩和升级总是合并所有文件。
+    pub compaction_strategy: Arc<dyn CompactionStrategy>,
+    /// 默认的存活时间。设置后没有通过`PutOption::ttl`指定存活时间的写入（包括`BitCask::put_many_sorted`）
+    /// 都会在该时间后过期，适合缓存场景，避免忘记设置存活时间的数据永远不过期。
+    /// 重命名、复制和恢复软删除的键时保留它们原来的过期时间；树可以通过`Tree::with_default_ttl`使用自己的默认值。
+    /// 为`None`时（默认）不过期。
+    pub default_ttl: Option<Duration>,
+    /// IO看门狗。设置后追加和fsync超过期限时（例如NFS挂起或者磁盘即将损坏）记录诊断信息，
+    /// 并按配置让操作失败或者让存储进入只读的降级模式，状态通过`BitCask::health`报告。为`None`时（默认）不监视。
+    pub io_watchdog: Option<IoWatchdog>,
+    /// 后台维护线程池的线程数量。按间隔fsync、清理过期键和定期保存keydir都在这个线程池中执行，
+    /// 同时到期时先执行fsync，再清理过期键，最后保存keydir。线程在第一次有后台任务时才创建。
+    /// 默认为2，为0时按1处理；IO看门狗使用自己的线程，不受线程池中挂起的fsync影响。
+    pub maintenance_threads: usize,
+    /// 定期保存keydir的间隔，见`BitCask::save_keydir`。设置后崩溃之后重新打开时只需要扫描上一次保存之后的写入，
+    /// 保存期间写入会被阻塞。为`None`时（默认）只在调用`BitCask::save_keydir`或`BitCask::close`时保存。
+    pub keydir_interval: Option<Duration>,
+    /// 通过`actor::BitCaskActor`使用存储时get、put和delete的最长等待时间。存储设备挂起时，
+    /// 超过这个时间的操作以`BitCaskError::Timeout`返回，调用方不会被无限期阻塞；
+    /// 超时的写入仍然可能在之后完成。直接使用`BitCask`时不生效。为`None`时（默认）不限制。
+    pub op_timeout: Option<Duration>,
+    /// 数据文件总大小的硬上限。超过上限时写入（删除、软删除和清理过期键除外，它们是回收空间的途径）
+    /// 按配置阻塞等待压缩回收空间或者立即失败，而不是让数据文件无限增长；阻塞和失败的次数通过`BitCask::stats`报告。
+    /// 为`None`时（默认）不限制。
+    pub disk_usage_cap: Option<DiskUsageCap>,
+}
+
+impl Default for BitCaskOptions {
+    /// 返回默认的配置选项。
+    fn default() -> Self {
+        Self {
+            slow_op_threshold: None,
+            min_available_space: 64 * 1024 * 1024, // 64MB
+            health_window: 100,
+            dictionary_compression: None,
+            dedup: false,
+            blob_threshold: None,
+            inline_value_threshold: None,
+            index_backend: IndexBackendKind::Bytes,
+            sync_policy: SyncPolicy::Manual,
+            sync_directory: true,
+            max_file_size: 1024 * 1024 * 1024, // 1GB
+            file_naming: FileNaming::default(),
+            expiry_sweep: None,
+            trace_file: None,
+            audit: None,
+            compact_on_open: None,
+            sparse_index: None,
+            paranoid_checks: false,
+            read_only_on_disk_full: false,
+            reserved_space: None,
+            request_id_window: Duration::from_secs(10 * 60),
+            retention: None,
+            file_hook: None,
+            append_only: false,
+            compaction_filter: None,
+            verify_compaction: false,
+            compaction_strategy: Arc::new(MergeAll),
+            default_ttl: None,
+            io_watchdog: None,
+            maintenance_threads: 2,
+            keydir_interval: None,
+            op_timeout: None,
+            disk_usage_cap: None,
+        }
+    }
+}
+
+/// `TunableOptions` 是`BitCaskOptions`中可以在运行时通过`BitCask::reconfigure`修改的部分。
+///
+/// 修改只影响之后的操作：例如修改`blob_threshold`或`dedup`不会重写已经写入的值。
+/// 其余选项（例如文件命名、字典压缩和内联值阈值）决定了磁盘或内存中的数据格式，只能在打开存储时设置。
+#[derive(Debug, Clone)]
+pub struct TunableOptions {
+    /// 见`BitCaskOptions::slow_op_threshold`。
+    pub slow_op_threshold: Option<Duration>,
+    /// 见`BitCaskOptions::min_available_space`。
+    pub min_available_space: u64,
+    /// 见`BitCaskOptions::dedup`。
+    pub dedup: bool,
+    /// 见`BitCaskOptions::blob_threshold`。
+    pub blob_threshold: Option<usize>,
+    /// 见`BitCaskOptions::sync_policy`，改为`SyncPolicy::Always`时会先fsync之前的写入。
+    pub sync_policy: SyncPolicy,
+    /// 见`BitCaskOptions::expiry_sweep`。
+    pub expiry_sweep: Option<ExpirySweep>,
+    /// 见`BitCaskOptions::paranoid_checks`。
+    pub paranoid_checks: bool,
+    /// 见`BitCaskOptions::read_only_on_disk_full`。
+    pub read_only_on_disk_full: bool,
+    /// 见`BitCaskOptions::request_id_window`。
+    pub request_id_window: Duration,
+    /// 见`BitCaskOptions::retention`。
+    pub retention: Option<Duration>,
+    /// 见`BitCaskOptions::default_ttl`。
+    pub default_ttl: Option<Duration>,
+    /// 见`BitCaskOptions::keydir_interval`。
+    pub keydir_interval: Option<Duration>,
+    /// 见`BitCaskOptions::disk_usage_cap`。
+    pub disk_usage_cap: Option<DiskUsageCap>,
+}
+
+impl From<&BitCaskOptions> for TunableOptions {
+    /// 返回给定配置中可以在运行时修改的选项。
+    fn from(options: &BitCaskOptions) -> Self {
+        Self {
+            slow_op_threshold: options.slow_op_threshold,
+            min_available_space: options.min_available_space,
+            dedup: options.dedup,
+            blob_threshold: options.blob_threshold,
+            sync_policy: options.sync_policy,
+            expiry_sweep: options.expiry_sweep.clone(),
+            paranoid_checks: options.paranoid_checks,
+            read_only_on_disk_full: options.read_only_on_disk_full,
+            request_id_window: options.request_id_window,
+            retention: options.retention,
+            default_ttl: options.default_ttl,
+            keydir_interval: options.keydir_interval,
+            disk_usage_cap: options.disk_usage_cap.clone(),
+        }
+    }
+}
+
+impl BitCaskOptions {
+    /// 用`tunable`中的值替换对应的选项。
+    pub(crate) fn apply(&mut self, tunable: TunableOptions) {
+        self.slow_op_threshold = tunable.slow_op_threshold;
+        self.min_available_space = tunable.min_available_space;
+        self.dedup = tunable.dedup;
+        self.blob_threshold = tunable.blob_threshold;
+        self.sync_policy = tunable.sync_policy;
+        self.expiry_sweep = tunable.expiry_sweep;
+        self.paranoid_checks = tunable.paranoid_checks;
+        self.read_only_on_disk_full = tunable.read_only_on_disk_full;
+        self.request_id_window = tunable.request_id_window;
+        self.retention = tunable.retention;
+        self.default_ttl = tunable.default_ttl;
+        self.keydir_interval = tunable.keydir_interval;
+        self.disk_usage_cap = tunable.disk_usage_cap;
+    }
+}
+
+/// `CompactOnOpen` 结构体配置打开存储时的压缩，适合从干净、最小的文件集合开始的长时间批处理任务。
+///
+/// 与`BitCask::compact_to_new_dir`不同，压缩在原数据目录中进行：所有数据文件被合并为一个新文件，
+/// 之后按从旧到新的顺序删除旧文件，中途崩溃时剩余的文件仍然表示同一份数据。
+/// 大值文件不会被重写，不再被引用的大值文件也不会被删除。
+#[derive(Debug, Clone)]
+pub struct CompactOnOpen {
+    /// 只有预计可回收的字节数占数据文件总大小的比例（见`CompactionEstimate::reclaimable_bytes`）
+    /// 不小于该值时才压缩，为0时每次打开都压缩。
+    pub min_reclaimable_ratio: f64,
+}
+
+impl Default for CompactOnOpen {
+    /// 返回默认的配置：至少一半的数据可以回收时才压缩。
+    fn default() -> Self {
+        Self {
+            min_reclaimable_ratio: 0.5,
+        }
+    }
+}
+
+/// `SparseIndex` 结构体配置稀疏索引，用键空间很大、大部分键很少被访问的存储，以读取延迟换取内存。
+///
+/// 打开存储时，除最后一个（继续写入的）文件之外的数据文件每`interval`条记录只有一条被加入内存索引，
+/// 墓碑以及之后覆盖已索引键的记录也会被加入。其余的记录按块记录位置和键的过滤器，
+/// 读取不在索引中的键时需要在这些文件中从新到旧扫描可能包含它的块。打开之后写入的键总是被完整索引。
+///
+/// 范围扫描、迭代、`size`、统计信息、默克尔树和反熵同步需要遍历所有键，
+/// 每次调用都会重新扫描稀疏加载的文件；后台过期清理只会清理索引中的键，其余过期的键在读取时仍被视为不存在。
+/// 不在索引中的键通过`BitCask::get_with_meta`读取到的版本号为0。
+#[derive(Debug, Clone)]
+pub struct SparseIndex {
+    /// 每隔多少条记录索引一条，为1时与完整索引相同。
+    pub interval: usize,
+}
+
+impl Default for SparseIndex {
+    /// 返回默认的配置：每16条记录索引一条。
+    fn default() -> Self {
+        Self { interval: 16 }
+    }
+}
+
+/// `IndexBackendKind` 选择内存索引中保存键的数据结构，两者都是有序的持久化映射，行为完全相同，
+/// 只是内存占用和性能不同，可以用`bitcask-bench --index-backend`比较。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexBackendKind {
+    /// 每个键单独在堆上分配一个`Vec<u8>`。
+    #[default]
+    Bytes,
+    /// 不超过22字节的短键直接内联在映射的节点中，不需要单独的堆分配，比较键时也不必跟随指针访问另一块内存；
+    /// 更长的键仍然在堆上分配。
+    InlineKeys,
+}
+
+/// `SyncPolicy` 决定写入何时通过fsync持久化到磁盘。
+///
+/// 除`Always`之外的策略都是放宽的：写入返回时数据可能只在操作系统的页缓存中，
+/// 需要确认某次写入已经持久化时可以使用`BitCask::put_with_ack`。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// 每次写入后都立即fsync。
+    Always,
+    /// 由后台维护线程池按给定的间隔fsync，多次写入共享一次fsync。
+    Interval(Duration),
+    /// 只在调用`BitCask::sync`以及封存数据文件时fsync。
+    Manual,
+}
+
+/// `ExpirySweep` 结构体配置后台清理过期键的任务。
+///
+/// 后台维护线程池每隔`interval`按过期时间顺序找出已经过期的键，并分批为它们写入墓碑，
+/// 清理的数量通过`BitCask::stats`报告。
+#[derive(Debug, Clone)]
+pub struct ExpirySweep {
+    /// 两轮清理之间的间隔。
+    pub interval: Duration,
+    /// 每批写入的墓碑数量，每批之间会释放写锁，避免长时间阻塞写入。
+    pub batch_size: usize,
+}
+
+impl Default for ExpirySweep {
+    /// 返回默认的清理配置。
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            batch_size: 1000,
+        }
+    }
+}
+
+/// `DiskUsageCap` 结构体配置数据文件总大小的硬上限，见`BitCaskOptions::disk_usage_cap`。
+///
+/// 总大小包括所有数据文件（已封存的文件和活跃文件），不包括大值文件、keydir等其他文件。
+/// 在线压缩（`BitCask::compact_to_new_dir`）完成后总大小变为压缩后的大小，被阻塞的写入随即继续。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskUsageCap {
+    /// 数据文件总大小的上限，以字节为单位。达到上限之后的写入受`action`限制。
+    pub max_bytes: u64,
+    /// 超过上限时对写入的处理。
+    pub action: CapAction,
+}
+
+/// `CapAction` 是数据文件总大小超过`DiskUsageCap::max_bytes`时对写入的处理。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapAction {
+    /// 阻塞写入，直到总大小回到上限以下；等待超过给定的时间后返回`BitCaskError::WriteStalled`，为`None`时一直等待。
+    /// 等待期间不持有存储的锁，读取、删除和压缩照常进行。
+    Block(Option<Duration>),
+    /// 立即返回`BitCaskError::WriteStalled`。
+    Fail,
+}
+
+/// `IoWatchdog` 结构体配置IO看门狗，见`BitCaskOptions::io_watchdog`。
+///
+/// 后台线程在追加或fsync仍在进行时就报告超过期限的操作，因此IO挂起期间也能在日志中看到是哪个操作、
+/// 哪个数据文件卡住了多久；操作完成之后再按`action`处理。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IoWatchdog {
+    /// 一次追加或fsync允许的最长耗时。
+    pub deadline: Duration,
+    /// 操作超过期限之后的处理方式。
+    pub action: StallAction,
+}
+
+impl Default for IoWatchdog {
+    /// 返回默认的看门狗配置：期限为5秒，超时只记录日志。
+    fn default() -> Self {
+        Self {
+            deadline: Duration::from_secs(5),
+            action: StallAction::Log,
+        }
+    }
+}
+
+/// `StallAction` 是IO操作超过看门狗期限之后的处理方式。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallAction {
+    /// 只记录日志和计数，操作的结果不变。
+    Log,
+    /// 操作返回`BitCaskError::IoStalled`，超时的追加会被截断回写入之前的长度，就像没有发生过一样。
+    Fail,
+    /// 操作的结果不变，但存储进入只读的降级模式：之后的写入返回`BitCaskError::ReadOnly`，
+    /// 直到调用`BitCask::resume_writes`。
+    ReadOnly,
+}
+
+/// `FileNaming` 配置数据文件的文件名，数据文件命名为`<prefix><文件ID>.<extension>`。
+///
+/// 打开存储时只会加载符合命名方式的文件，因此多个前缀或扩展名不同的存储可以共享同一个数据目录。
+/// 压缩字典和大值文件不区分命名方式，共享目录的存储不应开启字典压缩或大值文件。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileNaming {
+    /// 文件名前缀，不能包含路径分隔符或`.`，也不能以数字结尾，默认为空。
+    pub prefix: String,
+    /// 文件扩展名（不含`.`），只能由字母、数字、`-`和`_`组成，不能是临时文件使用的`tmp`，默认为`bitcask`。
+    pub extension: String,
+}
+
+impl Default for FileNaming {
+    /// 返回默认的命名方式。
+    fn default() -> Self {
+        Self {
+            prefix: String::new(),
+            extension: "bitcask".to_string(),
+        }
+    }
+}
+
+impl FileNaming {
+    /// 检查命名方式是否合法，打开存储时调用。
+    ///
+    /// 前缀以数字结尾时，前缀为`a1`的文件`a10.bitcask`与前缀为`a`的文件ID 10无法区分，因此不允许。
+    pub(crate) fn validate(&self) -> Result<(), BitCaskError> {
+        let invalid = |reason: &str| {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid file naming {:?}: {}", self, reason),
+            )
+            .into())
+        };
+        if self.prefix.contains(['/', '\\', '.']) {
+            return invalid("prefix must not contain path separators or '.'");
+        }
+        if self.prefix.ends_with(|c: char| c.is_ascii_digit()) {
+            return invalid("prefix must not end with a digit");
+        }
+        if self.extension.is_empty()
+            || !self.extension.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return invalid("extension must be non-empty and contain only ASCII letters, digits, '-' and '_'");
+        }
+        if self.extension == "tmp" {
+            return invalid("extension 'tmp' is reserved for temporary files");
+        }
+        Ok(())
+    }
+
+    /// 返回给定文件ID的数据文件名。
+    pub(crate) fn file_name(&self, file_id: FileId) -> String {
+        format!("{}{}.{}", self.prefix, file_id, self.extension)
+    }
+
+    /// 返回目录锁文件的文件名，见`BitCask::compact_offline`。命名方式不同的存储使用不同的锁文件。
+    pub(crate) fn lock_file_name(&self) -> String {
+        format!("{}LOCK.{}", self.prefix, self.extension)
+    }
+
+    /// 如果路径是符合命名方式的数据文件，返回它的文件ID。
+    pub(crate) fn file_id(&self, path: &Path) -> Option<FileId> {
+        if path.extension()?.to_str()? != self.extension {
+            return None;
+        }
+        let file_stem = path.file_stem()?.to_str()?.strip_prefix(self.prefix.as_str())?;
+        // 只接受十进制数字，避免`+1`之类的文件名被解析为文件ID
+        if !file_stem.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        file_stem.parse().ok()
+    }
+}
+
+/// `FileEvent` 是传给`FileHook`的数据文件事件。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileEvent {
+    /// 活跃文件写满或者压缩开始时被封存，之后不会再被修改，但存储仍然会读取它。
+    Sealed(PathBuf),
+    /// 文件被压缩取代，存储不再读取它。`BitCask::compact_to_new_dir`之后文件留在旧的数据目录中；
+    /// 在原目录中压缩（离线压缩和打开时的压缩）时，钩子返回之后文件就会被删除。
+    Superseded(PathBuf),
+}
+
+/// `FileHook` 是`BitCaskOptions::file_hook`的回调，见`FileEvent`。
+///
+/// 钩子在写入或压缩的线程中同步调用，调用期间持有存储的写锁，耗时的工作（例如上传文件）应该交给其他线程。
+#[derive(Clone)]
+pub struct FileHook(Arc<dyn Fn(&FileEvent) + Send + Sync>);
+
+impl FileHook {
+    /// 用给定的回调创建钩子。
+    pub fn new(hook: impl Fn(&FileEvent) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+
+    /// 调用钩子。
+    pub(crate) fn fire(&self, event: FileEvent) {
+        (self.0)(&event)
+    }
+}
+
+impl fmt::Debug for FileHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FileHook")
+    }
+}
+
+/// `CompactionDecision` 是`CompactionFilter`对一个键的处理结果。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompactionDecision {
+    /// 原样保留。
+    Keep,
+    /// 丢弃键，压缩完成后键不再存在。
+    Drop,
+    /// 用新的值替换，过期时间、元数据、时间戳和版本号保持不变，新的值不会被字典压缩。
+    Replace(Value),
+}
+
+/// `CompactionFilter` 是`BitCaskOptions::compaction_filter`的回调，参数是键、值和写入时附加的元数据。
+///
+/// 过滤器只作用于压缩的输入，也就是压缩开始时已经封存的文件中仍然存在的键：已经删除、过期或超过保留期限的键
+/// 不会传给过滤器，压缩期间的新写入也不受影响。值是解压之后的原始值，大值也会被读取出来。
+/// 过滤器在压缩的线程中同步调用，`BitCask::compact_to_new_dir`调用期间不持有存储的锁；
+/// `BitCask::estimate_compaction`的预估不考虑过滤器。
+#[derive(Clone)]
+pub struct CompactionFilter(Arc<FilterFn>);
+
+/// 过滤器回调的类型。
+type FilterFn = dyn Fn(&[u8], &[u8], Option<&[u8]>) -> CompactionDecision + Send + Sync;
+
+impl CompactionFilter {
+    /// 用给定的回调创建过滤器。
+    pub fn new(filter: impl Fn(&[u8], &[u8], Option<&[u8]>) -> CompactionDecision + Send + Sync + 'static) -> Self {
+        Self(Arc::new(filter))
+    }
+
+    /// 调用过滤器。
+    pub(crate) fn decide(&self, key: &[u8], value: &[u8], metadata: Option<&[u8]>) -> CompactionDecision {
+        (self.0)(key, value, metadata)
+    }
+}
+
+impl fmt::Debug for CompactionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CompactionFilter")
+    }
+}
+
+/// `DictionaryCompression` 结构体配置小值的zstd字典压缩。
+///
+/// 存储会先从写入的值中采样，样本数量达到`training_samples`后训练字典并保存在数据目录中，
+/// 之后不超过`max_value_size`的值都会使用该字典压缩。
+#[derive(Debug, Clone)]
+pub struct DictionaryCompression {
+    /// 使用字典压缩的值的最大字节数，更大的值按原样存储。
+    pub max_value_size: usize,
+    /// 训练字典所需的样本数量。
+    pub training_samples: usize,
+    /// 字典的最大字节数。
+    pub dictionary_size: usize,
+    /// zstd压缩级别。
+    pub level: i32,
+}
+
+impl Default for DictionaryCompression {
+    /// 返回默认的字典压缩配置。
+    fn default() -> Self {
+        Self {
+            max_value_size: 1024,
+            training_samples: 1000,
+            dictionary_size: 16 * 1024, // 16KB
+            level: 3,
+        }
+    }
+}
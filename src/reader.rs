@@ -0,0 +1,270 @@
+//! 只读地打开数据目录，可以与正在写入同一个目录的存储（包括其他进程中的存储）同时使用。
+
+use crate::bitcask::{prefix_range, ByteOffset, FileId, Key, Value, ValueMeta};
+use crate::blob::BlobStorage;
+use crate::compression::DictionaryCompressor;
+use crate::disk_logs::DiskLogReader;
+use crate::error::BitCaskError;
+use crate::health::OpHistory;
+use crate::iter::Iter;
+use crate::keydir::{Keydir, KEYDIR_FILE};
+use crate::lock::DirLock;
+use crate::log_file::DiskLogFile;
+use crate::memory_index::MemIndexStorage;
+use crate::options::BitCaskOptions;
+use crate::snapshot::ReadSnapshot;
+use crate::stats::{Stats, StatsCounters};
+use arc_swap::ArcSwap;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+use tracing::trace;
+
+/// `ReadOnlyBitCask` 以只读方式打开一个数据目录，写入者（可能在另一个进程中）可以同时打开并写入同一个目录。
+///
+/// 读者能看到打开或最近一次`refresh`时已经封存的数据文件中的写入，以及最近一次保存的keydir
+/// （见`BitCask::save_keydir`和`BitCask::close`）覆盖的写入。写入者仍在追加的活跃文件中之后的写入，
+/// 要等到这个文件被封存或者keydir被重新保存之后才能通过`refresh`看到，因此读者不会读到写了一半的记录。
+///
+/// 读者与其他存储一样持有目录的共享锁，离线压缩和打开时的压缩不会在读取期间替换数据文件；
+/// 写入者在线压缩到新的目录之后，旧目录不再变化，读者需要重新打开新的目录才能看到之后的写入。
+pub struct ReadOnlyBitCask {
+    data_dir: PathBuf,
+    options: BitCaskOptions,
+    /// 最近一次加载的只读快照，读取不会被`refresh`阻塞。
+    snapshot: ArcSwap<ReadSnapshot>,
+    /// 已经加载的索引和数据文件，`refresh`在此基础上加载新的记录。
+    state: Mutex<ReaderState>,
+    op_history: Arc<OpHistory>,
+    counters: Arc<StatsCounters>,
+    /// 目录的共享锁，在读者被丢弃时释放。
+    _dir_lock: DirLock,
+}
+
+/// 读者已经加载的内容。
+struct ReaderState {
+    mem_index: MemIndexStorage,
+    /// 已经打开的数据文件，按文件ID升序排列。
+    files: BTreeMap<FileId, Arc<DiskLogFile>>,
+    /// 每个文件已经加载到的偏移量。
+    loaded: HashMap<FileId, ByteOffset>,
+    /// 最近一次加载keydir时keydir文件的修改时间和长度，用于判断写入者是否重新保存了keydir。
+    keydir_stamp: Option<(SystemTime, u64)>,
+    /// 上一次加载失败或者中途panic，之后需要从头重新加载。
+    dirty: bool,
+}
+
+impl ReadOnlyBitCask {
+    /// 以只读方式打开一个已经存在的数据目录，不会创建或修改任何数据文件。
+    ///
+    /// # 参数
+    /// - `data_dir`: 数据目录的路径。
+    /// - `options`: 只使用数据文件的命名方式、内联阈值、慢操作阈值、健康检查窗口和严格校验，其他选项被忽略。
+    ///
+    /// # 返回
+    /// 目录不存在时返回IO错误；目录正在被离线压缩时返回`BitCaskError::DirectoryLocked`。
+    pub fn open<T: Into<PathBuf>>(data_dir: T, options: BitCaskOptions) -> Result<Self, BitCaskError> {
+        let data_dir: PathBuf = data_dir.into();
+        options.file_naming.validate()?;
+        if !data_dir.is_dir() {
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "data directory does not exist").into());
+        }
+        let dir_lock = DirLock::shared(&data_dir, &options.file_naming)?;
+        let state = ReaderState::load(&data_dir, &options)?;
+        let op_history = Arc::new(OpHistory::new(options.health_window));
+        let counters = Arc::new(StatsCounters::default());
+        let snapshot = ArcSwap::from_pointee(state.snapshot(&data_dir, &options, &op_history, &counters)?);
+        Ok(Self {
+            data_dir,
+            options,
+            snapshot,
+            state: Mutex::new(state),
+            op_history,
+            counters,
+            _dir_lock: dir_lock,
+        })
+    }
+
+    /// 加载打开之后新封存的数据文件和重新保存的keydir，返回是否加载了新的内容。
+    ///
+    /// 只有新的文件时增量地加载它们；keydir被重新保存或者数据文件被替换时从头重新加载。
+    /// 加载期间读取仍然使用之前的快照，加载失败时之前的快照保持不变，下一次调用会从头重新加载。
+    pub fn refresh(&self) -> Result<bool, BitCaskError> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let current = list_files(&self.data_dir, &self.options)?;
+        let replaced = state.files.keys().any(|file_id| !current.iter().any(|(id, _, _)| id == file_id));
+        let changed = if state.dirty || replaced || keydir_stamp(&self.data_dir) != state.keydir_stamp {
+            trace!("reloading read-only store from {:?}", self.data_dir);
+            state.dirty = true;
+            *state = ReaderState::load(&self.data_dir, &self.options)?;
+            true
+        } else {
+            state.dirty = true;
+            let changed = state.load_sealed(&current)?;
+            state.dirty = false;
+            changed
+        };
+        if changed {
+            let snapshot = state.snapshot(&self.data_dir, &self.options, &self.op_history, &self.counters)?;
+            self.snapshot.store(Arc::new(snapshot));
+        }
+        Ok(changed)
+    }
+
+    /// 根据键获取值，读取失败时记录错误并返回`None`，见`KVStorage::get`。
+    pub fn get(&self, key: &Key) -> Option<Value> {
+        self.snapshot.load().get(key)
+    }
+
+    /// 根据键获取值，读取失败时返回错误，见`BitCask::try_get`。
+    pub fn try_get(&self, key: &Key) -> Result<Option<Value>, BitCaskError> {
+        self.snapshot.load().try_get(key)
+    }
+
+    /// 读取键的值和元信息，见`BitCask::get_with_meta`。
+    pub fn get_with_meta(&self, key: &Key) -> Result<Option<ValueMeta>, BitCaskError> {
+        self.snapshot.load().get_with_meta(key)
+    }
+
+    /// 键是否存在且未过期，只查找索引。
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.snapshot.load().contains_key(key)
+    }
+
+    /// 返回未删除且未过期的键的数量。
+    pub fn len(&self) -> usize {
+        self.snapshot.load().count(..)
+    }
+
+    /// 是否没有任何键。
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 按键的顺序遍历给定范围内的键值对，见`BitCask::iter`。
+    pub fn iter<R: RangeBounds<Key>>(&self, range: R) -> Iter {
+        Iter::new(self.snapshot.load_full(), range)
+    }
+
+    /// 按键的顺序遍历以给定前缀开头的键值对，见`BitCask::iter_prefix`。
+    pub fn iter_prefix(&self, prefix: &[u8]) -> Iter {
+        Iter::new(self.snapshot.load_full(), prefix_range(prefix))
+    }
+
+    /// 返回最近一次加载的内容的统计信息，见`BitCask::stats`。
+    pub fn stats(&self) -> Stats {
+        self.snapshot.load().stats()
+    }
+}
+
+impl ReaderState {
+    /// 从头加载数据目录：先加载仍然描述这些数据文件的keydir，再加载keydir之后封存的内容。
+    fn load(data_dir: &Path, options: &BitCaskOptions) -> Result<Self, BitCaskError> {
+        let mut state = ReaderState {
+            mem_index: MemIndexStorage::with_inline_value_threshold(options.inline_value_threshold)
+                .with_backend(options.index_backend),
+            files: BTreeMap::new(),
+            loaded: HashMap::new(),
+            keydir_stamp: keydir_stamp(data_dir),
+            dirty: false,
+        };
+        let current = list_files(data_dir, options)?;
+        let lengths: Vec<(FileId, u64)> = current.iter().map(|(file_id, _, len)| (*file_id, *len)).collect();
+        if let Some(keydir) = Keydir::open(data_dir, None)? {
+            match keydir.resume_offsets(&lengths) {
+                Some(offsets) => {
+                    keydir.load_into(&mut state.mem_index)?;
+                    for (file_id, path, _) in &current {
+                        if let Some((offset, records)) = offsets.get(file_id) {
+                            state.open_file(*file_id, path)?.add_records(*records);
+                            state.loaded.insert(*file_id, *offset);
+                        }
+                    }
+                }
+                None => trace!("ignoring keydir that does not match the data files in {:?}", data_dir),
+            }
+        }
+        state.load_sealed(&current)?;
+        Ok(state)
+    }
+
+    /// 加载已经封存的文件（除了ID最大的活跃文件之外的所有文件）中尚未加载的记录，返回是否加载了新的记录。
+    fn load_sealed(&mut self, current: &[(FileId, PathBuf, u64)]) -> Result<bool, BitCaskError> {
+        let Some(((active_id, active_path, _), sealed)) = current.split_last() else {
+            return Ok(false);
+        };
+        let mut changed = false;
+        for (file_id, path, len) in sealed {
+            let disk_log_file = self.open_file(*file_id, path)?;
+            let start = self.loaded.get(file_id).copied().unwrap_or(disk_log_file.data_start());
+            if start >= *len {
+                continue;
+            }
+            let (records, _) = disk_log_file.populate_mem_index_from(&mut self.mem_index, start)?;
+            disk_log_file.add_records(records);
+            self.loaded.insert(*file_id, *len);
+            changed |= records > 0;
+        }
+        // 活跃文件中keydir覆盖的部分可能已经在索引中，读取它们需要打开这个文件
+        self.open_file(*active_id, active_path)?;
+        Ok(changed)
+    }
+
+    /// 返回已经打开的文件，没有打开时以只读方式打开它。
+    fn open_file(&mut self, file_id: FileId, path: &Path) -> Result<Arc<DiskLogFile>, BitCaskError> {
+        if let Some(disk_log_file) = self.files.get(&file_id) {
+            return Ok(disk_log_file.clone());
+        }
+        let disk_log_file = Arc::new(DiskLogFile::open_read_only(file_id, path.to_path_buf())?);
+        self.files.insert(file_id, disk_log_file.clone());
+        Ok(disk_log_file)
+    }
+
+    /// 根据已经加载的内容创建只读快照，压缩字典和大值目录每次重新打开，它们可能是在上一次加载之后创建的。
+    fn snapshot(
+        &self,
+        data_dir: &Path,
+        options: &BitCaskOptions,
+        op_history: &Arc<OpHistory>,
+        counters: &Arc<StatsCounters>,
+    ) -> Result<ReadSnapshot, BitCaskError> {
+        Ok(ReadSnapshot {
+            mem_index: self.mem_index.clone(),
+            disk_log: DiskLogReader::new(self.files.values().cloned().collect()),
+            blobs: BlobStorage::open(data_dir)?,
+            decoder: DictionaryCompressor::open(data_dir, None)?.decoder(),
+            op_history: op_history.clone(),
+            slow_op_threshold: options.slow_op_threshold,
+            paranoid_checks: options.paranoid_checks,
+            counters: counters.clone(),
+        })
+    }
+}
+
+/// 按文件ID升序返回数据目录中的数据文件及其当前长度，写入者创建文件时留下的临时文件不包括在内。
+fn list_files(data_dir: &Path, options: &BitCaskOptions) -> Result<Vec<(FileId, PathBuf, u64)>, BitCaskError> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(data_dir)? {
+        let path = entry?.path();
+        let Some(file_id) = options.file_naming.file_id(&path) else {
+            continue;
+        };
+        // 列出目录之后文件可能被删除，例如写入者打开时清理临时文件
+        match std::fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => files.push((file_id, path, metadata.len())),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    files.sort_by_key(|(file_id, _, _)| *file_id);
+    Ok(files)
+}
+
+/// 返回keydir文件的修改时间和长度，文件不存在时返回`None`。
+fn keydir_stamp(data_dir: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(data_dir.join(KEYDIR_FILE)).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
@@ -0,0 +1,38 @@
+use crate::error::BitCaskError;
+use fs2::FileExt;
+use std::path::{Path, PathBuf};
+
+/// 数据目录中保留文件的文件名。
+pub(crate) const RESERVE_FILE: &str = "reserve";
+
+/// `SpaceReserve` 是数据目录中预先分配了磁盘块的保留文件，见`BitCaskOptions::reserved_space`。
+///
+/// 磁盘写满时删除保留文件，腾出的空间足以写入墓碑并完成一次回收空间的压缩。
+pub(crate) struct SpaceReserve {
+    path: PathBuf,
+}
+
+impl SpaceReserve {
+    /// 在数据目录中创建大小为`size`的保留文件，已经存在的保留文件会被补齐到`size`。
+    ///
+    /// 磁盘块在创建时就被分配，而不是稀疏文件，之后删除它才能真正释放空间。
+    pub(crate) fn create(data_dir: &Path, size: u64) -> Result<Self, BitCaskError> {
+        let path = data_dir.join(RESERVE_FILE);
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        file.allocate(size)?;
+        file.sync_all()?;
+        Ok(Self { path })
+    }
+
+    /// 删除保留文件，把空间还给文件系统。
+    pub(crate) fn release(self) -> Result<(), BitCaskError> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,388 @@
+use crate::bitcask::{BitCask, KVStorage, Key};
+use crate::error::BitCaskError;
+use crate::tree::Tree;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use tracing::warn;
+
+/// 单个批量字符串（键、字段或值）允许的最大字节数，与Redis的`proto-max-bulk-len`默认值相同（512 MB）。
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// 一条命令允许的最大参数数量，与Redis相同。
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// 一行（内联命令或者类型和长度的前缀）允许的最大字节数，与Redis相同（64 KB）。
+const MAX_LINE_LEN: u64 = 64 * 1024;
+
+/// SCAN/HSCAN没有指定`COUNT`时每页的键数量，与Redis相同。
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// 支持的命令，参数数量不对时返回`wrong number of arguments`而不是`unknown command`。
+const COMMANDS: [&[u8]; 10] = [
+    b"PING", b"QUIT", b"GET", b"SET", b"DEL", b"SCAN", b"HSET", b"HGET", b"HDEL", b"HSCAN",
+];
+
+/// 在`listener`上提供RESP（Redis序列化协议）服务，每个连接使用一个线程，直到接受连接失败才返回。
+///
+/// 支持`PING`、`QUIT`、`GET`、`SET`、`DEL`、`SCAN`，以及哈希命令`HSET`、`HGET`、`HDEL`和`HSCAN`。
+/// 字符串直接保存为存储中的键；哈希保存在与键同名的树中（见`BitCask::open_tree`），
+/// 它们的字段在存储中带有树的前缀，也会出现在`SCAN`的结果中，因此同一个存储最好只使用其中一种类型。
+/// 不支持认证和TLS，只应该在可信的网络中使用。开启审计日志时，每个连接以对端地址作为客户端ID。
+pub fn serve(listener: TcpListener, bitcask: BitCask) -> std::io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let bitcask = match stream.peer_addr() {
+            Ok(peer) => bitcask.with_client_id(&peer.to_string()),
+            Err(_) => bitcask.clone(),
+        };
+        std::thread::spawn(move || {
+            let res = stream
+                .try_clone()
+                .and_then(|writer| handle_connection(BufReader::new(stream), writer, bitcask));
+            if let Err(e) = res {
+                warn!("resp connection closed with error: {:?}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// 处理一个连接上的所有命令，直到对方关闭连接或发送`QUIT`。
+///
+/// 命令可以是RESP数组，也可以是以空格分隔的内联命令。与Redis相同，协议错误时回复错误并关闭连接。
+///
+/// `SCAN`和`HSCAN`的游标是无状态的：游标编码了上一页的最后一个键，见`BitCask::scan_keys`。
+/// 因此服务端不为遍历保存任何状态，游标可以在任意连接上继续使用；并发写入时不会重复返回同一个键，
+/// 遍历期间一直存在的键一定会被返回。游标仍然是Redis客户端可以解析的十进制数字，`0`表示开始和结束。
+pub fn handle_connection<R: BufRead, W: Write>(mut reader: R, mut writer: W, mut bitcask: BitCask) -> std::io::Result<()> {
+    let mut response = Vec::new();
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                writer.write_all(format!("-ERR Protocol error: {}\r\n", e).as_bytes())?;
+                return writer.flush();
+            }
+            Err(e) => return Err(e),
+        };
+        let Some((command, args)) = args.split_first() else {
+            continue;
+        };
+        let command = command.to_ascii_uppercase();
+        response.clear();
+        execute(&mut bitcask, &command, args).write_to(&mut response);
+        writer.write_all(&response)?;
+        writer.flush()?;
+        if command == b"QUIT" {
+            return Ok(());
+        }
+    }
+}
+
+/// 读取一条命令，连接在两条命令之间关闭时返回`None`；协议错误返回`InvalidData`错误。
+fn read_command<R: BufRead>(reader: &mut R) -> std::io::Result<Option<Vec<Vec<u8>>>> {
+    let Some(line) = read_line(reader)? else {
+        return Ok(None);
+    };
+    let Some(count) = line.strip_prefix(b"*") else {
+        // 内联命令，例如通过telnet发送的`PING`
+        let args = line
+            .split(|byte| *byte == b' ' || *byte == b'\t')
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    };
+    let count = parse_len(count, MAX_ARGS, "invalid multibulk length")?;
+    let mut args = Vec::with_capacity(count.min(64));
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or(std::io::ErrorKind::UnexpectedEof)?;
+        let Some(len) = header.strip_prefix(b"$") else {
+            return Err(protocol_error(format!("expected '$', got '{}'", String::from_utf8_lossy(&header))));
+        };
+        let len = parse_len(len, MAX_BULK_LEN, "invalid bulk length")?;
+        // 随着数据到达逐步分配，声称很长却不发送数据的客户端不会让服务端预先分配内存
+        let mut arg = Vec::new();
+        reader.take(len as u64 + 2).read_to_end(&mut arg)?;
+        if arg.len() < len + 2 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string is not terminated by CRLF".to_string()));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// 读取一行并去掉结尾的换行，连接已经关闭时返回`None`。
+fn read_line<R: BufRead>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    let Some(line) = line.strip_suffix(b"\n") else {
+        return match line.len() as u64 == MAX_LINE_LEN {
+            true => Err(protocol_error("too big inline request".to_string())),
+            false => Err(std::io::ErrorKind::UnexpectedEof.into()),
+        };
+    };
+    Ok(Some(line.strip_suffix(b"\r").unwrap_or(line).to_vec()))
+}
+
+/// 解析数组或批量字符串的长度，超过`max`时视为协议错误。
+fn parse_len(len: &[u8], max: usize, message: &str) -> std::io::Result<usize> {
+    parse_number(len)
+        .filter(|len| *len <= max)
+        .ok_or_else(|| protocol_error(message.to_string()))
+}
+
+fn protocol_error(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// 解析十进制数字参数。
+fn parse_number<T: std::str::FromStr>(token: &[u8]) -> Option<T> {
+    std::str::from_utf8(token).ok()?.parse().ok()
+}
+
+/// 命令的回复。
+#[derive(Debug)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(usize),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Reply::Simple("OK")
+    }
+
+    fn syntax_error() -> Self {
+        Reply::Error("ERR syntax error".to_string())
+    }
+
+    /// 按RESP编码回复。
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(message) => buf.extend_from_slice(format!("+{}\r\n", message).as_bytes()),
+            Reply::Error(message) => buf.extend_from_slice(format!("-{}\r\n", message).as_bytes()),
+            Reply::Integer(n) => buf.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(None) => buf.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                buf.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                buf.extend_from_slice(bytes);
+                buf.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(replies) => {
+                buf.extend_from_slice(format!("*{}\r\n", replies.len()).as_bytes());
+                replies.iter().for_each(|reply| reply.write_to(buf));
+            }
+        }
+    }
+}
+
+impl From<BitCaskError> for Reply {
+    fn from(e: BitCaskError) -> Self {
+        Reply::Error(format!("ERR {}", e))
+    }
+}
+
+/// 执行一条命令，`command`已经转换为大写。
+fn execute(bitcask: &mut BitCask, command: &[u8], args: &[Vec<u8>]) -> Reply {
+    let res = match (command, args) {
+        (b"PING", []) => Ok(Reply::Simple("PONG")),
+        (b"PING", [message]) => Ok(Reply::Bulk(Some(message.clone()))),
+        (b"QUIT", _) => Ok(Reply::ok()),
+        (b"GET", [key]) => bitcask.try_get(key).map(Reply::Bulk),
+        (b"SET", [key, value]) => bitcask.put(key, value).map(|()| Reply::ok()),
+        (b"DEL", keys) if !keys.is_empty() => delete(bitcask, keys),
+        (b"SCAN", [cursor, options @ ..]) => Ok(scan_store(bitcask, cursor, options)),
+        (b"HSET", [key, pairs @ ..]) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+            bitcask.open_tree(key).and_then(|mut tree| hset(&mut tree, pairs))
+        }
+        (b"HGET", [key, field]) => bitcask.open_tree(key).and_then(|tree| tree.try_get(field)).map(Reply::Bulk),
+        (b"HDEL", [key, fields @ ..]) if !fields.is_empty() => bitcask.open_tree(key).and_then(|mut tree| delete(&mut tree, fields)),
+        (b"HSCAN", [key, cursor, options @ ..]) => bitcask.open_tree(key).and_then(|tree| hscan(&tree, cursor, options)),
+        (command, _) if COMMANDS.contains(&command) => Ok(Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            String::from_utf8_lossy(command).to_lowercase()
+        ))),
+        (command, _) => Ok(Reply::Error(format!(
+            "ERR unknown command '{}'",
+            String::from_utf8_lossy(command)
+        ))),
+    };
+    res.unwrap_or_else(Reply::from)
+}
+
+/// 删除存在的键，返回删除的数量。`DEL`和`HDEL`共用。
+fn delete<S: KVStorage>(storage: &mut S, keys: &[Key]) -> Result<Reply, BitCaskError> {
+    let mut deleted = 0;
+    for key in keys {
+        if storage.contains_key(key) {
+            storage.delete(key)?;
+            deleted += 1;
+        }
+    }
+    Ok(Reply::Integer(deleted))
+}
+
+/// 写入哈希的字段，返回新增的字段数量。
+fn hset(tree: &mut Tree, pairs: &[Vec<u8>]) -> Result<Reply, BitCaskError> {
+    let mut added = 0;
+    for pair in pairs.chunks(2) {
+        if !tree.contains_key(&pair[0]) {
+            added += 1;
+        }
+        tree.put(&pair[0], &pair[1])?;
+    }
+    Ok(Reply::Integer(added))
+}
+
+/// 遍历存储的一页键。
+fn scan_store(bitcask: &BitCask, cursor: &[u8], options: &[Vec<u8>]) -> Reply {
+    let page = |cursor: Option<&[u8]>, count| bitcask.scan_keys(cursor.map(<[u8]>::to_vec).as_ref(), count);
+    match scan(cursor, options, page) {
+        Ok((cursor, keys)) => scan_reply(cursor, keys.into_iter().map(|key| Reply::Bulk(Some(key)))),
+        Err(reply) => reply,
+    }
+}
+
+/// 遍历哈希的一页字段，回复中字段和值交替出现；页中的字段在读取值之前被删除时跳过它。
+fn hscan(tree: &Tree, cursor: &[u8], options: &[Vec<u8>]) -> Result<Reply, BitCaskError> {
+    let (cursor, fields) = match scan(cursor, options, |cursor, count| tree.scan_keys(cursor, count)) {
+        Ok(page) => page,
+        Err(reply) => return Ok(reply),
+    };
+    let mut pairs = Vec::with_capacity(fields.len() * 2);
+    for field in fields {
+        if let Some(value) = tree.try_get(&field)? {
+            pairs.push(Reply::Bulk(Some(field)));
+            pairs.push(Reply::Bulk(Some(value)));
+        }
+    }
+    Ok(scan_reply(cursor, pairs.into_iter()))
+}
+
+/// `SCAN`和`HSCAN`的回复：下一次调用使用的游标，以及本页的元素。
+fn scan_reply(cursor: Vec<u8>, elements: impl Iterator<Item = Reply>) -> Reply {
+    Reply::Array(vec![Reply::Bulk(Some(cursor)), Reply::Array(elements.collect())])
+}
+
+/// 解析游标和`MATCH`/`COUNT`选项并通过`page`取出一页键，返回编码后的下一个游标和本页中匹配模式的键。
+///
+/// 与Redis相同，`MATCH`在取出一页之后才过滤，因此一页中可能没有任何键，而遍历仍未结束。
+fn scan(
+    cursor: &[u8],
+    options: &[Vec<u8>],
+    page: impl FnOnce(Option<&[u8]>, usize) -> (Vec<Key>, Option<Key>),
+) -> Result<(Vec<u8>, Vec<Key>), Reply> {
+    let cursor = decode_cursor(cursor).ok_or_else(|| Reply::Error("ERR invalid cursor".to_string()))?;
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options.next().ok_or_else(Reply::syntax_error)?;
+        match option.to_ascii_uppercase().as_slice() {
+            b"MATCH" => pattern = Some(value),
+            b"COUNT" => count = parse_number(value).filter(|count| *count >= 1).ok_or_else(Reply::syntax_error)?,
+            _ => return Err(Reply::syntax_error()),
+        }
+    }
+    let (mut keys, next) = page(cursor.as_deref(), count);
+    if let Some(pattern) = pattern {
+        keys.retain(|key| glob_match(pattern, key));
+    }
+    Ok((encode_cursor(next.as_deref()), keys))
+}
+
+/// 把游标（上一页的最后一个键）编码为十进制数字：`1`之后每个字节写成3位十进制数，
+/// 遍历结束时为`0`。编码后的游标总是以`1`开头，不会与`0`混淆，键开头的0字节也不会丢失。
+fn encode_cursor(cursor: Option<&[u8]>) -> Vec<u8> {
+    let Some(key) = cursor else {
+        return b"0".to_vec();
+    };
+    let mut encoded = Vec::with_capacity(1 + key.len() * 3);
+    encoded.push(b'1');
+    for byte in key {
+        encoded.extend_from_slice(format!("{:03}", byte).as_bytes());
+    }
+    encoded
+}
+
+/// 解码`encode_cursor`编码的游标，`0`表示从头开始，游标无效时返回`None`。
+fn decode_cursor(cursor: &[u8]) -> Option<Option<Key>> {
+    if cursor == b"0" {
+        return Some(None);
+    }
+    let digits = cursor.strip_prefix(b"1")?;
+    if digits.len() % 3 != 0 || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    digits.chunks(3).map(parse_number::<u8>).collect::<Option<Key>>().map(Some)
+}
+
+/// 按Redis的glob语法匹配：`*`匹配任意字节序列，`?`匹配任意一个字节，`[abc]`、`[^a]`和`[a-z]`匹配字节类，
+/// `\`转义下一个字符。
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // 最近的`*`之后的模式位置，以及`*`当前匹配到的文本位置，匹配失败时让`*`多匹配一个字节再重试
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            star = Some((p + 1, t));
+            p += 1;
+            continue;
+        }
+        if let Some(next) = (p < pattern.len()).then(|| match_one(pattern, p, text[t])).flatten() {
+            p = next;
+            t += 1;
+            continue;
+        }
+        let Some((star_p, star_t)) = star else {
+            return false;
+        };
+        p = star_p;
+        t = star_t + 1;
+        star = Some((star_p, t));
+    }
+    pattern[p..].iter().all(|byte| *byte == b'*')
+}
+
+/// 用模式中从`p`开始的一个元素（普通字符、`?`、字节类或转义的字符）匹配`byte`，匹配时返回下一个元素的位置。
+fn match_one(pattern: &[u8], p: usize, byte: u8) -> Option<usize> {
+    match pattern[p] {
+        b'?' => Some(p + 1),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == byte).then_some(p + 2),
+        b'[' => {
+            let mut i = p + 1;
+            let negate = pattern.get(i) == Some(&b'^');
+            if negate {
+                i += 1;
+            }
+            let mut matched = false;
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    matched |= pattern[i + 1] == byte;
+                    i += 2;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+                    let (low, high) = (pattern[i].min(pattern[i + 2]), pattern[i].max(pattern[i + 2]));
+                    matched |= (low..=high).contains(&byte);
+                    i += 3;
+                } else {
+                    matched |= pattern[i] == byte;
+                    i += 1;
+                }
+            }
+            // 没有结尾的`]`时字节类延续到模式的末尾
+            (matched != negate).then_some((i + 1).min(pattern.len()))
+        }
+        literal => (literal == byte).then_some(p + 1),
+    }
+}
@@ -0,0 +1,242 @@
+//! 损坏存储的紧急导出：正常打开失败时的最后手段。
+//!
+//! 宽松地扫描数据目录中的每个数据文件，跳过无法解析的区域，把其中所有能够解码并通过校验的记录
+//! （连同过期时间和元数据）按写入顺序重放到一个新的存储中，并在新存储的目录中写入一份报告。
+
+use crate::bitcask::{BitCask, ByteOffset, KVStorage, Key, PutOption, Value};
+use crate::blob::BlobStorage;
+use crate::clock::now_millis;
+use crate::compression::{DictionaryCompressor, DictionaryDecoder};
+use crate::error::BitCaskError;
+use crate::log_entry::{Decoded, Deserialize, DiskLogEntry};
+use crate::log_file;
+use crate::options::BitCaskOptions;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// 导出完成后写入新存储目录的报告文件名，内容见`SalvageReport`的`Display`实现。
+pub const SALVAGE_REPORT: &str = "salvage-report.txt";
+
+/// `SkippedRegion` 是数据文件中一段无法解析的字节，导出时被跳过。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedRegion {
+    /// 数据文件的路径。
+    pub path: PathBuf,
+    /// 区域在文件中的起始偏移量。
+    pub offset: u64,
+    /// 区域的字节数。
+    pub len: u64,
+}
+
+/// `SalvageReport` 描述一次紧急导出找回了什么、丢失了什么，见`salvage`。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SalvageReport {
+    /// 扫描的数据文件数量。
+    pub files: usize,
+    /// 能够解码并通过校验的记录数量，包括墓碑和已被覆盖的记录。
+    pub records: usize,
+    /// 跳过的无法解析的区域，按文件和偏移量排列。
+    pub skipped_regions: Vec<SkippedRegion>,
+    /// 记录本身完好、但值无法找回的记录数量：引用的共享记录已经损坏、大值文件缺失或者无法解压。
+    /// 这些键被当作已经删除，避免它们之前的旧值重新出现。
+    pub unreadable_values: usize,
+    /// 写入新存储的键的数量。
+    pub keys: usize,
+}
+
+impl SalvageReport {
+    /// 跳过的字节总数。
+    pub fn skipped_bytes(&self) -> u64 {
+        self.skipped_regions.iter().map(|region| region.len).sum()
+    }
+}
+
+impl fmt::Display for SalvageReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "files scanned: {}", self.files)?;
+        writeln!(f, "records recovered: {}", self.records)?;
+        writeln!(f, "unreadable values: {}", self.unreadable_values)?;
+        writeln!(f, "keys written: {}", self.keys)?;
+        writeln!(
+            f,
+            "skipped regions: {} ({} bytes)",
+            self.skipped_regions.len(),
+            self.skipped_bytes()
+        )?;
+        for region in &self.skipped_regions {
+            writeln!(f, "  {:?} offset {} length {}", region.path, region.offset, region.len)?;
+        }
+        Ok(())
+    }
+}
+
+/// 把`data_dir`中能够找回的数据导出到`out_dir`中的新存储，返回导出的报告，报告同时写入`out_dir`中的`SALVAGE_REPORT`。
+///
+/// 数据文件按文件ID的顺序逐个读入内存扫描：从文件开头起逐条解析记录，解析失败或者记录不完整时
+/// 逐字节向后寻找下一条能够解析的记录，中间的字节作为跳过的区域记入报告。每条找回的记录按扫描的顺序
+/// 写入新存储，因此每个键保留最后一条找回的记录；墓碑删除键，已经过期的记录被丢弃。
+/// 不会修改`data_dir`，也不获取它的目录锁，调用时存储不应该被其他进程打开。
+///
+/// # 参数
+/// - `data_dir`: 损坏的存储的数据目录，按`options.file_naming`识别数据文件。
+/// - `out_dir`: 新存储的目录，如果不存在会被创建，但不能包含其他文件。
+/// - `options`: 用于识别和解压源数据文件，以及打开新存储的配置。
+pub fn salvage<P: AsRef<Path>, Q: AsRef<Path>>(
+    data_dir: P,
+    out_dir: Q,
+    options: BitCaskOptions,
+) -> Result<SalvageReport, BitCaskError> {
+    let (data_dir, out_dir) = (data_dir.as_ref(), out_dir.as_ref());
+    let naming = options.file_naming.clone();
+    let mut files = std::fs::read_dir(data_dir)?
+        .filter_map(|path| path.ok().map(|path| path.path()))
+        .filter_map(|path| naming.file_id(&path).map(|file_id| (file_id, path)))
+        .collect::<Vec<_>>();
+    files.sort();
+    std::fs::create_dir_all(out_dir)?;
+    if std::fs::read_dir(out_dir)?.next().is_some() {
+        return Err(BitCaskError::UnexpectedError(anyhow::anyhow!(
+            "salvage directory {:?} is not empty",
+            out_dir
+        )));
+    }
+    // 字典或大值目录本身损坏时仍然导出其余的记录，需要它们的值计入无法找回的值
+    let decoder = DictionaryCompressor::open(data_dir, options.dictionary_compression.clone())
+        .map(|compressor| compressor.decoder())
+        .ok();
+    let blobs = BlobStorage::open(data_dir).ok();
+
+    let mut salvager = Salvager {
+        bitcask: BitCask::new_with_options(out_dir, options)?,
+        decoder,
+        blobs,
+        live: HashSet::new(),
+        report: SalvageReport::default(),
+    };
+    for (_, path) in files {
+        salvager.salvage_file(path)?;
+    }
+    let Salvager { bitcask, live, mut report, .. } = salvager;
+    report.keys = live.len();
+    bitcask.sync()?;
+    std::fs::write(out_dir.join(SALVAGE_REPORT), report.to_string())?;
+    Ok(report)
+}
+
+/// 导出过程中的状态。
+struct Salvager {
+    bitcask: BitCask,
+    decoder: Option<DictionaryDecoder>,
+    blobs: Option<BlobStorage>,
+    /// 新存储中存在的键，只为它们写入墓碑。
+    live: HashSet<Key>,
+    report: SalvageReport,
+}
+
+impl Salvager {
+    /// 扫描一个数据文件，把找回的记录写入新存储。
+    fn salvage_file(&mut self, path: PathBuf) -> Result<(), BitCaskError> {
+        let buf = std::fs::read(&path)?;
+        // 文件中通过校验的值的偏移量，引用条目只有指向其中之一时才可信
+        let mut verified: HashSet<ByteOffset> = HashSet::new();
+        let mut skipped: Option<usize> = None;
+        let mut pos = log_file::data_start_of(&buf) as usize;
+        self.report.files += 1;
+        while pos < buf.len() {
+            let entry = match DiskLogEntry::deserialize(&buf[pos..]) {
+                Ok(Decoded::Complete(entry, len)) if plausible(&entry) => Some((entry, len)),
+                _ => None,
+            };
+            let Some((entry, len)) = entry else {
+                skipped.get_or_insert(pos);
+                pos += 1;
+                continue;
+            };
+            if let Some(start) = skipped.take() {
+                self.skip(&path, start, pos);
+            }
+            let value_offset = pos as ByteOffset + entry.value_byte_offset();
+            pos += len;
+            self.report.records += 1;
+            if entry.value.is_some() && !entry.reference && !entry.blob {
+                verified.insert(value_offset);
+            }
+            let value = self.value(&entry, &buf, &verified);
+            self.apply(entry, value)?;
+        }
+        if let Some(start) = skipped {
+            self.skip(&path, start, buf.len());
+        }
+        Ok(())
+    }
+
+    /// 记录一段跳过的区域。
+    fn skip(&mut self, path: &Path, start: usize, end: usize) {
+        self.report.skipped_regions.push(SkippedRegion {
+            path: path.to_path_buf(),
+            offset: start as u64,
+            len: (end - start) as u64,
+        });
+    }
+
+    /// 还原记录的原始值。墓碑返回`Ok(None)`，值无法找回时返回`Err`。
+    fn value(&self, entry: &DiskLogEntry, buf: &[u8], verified: &HashSet<ByteOffset>) -> Result<Option<Value>, ()> {
+        if entry.is_tombstone() {
+            return Ok(None);
+        }
+        let (stored, compressed) = match (entry.blob_target(), entry.reference_target()) {
+            (Some((blob_id, value_size)), _) => {
+                let blobs = self.blobs.as_ref().ok_or(())?;
+                return blobs.read(blob_id, value_size).map(Some).map_err(|_| ());
+            }
+            (_, Some((value_offset, value_size, compressed))) if verified.contains(&value_offset) => {
+                let start = value_offset as usize;
+                let stored = buf.get(start..start.saturating_add(value_size as usize)).ok_or(())?;
+                (stored.to_vec(), compressed)
+            }
+            (_, Some(_)) => return Err(()),
+            (None, None) => (entry.value.clone().unwrap_or_default(), entry.compressed),
+        };
+        match compressed {
+            true => self.decoder.as_ref().ok_or(())?.decode(&stored).map(Some).map_err(|_| ()),
+            false => Ok(Some(stored)),
+        }
+    }
+
+    /// 把一条找回的记录写入新存储。
+    fn apply(&mut self, entry: DiskLogEntry, value: Result<Option<Value>, ()>) -> Result<(), BitCaskError> {
+        let now = now_millis();
+        let value = match value {
+            Ok(Some(value)) if entry.expire_at.is_none_or(|expire_at| expire_at > now) => value,
+            Ok(_) => return self.remove(&entry.key),
+            Err(()) => {
+                self.report.unreadable_values += 1;
+                return self.remove(&entry.key);
+            }
+        };
+        let option = PutOption {
+            ttl: entry.expire_at.map(|expire_at| Duration::from_millis(expire_at - now)),
+            metadata: entry.metadata,
+            ..PutOption::default()
+        };
+        self.bitcask.put_with_option(&entry.key, &value, Some(option))?;
+        self.live.insert(entry.key);
+        Ok(())
+    }
+
+    /// 从新存储中删除一个键，键不存在时不写入墓碑。
+    fn remove(&mut self, key: &Key) -> Result<(), BitCaskError> {
+        match self.live.remove(key) {
+            true => self.bitcask.delete(key),
+            false => Ok(()),
+        }
+    }
+}
+
+/// 在损坏区域中逐字节寻找记录时，没有值的墓碑不受值的校验和保护，很容易从任意字节中误解析出来。
+/// 真实的墓碑的校验和总是0，据此排除大部分误解析的墓碑。
+fn plausible(entry: &DiskLogEntry) -> bool {
+    entry.value.is_some() || entry.check_sum == 0
+}
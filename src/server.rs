@@ -0,0 +1,122 @@
+use crate::bitcask::BitCask;
+use crate::error::BitCaskError;
+use crate::memcached::{spawn_connection, ConnectionSettings, ServerOptions};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// 接受连接的线程检查是否需要停止的间隔。
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// 一个正在处理的连接：用于关闭连接的套接字副本，以及处理它的线程。
+type Connection = (TcpStream, JoinHandle<()>);
+
+/// `BitCaskServer` 在后台线程中提供memcached协议服务，并支持有序的关闭，见`shutdown`。
+///
+/// 与`memcached::serve_with_options`不同，`start`会立即返回，服务在后台运行直到调用`shutdown`。
+pub struct BitCaskServer {
+    bitcask: BitCask,
+    local_addr: SocketAddr,
+    stopping: Arc<AtomicBool>,
+    accept_thread: JoinHandle<()>,
+    connections: Arc<Mutex<Vec<Connection>>>,
+}
+
+impl BitCaskServer {
+    /// 在`listener`上启动服务，证书无法读取时返回错误。
+    pub fn start(listener: TcpListener, bitcask: BitCask, options: ServerOptions) -> std::io::Result<Self> {
+        let settings = ConnectionSettings::new(options)?;
+        let local_addr = listener.local_addr()?;
+        // 以非阻塞方式接受连接，接受线程才能及时发现服务正在关闭
+        listener.set_nonblocking(true)?;
+        let stopping = Arc::new(AtomicBool::new(false));
+        let connections = Arc::new(Mutex::new(Vec::new()));
+        let accept_thread = {
+            let (bitcask, stopping, connections) = (bitcask.clone(), stopping.clone(), connections.clone());
+            std::thread::spawn(move || accept_loop(listener, bitcask, settings, stopping, connections))
+        };
+        Ok(Self {
+            bitcask,
+            local_addr,
+            stopping,
+            accept_thread,
+            connections,
+        })
+    }
+
+    /// 返回服务监听的地址，用于绑定端口0时获取实际的端口。
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// 有序地关闭服务：
+    /// 1. 停止接受新的连接；
+    /// 2. 关闭所有连接的读取端，正在执行的请求会完成并写回响应，空闲的连接随即结束；
+    /// 3. 最多等待`timeout`让连接结束，超时后强制关闭剩余的连接；
+    /// 4. 通过`BitCask::close`正常关闭存储：fsync所有写入，保存keydir并写入正常关闭的标记，下次打开存储时不必扫描数据文件。
+    ///
+    /// 其他线程中正在进行的压缩不会被打断，它会在完成后正常切换数据文件。
+    /// 返回后服务持有的存储句柄被丢弃，其他句柄仍然可以使用存储。
+    pub fn shutdown(self, timeout: Duration) -> Result<(), BitCaskError> {
+        self.stopping.store(true, Ordering::SeqCst);
+        if self.accept_thread.join().is_err() {
+            warn!("memcached accept thread panicked");
+        }
+        let connections = std::mem::take(&mut *self.connections.lock().unwrap_or_else(PoisonError::into_inner));
+        for (stream, _) in &connections {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        let deadline = Instant::now() + timeout;
+        for (stream, handle) in connections {
+            while !handle.is_finished() && Instant::now() < deadline {
+                std::thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+            if !handle.is_finished() {
+                warn!("closing memcached connection that did not finish within {:?}", timeout);
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            // 强制关闭后线程会因读写失败而很快结束
+            let _ = handle.join();
+        }
+        self.bitcask.close()
+    }
+}
+
+/// 接受连接，直到服务开始关闭。
+fn accept_loop(
+    listener: TcpListener,
+    bitcask: BitCask,
+    settings: ConnectionSettings,
+    stopping: Arc<AtomicBool>,
+    connections: Arc<Mutex<Vec<Connection>>>,
+) {
+    while !stopping.load(Ordering::SeqCst) {
+        let (stream, peer) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(ACCEPT_POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                warn!("failed to accept memcached connection: {:?}", e);
+                continue;
+            }
+        };
+        let socket = stream.set_nonblocking(false).and_then(|_| stream.try_clone());
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("failed to set up memcached connection: {:?}", e);
+                continue;
+            }
+        };
+        let thread = spawn_connection(stream, bitcask.with_client_id(&peer.to_string()), settings.clone());
+        let mut connections = connections.lock().unwrap_or_else(PoisonError::into_inner);
+        // 顺便清理已经结束的连接
+        connections.retain(|(_, thread)| !thread.is_finished());
+        connections.push((socket, thread));
+    }
+}
@@ -0,0 +1,54 @@
+use crate::bitcask::{BitCask, KVStorage, Key, PutOption, Value};
+use crate::error::BitCaskError;
+use std::future::{ready, Ready};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower_service::Service;
+
+/// `Request` 是通过`tower_service::Service`发送给存储的命令。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// 读取键的值。
+    Get { key: Key },
+    /// 写入键值对，`ttl`为`Some`时键在给定时间后过期。
+    Put { key: Key, value: Value, ttl: Option<Duration> },
+    /// 删除键。
+    Delete { key: Key },
+}
+
+/// `Response` 是命令的结果。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// `Get`的结果，键不存在时为`None`。
+    Value(Option<Value>),
+    /// `Put`或`Delete`已经完成。
+    Done,
+}
+
+/// 把存储作为`tower_service::Service`使用，可以与超时、限流、指标等中间件组合，
+/// 或挂载到基于tower的服务框架中。
+///
+/// 存储始终处于就绪状态。命令在`call`中同步执行，返回的future已经完成：
+/// 读取不会阻塞，写入需要获取写锁并写入磁盘，在异步运行时中处理大量写入时
+/// 应该把服务放到阻塞线程池中执行。克隆的服务共享同一个存储。
+impl Service<Request> for BitCask {
+    type Response = Response;
+    type Error = BitCaskError;
+    type Future = Ready<Result<Response, BitCaskError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), BitCaskError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let res = match request {
+            Request::Get { key } => self.get_with_meta(&key).map(|meta| Response::Value(meta.map(|meta| meta.value))),
+            Request::Put { key, value, ttl } => {
+                let option = ttl.and_then(PutOption::ttl);
+                self.put_with_option(&key, &value, option).map(|()| Response::Done)
+            }
+            Request::Delete { key } => self.delete(&key).map(|()| Response::Done),
+        };
+        ready(res)
+    }
+}
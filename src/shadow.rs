@@ -0,0 +1,160 @@
+use crate::bitcask::{KVStorage, Key, PutOption, Value};
+use crate::error::BitCaskError;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::warn;
+
+/// `ShadowStore` 把对主存储的每次写入镜像到一个影子存储，用于在生产环境中验证格式迁移。
+///
+/// 所有读写的结果都只来自主存储：只有在主存储上成功的写入才会被镜像，
+/// 影子存储写入失败只会打印警告并计数，不会影响调用方。
+/// 写入条件（`nx`、`xx`和期望的版本号）已经在主存储上检查过，镜像时只保留值和存活时间，
+/// 因此两个存储的版本号不同也不会导致镜像失败。
+///
+/// 开启读比较时，每次读取还会读取影子存储并比较两边的结果，不一致时打印警告并计数。
+#[derive(Clone)]
+pub struct ShadowStore<P: KVStorage, S: KVStorage> {
+    primary: P,
+    shadow: S,
+    compare_reads: bool,
+    counters: Arc<ShadowCounters>,
+}
+
+/// 同一个`ShadowStore`的所有克隆共享的计数器。
+#[derive(Debug, Default)]
+struct ShadowCounters {
+    mirrored_writes: AtomicU64,
+    shadow_errors: AtomicU64,
+    compared_reads: AtomicU64,
+    mismatches: AtomicU64,
+}
+
+/// `ShadowReport` 是`ShadowStore::report`返回的镜像统计。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShadowReport {
+    /// 成功镜像到影子存储的写入数量。
+    pub mirrored_writes: u64,
+    /// 影子存储写入失败的数量。
+    pub shadow_errors: u64,
+    /// 比较过两边结果的读取数量。
+    pub compared_reads: u64,
+    /// 两边结果不一致的读取数量。
+    pub mismatches: u64,
+}
+
+impl ShadowReport {
+    /// 影子存储是否与主存储完全一致：没有写入失败，也没有读取结果不一致。
+    pub fn is_consistent(&self) -> bool {
+        self.shadow_errors == 0 && self.mismatches == 0
+    }
+}
+
+impl<P: KVStorage, S: KVStorage> ShadowStore<P, S> {
+    /// 创建一个把写入镜像到`shadow`的包装，`compare_reads`为`true`时同时比较读取结果。
+    pub fn new(primary: P, shadow: S, compare_reads: bool) -> Self {
+        Self {
+            primary,
+            shadow,
+            compare_reads,
+            counters: Arc::new(ShadowCounters::default()),
+        }
+    }
+
+    /// 返回主存储。
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    /// 返回影子存储。
+    pub fn shadow(&self) -> &S {
+        &self.shadow
+    }
+
+    /// 返回到目前为止的镜像统计。
+    pub fn report(&self) -> ShadowReport {
+        ShadowReport {
+            mirrored_writes: self.counters.mirrored_writes.load(Ordering::Relaxed),
+            shadow_errors: self.counters.shadow_errors.load(Ordering::Relaxed),
+            compared_reads: self.counters.compared_reads.load(Ordering::Relaxed),
+            mismatches: self.counters.mismatches.load(Ordering::Relaxed),
+        }
+    }
+
+    /// 记录一次镜像写入的结果。
+    fn record_mirror(&self, operation: &str, key: &Key, res: Result<(), BitCaskError>) {
+        match res {
+            Ok(()) => {
+                self.counters.mirrored_writes.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(e) => {
+                self.counters.shadow_errors.fetch_add(1, Ordering::Relaxed);
+                warn!("Shadow {} failed for key {:?}: {:?}", operation, key, e);
+            }
+        }
+    }
+}
+
+impl<P: KVStorage, S: KVStorage> KVStorage for ShadowStore<P, S> {
+    /// 从主存储读取值，开启读比较时同时读取影子存储并比较结果。
+    fn get(&self, key: &Key) -> Option<Value> {
+        let value = self.primary.get(key);
+        if self.compare_reads {
+            self.counters.compared_reads.fetch_add(1, Ordering::Relaxed);
+            if self.shadow.get(key) != value {
+                self.counters.mismatches.fetch_add(1, Ordering::Relaxed);
+                warn!("Shadow read mismatch for key {:?}", key);
+            }
+        }
+        value
+    }
+
+    /// 写入主存储，成功后以相同的值和存活时间写入影子存储。
+    fn put_with_option(&mut self, key: &Key, value: &Value, option: Option<PutOption>) -> Result<(), BitCaskError> {
+        let ttl = option.as_ref().and_then(|option| option.ttl);
+        self.primary.put_with_option(key, value, option)?;
+        let mirrored = match ttl {
+            Some(ttl) => self.shadow.put_with_option(key, value, PutOption::ttl(ttl)),
+            None => self.shadow.put(key, value),
+        };
+        self.record_mirror("put", key, mirrored);
+        Ok(())
+    }
+
+    /// 从主存储删除键，成功后从影子存储删除同一个键。
+    fn delete(&mut self, key: &Key) -> Result<(), BitCaskError> {
+        self.primary.delete(key)?;
+        let mirrored = self.shadow.delete(key);
+        self.record_mirror("delete", key, mirrored);
+        Ok(())
+    }
+
+    /// 返回主存储中的条目数量。
+    fn size(&self) -> usize {
+        self.primary.size()
+    }
+
+    /// 遍历主存储中的键值对。
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<(Key, Value), BitCaskError>> + '_> {
+        self.primary.iter()
+    }
+
+    /// 判断键是否存在于主存储中。
+    fn contains_key(&self, key: &Key) -> bool {
+        self.primary.contains_key(key)
+    }
+
+    /// 返回主存储中存在的键的数量。
+    fn len(&self) -> usize {
+        self.primary.len()
+    }
+
+    /// 依次持久化主存储和影子存储，影子存储失败只会打印警告并计数。
+    fn flush(&self) -> Result<(), BitCaskError> {
+        self.primary.flush()?;
+        if let Err(e) = self.shadow.flush() {
+            self.counters.shadow_errors.fetch_add(1, Ordering::Relaxed);
+            warn!("Shadow flush failed: {:?}", e);
+        }
+        Ok(())
+    }
+}
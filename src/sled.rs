@@ -0,0 +1,114 @@
+//! 与sled相似的接口，方便从sled迁移的项目以最少的改动换用本引擎。
+//!
+//! `open`打开数据目录并返回`Db`，`Db`可以直接作为默认的`Tree`使用，也可以通过`Db::open_tree`打开命名的树。
+//! `Tree`提供与sled签名相同的`insert`、`get`、`remove`、`contains_key`、`scan_prefix`、`iter`和`flush`，
+//! 值的类型`IVec`就是`Vec<u8>`。
+//!
+//! 每个树就是`BitCask::open_tree`打开的树，键前缀的规则见`crate::tree`，默认树的名字为空。
+//! 与sled不同，`insert`和`remove`返回的旧值与写入不是原子的，其他写入者可能在读取旧值和写入之间修改同一个键。
+
+use crate::bitcask::{BitCask, KVStorage};
+use crate::error::BitCaskError;
+use std::ops::Deref;
+use std::path::Path;
+
+pub use crate::tree::Iter;
+
+/// 键和值的类型，对应sled的`IVec`。
+pub type IVec = Vec<u8>;
+
+/// 对应sled的`Result`，错误类型是`BitCaskError`。
+pub type Result<T> = std::result::Result<T, BitCaskError>;
+
+/// 打开（必要时创建）数据目录中的存储，对应`sled::open`。
+pub fn open<P: AsRef<Path>>(path: P) -> Result<Db> {
+    Ok(Db::from(BitCask::new(path.as_ref())?))
+}
+
+/// `Db` 是通过`open`打开的存储，解引用为默认的树。
+#[derive(Clone)]
+pub struct Db {
+    bitcask: BitCask,
+    default: Tree,
+}
+
+impl Db {
+    /// 打开给定名字的树，树不需要预先创建，名字不能超过65535字节。
+    pub fn open_tree<V: AsRef<[u8]>>(&self, name: V) -> Result<Tree> {
+        Ok(Tree {
+            tree: self.bitcask.open_tree(name)?,
+        })
+    }
+}
+
+impl From<BitCask> for Db {
+    /// 在已经打开的存储上使用sled风格的接口。
+    fn from(bitcask: BitCask) -> Self {
+        let default = Tree {
+            tree: crate::tree::Tree::new(bitcask.clone(), &[]),
+        };
+        Db { bitcask, default }
+    }
+}
+
+impl Deref for Db {
+    type Target = Tree;
+
+    fn deref(&self) -> &Tree {
+        &self.default
+    }
+}
+
+/// `Tree` 是存储中的一个键空间，对应sled的`Tree`，克隆的树共享同一个存储。
+#[derive(Clone)]
+pub struct Tree {
+    tree: crate::tree::Tree,
+}
+
+impl Tree {
+    /// 写入键值对，返回键之前的值。
+    pub fn insert<K: AsRef<[u8]>, V: Into<IVec>>(&self, key: K, value: V) -> Result<Option<IVec>> {
+        let key = key.as_ref().to_vec();
+        let previous = self.tree.get(&key);
+        self.tree.clone().put(&key, &value.into())?;
+        Ok(previous)
+    }
+
+    /// 读取键的值。
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
+        let meta = self.tree.get_with_meta(key.as_ref())?;
+        Ok(meta.map(|meta| meta.value))
+    }
+
+    /// 删除键，返回键之前的值，键不存在时不写入任何数据。
+    pub fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<IVec>> {
+        let key = key.as_ref().to_vec();
+        let previous = self.tree.get(&key);
+        if previous.is_some() {
+            self.tree.clone().delete(&key)?;
+        }
+        Ok(previous)
+    }
+
+    /// 键是否存在。
+    pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+        self.get(key).map(|value| value.is_some())
+    }
+
+    /// 按键的顺序遍历以给定前缀开头的键值对，快照语义与`BitCask::iter`相同。
+    pub fn scan_prefix<P: AsRef<[u8]>>(&self, prefix: P) -> Iter {
+        self.tree.iter_prefix(prefix.as_ref())
+    }
+
+    /// 按键的顺序遍历树中的所有键值对。
+    pub fn iter(&self) -> Iter {
+        self.tree.iter()
+    }
+
+    /// 把目前为止的所有写入通过fsync持久化到磁盘，对应sled的`flush`。
+    /// 存储不统计尚未持久化的字节数，因此总是返回0。
+    pub fn flush(&self) -> Result<usize> {
+        self.tree.sync()?;
+        Ok(0)
+    }
+}
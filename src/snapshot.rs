@@ -0,0 +1,503 @@
+use crate::bitcask::{prefix_range, Key, Value, ValueMeta};
+use crate::blob::BlobStorage;
+use crate::buffer::{buffer_stats, with_scratch};
+use crate::clock::now_millis;
+use crate::compaction::CompactionEstimate;
+use crate::compression::DictionaryDecoder;
+use crate::disk_logs::DiskLogReader;
+use crate::error::BitCaskError;
+use crate::health::OpHistory;
+use crate::log_entry::DiskLogEntry;
+use crate::memory_index::{MemIndexEntry, MemIndexStorage};
+use crate::merkle::{segment_of, MerkleTree, SyncEntry};
+use crate::stats::{FileStats, RangeEstimate, Stats, StatsCounters};
+use crate::value_ref::ValueRef;
+use crate::storage::{log_slow_op, op_span};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+/// `ReadSnapshot` 是某次写入完成后存储状态的不可变快照，包含内存索引以及读取值所需的文件和字典。
+///
+/// 写入者在每次写入后发布一个新的快照，读者只需原子地加载当前快照，
+/// 因此读取永远不会因为写入或压缩而阻塞。内存索引在克隆时共享结构，发布快照的代价与键的数量无关。
+pub(crate) struct ReadSnapshot {
+    /// 快照时刻的内存索引。
+    pub(crate) mem_index: MemIndexStorage,
+    /// 快照时刻的所有数据文件。
+    pub(crate) disk_log: DiskLogReader,
+    /// 存放大值的独立文件。
+    pub(crate) blobs: BlobStorage,
+    /// 快照时刻的压缩字典。
+    pub(crate) decoder: DictionaryDecoder,
+    /// 最近操作的IO错误记录，与写入者共享。
+    pub(crate) op_history: Arc<OpHistory>,
+    /// 慢操作阈值，见`BitCaskOptions::slow_op_threshold`。
+    pub(crate) slow_op_threshold: Option<Duration>,
+    /// 是否开启严格校验，见`BitCaskOptions::paranoid_checks`。
+    pub(crate) paranoid_checks: bool,
+    /// 运行时计数器，与写入者共享。
+    pub(crate) counters: Arc<StatsCounters>,
+}
+
+impl ReadSnapshot {
+    /// 根据键获取值，读取失败时记录错误并返回`None`。
+    pub(crate) fn get(&self, key: &Key) -> Option<Value> {
+        self.try_get(key).unwrap_or_else(|e| {
+            error!("Error while getting value from disk log: {:?}", e);
+            None
+        })
+    }
+
+    /// 根据键获取值，读取磁盘日志时发生的IO错误和数据损坏通过`Err`返回。
+    pub(crate) fn try_get(&self, key: &Key) -> Result<Option<Value>, BitCaskError> {
+        let span = op_span("get");
+        let _entered = span.enter();
+        let started = Instant::now();
+        let res = self.get_inner(key);
+        self.op_history.record(&res);
+        let mem_index_entry = self.mem_index.get(key);
+        log_slow_op(
+            self.slow_op_threshold,
+            "get",
+            started.elapsed(),
+            Some(key.len()),
+            mem_index_entry.map(|entry| entry.value_size),
+            mem_index_entry.map(|entry| entry.file_id),
+        );
+        res
+    }
+
+    /// 批量读取多个键的值，返回的结果与键一一对应，见`BitCask::get_entries`。
+    ///
+    /// 需要从数据文件读取的值按文件ID和偏移量排序后一起读取；内联的值和大值文件中的值直接读取，
+    /// 开启严格校验时每个值单独读取并校验整条记录。
+    pub(crate) fn get_entries(&self, keys: &[Key]) -> Vec<Result<Option<Value>, BitCaskError>> {
+        let span = op_span("get_entries");
+        let _entered = span.enter();
+        let started = Instant::now();
+        let now = now_millis();
+        let mut results = Vec::with_capacity(keys.len());
+        let mut pending = Vec::new();
+        for (index, key) in keys.iter().enumerate() {
+            match self.mem_index.lookup(key) {
+                Ok(Some(mem_index_entry)) if mem_index_entry.is_live(now) => {
+                    if mem_index_entry.inline_value.is_some() || mem_index_entry.blob || self.paranoid_checks {
+                        results.push(self.read_value(&mem_index_entry).map(Some));
+                    } else {
+                        results.push(Ok(None));
+                        pending.push((index, mem_index_entry.into_owned()));
+                    }
+                }
+                Ok(_) => results.push(Ok(None)),
+                Err(e) => results.push(Err(e)),
+            }
+        }
+        pending.sort_by_key(|(_, mem_index_entry)| (mem_index_entry.file_id, mem_index_entry.value_offset));
+        let entries: Vec<&MemIndexEntry> = pending.iter().map(|(_, mem_index_entry)| mem_index_entry).collect();
+        for ((index, mem_index_entry), stored) in pending.iter().zip(self.disk_log.get_sorted(&entries)) {
+            results[*index] = stored
+                .and_then(|stored| match mem_index_entry.compressed {
+                    true => self.decoder.decode(&stored),
+                    false => Ok(stored),
+                })
+                .map(Some);
+        }
+        for res in &results {
+            self.op_history.record(res);
+        }
+        log_slow_op(self.slow_op_threshold, "get_entries", started.elapsed(), None, None, None);
+        results
+    }
+
+    /// `get`的实际实现，根据键从内存索引和磁盘日志中读取值。
+    ///
+    /// 与`get`不同，读取磁盘日志时发生的错误会通过`Err`返回。
+    fn get_inner(&self, key: &Key) -> Result<Option<Value>, BitCaskError> {
+        match self.mem_index.lookup(key)? {
+            // 被标记为删除（墓碑）或已经过期的键视为不存在
+            Some(mem_index_entry) if mem_index_entry.is_live(now_millis()) => {
+                self.read_value(&mem_index_entry).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// 键是否存在且未过期，只查找索引，不读取值；查找失败时记录错误并返回`false`。
+    pub(crate) fn contains_key(&self, key: &Key) -> bool {
+        match self.mem_index.lookup(key) {
+            Ok(mem_index_entry) => mem_index_entry.is_some_and(|entry| entry.is_live(now_millis())),
+            Err(e) => {
+                error!("Error while looking up key in sparse index: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// 返回未删除且未过期的键的过期时间（毫秒时间戳），键不存在或者没有设置过期时间时返回`None`；
+    /// 只查找索引，查找失败时记录错误并返回`None`。
+    pub(crate) fn expire_at(&self, key: &Key) -> Option<u64> {
+        match self.mem_index.lookup(key) {
+            Ok(mem_index_entry) => mem_index_entry
+                .filter(|entry| entry.is_live(now_millis()))
+                .and_then(|entry| entry.expire_at),
+            Err(e) => {
+                error!("Error while looking up key in sparse index: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// 根据键读取值及其版本号，已删除或已过期的键返回`None`。
+    pub(crate) fn get_with_meta(&self, key: &Key) -> Result<Option<ValueMeta>, BitCaskError> {
+        match self.mem_index.lookup(key)? {
+            Some(mem_index_entry) if mem_index_entry.is_live(now_millis()) => {
+                let value = self.read_value(&mem_index_entry)?;
+                Ok(Some(ValueMeta {
+                    value,
+                    version: mem_index_entry.version,
+                    timestamp: mem_index_entry.timestamp,
+                    expire_at: mem_index_entry.expire_at,
+                    metadata: mem_index_entry.metadata.clone(),
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// 根据内存索引项读取值，并在需要时解压。
+    pub(crate) fn read_value(&self, mem_index_entry: &MemIndexEntry) -> Result<Value, BitCaskError> {
+        if self.paranoid_checks && mem_index_entry.inline_value.is_none() {
+            let entry = self.disk_log.read_verified(mem_index_entry)?;
+            // 普通记录的值就在校验过的记录中，引用条目和大值指针条目仍然按位置读取它们指向的值
+            if !entry.blob && !entry.reference {
+                let stored = entry.value.unwrap_or_default();
+                return match entry.compressed {
+                    true => self.decoder.decode(&stored),
+                    false => Ok(stored),
+                };
+            }
+        }
+        if mem_index_entry.blob {
+            return self.blobs.read(mem_index_entry.value_offset, mem_index_entry.value_size);
+        }
+        match (&mem_index_entry.inline_value, mem_index_entry.compressed) {
+            (Some(stored), true) => self.decoder.decode(stored),
+            (Some(stored), false) => Ok(stored.clone()),
+            // 压缩的值只是解压的输入，读取到复用的缓冲区中
+            (None, true) => with_scratch(|buf| {
+                self.disk_log.get_into(mem_index_entry, buf)?;
+                self.decoder.decode(buf)
+            }),
+            (None, false) => self.disk_log.get(mem_index_entry),
+        }
+    }
+
+    /// 根据键读取值，已封存文件中的未压缩值不会被复制，见`ValueRef`。
+    pub(crate) fn get_ref(&self, key: &Key) -> Result<Option<ValueRef>, BitCaskError> {
+        match self.mem_index.lookup(key)? {
+            Some(mem_index_entry) if mem_index_entry.is_live(now_millis()) => {
+                // 严格校验需要读取整条记录，不能直接引用内存映射中的值
+                let zero_copy = !self.paranoid_checks
+                    && !mem_index_entry.blob
+                    && !mem_index_entry.compressed
+                    && mem_index_entry.inline_value.is_none();
+                match zero_copy {
+                    true => self.disk_log.get_ref(&mem_index_entry).map(Some),
+                    false => self.read_value(&mem_index_entry).map(|value| Some(ValueRef::owned(value))),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// 按键的顺序读取给定范围内的所有键值对，已删除和已过期的键会被跳过。
+    pub(crate) fn scan<R: RangeBounds<Key>>(&self, range: R) -> Result<Vec<(Key, Value)>, BitCaskError> {
+        let now = now_millis();
+        self.mem_index
+            .materialize()?
+            .range(range)
+            .filter(|(_, mem_index_entry)| mem_index_entry.is_live(now))
+            .map(|(key, mem_index_entry)| {
+                self.read_value(mem_index_entry)
+                    .map(|value| (key.to_vec(), value))
+            })
+            .collect()
+    }
+
+    /// 与`scan`相同，但只返回满足条件的键值对：`metadata_filter`只根据键和索引中的用户元数据判断，
+    /// 不满足的键不会读取值；`filter`在读取值之后判断，不满足的值在这里就被丢弃。
+    pub(crate) fn scan_filtered<R, M, F>(
+        &self,
+        range: R,
+        mut metadata_filter: M,
+        mut filter: F,
+    ) -> Result<Vec<(Key, Value)>, BitCaskError>
+    where
+        R: RangeBounds<Key>,
+        M: FnMut(&[u8], Option<&[u8]>) -> bool,
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        let now = now_millis();
+        let mut pairs = Vec::new();
+        for (key, mem_index_entry) in self.mem_index.materialize()?.range(range) {
+            if !mem_index_entry.is_live(now) || !metadata_filter(key, mem_index_entry.metadata.as_deref()) {
+                continue;
+            }
+            let value = self.read_value(mem_index_entry)?;
+            if filter(key, &value) {
+                pairs.push((key.to_vec(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// 返回以`prefix`开头、键大于`cursor`（为`None`时从头开始）的最多`count`个未删除且未过期的键，
+    /// 以及下一次调用使用的游标。
+    pub(crate) fn scan_keys(&self, prefix: &[u8], cursor: Option<&Key>, count: usize) -> (Vec<Key>, Option<Key>) {
+        let count = count.max(1);
+        let now = now_millis();
+        let (mut lower, upper) = prefix_range(prefix);
+        if let Some(cursor) = cursor.filter(|cursor| cursor.as_slice() >= prefix) {
+            lower = Bound::Excluded(cursor.clone());
+        }
+        let mut keys: Vec<Key> = self
+            .full_index()
+            .range((lower, upper))
+            .filter(|(_, mem_index_entry)| mem_index_entry.is_live(now))
+            .take(count.saturating_add(1))
+            .map(|(key, _)| key.to_vec())
+            .collect();
+        // 多取一个键来判断是否还有剩余的键
+        if keys.len() <= count {
+            return (keys, None);
+        }
+        keys.truncate(count);
+        let next_cursor = keys.last().cloned();
+        (keys, next_cursor)
+    }
+
+    /// 按键的顺序返回给定范围内所有未删除且未过期的键，不读取值。
+    pub(crate) fn keys<R: RangeBounds<Key>>(&self, range: R) -> Vec<Key> {
+        let now = now_millis();
+        self.full_index()
+            .range(range)
+            .filter(|(_, mem_index_entry)| mem_index_entry.is_live(now))
+            .map(|(key, _)| key.to_vec())
+            .collect()
+    }
+
+    /// 返回给定范围内未删除且未过期的键的数量，不读取值。
+    pub(crate) fn count<R: RangeBounds<Key>>(&self, range: R) -> usize {
+        let now = now_millis();
+        self.full_index()
+            .range(range)
+            .filter(|(_, mem_index_entry)| mem_index_entry.is_live(now))
+            .count()
+    }
+
+    /// 按值在磁盘上的位置顺序读取给定键的值并丢弃，使它们进入操作系统的页缓存，返回从磁盘读取了值的键的数量。
+    ///
+    /// 顺序读取让内核的预读生效；不存在、已删除或已过期的键以及内联在索引中的值被跳过。
+    pub(crate) fn warm_up<'a>(&self, keys: impl IntoIterator<Item = &'a [u8]>) -> Result<usize, BitCaskError> {
+        let now = now_millis();
+        let mut entries = Vec::new();
+        for key in keys {
+            if let Some(mem_index_entry) = self.mem_index.lookup(key)? {
+                if mem_index_entry.is_live(now) && mem_index_entry.inline_value.is_none() {
+                    entries.push(mem_index_entry.into_owned());
+                }
+            }
+        }
+        self.read_entries_in_order(entries)
+    }
+
+    /// 与`warm_up`相同，但读取给定范围内的所有键的值。
+    pub(crate) fn warm_up_range<R: RangeBounds<Key>>(&self, range: R) -> Result<usize, BitCaskError> {
+        let now = now_millis();
+        let entries = self
+            .full_index()
+            .range(range)
+            .filter(|(_, mem_index_entry)| mem_index_entry.is_live(now) && mem_index_entry.inline_value.is_none())
+            .map(|(_, mem_index_entry)| mem_index_entry.clone())
+            .collect();
+        self.read_entries_in_order(entries)
+    }
+
+    /// 按大值文件、数据文件和偏移量的顺序读取索引项的值，返回读取的值的数量。
+    fn read_entries_in_order(&self, mut entries: Vec<MemIndexEntry>) -> Result<usize, BitCaskError> {
+        entries.sort_by_key(|mem_index_entry| (mem_index_entry.blob, mem_index_entry.file_id, mem_index_entry.value_offset));
+        with_scratch(|buf| {
+            for mem_index_entry in &entries {
+                if mem_index_entry.blob {
+                    self.blobs.read(mem_index_entry.value_offset, mem_index_entry.value_size)?;
+                } else {
+                    self.disk_log.get_into(mem_index_entry, buf)?;
+                }
+            }
+            Ok(entries.len())
+        })
+    }
+
+    /// 估计给定范围内未删除且未过期的键的数量和大小，只访问索引。
+    pub(crate) fn estimate_range<R: RangeBounds<Key>>(&self, range: R) -> RangeEstimate {
+        let now = now_millis();
+        self.full_index()
+            .range(range)
+            .filter(|(_, mem_index_entry)| mem_index_entry.is_live(now))
+            .fold(RangeEstimate::default(), |mut estimate, (key, mem_index_entry)| {
+                estimate.keys += 1;
+                estimate.key_bytes += key.len() as u64;
+                estimate.value_bytes += mem_index_entry.value_size;
+                estimate
+            })
+    }
+
+    /// 返回快照时刻的运行时统计信息。
+    pub(crate) fn stats(&self) -> Stats {
+        let (buffer_allocations, buffer_reuses) = buffer_stats();
+        let full_index = self.full_index();
+        let (key_sizes, value_sizes) = full_index.size_histograms();
+        let files = self.disk_log.file_usage().unwrap_or_else(|e| {
+            error!("Error while reading the length of data files: {:?}", e);
+            Vec::new()
+        });
+        let files = files
+            .into_iter()
+            .map(|(file_id, bytes, entries)| {
+                let (live_entries, live_bytes) = full_index.file_live(file_id);
+                FileStats {
+                    file_id,
+                    bytes,
+                    entries,
+                    live_entries,
+                    live_bytes,
+                }
+            })
+            .collect();
+        Stats {
+            index_entries: self.mem_index.size(),
+            data_files: self.disk_log.file_count(),
+            buffer_allocations,
+            buffer_reuses,
+            expired_swept: self.counters.expired_swept.load(Ordering::Relaxed),
+            write_stalls: self.counters.write_stalls.load(Ordering::Relaxed),
+            write_stall_time: Duration::from_micros(self.counters.write_stall_micros.load(Ordering::Relaxed)),
+            key_sizes,
+            value_sizes,
+            files,
+        }
+    }
+
+    /// 返回索引中的条目数量，稀疏索引模式下包括不在内存索引中的键。
+    pub(crate) fn size(&self) -> usize {
+        self.full_index().size()
+    }
+
+    /// 按过期时间顺序返回最多`limit`个已经过期但尚未删除的键。
+    pub(crate) fn expired_keys(&self, limit: usize) -> Vec<Key> {
+        self.full_index().expired_keys(now_millis(), limit)
+    }
+
+    /// 按过期时间顺序返回最多`limit`个尚未过期、但会在`before`（Unix毫秒时间戳）之前过期的键及其过期时间。
+    pub(crate) fn expiring_keys(&self, before: u64, limit: usize) -> Vec<(Key, u64)> {
+        self.full_index().expiring_keys(now_millis(), before, limit)
+    }
+
+    /// 返回包含所有键的索引，见`MemIndexStorage::materialize`。
+    ///
+    /// 用于无法返回错误的操作：扫描稀疏加载的文件失败时记录错误，并退回到只包含内存索引中的键。
+    pub(crate) fn full_index(&self) -> Cow<'_, MemIndexStorage> {
+        self.mem_index.materialize().unwrap_or_else(|e| {
+            error!("Error while scanning sparsely indexed files: {:?}", e);
+            Cow::Borrowed(&self.mem_index)
+        })
+    }
+
+    /// 预估在快照时刻进行压缩的结果，不写入任何文件。
+    ///
+    /// 输出大小按`start_compaction`的写法计算：过期的键和墓碑被丢弃，大值只复制指针，
+    /// 多个键共享的记录只写入一次，其余的键写入引用条目。
+    pub(crate) fn estimate_compaction(&self) -> Result<CompactionEstimate, BitCaskError> {
+        let (input_files, input_bytes, input_entries) = self.disk_log.file_stats()?;
+        let mut estimate = CompactionEstimate {
+            input_files,
+            input_bytes,
+            input_entries,
+            ..Default::default()
+        };
+        let mut written = HashSet::new();
+        let now = now_millis();
+        for (key, mem_index_entry) in self.mem_index.materialize()?.range(..) {
+            if !mem_index_entry.is_live(now) {
+                continue;
+            }
+            let mut record = match mem_index_entry.blob {
+                true => DiskLogEntry::new_blob_pointer(
+                    key.to_vec(),
+                    mem_index_entry.value_offset,
+                    mem_index_entry.value_size,
+                ),
+                false => DiskLogEntry::new_reference(
+                    key.to_vec(),
+                    mem_index_entry.value_offset,
+                    mem_index_entry.value_size,
+                    mem_index_entry.compressed,
+                ),
+            };
+            record.expire_at = mem_index_entry.expire_at;
+            record.timestamp = Some(mem_index_entry.timestamp).filter(|timestamp| *timestamp != 0);
+            let shared = mem_index_entry.blob
+                || !written.insert((mem_index_entry.file_id, mem_index_entry.value_offset));
+            estimate.output_bytes += match shared {
+                true => record.total_byte_size(),
+                // 第一次写入共享记录时写入完整的值
+                false => record.value_byte_offset() + mem_index_entry.value_size,
+            };
+            estimate.output_entries += 1;
+        }
+        Ok(estimate)
+    }
+
+    /// 返回快照时刻的默克尔树。
+    pub(crate) fn merkle_tree(&self) -> MerkleTree {
+        self.full_index().merkle_tree()
+    }
+
+    /// 导出给定段（为`None`时导出所有段）中每个键的最后一条记录，包括删除。
+    pub(crate) fn sync_entries(&self, segments: Option<&[usize]>) -> Result<Vec<SyncEntry>, BitCaskError> {
+        let selected = |key: &[u8]| segments.is_none_or(|segments| segments.contains(&segment_of(key)));
+        let mem_index = self.mem_index.materialize()?;
+        let mut entries = Vec::new();
+        for (key, mem_index_entry) in mem_index.range(..) {
+            if !selected(key) {
+                continue;
+            }
+            let value = match mem_index_entry.is_tombstone() {
+                true => None,
+                false => Some(self.read_value(mem_index_entry)?),
+            };
+            entries.push(SyncEntry {
+                key: key.to_vec(),
+                timestamp: mem_index_entry.timestamp,
+                value,
+                expire_at: mem_index_entry.expire_at,
+            });
+        }
+        for (key, timestamp) in mem_index.removed() {
+            if selected(key) {
+                entries.push(SyncEntry {
+                    key: key.to_vec(),
+                    timestamp,
+                    value: None,
+                    expire_at: None,
+                });
+            }
+        }
+        Ok(entries)
+    }
+}
@@ -0,0 +1,136 @@
+use crate::bitcask::{ByteOffset, FileId};
+use crate::error::BitCaskError;
+use crate::log_file::DiskLogFile;
+use crate::memory_index::MemIndexEntry;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+/// 过滤器中每个键占用的位数，误判率约为1%。
+const FILTER_BITS_PER_KEY: usize = 10;
+/// 判断一个键是否可能存在时检查的位数。
+const FILTER_PROBES: u64 = 4;
+
+/// `KeyFilter` 是一个块中所有键的布隆过滤器：不在块中的键大多可以直接排除，不需要扫描块。
+#[derive(Debug, Clone)]
+pub(crate) struct KeyFilter {
+    bits: Vec<u64>,
+}
+
+impl KeyFilter {
+    /// 创建一个能容纳约`keys`个键的空过滤器。
+    fn new(keys: usize) -> Self {
+        Self {
+            bits: vec![0; (keys * FILTER_BITS_PER_KEY).div_ceil(64).max(1)],
+        }
+    }
+
+    /// 把键加入过滤器。
+    fn insert(&mut self, key: &[u8]) {
+        for bit in probes(self.bits.len(), key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// 键是否可能在过滤器中，返回`false`时键一定不在其中。
+    fn may_contain(&self, key: &[u8]) -> bool {
+        probes(self.bits.len(), key).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+/// 返回键在由`words`个64位字组成的过滤器中对应的各个位的位置（双重哈希）。
+fn probes(words: usize, key: &[u8]) -> impl Iterator<Item = usize> {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    let hash = hasher.finish();
+    let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+    let bits = words as u64 * 64;
+    (0..FILTER_PROBES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+}
+
+/// `SparseBlock` 是数据文件中连续的一段记录，从一条被索引的记录开始，到下一条被索引的记录之前结束。
+#[derive(Debug, Clone)]
+pub(crate) struct SparseBlock {
+    /// 块中第一条记录的起始偏移量。
+    start: ByteOffset,
+    /// 块中最后一条记录的结束偏移量。
+    end: ByteOffset,
+    /// 块中所有键的过滤器。
+    filter: KeyFilter,
+}
+
+impl SparseBlock {
+    /// 创建一个从`start`开始、最多包含`records`条记录的空块。
+    pub(crate) fn new(start: ByteOffset, records: usize) -> Self {
+        Self {
+            start,
+            end: start,
+            filter: KeyFilter::new(records),
+        }
+    }
+
+    /// 把一条结束于`end`的记录加入块中。
+    pub(crate) fn add(&mut self, key: &[u8], end: ByteOffset) {
+        self.filter.insert(key);
+        self.end = end;
+    }
+}
+
+/// `SparseFile` 是一个只有部分记录被加入内存索引的数据文件，见`BitCaskOptions::sparse_index`。
+///
+/// 没有被索引的记录按块记录位置，查找时只扫描过滤器显示可能包含该键的块。
+#[derive(Clone)]
+pub(crate) struct SparseFile {
+    file: Arc<DiskLogFile>,
+    blocks: Vec<SparseBlock>,
+}
+
+impl SparseFile {
+    /// 根据文件和它的块构造稀疏文件，块按偏移量升序排列。
+    pub(crate) fn new(file: Arc<DiskLogFile>, blocks: Vec<SparseBlock>) -> Self {
+        Self { file, blocks }
+    }
+
+    /// 返回文件ID。
+    pub(crate) fn file_id(&self) -> FileId {
+        self.file.file_id
+    }
+
+    /// 返回数据文件，用于重建完整的索引。
+    pub(crate) fn file(&self) -> &DiskLogFile {
+        &self.file
+    }
+
+    /// 在文件中查找键的最后一条记录（包括墓碑），返回对应的索引项，版本号为0。
+    pub(crate) fn find(&self, key: &[u8]) -> Result<Option<MemIndexEntry>, BitCaskError> {
+        for block in self.blocks.iter().rev() {
+            if !block.filter.may_contain(key) {
+                continue;
+            }
+            let mut found = None;
+            self.file.for_each_entry_in(block.start, block.end, |cursor, entry| {
+                if entry.key == key {
+                    found = Some(MemIndexEntry::from_log_entry(
+                        self.file.file_id,
+                        cursor + entry.value_byte_offset(),
+                        &entry,
+                    ));
+                }
+            })?;
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl fmt::Debug for SparseFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SparseFile")
+            .field("file_id", &self.file.file_id)
+            .field("blocks", &self.blocks.len())
+            .finish()
+    }
+}
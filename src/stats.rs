@@ -0,0 +1,161 @@
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
+
+/// `Stats` 结构体是`BitCask::stats()`返回的运行时统计信息。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// 内存索引中的条目数量，包括尚未被压缩清除的墓碑。
+    /// 开启稀疏索引时不包括只记录在数据文件块中的键，见`BitCaskOptions::sparse_index`。
+    pub index_entries: usize,
+    /// 数据文件的数量。
+    pub data_files: usize,
+    /// 编码条目和读取压缩值时，临时缓冲区需要分配（或扩容）内存的次数。
+    /// 缓冲区是线程本地的，该计数在进程内的所有存储之间共享。
+    pub buffer_allocations: u64,
+    /// 编码条目和读取压缩值时，直接复用临时缓冲区已有内存的次数，同样在进程内共享。
+    pub buffer_reuses: u64,
+    /// 后台清理（或`BitCask::sweep_expired`）为过期的键写入的墓碑数量。
+    pub expired_swept: u64,
+    /// 未删除的键的大小分布（字节）。已过期但尚未被清理的键仍然计算在内。
+    pub key_sizes: SizeHistogram,
+    /// 未删除的键的值在磁盘上存储的大小分布（字节），压缩的值按压缩后的大小计算，
+    /// 与`BitCaskOptions::inline_value_threshold`比较的也是这个大小。
+    pub value_sizes: SizeHistogram,
+    /// 每个数据文件的记录数量和存活情况，按文件ID升序排列。
+    pub files: Vec<FileStats>,
+    /// 因为数据文件总大小超过`BitCaskOptions::disk_usage_cap`而被阻塞或者被拒绝的写入数量。
+    pub write_stalls: u64,
+    /// 这些写入累计阻塞的时间。
+    pub write_stall_time: Duration,
+}
+
+/// `FileStats` 是`Stats::files`中一个数据文件的统计信息，用于判断文件的碎片化程度。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileStats {
+    /// 数据文件的ID。
+    pub file_id: usize,
+    /// 文件中的记录占用的字节数，不包括文件头部。
+    pub bytes: u64,
+    /// 文件中的记录数量，包括墓碑和已经被覆盖的记录。
+    pub entries: u64,
+    /// 文件中作为某个未删除的键的最后一条记录的数量。已过期但尚未被清理的键仍然计算在内。
+    pub live_entries: u64,
+    /// 这些记录占用的字节数。
+    pub live_bytes: u64,
+}
+
+impl FileStats {
+    /// 返回存活记录的字节数占文件字节数的比例，空文件返回1。压缩这个文件能回收的空间约为`1 - live_ratio`。
+    pub fn live_ratio(&self) -> f64 {
+        match self.bytes {
+            0 => 1.0,
+            bytes => (self.live_bytes as f64 / bytes as f64).min(1.0),
+        }
+    }
+}
+
+/// `RangeEstimate` 是`BitCask::estimate_range_size`返回的一个键范围的大小估计，只根据索引计算，不读取值。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RangeEstimate {
+    /// 范围内未删除且未过期的键的数量。
+    pub keys: usize,
+    /// 这些键的字节数之和。
+    pub key_bytes: u64,
+    /// 这些键的值在磁盘上存储的字节数之和，压缩的值按压缩后的大小计算。
+    pub value_bytes: u64,
+}
+
+impl RangeEstimate {
+    /// 返回键和值的总字节数，不包括记录头等存储开销。
+    pub fn bytes(&self) -> u64 {
+        self.key_bytes + self.value_bytes
+    }
+}
+
+/// `StatsCounters` 是写入者累加、只读快照读取的计数器，由存储和所有快照共享。
+#[derive(Debug, Default)]
+pub(crate) struct StatsCounters {
+    /// 为过期的键写入的墓碑数量。
+    pub(crate) expired_swept: AtomicU64,
+    /// 被阻塞或者被拒绝的写入数量，见`Stats::write_stalls`。
+    pub(crate) write_stalls: AtomicU64,
+    /// 写入累计阻塞的微秒数。
+    pub(crate) write_stall_micros: AtomicU64,
+}
+
+/// 直方图的桶数量：大小为0的桶加上每个2的幂一个桶。
+const SIZE_BUCKETS: usize = u64::BITS as usize + 1;
+
+/// `SizeHistogram` 是按2的幂分桶的大小分布，用于`Stats`中键和值的大小统计。
+///
+/// 第0个桶统计大小为0的条目，第`i`个桶统计大小在`[2^(i-1), 2^i)`之间的条目。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SizeHistogram {
+    counts: [u64; SIZE_BUCKETS],
+}
+
+impl Default for SizeHistogram {
+    fn default() -> Self {
+        SizeHistogram {
+            counts: [0; SIZE_BUCKETS],
+        }
+    }
+}
+
+impl SizeHistogram {
+    /// 返回大小所在的桶。
+    fn bucket_of(size: u64) -> usize {
+        (u64::BITS - size.leading_zeros()) as usize
+    }
+
+    /// 返回第`bucket`个桶包含的最小和最大大小。
+    fn bucket_bounds(bucket: usize) -> (u64, u64) {
+        match bucket {
+            0 => (0, 0),
+            _ => (1 << (bucket - 1), u64::MAX >> (SIZE_BUCKETS - 1 - bucket)),
+        }
+    }
+
+    /// 记录一个条目的大小。
+    pub(crate) fn record(&mut self, size: u64) {
+        self.counts[Self::bucket_of(size)] += 1;
+    }
+
+    /// 去掉之前记录的一个条目的大小。
+    pub(crate) fn remove(&mut self, size: u64) {
+        let count = &mut self.counts[Self::bucket_of(size)];
+        *count = count.saturating_sub(1);
+    }
+
+    /// 直方图中的条目总数。
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// 按大小升序返回所有非空的桶，每个桶为`(最小大小, 最大大小, 条目数量)`。
+    pub fn buckets(&self) -> Vec<(u64, u64, u64)> {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(bucket, count)| {
+                let (min, max) = Self::bucket_bounds(bucket);
+                (min, max, *count)
+            })
+            .collect()
+    }
+
+    /// 返回至少有`percentile`（0到100之间）的条目不超过的大小，取所在桶的上界，直方图为空时返回0。
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let total = self.count();
+        let target = ((total as f64) * percentile.clamp(0.0, 100.0) / 100.0).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if *count > 0 && seen >= target.max(1) {
+                return Self::bucket_bounds(bucket).1;
+            }
+        }
+        0
+    }
+}
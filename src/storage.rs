@@ -1,11 +1,39 @@
-use crate::bitcask::{Key, PutOption, Value};
+use crate::bitcask::{
+    BatchWrite, ByteOffset, ByteSize, Condition, FileId, Key, PutOption, Value, WriteReceipt, MAX_METADATA_SIZE,
+};
+use crate::backup::{backup_incremental, BackupReport};
+use crate::blob::BlobStorage;
+use crate::clock::{now_millis, timestamp_at, HybridClock};
+use crate::compaction::CompactionEstimate;
+use crate::compression::{DictionaryCompressor, DICTIONARY_FILE};
 use crate::disk_logs::DiskLogFileStorage;
+use crate::durability::DurabilityTracker;
 use crate::error::BitCaskError;
+use crate::health::{Health, OpHistory};
+use crate::keydir::{self, CleanShutdown};
+use crate::lock::DirLock;
 use crate::log_entry::DiskLogEntry;
-use crate::log_file::DiskLogFile;
-use crate::memory_index::MemIndexStorage;
-use std::path::PathBuf;
-use tracing::error;
+use crate::log_file::{sync_dir, DiskLogFile};
+use crate::merge::MergeReport;
+use crate::memory_index::{MemIndexEntry, MemIndexStorage};
+use crate::merkle::SyncEntry;
+use crate::options::{BitCaskOptions, CapAction, CompactionDecision, FileEvent, FileNaming, SyncPolicy, TunableOptions};
+use crate::reserve::SpaceReserve;
+use crate::snapshot::ReadSnapshot;
+use crate::stats::{FileStats, StatsCounters};
+use crate::trace::{TraceOp, TraceWriter};
+use crate::watchdog::Watchdog;
+use arc_swap::ArcSwap;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering as AtomicOrdering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::field::Empty;
+use tracing::{error, info_span, warn, Span};
 
 /// `LogStorage` 结构体用于管理日志的存储。
 /// 它主要负责在磁盘上存储日志数据，并在内存中维护索引，以便快速检索。
@@ -18,52 +46,370 @@ pub struct LogStorage {
 
     /// 用于在内存中快速查找日志条目的 `MemIndex` 实例。
     mem_index: MemIndexStorage,
+
+    /// 当前的配置选项，其中的`TunableOptions`部分可以通过`reconfigure`修改。
+    pub(crate) options: BitCaskOptions,
+
+    /// 每次`reconfigure`后加一，后台维护任务发现它变化后停止，由按新的配置提交的任务接替。
+    pub(crate) options_epoch: u64,
+
+    /// 最近操作的IO错误记录，用于健康检查，与只读快照共享。
+    op_history: Arc<OpHistory>,
+
+    /// 小值的字典压缩器。
+    compressor: DictionaryCompressor,
+
+    /// 去重模式下，活跃文件中每个不同值的哈希到其共享记录的映射。
+    /// 引用条目只能指向同一文件中的记录，因此活跃文件切换后映射会被清空。
+    dedup_index: HashMap<u64, MemIndexEntry>,
+
+    /// `dedup_index`对应的活跃文件ID。
+    dedup_file_id: FileId,
+
+    /// 存放大值的独立文件。
+    blobs: BlobStorage,
+
+    /// 最近一次写入后发布的只读快照，读者无需获取存储的锁即可读取。
+    snapshot: Arc<ArcSwap<ReadSnapshot>>,
+
+    /// 写入与fsync的进度，用于完成提交确认。
+    durability: Arc<DurabilityTracker>,
+
+    /// 为每个写入的条目生成时间戳的混合逻辑时钟。
+    clock: HybridClock,
+
+    /// 运行时计数器，与只读快照共享。
+    counters: Arc<StatsCounters>,
+
+    /// 操作记录文件，见`BitCaskOptions::trace_file`。
+    trace: Option<TraceWriter>,
+
+    /// 是否因为磁盘空间不足进入了只读模式，见`BitCaskOptions::read_only_on_disk_full`。
+    read_only: bool,
+
+    /// 数据目录中尚未释放的保留文件，见`BitCaskOptions::reserved_space`。
+    reserve: Option<SpaceReserve>,
+
+    /// 数据目录上的共享锁，存储打开期间一直持有，见`DirLock`。
+    dir_lock: DirLock,
+
+    /// 监视追加和fsync的看门狗，见`BitCaskOptions::io_watchdog`。
+    watchdog: Option<Arc<Watchdog>>,
 }
 impl LogStorage {
     /// 创建一个新的BitCask实例。
     ///
     /// # 参数
     /// - `data_dir`: 数据目录的路径，可以是任何可以转换为`PathBuf`的类型。
+    /// - `options`: 存储的配置选项。
     ///
     /// # 返回
     /// 返回一个`Result`，在成功创建BitCask实例时包含`Ok(Self)`，
     /// 在遇到错误时包含`Err(BitCaskError)`。
-    pub fn new<T: Into<PathBuf>>(data_dir: T) -> Result<Self, BitCaskError> {
+    pub fn new<T: Into<PathBuf>>(data_dir: T, options: BitCaskOptions) -> Result<Self, BitCaskError> {
         
         // 将输入的数据目录路径转换为`PathBuf`类型
         let data_dir: PathBuf = data_dir.into();
         
         // 确保数据目录已经存在，如果不存在则创建它
         std::fs::create_dir_all(&data_dir)?;
-        
+        let dir_lock = DirLock::shared(&data_dir, &options.file_naming)?;
+        Self::new_locked(data_dir, options, dir_lock)
+    }
+
+    /// 与`new`相同，但使用已经持有的目录锁。
+    fn new_locked(data_dir: PathBuf, options: BitCaskOptions, dir_lock: DirLock) -> Result<Self, BitCaskError> {
+        // 检查数据文件的命名方式是否合法
+        options.file_naming.validate()?;
+
         // 创建一个新的内存索引实例
-        let mut mem_index = MemIndexStorage::new();
+        let mut mem_index = MemIndexStorage::with_inline_value_threshold(options.inline_value_threshold)
+            .with_backend(options.index_backend)
+            .with_sparse_interval(options.sparse_index.as_ref().map(|sparse| sparse.interval));
         
         // 从磁盘上的数据目录和内存索引中恢复磁盘日志
-        let disk_log = DiskLogFileStorage::from_disk(&data_dir, &mut mem_index)?;
+        let mut disk_log = DiskLogFileStorage::from_disk(&data_dir, options.file_naming.clone(), &mut mem_index)?;
+        disk_log.set_file_hook(options.file_hook.clone());
+        disk_log.set_sync_directory(options.sync_directory);
+        disk_log.set_max_file_size(options.max_file_size);
+        // 打开时可能创建了第一个数据文件、删除了临时文件或者截断了未提交的批次
+        if options.sync_directory {
+            sync_dir(&data_dir)?;
+        }
+        let watchdog = options.io_watchdog.clone().map(|watchdog| Watchdog::start(watchdog, data_dir.clone()));
+        disk_log.set_watchdog(watchdog.clone());
+
+        // 加载数据目录中的压缩字典（如果存在）
+        let compressor = DictionaryCompressor::open(&data_dir, options.dictionary_compression.clone())?;
+        let blobs = BlobStorage::open(&data_dir)?;
+        let trace = options.trace_file.as_deref().map(TraceWriter::open).transpose()?;
+        
+        let op_history = Arc::new(OpHistory::new(options.health_window));
+        let counters = Arc::new(StatsCounters::default());
+        let mem_index_max_timestamp = mem_index.max_timestamp();
+        let reserve = create_reserve(&data_dir, &options);
+        let snapshot = Arc::new(ArcSwap::from_pointee(ReadSnapshot {
+            mem_index: mem_index.clone(),
+            disk_log: disk_log.reader(),
+            blobs: blobs.clone(),
+            decoder: compressor.decoder(),
+            op_history: op_history.clone(),
+            slow_op_threshold: options.slow_op_threshold,
+            paranoid_checks: options.paranoid_checks,
+            counters: counters.clone(),
+        }));
         
         // 成功创建BitCask实例后返回`Ok`
         Ok(Self {
             data_dir,
             disk_log,
             mem_index,
+            op_history,
+            compressor,
+            dedup_index: HashMap::new(),
+            dedup_file_id: 0,
+            blobs,
+            options,
+            options_epoch: 0,
+            snapshot,
+            durability: Arc::new(DurabilityTracker::default()),
+            clock: HybridClock::new(mem_index_max_timestamp),
+            counters,
+            trace,
+            read_only: false,
+            reserve,
+            dir_lock,
+            watchdog,
         })
     }
 
+    /// 打开存储，并按`BitCaskOptions::compact_on_open`在原数据目录中压缩数据文件。
+    pub(crate) fn open(data_dir: PathBuf, options: BitCaskOptions) -> Result<Self, BitCaskError> {
+        std::fs::create_dir_all(&data_dir)?;
+        let dir_lock = DirLock::shared(&data_dir, &options.file_naming)?;
+        remove_compaction_leftovers(&data_dir)?;
+        let storage = Self::new_locked(data_dir.clone(), options.clone(), dir_lock)?;
+        let Some(compact_on_open) = &options.compact_on_open else {
+            return Ok(storage);
+        };
+        let estimate = storage.snapshot.load().estimate_compaction()?;
+        let ratio = estimate.reclaimable_bytes() as f64 / estimate.input_bytes.max(1) as f64;
+        if estimate.input_bytes == 0 || ratio < compact_on_open.min_reclaimable_ratio {
+            return Ok(storage);
+        }
+        // 其他句柄也打开了存储时不能替换它们正在使用的数据文件，跳过压缩
+        if !storage.dir_lock.try_upgrade()? {
+            warn!("data directory {:?} is open elsewhere, skipping compaction on open", data_dir);
+            return Ok(storage);
+        }
+        let started = Instant::now();
+        let dir_lock = storage.compact_closed()?;
+        log_slow_op(options.slow_op_threshold, "compact_on_open", started.elapsed(), None, None, None);
+        dir_lock.downgrade()?;
+        Self::new_locked(data_dir, options, dir_lock)
+    }
+
+    /// 打开存储并在原数据目录中压缩所有数据文件，之后关闭存储，见`BitCask::compact_offline`。
+    ///
+    /// 整个过程持有排他的目录锁，数据目录被其他句柄打开时返回`BitCaskError::DirectoryLocked`。
+    /// 返回压缩之前的预估结果，没有数据文件时不做任何事。
+    pub(crate) fn compact_offline(data_dir: PathBuf, options: BitCaskOptions) -> Result<CompactionEstimate, BitCaskError> {
+        std::fs::create_dir_all(&data_dir)?;
+        let dir_lock = DirLock::exclusive(&data_dir, &options.file_naming)?;
+        remove_compaction_leftovers(&data_dir)?;
+        let storage = Self::new_locked(data_dir, options, dir_lock)?;
+        let estimate = storage.snapshot.load().estimate_compaction()?;
+        if estimate.input_bytes > 0 {
+            storage.compact_closed()?;
+        }
+        Ok(estimate)
+    }
+
+    /// 打开已经关闭的存储，通过压缩把所有数据文件重写为第二版记录格式，之后关闭存储，见`BitCask::upgrade`。
+    ///
+    /// 与`compact_offline`一样持有排他的目录锁。返回重写之前第一版格式的记录数量，没有这样的记录时不做任何事。
+    pub(crate) fn upgrade(data_dir: PathBuf, options: BitCaskOptions) -> Result<usize, BitCaskError> {
+        let dir_lock = DirLock::exclusive(&data_dir, &options.file_naming)?;
+        remove_compaction_leftovers(&data_dir)?;
+        let storage = Self::new_locked(data_dir, options, dir_lock)?;
+        let v1_records = storage.snapshot.load().disk_log.v1_record_count()?;
+        if v1_records > 0 {
+            storage.compact_closed()?;
+        }
+        Ok(v1_records)
+    }
+
+    /// 把`checkpoint`创建的副本整理为导出的检查点，见`BitCask::export_checkpoint`。
+    ///
+    /// 副本在原目录中被压缩为一个数据文件，之后打开并正常关闭一次，写入keydir和正常关闭的标记。
+    /// 只作用于源存储的选项（跟踪文件、文件事件回调、保留文件、打开时压缩和稀疏索引）在整理时不使用，
+    /// 整理完成后删除锁文件，目录中只剩下数据文件、keydir、标记以及大值和字典文件。
+    pub(crate) fn seal_export(export_dir: PathBuf, options: BitCaskOptions) -> Result<(), BitCaskError> {
+        let options = BitCaskOptions {
+            trace_file: None,
+            file_hook: None,
+            reserved_space: None,
+            compact_on_open: None,
+            sparse_index: None,
+            ..options
+        };
+        let lock_file = export_dir.join(options.file_naming.lock_file_name());
+        Self::compact_offline(export_dir.clone(), options.clone())?;
+        Self::new(export_dir, options)?.close()?;
+        std::fs::remove_file(lock_file)?;
+        Ok(())
+    }
+
+    /// 关闭存储并把所有数据文件合并为一个新文件，返回仍然持有的目录锁。
+    fn compact_closed(self) -> Result<DirLock, BitCaskError> {
+        let files = self.disk_log.file_sizes()?.into_iter().map(|(path, _)| path).collect();
+        let LogStorage {
+            data_dir,
+            options,
+            dir_lock,
+            ..
+        } = self;
+        compact_in_place(&data_dir, files, &options)?;
+        Ok(dir_lock)
+    }
+
+    /// 返回写入与fsync进度的共享句柄，用于创建提交确认。
+    pub(crate) fn durability_handle(&self) -> Arc<DurabilityTracker> {
+        self.durability.clone()
+    }
+
+    /// 将目前为止的所有写入通过fsync持久化到磁盘，并完成对应的提交确认。
+    pub(crate) fn sync(&self) -> Result<(), BitCaskError> {
+        let written = self.durability.last_written();
+        self.disk_log.sync()?;
+        self.durability.mark_synced(written);
+        Ok(())
+    }
+
+    /// 把内存索引保存到数据目录中的keydir文件，返回keydir的校验和，见`BitCask::save_keydir`。
+    pub(crate) fn save_keydir(&self) -> Result<u32, BitCaskError> {
+        // keydir记录的文件长度之前的写入必须已经持久化
+        self.sync()?;
+        let files = self.disk_log.file_records()?;
+        keydir::save(&self.data_dir, &files, self.mem_index.materialize()?.as_ref())
+    }
+
+    /// 封存活跃文件并创建新的活跃文件，然后保存keydir，返回被封存的文件ID；活跃文件为空时什么也不做并返回`None`。
+    /// 见`BitCask::rotate_active_file`。
+    pub(crate) fn rotate_active_file(&mut self) -> Result<Option<FileId>, BitCaskError> {
+        let (active_file_id, _) = *self.disk_log.file_lengths()?.last().unwrap();
+        if self.disk_log.active_file_is_empty() {
+            return Ok(None);
+        }
+        self.check_writable()?;
+        // 创建新文件时会fsync被封存的文件并触发FileEvent::Sealed
+        self.disk_log.create_new_file()?;
+        self.publish_snapshot();
+        self.save_keydir()?;
+        Ok(Some(active_file_id))
+    }
+
+    /// 保存keydir并写入正常关闭的标记，见`BitCask::close`。
+    pub(crate) fn close(&self) -> Result<(), BitCaskError> {
+        let keydir_checksum = self.save_keydir()?;
+        let last_file = *self.disk_log.file_lengths()?.last().unwrap();
+        keydir::write_clean_shutdown(&self.data_dir, CleanShutdown { last_file, keydir_checksum })
+    }
+
+    /// 记录一次成功的写入，并在`SyncPolicy::Always`下立即fsync。
+    fn after_write(&self) -> Result<(), BitCaskError> {
+        self.record_write().map(drop)
+    }
+
+    /// 与`after_write`相同，但返回这次写入的序号。
+    fn record_write(&self) -> Result<u64, BitCaskError> {
+        let sequence = self.durability.record_write();
+        if self.options.sync_policy == SyncPolicy::Always {
+            self.sync()?;
+        }
+        Ok(sequence)
+    }
+
+    /// 在运行时修改配置选项，之后由调用方按新的配置重新提交后台任务。
+    ///
+    /// 改为`SyncPolicy::Always`时先fsync之前的写入，之后的写入都是同步持久化的。
+    pub(crate) fn reconfigure(&mut self, tunable: TunableOptions) -> Result<(), BitCaskError> {
+        if tunable.sync_policy == SyncPolicy::Always && self.options.sync_policy != SyncPolicy::Always {
+            self.sync()?;
+        }
+        self.options.apply(tunable);
+        self.options_epoch += 1;
+        // 只读快照中保存着慢操作阈值和严格校验的开关
+        self.publish_snapshot();
+        Ok(())
+    }
+
+    /// 开启操作记录时记录一次修改操作，记录失败只会打印错误，不影响操作本身。
+    fn trace(&mut self, op: impl FnOnce() -> TraceOp) {
+        if let Some(trace) = &mut self.trace {
+            if let Err(e) = trace.record(op()) {
+                error!("Error while writing trace record: {:?}", e);
+            }
+        }
+    }
+
+    /// 返回只读快照的共享句柄，读者通过它加载最新发布的快照。
+    pub(crate) fn snapshot_handle(&self) -> Arc<ArcSwap<ReadSnapshot>> {
+        self.snapshot.clone()
+    }
+
+    /// 发布当前状态的只读快照，每次修改内存索引或数据文件后调用。
+    fn publish_snapshot(&self) {
+        self.snapshot.store(Arc::new(ReadSnapshot {
+            mem_index: self.mem_index.clone(),
+            disk_log: self.disk_log.reader(),
+            blobs: self.blobs.clone(),
+            decoder: self.compressor.decoder(),
+            op_history: self.op_history.clone(),
+            slow_op_threshold: self.options.slow_op_threshold,
+            paranoid_checks: self.options.paranoid_checks,
+            counters: self.counters.clone(),
+        }));
+    }
+
     /// 准备数据压缩
     ///
-    /// 此函数负责准备数据压缩的过程它首先创建一个新的空日志文件，然后返回所有不可变文件和内存索引
+    /// 此函数负责准备数据压缩的过程它首先创建一个新的空日志文件，然后按`BitCaskOptions::compaction_strategy`
+    /// 从所有不可变文件中选出这次要合并的文件
     /// 这是数据压缩过程中的关键步骤，旨在优化数据库性能和存储空间使用效率
     ///
     /// 返回:
-    ///     结果中包含一个可变长度的路径列表，这些路径指向所有不可变的文件如果操作成功，这些文件将被用于后续的压缩过程
+    ///     结果中包含这次压缩的计划，其中的文件将被用于后续的压缩过程
     ///     如果操作失败，则返回相应的错误
-    pub(crate) fn prepare_compaction(&mut self) -> Result<Vec<PathBuf>, BitCaskError> {
+    pub(crate) fn prepare_compaction(&mut self) -> Result<CompactionPlan, BitCaskError> {
         // step 0: create a new empty log file
         self.disk_log.create_new_file()?;
-        // step 1: return the immutable files and the mem_index
+        // step 1: select the immutable files to merge
         let immutable_files = self.disk_log.get_immutable_files();
-        Ok(immutable_files)
+        let mem_index = self.mem_index.materialize()?;
+        let files: Vec<FileStats> = self
+            .disk_log
+            .file_usage()?
+            .into_iter()
+            .take(immutable_files.len())
+            .map(|(file_id, bytes, entries)| {
+                let (live_entries, live_bytes) = mem_index.file_live(file_id);
+                FileStats {
+                    file_id,
+                    bytes,
+                    entries,
+                    live_entries,
+                    live_bytes,
+                }
+            })
+            .collect();
+        let range = self.options.compaction_strategy.select(&files);
+        let range = range.start.min(files.len())..range.end.min(files.len());
+        Ok(CompactionPlan {
+            output_id: files.get(range.start).map_or(0, |file| file.file_id),
+            includes_oldest: range.start == 0,
+            files: immutable_files[range.start..range.end.max(range.start)].to_vec(),
+        })
     }
 
     /// 完成压缩过程
@@ -84,52 +430,286 @@ impl LogStorage {
         immutable_files: Vec<PathBuf>,
         new_log_files_dir: PathBuf,
     ) -> Result<(), BitCaskError> {
+        // 新目录在切换之前就被锁定，切换完成后旧目录的锁随之释放
+        std::fs::create_dir_all(&new_log_files_dir)?;
+        let dir_lock = DirLock::shared(&new_log_files_dir, &self.options.file_naming)?;
         // step 3: copy the files to the new directory except the immutable files
         self.disk_log.copy_files_to_new_dir(immutable_files, new_log_files_dir.clone())?;
+        if let Some(dictionary_path) = self.compressor.dictionary_path() {
+            std::fs::copy(dictionary_path, new_log_files_dir.join(DICTIONARY_FILE))?;
+        }
+        self.compressor = DictionaryCompressor::open(
+            &new_log_files_dir,
+            self.options.dictionary_compression.clone(),
+        )?;
         // step 4: initialize a new DiskLog and MemIndex from the new log file
-        let mut mem_index = MemIndexStorage::new();
-        let disk_log = DiskLogFileStorage::from_disk(&new_log_files_dir, &mut mem_index)?;
+        let mut mem_index = MemIndexStorage::with_inline_value_threshold(self.options.inline_value_threshold)
+            .with_backend(self.options.index_backend)
+            .with_sparse_interval(self.options.sparse_index.as_ref().map(|sparse| sparse.interval));
+        let mut disk_log = DiskLogFileStorage::from_disk(
+            &new_log_files_dir,
+            self.options.file_naming.clone(),
+            &mut mem_index,
+        )?;
+        disk_log.set_file_hook(self.options.file_hook.clone());
+        disk_log.set_sync_directory(self.options.sync_directory);
+        disk_log.set_max_file_size(self.options.max_file_size);
+        disk_log.set_watchdog(self.watchdog.clone());
+        // 大值文件不会被重写，只把仍然被引用的文件链接到新目录中
+        self.link_blobs(&mem_index, &new_log_files_dir)?;
+        // 切换之前确保新目录本身以及其中的所有文件在断电后仍然存在
+        if self.options.sync_directory {
+            sync_dir(&new_log_files_dir)?;
+            // 相对路径的父目录可能是空路径，也就是当前目录
+            if let Some(parent) = new_log_files_dir.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                sync_dir(parent)?;
+            }
+        }
+        self.blobs = BlobStorage::open(&new_log_files_dir)?;
+        // 压缩不改变键的内容，版本号保持不变
+        mem_index.inherit_versions(&self.mem_index);
+        mem_index.inherit_request_ids(&self.mem_index);
+        let superseded = self.disk_log.file_sizes()?;
         self.disk_log = disk_log;
         self.mem_index = mem_index;
-        self.data_dir = new_log_files_dir.into();
+        self.data_dir = new_log_files_dir;
+        self.dir_lock = dir_lock;
+        // 旧目录中的保留文件不再需要，在新的数据目录中重新预留空间
+        if let Some(reserve) = self.reserve.take() {
+            reserve.release()?;
+        }
+        self.reserve = create_reserve(&self.data_dir, &self.options);
+        self.publish_snapshot();
+        // 旧目录中的所有数据文件（包括被复制到新目录的文件）都不再被读取
+        if let Some(hook) = &self.options.file_hook {
+            for (path, _) in superseded {
+                hook.fire(FileEvent::Superseded(path));
+            }
+        }
         Ok(())
     }
 
-    /// 根据键获取值，此函数仅在crate内部公开
+    /// 在`checkpoint_dir`中创建当前状态的只读自包含副本，可用于备份或派生测试环境。
     ///
     /// # 参数
-    /// - `key`: 需要查询的键，类型为`&Key`
+    /// - `checkpoint_dir`: 副本所在的目录，如果不存在会被创建，但不能包含其他数据文件。
     ///
-    /// # 返回值
-    /// - 返回`Option<Value>`类型，如果找到值则为`Some(Value)`，否则为`None`
-    ///
-    /// 此函数首先在内存索引中查找键，如果找到且键未被标记为删除（墓碑），则从磁盘日志中获取对应值
-    /// 如果在获取值的过程中发生错误，将打印错误信息并返回`None`
-    pub(crate) fn get(&self, key: &Key) -> Option<Value> {
-        // 在内存索引中查找键
-        let mem_index_entry = self.mem_index.get(key);
-        match mem_index_entry {
-            // 如果找到键的条目
-            Some(mem_index_entry) => {
-                // 如果条目被标记为删除（墓碑），则返回None
-                if mem_index_entry.is_tombstone() {
-                    return None;
-                }
-                // 从磁盘日志中获取对应值
-                let res = self.disk_log.get(&mem_index_entry);
-                match res {
-                    // 如果成功获取到值
-                    Ok(value) => Some(value),
-                    // 如果发生错误，打印错误信息并返回None
-                    Err(e) => {
-                        error!("Error while getting value from disk log: {:?}", e);
-                        None
-                    }
-                }
+    /// # 返回
+    /// - `Result<(), BitCaskError>`: 表示操作的成功或失败以及可能的错误信息
+    pub(crate) fn checkpoint(&self, checkpoint_dir: PathBuf) -> Result<(), BitCaskError> {
+        std::fs::create_dir_all(&checkpoint_dir)?;
+        if std::fs::read_dir(&checkpoint_dir)?.next().is_some() {
+            return Err(BitCaskError::UnexpectedError(anyhow::anyhow!(
+                "checkpoint directory {:?} is not empty",
+                checkpoint_dir
+            )));
+        }
+        self.disk_log.checkpoint(&checkpoint_dir)?;
+        self.link_blobs(&self.mem_index, &checkpoint_dir)?;
+        if let Some(dictionary_path) = self.compressor.dictionary_path() {
+            std::fs::copy(dictionary_path, checkpoint_dir.join(DICTIONARY_FILE))?;
+        }
+        Ok(())
+    }
+
+    /// 将当前状态增量备份到`backup_dir`，只复制与上一次备份相比新增或变化的数据文件。
+    ///
+    /// # 参数
+    /// - `backup_dir`: 备份目录，如果不存在会被创建。
+    ///
+    /// # 返回
+    /// - `Result<BackupReport, BitCaskError>`: 本次备份复制和跳过的文件统计
+    pub(crate) fn backup_incremental(&self, backup_dir: PathBuf) -> Result<BackupReport, BitCaskError> {
+        let mut files = self.disk_log.file_sizes()?;
+        if let Some(dictionary_path) = self.compressor.dictionary_path() {
+            let size = std::fs::metadata(&dictionary_path)?.len();
+            files.push((dictionary_path, size));
+        }
+        for (_, mem_index_entry) in self.mem_index.materialize()?.range(..) {
+            if mem_index_entry.blob {
+                files.push((self.blobs.path(mem_index_entry.value_offset), mem_index_entry.value_size));
+            }
+        }
+        backup_incremental(&self.data_dir, files, &backup_dir)
+    }
+
+    /// 将内存索引中引用的所有大值文件链接到另一个数据目录中。
+    fn link_blobs(&self, mem_index: &MemIndexStorage, data_dir: &std::path::Path) -> Result<(), BitCaskError> {
+        for (_, mem_index_entry) in mem_index.materialize()?.range(..) {
+            if mem_index_entry.blob {
+                self.blobs.link_to(mem_index_entry.value_offset, data_dir)?;
             }
-            // 如果在内存索引中未找到键，则返回None
-            None => None,
         }
+        Ok(())
+    }
+
+    /// 根据内存索引项读取值，并在需要时解压。
+    ///
+    /// 每次写入后都会发布快照，因此最新的快照总能读取内存索引中已有的条目。
+    fn read_value(&self, mem_index_entry: &MemIndexEntry) -> Result<Value, BitCaskError> {
+        self.snapshot.load().read_value(mem_index_entry)
+    }
+
+    /// 将键值对（必要时经过字典压缩）追加到磁盘日志中，返回对应的内存索引项。
+    ///
+    /// 开启去重时，如果活跃文件中已经有相同的值，则只写入一个引用条目，
+    /// 返回的内存索引项指向共享记录。`expire_at`、`request_id`、`metadata`和新生成的时间戳会被写入所有类型的条目中。
+    fn append_value(
+        &mut self,
+        key: &Key,
+        value: &Value,
+        expire_at: Option<u64>,
+        request_id: Option<u64>,
+        metadata: Option<&[u8]>,
+    ) -> Result<MemIndexEntry, BitCaskError> {
+        let timestamp = self.clock.now();
+        let index_entry = self.append_value_at(key, value, expire_at, timestamp, request_id, metadata)?;
+        if let Some(request_id) = request_id {
+            self.mem_index.record_request_id(request_id, timestamp);
+        }
+        Ok(index_entry)
+    }
+
+    /// 与`append_value`相同，但使用给定的时间戳，用于导入其他存储中的条目。
+    fn append_value_at(
+        &mut self,
+        key: &Key,
+        value: &Value,
+        expire_at: Option<u64>,
+        timestamp: u64,
+        request_id: Option<u64>,
+        metadata: Option<&[u8]>,
+    ) -> Result<MemIndexEntry, BitCaskError> {
+        // 大值不参与去重
+        if !self.options.dedup || self.is_blob(value) {
+            let mut entry = self.encode_entry(key, value)?;
+            entry.expire_at = expire_at;
+            entry.timestamp = Some(timestamp);
+            entry.request_id = request_id;
+            entry.metadata = metadata.map(<[u8]>::to_vec);
+            return self.put_log_entry(entry);
+        }
+
+        let active_file_id = self.disk_log.active_file_id();
+        if self.dedup_file_id != active_file_id {
+            self.dedup_index.clear();
+            self.dedup_file_id = active_file_id;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        if let Some(shared) = self.dedup_index.get(&hash) {
+            // 哈希可能冲突，只有值完全相同时才共享记录
+            if self.read_value(shared)? == *value {
+                let mut entry = DiskLogEntry::new_reference(
+                    key.clone(),
+                    shared.value_offset,
+                    shared.value_size,
+                    shared.compressed,
+                );
+                entry.expire_at = expire_at;
+                entry.timestamp = Some(timestamp);
+                entry.request_id = request_id;
+                entry.metadata = metadata.map(<[u8]>::to_vec);
+                let (inline_value, check_sum) = (shared.inline_value.clone(), shared.check_sum);
+                let mut index_entry = self.append_entry(entry)?;
+                index_entry.inline_value = inline_value;
+                index_entry.check_sum = check_sum;
+                return Ok(index_entry);
+            }
+        }
+
+        let mut entry = self.compressor.encode(key, value)?;
+        entry.expire_at = expire_at;
+        entry.timestamp = Some(timestamp);
+        entry.request_id = request_id;
+        entry.metadata = metadata.map(<[u8]>::to_vec);
+        let index_entry = self.put_log_entry(entry)?;
+        self.dedup_index.insert(hash, index_entry.clone());
+        Ok(index_entry)
+    }
+
+    /// 值是否超过大值阈值，需要写入独立的大值文件。
+    fn is_blob(&self, value: &Value) -> bool {
+        matches!(self.options.blob_threshold, Some(threshold) if value.len() > threshold)
+    }
+
+    /// 将键值对编码为日志条目，不考虑去重：大值写入独立的文件并返回指针条目，其他值按需经过字典压缩。
+    fn encode_entry(&mut self, key: &Key, value: &Value) -> Result<DiskLogEntry, BitCaskError> {
+        if self.is_blob(value) {
+            let blob_id = self.blobs.write(value)?;
+            return Ok(DiskLogEntry::new_blob_pointer(key.clone(), blob_id, value.len() as ByteSize));
+        }
+        self.compressor.encode(key, value)
+    }
+
+    /// 将条目追加到磁盘日志中，并在值足够小时把它内联到返回的内存索引项中。
+    fn put_log_entry(&mut self, entry: DiskLogEntry) -> Result<MemIndexEntry, BitCaskError> {
+        let inline_value = self.mem_index.inline_candidate(&entry);
+        let mut index_entry = self.append_entry(entry)?;
+        index_entry.inline_value = inline_value;
+        Ok(index_entry)
+    }
+
+    /// 向磁盘日志追加一个条目，所有写入都经过这里或`append_entries`。
+    ///
+    /// 只读模式下不写入并返回`BitCaskError::ReadOnly`；追加时磁盘空间不足并且开启了
+    /// `BitCaskOptions::read_only_on_disk_full`时进入只读模式。
+    fn append_entry(&mut self, entry: DiskLogEntry) -> Result<MemIndexEntry, BitCaskError> {
+        self.check_writable()?;
+        let res = self.disk_log.put_entry(entry);
+        self.observe_disk_full(&res);
+        res
+    }
+
+    /// 与`append_entry`相同，但按顺序批量追加多个条目。
+    fn append_entries(&mut self, entries: Vec<DiskLogEntry>) -> Result<Vec<MemIndexEntry>, BitCaskError> {
+        self.check_writable()?;
+        let res = self.disk_log.put_entries(entries);
+        self.observe_disk_full(&res);
+        res
+    }
+
+    /// 只读模式下检查磁盘空间是否已经释放：可用空间恢复到`BitCaskOptions::min_available_space`
+    /// 以上时退出只读模式，否则返回`BitCaskError::ReadOnly`。IO看门狗的降级模式只能通过`resume_writes`退出。
+    fn check_writable(&mut self) -> Result<(), BitCaskError> {
+        if self.watchdog.as_ref().is_some_and(|watchdog| watchdog.degraded()) {
+            return Err(BitCaskError::ReadOnly);
+        }
+        if !self.read_only {
+            return Ok(());
+        }
+        if fs2::available_space(&self.data_dir)? < self.options.min_available_space {
+            return Err(BitCaskError::ReadOnly);
+        }
+        warn!("disk space is available again, leaving read-only mode");
+        self.read_only = false;
+        Ok(())
+    }
+
+    /// 追加时磁盘空间不足，按配置进入只读模式。
+    fn observe_disk_full<T>(&mut self, res: &Result<T, BitCaskError>) {
+        if !matches!(res, Err(BitCaskError::DiskFull)) {
+            return;
+        }
+        self.release_reserve();
+        if self.options.read_only_on_disk_full {
+            warn!("disk is full, entering read-only mode until space is freed");
+            self.read_only = true;
+        }
+    }
+
+    /// 磁盘写满时删除保留文件，返回是否有保留文件被释放。
+    pub(crate) fn release_reserve(&mut self) -> bool {
+        let Some(reserve) = self.reserve.take() else {
+            return false;
+        };
+        warn!("disk is full, releasing the reserve file in {:?}", self.data_dir);
+        if let Err(e) = reserve.release() {
+            error!("Error while releasing the reserve file: {:?}", e);
+        }
+        true
     }
 
     /// 向BitCask数据结构中插入或更新键值对。
@@ -150,22 +730,272 @@ impl LogStorage {
         value: &Value,
         option: Option<PutOption>,
     ) -> Result<(), BitCaskError> {
+        self.put_sequenced(key, value, option).map(drop)
+    }
+
+    /// 与`put`相同，但返回写入的回执，见`BitCask::put_with_receipt`。
+    ///
+    /// 回执由这次追加产生的索引项和这次写入分配的序号构成；带有请求ID的重试没有写入新的记录，
+    /// 此时回执描述键当前的记录，序号是这次重试的序号。
+    pub(crate) fn put_with_receipt(
+        &mut self,
+        key: &Key,
+        value: &Value,
+        option: Option<PutOption>,
+    ) -> Result<WriteReceipt, BitCaskError> {
+        let (entry, sequence) = self.put_sequenced(key, value, option)?;
+        let entry = match entry {
+            Some(entry) => entry,
+            None => self.mem_index.lookup(key)?.ok_or(BitCaskError::KeyNotFound)?.into_owned(),
+        };
+        Ok(WriteReceipt {
+            file_id: entry.file_id,
+            offset: entry.record_offset,
+            sequence,
+            timestamp: entry.timestamp,
+        })
+    }
+
+    /// `put`和`put_with_receipt`的共同实现，返回追加产生的索引项（重试时为`None`）和这次写入的序号。
+    fn put_sequenced(
+        &mut self,
+        key: &Key,
+        value: &Value,
+        option: Option<PutOption>,
+    ) -> Result<(Option<MemIndexEntry>, u64), BitCaskError> {
+        self.trace(|| TraceOp::Put {
+            key: key.clone(),
+            value: value.clone(),
+            nx: option.as_ref().is_some_and(|option| option.nx),
+            xx: option.as_ref().is_some_and(|option| option.xx),
+            ttl: option.as_ref().and_then(|option| option.ttl),
+            expected_version: option.as_ref().and_then(|option| option.expected_version),
+            sync: option.as_ref().is_some_and(|option| option.sync),
+            request_id: option.as_ref().and_then(|option| option.request_id),
+            metadata: option.as_ref().and_then(|option| option.metadata.clone()),
+        });
+        let sync = option.as_ref().is_some_and(|option| option.sync);
+        let span = op_span("put");
+        let _entered = span.enter();
+        let started = Instant::now();
+        let res = self.put_inner(key, value, option).and_then(|entry| {
+            let sequence = self.record_write()?;
+            if sync && self.options.sync_policy != SyncPolicy::Always {
+                self.sync()?;
+            }
+            Ok((entry, sequence))
+        });
+        self.publish_snapshot();
+        self.op_history.record(&res);
+        self.log_slow_op(
+            "put",
+            started.elapsed(),
+            Some(key.len()),
+            Some(value.len() as ByteSize),
+            self.mem_index.get(key).map(|entry| entry.file_id),
+        );
+        res
+    }
+
+    /// 批量写入按键严格升序排列的键值对，用于导入大量预先排好序的数据。
+    ///
+    /// 条目按顺序编码后成批追加到日志中，整批索引项通过`MemIndexStorage::put_sorted`一次合并进内存索引，
+    /// 写入结束后只执行一次持久化检查并发布一次快照，而不是像`put`那样每个键各一次。
+    /// 键没有严格升序时不写入任何数据并返回错误。
+    pub(crate) fn put_many_sorted(&mut self, pairs: &[(Key, Value)]) -> Result<(), BitCaskError> {
+        if pairs.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "keys passed to put_many_sorted must be strictly ascending",
+            )
+            .into());
+        }
+        self.trace(|| TraceOp::PutManySorted { pairs: pairs.to_vec() });
+        let span = op_span("put_many_sorted");
+        let _entered = span.enter();
+        let started = Instant::now();
+        let res = self
+            .put_many_sorted_inner(pairs)
+            .and_then(|()| self.after_write());
+        self.publish_snapshot();
+        self.op_history.record(&res);
+        self.log_slow_op("put_many_sorted", started.elapsed(), None, None, None);
+        res
+    }
+
+    /// `put_many_sorted`的实际实现。
+    fn put_many_sorted_inner(&mut self, pairs: &[(Key, Value)]) -> Result<(), BitCaskError> {
+        // 追加模式下先检查所有的键，避免只写入一部分
+        for (key, _) in pairs {
+            self.check_append_only(key)?;
+        }
+        // 去重需要逐个查找活跃文件中的共享记录，退化为逐个写入。确认共享记录时通过快照读取值，
+        // 与`put`相同每次写入后都要发布快照，否则活跃文件切换之后快照中还没有新的文件
+        let expire_at = self.default_expire_at();
+        if self.options.dedup {
+            for (key, value) in pairs {
+                self.put_without_option(key, value, expire_at, None, None)?;
+                self.publish_snapshot();
+            }
+            return Ok(());
+        }
+        let mut entries = Vec::with_capacity(pairs.len());
+        let mut inline_values = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            let mut entry = self.encode_entry(key, value)?;
+            entry.expire_at = expire_at;
+            entry.timestamp = Some(self.clock.now());
+            inline_values.push(self.mem_index.inline_candidate(&entry));
+            entries.push(entry);
+        }
+        let index_entries = self.append_entries(entries)?;
+        // 输入已经按键排好序，整批索引项一次合并进内存索引
+        let index_entries = pairs
+            .iter()
+            .zip(index_entries)
+            .zip(inline_values)
+            .map(|(((key, _), mut index_entry), inline_value)| {
+                index_entry.inline_value = inline_value;
+                (key.clone(), index_entry)
+            })
+            .collect();
+        self.mem_index.put_sorted(index_entries);
+        Ok(())
+    }
+
+    /// 检查所有条件，全部成立时把所有写入作为一个批次原子地追加到日志中，见`BitCask::put_all_if`。
+    pub(crate) fn put_all_if(&mut self, conditions: &[Condition], writes: &[BatchWrite]) -> Result<(), BitCaskError> {
+        self.trace(|| TraceOp::PutAllIf {
+            conditions: conditions.to_vec(),
+            writes: writes.to_vec(),
+        });
+        let span = op_span("put_all_if");
+        let _entered = span.enter();
+        let started = Instant::now();
+        let res = self
+            .put_all_if_inner(conditions, writes)
+            .and_then(|()| self.after_write());
+        self.publish_snapshot();
+        self.op_history.record(&res);
+        self.log_slow_op("put_all_if", started.elapsed(), None, None, None);
+        res
+    }
+
+    /// `put_all_if`的实际实现。
+    fn put_all_if_inner(&mut self, conditions: &[Condition], writes: &[BatchWrite]) -> Result<(), BitCaskError> {
+        let now = now_millis();
+        for (index, condition) in conditions.iter().enumerate() {
+            let (Condition::Version(key, _) | Condition::Value(key, _) | Condition::Absent(key)) = condition;
+            let current = self.mem_index.lookup(key)?.filter(|entry| entry.is_live(now));
+            let holds = match (condition, current) {
+                (Condition::Version(_, version), Some(entry)) => entry.version == *version,
+                (Condition::Value(_, value), Some(entry)) => self.read_value(&entry)? == *value,
+                (Condition::Absent(_), current) => current.is_none(),
+                (_, None) => false,
+            };
+            if !holds {
+                return Err(BitCaskError::ConditionFailed(index));
+            }
+        }
+        if writes.is_empty() {
+            return Ok(());
+        }
+        // 先检查所有的写入，避免只写入一部分
+        for write in writes {
+            match write {
+                BatchWrite::Put(key, _) => self.check_append_only(key)?,
+                BatchWrite::Delete(_) => self.check_deletable()?,
+            }
+        }
+        let expire_at = self.default_expire_at();
+        let mut entries = Vec::with_capacity(writes.len());
+        let mut inline_values = Vec::with_capacity(writes.len());
+        for (index, write) in writes.iter().enumerate() {
+            let mut entry = match write {
+                BatchWrite::Put(key, value) => {
+                    let mut entry = self.encode_entry(key, value)?;
+                    entry.expire_at = expire_at;
+                    entry
+                }
+                BatchWrite::Delete(key) => DiskLogEntry::new_tombstone(key.clone()),
+            };
+            entry.timestamp = Some(self.clock.now());
+            entry.set_batch_remaining((writes.len() - index - 1) as u64);
+            inline_values.push(self.mem_index.inline_candidate(&entry));
+            entries.push(entry);
+        }
+        self.check_writable()?;
+        let res = self.disk_log.put_atomic(entries);
+        self.observe_disk_full(&res);
+        for ((write, mut index_entry), inline_value) in writes.iter().zip(res?).zip(inline_values) {
+            let (BatchWrite::Put(key, _) | BatchWrite::Delete(key)) = write;
+            index_entry.inline_value = inline_value;
+            self.mem_index.put(key.clone(), index_entry);
+        }
+        Ok(())
+    }
+
+    /// 按`BitCaskOptions::default_ttl`返回没有指定存活时间的写入的过期时间。
+    fn default_expire_at(&self) -> Option<u64> {
+        self.options
+            .default_ttl
+            .map(|ttl| now_millis().saturating_add(ttl.as_millis() as u64))
+    }
+
+    /// `put`的实际实现，根据选项分派到不同的插入方式，返回追加产生的索引项；重试的请求不写入，返回`None`。
+    fn put_inner(
+        &mut self,
+        key: &Key,
+        value: &Value,
+        option: Option<PutOption>,
+    ) -> Result<Option<MemIndexEntry>, BitCaskError> {
         match option {
             Some(option) => {
+                if let Some(metadata) = &option.metadata {
+                    if metadata.len() > MAX_METADATA_SIZE {
+                        return Err(BitCaskError::MetadataTooLarge(metadata.len()));
+                    }
+                }
+                // 窗口内已经写入过相同请求ID的请求是客户端重试，直接返回成功
+                if let Some(request_id) = option.request_id {
+                    let window = self.options.request_id_window.as_millis() as u64;
+                    self.mem_index
+                        .prune_request_ids(timestamp_at(now_millis().saturating_sub(window)));
+                    if self.mem_index.has_request_id(request_id) {
+                        return Ok(None);
+                    }
+                }
+                // 如果指定了期望的版本号，键必须存在且版本号一致
+                if let Some(expected_version) = option.expected_version {
+                    let current_version = self
+                        .mem_index
+                        .lookup(key)?
+                        .filter(|entry| entry.is_live(now_millis()))
+                        .map(|entry| entry.version);
+                    if current_version != Some(expected_version) {
+                        return Err(BitCaskError::VersionMismatch);
+                    }
+                }
+                // 根据`ttl`选项计算过期时间，没有指定时使用默认的存活时间
+                let expire_at = match option.ttl {
+                    Some(ttl) => Some(now_millis().saturating_add(ttl.as_millis() as u64)),
+                    None => self.default_expire_at(),
+                };
                 if option.nx {
                     // 当`nx`选项为真，且键不存在时进行插入。
-                    return self.put_nx(key, value);
+                    return self.put_nx(key, value, expire_at, option.request_id, option.metadata.as_deref()).map(Some);
                 }
                 if option.xx {
                     // 当`xx`选项为真，且键已存在时进行更新。
-                    return self.put_xx(key, value);
+                    return self.put_xx(key, value, expire_at, option.request_id, option.metadata.as_deref()).map(Some);
                 }
                 // 当`nx`和`xx`选项都为假，执行不含选项的插入或更新。
-                self.put_without_option(key, value)
+                self.put_without_option(key, value, expire_at, option.request_id, option.metadata.as_deref())
+                    .map(Some)
             }
             None => {
                 // 当没有提供任何选项时，执行不含选项的插入或更新。
-                self.put_without_option(key, value)
+                self.put_without_option(key, value, self.default_expire_at(), None, None).map(Some)
             }
         }
     }
@@ -178,9 +1008,12 @@ impl LogStorage {
     /// # 参数
     /// - `key`: 键，用于标识要存储的值
     /// - `value`: 要存储的值
+    /// - `expire_at`: 键的过期时间（Unix毫秒时间戳），为`None`表示永不过期
+    /// - `request_id`: 客户端提供的请求ID，写入后用于识别重试
+    /// - `metadata`: 随值一起保存的用户元数据
     ///
     /// # 返回值
-    /// - `Result<(), BitCaskError>`: 表示操作是否成功的结果类型如果操作成功，返回`Ok(())`；
+    /// - `Result<MemIndexEntry, BitCaskError>`: 表示操作是否成功的结果类型如果操作成功，返回追加产生的索引条目；
     ///   否则，返回包含错误信息的`Err`
     ///
     /// # 错误
@@ -189,13 +1022,17 @@ impl LogStorage {
         &mut self,
         key: &Key,
         value: &Value,
-    ) -> Result<(), BitCaskError> {
+        expire_at: Option<u64>,
+        request_id: Option<u64>,
+        metadata: Option<&[u8]>,
+    ) -> Result<MemIndexEntry, BitCaskError> {
+        self.check_append_only(key)?;
         // 将键值对写入磁盘日志，获取对应的索引条目
-        let index_entry = self.disk_log.put(key, value)?;
+        let index_entry = self.append_value(key, value, expire_at, request_id, metadata)?;
         // 将键和对应的索引条目存入内存索引中，以便后续快速查找
-        self.mem_index.put(key.clone(), index_entry);
-        // 返回操作成功的结果
-        Ok(())
+        self.mem_index.put(key.clone(), index_entry.clone());
+        // 返回追加产生的索引条目
+        Ok(index_entry)
     }
 
     /// 在BitCask中插入键值对，如果键已存在且不是墓碑，则返回错误
@@ -203,31 +1040,42 @@ impl LogStorage {
     /// # 参数
     /// - `key`: 键，用于标识值
     /// - `value`: 待插入的值
+    /// - `expire_at`: 键的过期时间（Unix毫秒时间戳），为`None`表示永不过期
+    /// - `request_id`: 客户端提供的请求ID，写入后用于识别重试
+    /// - `metadata`: 随值一起保存的用户元数据
     ///
     /// # 返回
-    /// - `Result<(), BitCaskError>`: 如果插入成功，则返回`Ok(())`；如果键已存在且不是墓碑，则返回`Err(BitCaskError::KeyExists)`；其他错误情况返回相应的`BitCaskError`
+    /// - `Result<MemIndexEntry, BitCaskError>`: 如果插入成功，则返回追加产生的索引条目；如果键已存在且不是墓碑，则返回`Err(BitCaskError::KeyExists)`；其他错误情况返回相应的`BitCaskError`
     ///
     /// # 说明
     /// 此方法用于向BitCask存储中插入一个键值对。首先检查内存索引中是否已存在该键，如果存在且不是墓碑，则拒绝插入。如果键不存在或是一个墓碑，则将键值对写入磁盘日志，并更新内存索引。
-    fn put_nx(&mut self, key: &Key, value: &Value) -> Result<(), BitCaskError> {
+    fn put_nx(
+        &mut self,
+        key: &Key,
+        value: &Value,
+        expire_at: Option<u64>,
+        request_id: Option<u64>,
+        metadata: Option<&[u8]>,
+    ) -> Result<MemIndexEntry, BitCaskError> {
         
         // 从内存索引中获取键对应的条目
-        let index_entry = self.mem_index.get(key);
+        let index_entry = self.mem_index.lookup(key)?;
         
-        // 检查键是否已存在且不是墓碑
+        // 检查键是否已存在且不是墓碑，已过期的键视为不存在
         if let Some(index_entry) = index_entry {
-            if !index_entry.is_tombstone() {
+            if index_entry.is_live(now_millis()) {
                 return Err(BitCaskError::KeyExists);
             }
         }
+        self.check_append_only(key)?;
         
         // 将键值对写入磁盘日志，并获取写入的条目
-        let index_entry = self.disk_log.put(key, value)?;
+        let index_entry = self.append_value(key, value, expire_at, request_id, metadata)?;
         
         // 更新内存索引
-        self.mem_index.put(key.clone(), index_entry);
+        self.mem_index.put(key.clone(), index_entry.clone());
         
-        Ok(())
+        Ok(index_entry)
     }
 
     /// 更新给定键的值，如果键已存在且不是墓碑，则更新磁盘日志和内存索引。
@@ -235,33 +1083,44 @@ impl LogStorage {
     /// # 参数
     /// - `key`: 需要更新的键引用。
     /// - `value`: 需要存储的新值引用。
+    /// - `expire_at`: 键的过期时间（Unix毫秒时间戳），为`None`表示永不过期。
+    /// - `request_id`: 客户端提供的请求ID，写入后用于识别重试。
+    /// - `metadata`: 随值一起保存的用户元数据。
     ///
     /// # 返回
-    /// - `Result<(), BitCaskError>`: 如果操作成功，则返回 `Ok(())`；否则返回错误类型 `BitCaskError`。
+    /// - `Result<MemIndexEntry, BitCaskError>`: 如果操作成功，则返回追加产生的索引条目；否则返回错误类型 `BitCaskError`。
     ///
     /// # 错误
-    /// - `BitCaskError::KeyNotFound`: 当键不存在或键是墓碑时触发。
-    pub(crate) fn put_xx(&mut self, key: &Key, value: &Value) -> Result<(), BitCaskError> {
+    /// - `BitCaskError::KeyNotFound`: 当键不存在、键是墓碑或键已过期时触发。
+    pub(crate) fn put_xx(
+        &mut self,
+        key: &Key,
+        value: &Value,
+        expire_at: Option<u64>,
+        request_id: Option<u64>,
+        metadata: Option<&[u8]>,
+    ) -> Result<MemIndexEntry, BitCaskError> {
        
         // 检查内存索引中是否已存在给定键
-        let index_entry = self.mem_index.get(key);
+        let index_entry = self.mem_index.lookup(key)?;
        
-        // 如果找到索引项且不是墓碑，则继续操作
+        // 如果找到索引项且不是墓碑也没有过期，则继续操作
         if let Some(index_entry) = index_entry {
-            if index_entry.is_tombstone() {
+            if !index_entry.is_live(now_millis()) {
                 return Err(BitCaskError::KeyNotFound);
             }
         } else {
             return Err(BitCaskError::KeyNotFound);
         }
+        self.check_append_only(key)?;
         
         // 在磁盘日志中更新键的值，并获取新的索引项
-        let index_entry = self.disk_log.put(key, value)?;
+        let index_entry = self.append_value(key, value, expire_at, request_id, metadata)?;
         
         // 将新的索引项更新到内存索引中
-        self.mem_index.put(key.clone(), index_entry);
+        self.mem_index.put(key.clone(), index_entry.clone());
         
-        Ok(())
+        Ok(index_entry)
     }
 
     /// 从BitCask存储中删除指定键的数据。
@@ -276,17 +1135,464 @@ impl LogStorage {
     /// 此函数负责删除给定键对应的数据。首先，它会调用磁盘日志的删除方法来实际删除数据，
     /// 然后将该删除操作的索引条目更新到内存索引中，以保持数据的一致性。
     pub(crate) fn delete(&mut self, key: &Key) -> Result<(), BitCaskError> {
-        let index_entry = self.disk_log.delete(key)?;
+        self.trace(|| TraceOp::Delete { key: key.clone() });
+        let span = op_span("delete");
+        let _entered = span.enter();
+        let started = Instant::now();
+        let mut tombstone = DiskLogEntry::new_tombstone(key.clone());
+        tombstone.timestamp = Some(self.clock.now());
+        let res = self.check_deletable().and_then(|_| self.append_entry(tombstone));
+        self.op_history.record(&res);
+        let index_entry = res?;
+        let file_id = index_entry.file_id;
         self.mem_index.put(key.clone(), index_entry);
+        self.publish_snapshot();
+        self.after_write()?;
+        self.log_slow_op("delete", started.elapsed(), Some(key.len()), None, Some(file_id));
+        Ok(())
+    }
+
+    /// 软删除指定的键：写入一个保留了当前值的墓碑，键随即不可见，但在下一次压缩之前可以通过`undelete`恢复。
+    ///
+    /// 键不存在、已经删除或已经过期时返回`BitCaskError::KeyNotFound`。值按写入时的方式（必要时经过字典压缩）
+    /// 保存在墓碑中，过期时间也一并保留。
+    pub(crate) fn soft_delete(&mut self, key: &Key) -> Result<(), BitCaskError> {
+        self.trace(|| TraceOp::SoftDelete { key: key.clone() });
+        let span = op_span("soft_delete");
+        let _entered = span.enter();
+        let started = Instant::now();
+        let res = self.soft_delete_inner(key);
+        self.op_history.record(&res);
+        let file_id = res?;
+        self.publish_snapshot();
+        self.after_write()?;
+        self.log_slow_op("soft_delete", started.elapsed(), Some(key.len()), None, Some(file_id));
+        Ok(())
+    }
+
+    /// `soft_delete`的实际实现，返回墓碑所在的文件ID。
+    fn soft_delete_inner(&mut self, key: &Key) -> Result<FileId, BitCaskError> {
+        self.check_deletable()?;
+        let index_entry = match self.mem_index.lookup(key)? {
+            Some(index_entry) if index_entry.is_live(now_millis()) => index_entry,
+            _ => return Err(BitCaskError::KeyNotFound),
+        };
+        let value = self.read_value(&index_entry)?;
+        let mut tombstone = self.compressor.encode(key, &value)?;
+        tombstone.soft_deleted = true;
+        tombstone.expire_at = index_entry.expire_at;
+        tombstone.metadata = index_entry.metadata.clone();
+        tombstone.timestamp = Some(self.clock.now());
+        let index_entry = self.append_entry(tombstone)?;
+        let file_id = index_entry.file_id;
+        self.mem_index.put(key.clone(), index_entry);
+        Ok(file_id)
+    }
+
+    /// 恢复通过`soft_delete`删除的键，把墓碑中保留的值重新写入。
+    ///
+    /// 键的最后一条记录不是软删除的墓碑（包括压缩已经丢弃了墓碑）或者值已经过期时返回`BitCaskError::KeyNotFound`。
+    pub(crate) fn undelete(&mut self, key: &Key) -> Result<(), BitCaskError> {
+        self.trace(|| TraceOp::Undelete { key: key.clone() });
+        let span = op_span("undelete");
+        let _entered = span.enter();
+        let started = Instant::now();
+        let res = self.undelete_inner(key);
+        self.op_history.record(&res);
+        res?;
+        self.publish_snapshot();
+        self.after_write()?;
+        self.log_slow_op(
+            "undelete",
+            started.elapsed(),
+            Some(key.len()),
+            None,
+            self.mem_index.get(key).map(|entry| entry.file_id),
+        );
+        Ok(())
+    }
+
+    /// `undelete`的实际实现。
+    fn undelete_inner(&mut self, key: &Key) -> Result<(), BitCaskError> {
+        let index_entry = match self.mem_index.lookup(key)? {
+            Some(index_entry) if index_entry.soft_deleted && !index_entry.is_expired(now_millis()) => index_entry,
+            _ => return Err(BitCaskError::KeyNotFound),
+        };
+        let value = self.read_value(&index_entry)?;
+        let metadata = index_entry.metadata.clone();
+        self.put_without_option(key, &value, index_entry.expire_at, None, metadata.as_deref())
+            .map(drop)
+    }
+
+    /// 把`old`的值移动到`new`下：新键的记录和旧键的墓碑在同一次追加中写入，并且只发布一次快照，
+    /// 读取方不会看到两个键同时存在或同时不存在。
+    ///
+    /// 旧键不存在或已经过期时返回`BitCaskError::KeyNotFound`；`nx`为真并且新键已经存在时返回
+    /// `BitCaskError::KeyExists`，否则覆盖新键。过期时间随值一起移动。
+    pub(crate) fn rename(&mut self, old: &Key, new: &Key, nx: bool) -> Result<(), BitCaskError> {
+        self.trace(|| TraceOp::Rename {
+            old: old.clone(),
+            new: new.clone(),
+            nx,
+        });
+        let span = op_span("rename");
+        let _entered = span.enter();
+        let started = Instant::now();
+        let res = self.copy_inner(old, new, nx, true);
+        self.op_history.record(&res);
+        res?;
+        self.publish_snapshot();
+        self.after_write()?;
+        self.log_slow_op("rename", started.elapsed(), Some(new.len()), None, None);
+        Ok(())
+    }
+
+    /// 把`src`的值复制到`dst`下，`src`保持不变，错误与`rename`相同。
+    pub(crate) fn copy(&mut self, src: &Key, dst: &Key, nx: bool) -> Result<(), BitCaskError> {
+        self.trace(|| TraceOp::Copy {
+            src: src.clone(),
+            dst: dst.clone(),
+            nx,
+        });
+        let span = op_span("copy");
+        let _entered = span.enter();
+        let started = Instant::now();
+        let res = self.copy_inner(src, dst, nx, false);
+        self.op_history.record(&res);
+        res?;
+        self.publish_snapshot();
+        self.after_write()?;
+        self.log_slow_op("copy", started.elapsed(), Some(dst.len()), None, None);
+        Ok(())
+    }
+
+    /// `rename`和`copy`的实际实现，`remove_source`为真时同时写入源键的墓碑。
+    ///
+    /// 大值只复制指针，大值文件在两个键之间共享；其他值重新编码（必要时经过字典压缩）后写入新记录。
+    fn copy_inner(&mut self, src: &Key, dst: &Key, nx: bool, remove_source: bool) -> Result<(), BitCaskError> {
+        let now = now_millis();
+        let source = match self.mem_index.lookup(src)? {
+            Some(source) if source.is_live(now) => source,
+            _ => return Err(BitCaskError::KeyNotFound),
+        };
+        if src == dst {
+            return Ok(());
+        }
+        if remove_source {
+            self.check_deletable()?;
+        }
+        self.check_append_only(dst)?;
+        if nx && self.mem_index.lookup(dst)?.is_some_and(|entry| entry.is_live(now)) {
+            return Err(BitCaskError::KeyExists);
+        }
+        let mut entry = match source.blob {
+            true => DiskLogEntry::new_blob_pointer(dst.clone(), source.value_offset, source.value_size),
+            false => {
+                let value = self.read_value(&source)?;
+                self.compressor.encode(dst, &value)?
+            }
+        };
+        entry.expire_at = source.expire_at;
+        entry.metadata = source.metadata.clone();
+        entry.timestamp = Some(self.clock.now());
+        let inline_value = self.mem_index.inline_candidate(&entry);
+        let mut entries = vec![entry];
+        if remove_source {
+            let mut tombstone = DiskLogEntry::new_tombstone(src.clone());
+            tombstone.timestamp = Some(self.clock.now());
+            entries.push(tombstone);
+        }
+        let mut index_entries = self.append_entries(entries)?.into_iter();
+        if let Some(mut index_entry) = index_entries.next() {
+            index_entry.inline_value = inline_value;
+            self.mem_index.put(dst.clone(), index_entry);
+        }
+        if let Some(tombstone) = index_entries.next() {
+            self.mem_index.put(src.clone(), tombstone);
+        }
         Ok(())
     }
 
-    /// 获取当前对象的大小
-    ///·
-    /// 返回值为当前对象占用的内存大小，以字节为单位
-    pub(crate) fn size(&self) -> usize {
-        self.mem_index.size()
+    /// 为最多`limit`个已经过期的键写入墓碑，返回实际清理的键数量。
+    ///
+    /// 过期的键按过期时间顺序取出，墓碑成批追加到日志中，之后只发布一次快照。
+    pub(crate) fn sweep_expired(&mut self, limit: usize) -> Result<usize, BitCaskError> {
+        let span = op_span("sweep_expired");
+        let _entered = span.enter();
+        let started = Instant::now();
+        // 追加模式下过期的键同样被保留
+        if self.options.append_only {
+            return Ok(0);
+        }
+        let keys = self.mem_index.expired_keys(now_millis(), limit);
+        if keys.is_empty() {
+            return Ok(0);
+        }
+        self.trace(|| TraceOp::SweepExpired { limit });
+        let tombstones = keys
+            .iter()
+            .map(|key| {
+                let mut tombstone = DiskLogEntry::new_tombstone(key.clone());
+                tombstone.timestamp = Some(self.clock.now());
+                tombstone
+            })
+            .collect();
+        let res = self.append_entries(tombstones);
+        self.op_history.record(&res);
+        for (key, index_entry) in keys.iter().zip(res?) {
+            self.mem_index.put(key.clone(), index_entry);
+        }
+        self.publish_snapshot();
+        self.after_write()?;
+        self.counters.expired_swept.fetch_add(keys.len() as u64, AtomicOrdering::Relaxed);
+        self.log_slow_op("sweep_expired", started.elapsed(), None, None, None);
+        Ok(keys.len())
+    }
+
+    /// 以最后写入者胜出（LWW）的方式合并另一个存储中的条目。
+    ///
+    /// 对于每个键，比较两边最后一条记录（写入或删除）的时间戳，另一个存储中的记录更新时才导入；
+    /// 时间戳相同时，有值的记录胜过删除，值较大的记录胜过值较小的记录，
+    /// 因此在两个存储上互相合并会得到相同的结果。导入的条目保留原来的时间戳和过期时间。
+    ///
+    /// # 参数
+    /// - `other_dir`: 另一个存储的数据目录，必须已经存在，合并期间不应被写入。
+    ///
+    /// # 返回
+    /// - `Result<MergeReport, BitCaskError>`: 导入和跳过的键的统计
+    pub(crate) fn merge_from(&mut self, other_dir: PathBuf) -> Result<MergeReport, BitCaskError> {
+        if !other_dir.is_dir() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("data directory {:?} does not exist", other_dir),
+            )
+            .into());
+        }
+        let other = LogStorage::new(other_dir, BitCaskOptions::default())?;
+        let entries = other.snapshot.load().sync_entries(None)?;
+        self.apply_sync_entries(entries)
+    }
+
+    /// 按最后写入者胜出的规则应用另一个存储导出的记录。
+    ///
+    /// 时间戳较大的记录胜出，时间戳相同时值较大的记录胜出（删除视为最小的值），
+    /// 因此两个存储以任意顺序互相应用对方的记录后内容一致。
+    pub(crate) fn apply_sync_entries(&mut self, entries: Vec<SyncEntry>) -> Result<MergeReport, BitCaskError> {
+        let mut report = MergeReport::default();
+        for SyncEntry { key, timestamp: remote_timestamp, value: remote_value, expire_at } in entries {
+            let (local_timestamp, local_live) = match self.mem_index.lookup(&key)? {
+                Some(entry) => (entry.timestamp, !entry.is_tombstone()),
+                None => (self.mem_index.removed_timestamp(&key).unwrap_or(0), false),
+            };
+            let remote_wins = match remote_timestamp.cmp(&local_timestamp) {
+                Ordering::Greater => true,
+                Ordering::Less => false,
+                Ordering::Equal => {
+                    let local_value = match local_live {
+                        true => self.get_value(&key)?,
+                        false => None,
+                    };
+                    remote_value > local_value
+                }
+            };
+            if !remote_wins {
+                report.skipped += 1;
+                continue;
+            }
+
+            self.check_append_only(&key)?;
+            if remote_value.is_none() {
+                self.check_deletable()?;
+            }
+            self.clock.observe(remote_timestamp);
+            match remote_value {
+                Some(value) => {
+                    let index_entry = self.append_value_at(&key, &value, expire_at, remote_timestamp, None, None)?;
+                    self.mem_index.put(key, index_entry);
+                    report.imported += 1;
+                }
+                None => {
+                    let mut tombstone = DiskLogEntry::new_tombstone(key.clone());
+                    tombstone.timestamp = Some(remote_timestamp);
+                    let index_entry = self.append_entry(tombstone)?;
+                    self.mem_index.put(key, index_entry);
+                    report.deleted += 1;
+                }
+            }
+            // 去重时可能需要读取刚刚写入的记录，每次写入后都发布快照
+            self.publish_snapshot();
+        }
+        self.after_write()?;
+        Ok(report)
+    }
+
+    /// 追加模式下键已经有记录时返回`BitCaskError::ReadOnlyKey`。
+    fn check_append_only(&self, key: &Key) -> Result<(), BitCaskError> {
+        if self.options.append_only && self.mem_index.lookup(key)?.is_some() {
+            return Err(BitCaskError::ReadOnlyKey);
+        }
+        Ok(())
+    }
+
+    /// 追加模式下不允许删除，返回`BitCaskError::ReadOnlyKey`。
+    fn check_deletable(&self) -> Result<(), BitCaskError> {
+        if self.options.append_only {
+            return Err(BitCaskError::ReadOnlyKey);
+        }
+        Ok(())
+    }
+
+    /// 读取键当前的值（包括已经过期的值），键不存在或已删除时返回`None`。
+    fn get_value(&self, key: &Key) -> Result<Option<Value>, BitCaskError> {
+        match self.mem_index.lookup(key)? {
+            Some(entry) if !entry.is_tombstone() => self.read_value(&entry).map(Some),
+            _ => Ok(None),
+        }
     }
+
+    /// 检查存储的健康状态。
+    ///
+    /// # 返回
+    /// - `Result<Health, BitCaskError>`: 包含活跃文件是否可写、磁盘可用空间以及最近操作IO错误数量的报告；
+    ///   如果无法获取磁盘可用空间，则返回错误。
+    pub(crate) fn health(&self) -> Result<Health, BitCaskError> {
+        let available_space = fs2::available_space(&self.data_dir)?;
+        let (recent_ops, recent_io_errors) = self.op_history.summary();
+        Ok(Health {
+            active_file_writable: self.disk_log.active_file_writable(),
+            available_space,
+            min_available_space: self.options.min_available_space,
+            recent_ops,
+            recent_io_errors,
+            read_only: self.read_only,
+            io_stalls: self.watchdog.as_ref().map_or(0, |watchdog| watchdog.stalls()),
+            degraded: self.watchdog.as_ref().is_some_and(|watchdog| watchdog.degraded()),
+        })
+    }
+
+    /// 数据文件的总大小超过`BitCaskOptions::disk_usage_cap`时返回配置的处理方式。
+    pub(crate) fn disk_usage_exceeded(&self) -> Option<CapAction> {
+        self.options
+            .disk_usage_cap
+            .as_ref()
+            .filter(|cap| self.disk_log.disk_usage() > cap.max_bytes)
+            .map(|cap| cap.action)
+    }
+
+    /// 记录一次因为超过`BitCaskOptions::disk_usage_cap`而被阻塞或者被拒绝的写入，以及它阻塞的时间。
+    pub(crate) fn record_write_stall(&self, stalled: Duration) {
+        self.counters.write_stalls.fetch_add(1, AtomicOrdering::Relaxed);
+        self.counters
+            .write_stall_micros
+            .fetch_add(stalled.as_micros() as u64, AtomicOrdering::Relaxed);
+    }
+
+    /// 退出IO看门狗的只读降级模式，返回之前是否处于降级模式，见`BitCask::resume_writes`。
+    pub(crate) fn resume_writes(&self) -> bool {
+        let resumed = self.watchdog.as_ref().is_some_and(|watchdog| watchdog.resume());
+        if resumed {
+            warn!("leaving read-only mode entered after an IO stall in {:?}", self.data_dir);
+        }
+        resumed
+    }
+
+    /// 把操作的属性记录到当前的操作span上，并且如果操作耗时超过了配置的慢操作阈值，以warn级别记录该操作。
+    ///
+    /// # 参数
+    /// - `op`: 操作名称，例如`get`、`put`或压缩的某个步骤。
+    /// - `elapsed`: 操作的耗时。
+    /// - `key_size`: 键的字节大小，与键无关的操作为`None`。
+    /// - `value_size`: 值的字节大小，未知或与值无关时为`None`。
+    /// - `file_id`: 操作涉及的数据文件ID，未知时为`None`。
+    pub(crate) fn log_slow_op(
+        &self,
+        op: &'static str,
+        elapsed: Duration,
+        key_size: Option<usize>,
+        value_size: Option<ByteSize>,
+        file_id: Option<FileId>,
+    ) {
+        log_slow_op(self.options.slow_op_threshold, op, elapsed, key_size, value_size, file_id);
+    }
+}
+
+/// 操作span的名称，具体的操作由span的`op`和`otel.name`字段区分。
+const OP_SPAN_NAME: &str = "bitcask_op";
+
+/// 创建一个info级别的操作span，调用方在操作期间进入该span。
+///
+/// 键大小、值大小、文件ID和耗时（微秒）在操作结束时由`log_slow_op`记录到span上。
+/// `otel.name`字段使通过`tracing-opentelemetry`导出的span以操作名命名。
+pub(crate) fn op_span(op: &'static str) -> Span {
+    info_span!(
+        "bitcask_op",
+        otel.name = op,
+        op,
+        key_size = Empty,
+        value_size = Empty,
+        file_id = Empty,
+        duration_us = Empty,
+    )
+}
+
+/// 把操作的属性记录到当前的操作span上，当前span不是`op_span`创建的span时什么也不做。
+pub(crate) fn record_op_span(
+    elapsed: Duration,
+    key_size: Option<usize>,
+    value_size: Option<ByteSize>,
+    file_id: Option<FileId>,
+) {
+    let span = Span::current();
+    if span.metadata().is_none_or(|metadata| metadata.name() != OP_SPAN_NAME) {
+        return;
+    }
+    span.record("duration_us", elapsed.as_micros() as u64);
+    if let Some(key_size) = key_size {
+        span.record("key_size", key_size as u64);
+    }
+    if let Some(value_size) = value_size {
+        span.record("value_size", value_size);
+    }
+    if let Some(file_id) = file_id {
+        span.record("file_id", file_id as u64);
+    }
+}
+
+/// 把操作的属性记录到当前的操作span上，并且如果操作耗时超过了给定的慢操作阈值，以warn级别记录该操作。
+///
+/// 参数与`LogStorage::log_slow_op`相同，`threshold`为`None`时不记录慢操作日志。
+pub(crate) fn log_slow_op(
+    threshold: Option<Duration>,
+    op: &'static str,
+    elapsed: Duration,
+    key_size: Option<usize>,
+    value_size: Option<ByteSize>,
+    file_id: Option<FileId>,
+) {
+    record_op_span(elapsed, key_size, value_size, file_id);
+    match threshold {
+        Some(threshold) if elapsed > threshold => {
+            warn!(
+                op,
+                elapsed_ms = elapsed.as_millis() as u64,
+                key_size = ?key_size,
+                value_size = ?value_size,
+                file_id = ?file_id,
+                "slow operation"
+            );
+        }
+        _ => {}
+    }
+}
+
+/// 一次压缩要合并的文件，由`LogStorage::prepare_compaction`按`BitCaskOptions::compaction_strategy`选出。
+pub(crate) struct CompactionPlan {
+    /// 要合并的文件，按文件ID升序排列，是所有不可变文件中连续的一段。
+    pub(crate) files: Vec<PathBuf>,
+    /// 合并结果使用的文件ID，即第一个被合并的文件的ID，合并结果因此排在同样的位置。
+    pub(crate) output_id: FileId,
+    /// 是否包含最旧的文件。不包含时更旧的文件中可能还有被覆盖的值，墓碑不能被丢弃。
+    pub(crate) includes_oldest: bool,
 }
 
 /// 开始压缩
@@ -297,34 +1603,330 @@ impl LogStorage {
 /// 回收磁盘空间和提高数据库的查询效率。
 ///
 /// 参数:
-/// - immutable_files: 一个包含不可变文件路径的向量。
+/// - plan: 要合并的文件以及合并结果的文件ID，不包含最旧的文件时被删除或丢弃的键以墓碑的形式保留。
 /// - new_log_file_path: 新日志文件的路径。
+/// - options: 存储的配置选项，决定数据文件的命名方式，以及是否按`BitCaskOptions::retention`丢弃超过保留期限的记录；
+///   追加模式（见`BitCaskOptions::append_only`）下过期的键和超过保留期限的记录都会被保留。
 ///
 /// 返回:
 /// - 结果类型 `Result<(), BitCaskError>` 表示操作的成功或失败以及可能的错误信息。
 pub(crate) fn start_compaction(
-    immutable_files: Vec<PathBuf>,
+    plan: &CompactionPlan,
     new_log_file_path: PathBuf,
+    options: &BitCaskOptions,
 ) -> Result<(), BitCaskError> {
+    let immutable_files = plan.files.clone();
+    let naming = options.file_naming.clone();
     // 创建新的日志文件的目录
     std::fs::create_dir_all(&new_log_file_path)?;
     // 初始化新的日志文件对象
-    let mut new_log_file = DiskLogFile::new(&new_log_file_path, 0)?;
+    // 压缩的输出先写入临时文件，全部写完并刷新到磁盘后再重命名为正式文件
+    let new_log_file = DiskLogFile::new_temp(&new_log_file_path, plan.output_id, &naming)?;
+    // 压缩过滤器需要原始值：大值从大值文件中读取，压缩过的值用数据目录中的字典解压
+    let filter = match (&options.compaction_filter, immutable_files.first().and_then(|path| path.parent())) {
+        (Some(filter), Some(data_dir)) if !options.append_only => Some((
+            filter,
+            BlobStorage::open(data_dir)?,
+            DictionaryCompressor::open(data_dir, options.dictionary_compression.clone())?.decoder(),
+        )),
+        _ => None,
+    };
     // 初始化内存索引对象
     let mut mem_index = MemIndexStorage::new();
     // 使用不可变文件初始化磁盘日志对象
-    let disk_logs = DiskLogFileStorage::immutable_initialization(immutable_files, &mut mem_index)?;
+    let disk_logs = DiskLogFileStorage::immutable_initialization(immutable_files, naming.clone(), &mut mem_index)?;
+    // 记录已经写入新文件的共享记录：多个键引用同一条记录时（去重模式），
+    // 只要还有键引用它，就只写入一次，其余的键写入引用条目
+    let mut written: HashMap<(FileId, ByteOffset), MemIndexEntry> = HashMap::new();
+    // 开启输出校验时记录每个键应有的内容，输出文件写完后与重新打开得到的索引比较
+    let mut expected: Option<HashMap<Key, ExpectedRecord>> = options.verify_compaction.then(HashMap::new);
+    // 已经过期的键以及超过保留期限的记录在压缩时直接丢弃
+    let now = now_millis();
+    let horizon = options
+        .retention
+        .filter(|_| !options.append_only)
+        .map(|retention| timestamp_at(now.saturating_sub(retention.as_millis() as u64)));
+    // 加载时墓碑不会留在内存索引中，只记录了被删除的键
+    if !plan.includes_oldest {
+        let removed: Vec<(Key, u64)> = mem_index.removed().map(|(key, timestamp)| (key.to_vec(), timestamp)).collect();
+        for (key, timestamp) in removed {
+            discard(plan, &new_log_file, &mut expected, key, timestamp)?;
+        }
+    }
     // 创建内存索引的迭代器
     let iter = mem_index.into_iter();
     // 遍历内存索引中的每个条目
     for (key, mem_index_entry) in iter {
+        // 软删除的墓碑和它保留的值一起被丢弃
+        if mem_index_entry.is_tombstone() || (mem_index_entry.is_expired(now) && !options.append_only) {
+            discard(plan, &new_log_file, &mut expected, key, mem_index_entry.timestamp)?;
+            continue;
+        }
+        // 旧版本写入的记录没有时间戳，无法判断写入时间，总是保留
+        if matches!(horizon, Some(horizon) if mem_index_entry.timestamp != 0 && mem_index_entry.timestamp < horizon) {
+            discard(plan, &new_log_file, &mut expected, key, mem_index_entry.timestamp)?;
+            continue;
+        }
+        if let Some((filter, blobs, decoder)) = &filter {
+            let value = match (mem_index_entry.blob, mem_index_entry.compressed) {
+                (true, _) => blobs.read(mem_index_entry.value_offset, mem_index_entry.value_size)?,
+                (false, true) => decoder.decode(&disk_logs.get(&mem_index_entry)?)?,
+                (false, false) => disk_logs.get(&mem_index_entry)?,
+            };
+            match filter.decide(&key, &value, mem_index_entry.metadata.as_deref()) {
+                CompactionDecision::Keep => {}
+                CompactionDecision::Drop => {
+                    discard(plan, &new_log_file, &mut expected, key, mem_index_entry.timestamp)?;
+                    continue;
+                }
+                CompactionDecision::Replace(value) => {
+                    if let Some(expected) = &mut expected {
+                        let stored = ExpectedValue::Stored(crc32c::crc32c(&value), false);
+                        expected.insert(key.clone(), ExpectedRecord::new(stored, &mem_index_entry));
+                    }
+                    let mut disk_log_entry = DiskLogEntry::new_entry(key, value);
+                    disk_log_entry.expire_at = mem_index_entry.expire_at;
+                    disk_log_entry.metadata = mem_index_entry.metadata.clone();
+                    disk_log_entry.timestamp = entry_timestamp(&mem_index_entry);
+                    new_log_file.append_new_entry(disk_log_entry)?;
+                    continue;
+                }
+            }
+        }
+        // 大值文件不需要重写，只复制指针
+        if mem_index_entry.blob {
+            if let Some(expected) = &mut expected {
+                let blob = ExpectedValue::Blob(mem_index_entry.value_offset, mem_index_entry.value_size);
+                expected.insert(key.clone(), ExpectedRecord::new(blob, &mem_index_entry));
+            }
+            let mut pointer = DiskLogEntry::new_blob_pointer(
+                key,
+                mem_index_entry.value_offset,
+                mem_index_entry.value_size,
+            );
+            pointer.expire_at = mem_index_entry.expire_at;
+            pointer.metadata = mem_index_entry.metadata.clone();
+            pointer.timestamp = entry_timestamp(&mem_index_entry);
+            new_log_file.append_new_entry(pointer)?;
+            continue;
+        }
+        let location = (mem_index_entry.file_id, mem_index_entry.value_offset);
+        if let Some(shared) = written.get(&location) {
+            if let (Some(expected), Some(check_sum)) = (&mut expected, shared.check_sum) {
+                let stored = ExpectedValue::Stored(check_sum, shared.compressed);
+                expected.insert(key.clone(), ExpectedRecord::new(stored, &mem_index_entry));
+            }
+            let mut reference = DiskLogEntry::new_reference(
+                key,
+                shared.value_offset,
+                shared.value_size,
+                shared.compressed,
+            );
+            reference.expire_at = mem_index_entry.expire_at;
+            reference.metadata = mem_index_entry.metadata.clone();
+            reference.timestamp = entry_timestamp(&mem_index_entry);
+            new_log_file.append_new_entry(reference)?;
+            continue;
+        }
         // 根据内存索引条目从磁盘日志中获取对应的值
         let value = disk_logs.get(&mem_index_entry)?;
-        // 创建一个新的磁盘日志条目
-        let disk_log_entry = DiskLogEntry::new_entry(key, value);
+        if let Some(expected) = &mut expected {
+            let stored = ExpectedValue::Stored(crc32c::crc32c(&value), mem_index_entry.compressed);
+            expected.insert(key.clone(), ExpectedRecord::new(stored, &mem_index_entry));
+        }
+        // 创建一个新的磁盘日志条目，压缩过的值原样保留
+        let mut disk_log_entry = DiskLogEntry::new_entry(key, value);
+        disk_log_entry.compressed = mem_index_entry.compressed;
+        disk_log_entry.expire_at = mem_index_entry.expire_at;
+        disk_log_entry.metadata = mem_index_entry.metadata.clone();
+        disk_log_entry.timestamp = entry_timestamp(&mem_index_entry);
         // 将新的磁盘日志条目写入新的日志文件中
-        new_log_file.append_new_entry(disk_log_entry)?;
+        let value_offset = new_log_file.append_new_entry(disk_log_entry.clone())?;
+        let new_entry = MemIndexEntry::from_log_entry(new_log_file.file_id, value_offset, &disk_log_entry);
+        written.insert(location, new_entry);
+    }
+    let output = new_log_file.persist()?;
+    if let Some(expected) = expected {
+        // 校验失败的输出不能留在输出目录中，否则之后打开这个目录时会被当作数据文件加载
+        verify_compaction_output(&output, naming, expected)
+            .inspect_err(|_| drop(std::fs::remove_file(&output)))?;
     }
     // 返回Ok(())表示操作成功
     Ok(())
 }
+
+/// 在压缩中丢弃一个键。合并的文件包含最旧的文件时直接跳过；否则更旧的文件中可能还有这个键被覆盖的值，
+/// 写入一个墓碑使它继续被覆盖。
+fn discard(
+    plan: &CompactionPlan,
+    new_log_file: &DiskLogFile,
+    expected: &mut Option<HashMap<Key, ExpectedRecord>>,
+    key: Key,
+    timestamp: u64,
+) -> Result<(), BitCaskError> {
+    if plan.includes_oldest {
+        return Ok(());
+    }
+    if let Some(expected) = expected {
+        expected.insert(key.clone(), ExpectedRecord::tombstone());
+    }
+    let mut tombstone = DiskLogEntry::new_tombstone(key);
+    tombstone.timestamp = Some(timestamp).filter(|timestamp| *timestamp != 0);
+    new_log_file.append_new_entry(tombstone)?;
+    Ok(())
+}
+
+/// 压缩输出中一个键应有的内容，见`BitCaskOptions::verify_compaction`。
+#[derive(Debug, PartialEq)]
+struct ExpectedRecord {
+    value: ExpectedValue,
+    expire_at: Option<u64>,
+    metadata: Option<Vec<u8>>,
+}
+
+/// 压缩输出中一个键应有的值。
+#[derive(Debug, PartialEq)]
+enum ExpectedValue {
+    /// 存储在数据文件中的值：磁盘上的字节（可能经过压缩）的CRC32C校验和，以及值是否经过压缩。
+    Stored(u32, bool),
+    /// 大值指针：大值文件的ID和值的大小。
+    Blob(ByteOffset, ByteSize),
+    /// 墓碑，见`discard`。
+    Tombstone,
+}
+
+impl ExpectedRecord {
+    /// 用值和源索引项中的过期时间、元数据构造。
+    fn new(value: ExpectedValue, mem_index_entry: &MemIndexEntry) -> Self {
+        Self {
+            value,
+            expire_at: mem_index_entry.expire_at,
+            metadata: mem_index_entry.metadata.clone(),
+        }
+    }
+
+    /// 墓碑没有过期时间和元数据。
+    fn tombstone() -> Self {
+        Self {
+            value: ExpectedValue::Tombstone,
+            expire_at: None,
+            metadata: None,
+        }
+    }
+}
+
+/// 重新打开压缩输出的文件：加载时每条记录都会按校验和校验，再逐个读取值，
+/// 检查重建的索引与合并时写入的键完全一致，没有多出、缺少或者内容不同的键。
+fn verify_compaction_output(
+    output: &Path,
+    naming: FileNaming,
+    mut expected: HashMap<Key, ExpectedRecord>,
+) -> Result<(), BitCaskError> {
+    let mut mem_index = MemIndexStorage::new();
+    let disk_logs = DiskLogFileStorage::immutable_initialization(vec![output.to_path_buf()], naming, &mut mem_index)?;
+    for (key, _) in mem_index.removed() {
+        if expected.remove(key) != Some(ExpectedRecord::tombstone()) {
+            return Err(BitCaskError::CorruptedData(format!(
+                "compaction output {:?} has an unexpected tombstone for key {:?}",
+                output, key
+            )));
+        }
+    }
+    for (key, mem_index_entry) in mem_index.into_iter() {
+        let actual = match (mem_index_entry.is_tombstone(), mem_index_entry.blob) {
+            (true, _) => ExpectedRecord::tombstone(),
+            (false, true) => ExpectedRecord::new(
+                ExpectedValue::Blob(mem_index_entry.value_offset, mem_index_entry.value_size),
+                &mem_index_entry,
+            ),
+            (false, false) => ExpectedRecord::new(
+                ExpectedValue::Stored(crc32c::crc32c(&disk_logs.get(&mem_index_entry)?), mem_index_entry.compressed),
+                &mem_index_entry,
+            ),
+        };
+        match expected.remove(&key) {
+            Some(record) if record == actual => {}
+            record => {
+                return Err(BitCaskError::CorruptedData(format!(
+                    "compaction output {:?} has {:?} for key {:?}, expected {:?}",
+                    output, actual, key, record
+                )))
+            }
+        }
+    }
+    match expected.keys().next() {
+        Some(key) => Err(BitCaskError::CorruptedData(format!(
+            "compaction output {:?} is missing {} keys, including {:?}",
+            output,
+            expected.len(),
+            key
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// 按`BitCaskOptions::reserved_space`在数据目录中创建保留文件。
+///
+/// 保留空间只是尽力而为：磁盘已经写满等原因导致创建失败时只记录警告，不影响打开存储。
+fn create_reserve(data_dir: &Path, options: &BitCaskOptions) -> Option<SpaceReserve> {
+    let size = options.reserved_space?;
+    SpaceReserve::create(data_dir, size)
+        .inspect_err(|e| warn!("Error while creating the reserve file in {:?}: {:?}", data_dir, e))
+        .ok()
+}
+
+/// 打开时压缩的输出目录，位于数据目录中。
+const COMPACT_ON_OPEN_DIR: &str = "compact-on-open";
+
+/// 上一次在原目录中压缩到一半崩溃留下的输出不包含任何独有的数据，直接删除。
+fn remove_compaction_leftovers(data_dir: &Path) -> Result<(), BitCaskError> {
+    let compaction_dir = data_dir.join(COMPACT_ON_OPEN_DIR);
+    if compaction_dir.exists() {
+        std::fs::remove_dir_all(&compaction_dir)?;
+    }
+    Ok(())
+}
+
+/// 把数据目录中的`files`合并为一个ID比它们都大的新文件，再删除这些文件，只在持有排他的目录锁时调用。
+///
+/// 旧文件按从旧到新的顺序删除：崩溃时剩下的是最新的若干个旧文件加上合并后的文件，
+/// 每个键在剩下的文件中的最后一条记录都与合并前相同，因此重新打开后的数据不变。
+/// 每个旧文件在删除之前触发`FileEvent::Superseded`。
+fn compact_in_place(data_dir: &Path, mut files: Vec<PathBuf>, options: &BitCaskOptions) -> Result<(), BitCaskError> {
+    let naming = &options.file_naming;
+    files.sort_by_key(|path| naming.file_id(path));
+    let Some(last_id) = files.last().and_then(|path| naming.file_id(path)) else {
+        return Ok(());
+    };
+    let compaction_dir = data_dir.join(COMPACT_ON_OPEN_DIR);
+    let plan = CompactionPlan {
+        files: files.clone(),
+        output_id: 0,
+        includes_oldest: true,
+    };
+    start_compaction(&plan, compaction_dir.clone(), options)?;
+    std::fs::rename(
+        compaction_dir.join(naming.file_name(0)),
+        data_dir.join(naming.file_name(last_id + 1)),
+    )?;
+    // 合并后的文件必须先于旧文件的删除持久化，否则断电后可能两者都不存在
+    if options.sync_directory {
+        sync_dir(data_dir)?;
+    }
+    for path in files {
+        if let Some(hook) = &options.file_hook {
+            hook.fire(FileEvent::Superseded(path.clone()));
+        }
+        std::fs::remove_file(path)?;
+    }
+    std::fs::remove_dir_all(compaction_dir)?;
+    if options.sync_directory {
+        sync_dir(data_dir)?;
+    }
+    Ok(())
+}
+
+/// 返回内存索引项的时间戳，旧版本写入的没有时间戳的条目返回`None`。
+fn entry_timestamp(mem_index_entry: &MemIndexEntry) -> Option<u64> {
+    Some(mem_index_entry.timestamp).filter(|timestamp| *timestamp != 0)
+}
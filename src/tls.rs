@@ -0,0 +1,60 @@
+use crate::error::BitCaskError;
+use anyhow::anyhow;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// `TlsOptions` 配置网络前端的TLS，证书和私钥从PEM文件读取。
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    /// 服务端证书链，第一个证书是服务端自己的证书。
+    pub cert_file: PathBuf,
+    /// 服务端证书的私钥（PKCS#8、PKCS#1或SEC1格式）。
+    pub key_file: PathBuf,
+    /// 用于验证客户端证书的CA证书。设置后开启双向TLS（mTLS），没有有效客户端证书的连接会在握手时被拒绝；
+    /// 为`None`时（默认）不要求客户端证书。
+    pub client_ca_file: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    /// 返回使用给定证书链和私钥、不验证客户端证书的配置。
+    pub fn new<T: Into<PathBuf>>(cert_file: T, key_file: T) -> Self {
+        Self {
+            cert_file: cert_file.into(),
+            key_file: key_file.into(),
+            client_ca_file: None,
+        }
+    }
+
+    /// 读取证书和私钥，构造rustls的服务端配置，文件不存在或无法解析时返回错误。
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, BitCaskError> {
+        let certs = CertificateDer::pem_file_iter(&self.cert_file)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| anyhow!("invalid certificate file {:?}: {}", self.cert_file, e))?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_file)
+            .map_err(|e| anyhow!("invalid private key file {:?}: {}", self.key_file, e))?;
+        let builder = match &self.client_ca_file {
+            Some(client_ca_file) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(client_ca_file)
+                    .map_err(|e| anyhow!("invalid client CA file {:?}: {}", client_ca_file, e))?
+                {
+                    let cert = cert.map_err(|e| anyhow!("invalid client CA file {:?}: {}", client_ca_file, e))?;
+                    roots.add(cert).map_err(|e| anyhow!("invalid client CA certificate: {}", e))?;
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                    .build()
+                    .map_err(|e| anyhow!("invalid client CA certificates: {}", e))?;
+                ServerConfig::builder().with_client_cert_verifier(verifier)
+            }
+            None => ServerConfig::builder().with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| anyhow!("invalid certificate or private key: {}", e))?;
+        Ok(Arc::new(config))
+    }
+}
@@ -0,0 +1,516 @@
+use crate::bitcask::{BatchWrite, BitCask, Condition, KVStorage, Key, PutOption, Value};
+use crate::clock::now_millis;
+use crate::error::BitCaskError;
+use crc::{Crc, CRC_32_CKSUM};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::Duration;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
+
+/// 每条记录头部的长度：4字节的负载长度加上4字节的CRC32校验和。
+const RECORD_HEADER_SIZE: usize = 8;
+
+const OP_PUT: u8 = 0;
+const OP_DELETE: u8 = 1;
+const OP_PUT_MANY_SORTED: u8 = 2;
+const OP_SWEEP_EXPIRED: u8 = 3;
+const OP_SOFT_DELETE: u8 = 4;
+const OP_UNDELETE: u8 = 5;
+const OP_RENAME: u8 = 6;
+const OP_COPY: u8 = 7;
+const OP_PUT_ALL_IF: u8 = 8;
+
+const CONDITION_VERSION: u8 = 0;
+const CONDITION_VALUE: u8 = 1;
+const CONDITION_ABSENT: u8 = 2;
+
+const WRITE_PUT: u8 = 0;
+const WRITE_DELETE: u8 = 1;
+
+const FLAG_NX: u8 = 1;
+const FLAG_XX: u8 = 1 << 1;
+const FLAG_TTL: u8 = 1 << 2;
+const FLAG_EXPECTED_VERSION: u8 = 1 << 3;
+const FLAG_SYNC: u8 = 1 << 4;
+const FLAG_REQUEST_ID: u8 = 1 << 5;
+const FLAG_METADATA: u8 = 1 << 6;
+
+/// `TraceOp` 是操作记录文件中的一次修改操作。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceOp {
+    /// 一次`put_with_option`调用（包括`put`和`put_with_ack`），保留调用时的所有选项。
+    Put {
+        key: Key,
+        value: Value,
+        nx: bool,
+        xx: bool,
+        ttl: Option<Duration>,
+        expected_version: Option<u64>,
+        sync: bool,
+        request_id: Option<u64>,
+        metadata: Option<Vec<u8>>,
+    },
+    /// 一次`delete`调用。
+    Delete { key: Key },
+    /// 一次`put_many_sorted`调用。
+    PutManySorted { pairs: Vec<(Key, Value)> },
+    /// 一次清理了至少一个过期键的`sweep_expired`调用（包括后台清理）。
+    SweepExpired { limit: usize },
+    /// 一次`soft_delete`调用。
+    SoftDelete { key: Key },
+    /// 一次`undelete`调用。
+    Undelete { key: Key },
+    /// 一次`rename`调用。
+    Rename { old: Key, new: Key, nx: bool },
+    /// 一次`copy`调用。
+    Copy { src: Key, dst: Key, nx: bool },
+    /// 一次`put_all_if`调用。
+    PutAllIf {
+        conditions: Vec<Condition>,
+        writes: Vec<BatchWrite>,
+    },
+}
+
+/// `TraceRecord` 是操作记录文件中的一条记录。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// 操作开始执行时的Unix毫秒时间戳。
+    pub at: u64,
+    /// 执行的操作。
+    pub op: TraceOp,
+}
+
+impl TraceRecord {
+    /// 将记录编码为`负载长度 | CRC32 | 负载`，负载以时间戳和操作类型开头。
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&self.at.to_le_bytes());
+        match &self.op {
+            TraceOp::Put {
+                key,
+                value,
+                nx,
+                xx,
+                ttl,
+                expected_version,
+                sync,
+                request_id,
+                metadata,
+            } => {
+                payload.push(OP_PUT);
+                let mut flags = 0;
+                if *nx {
+                    flags |= FLAG_NX;
+                }
+                if *xx {
+                    flags |= FLAG_XX;
+                }
+                if ttl.is_some() {
+                    flags |= FLAG_TTL;
+                }
+                if expected_version.is_some() {
+                    flags |= FLAG_EXPECTED_VERSION;
+                }
+                if *sync {
+                    flags |= FLAG_SYNC;
+                }
+                if request_id.is_some() {
+                    flags |= FLAG_REQUEST_ID;
+                }
+                if metadata.is_some() {
+                    flags |= FLAG_METADATA;
+                }
+                payload.push(flags);
+                if let Some(ttl) = ttl {
+                    payload.extend_from_slice(&(ttl.as_millis() as u64).to_le_bytes());
+                }
+                if let Some(expected_version) = expected_version {
+                    payload.extend_from_slice(&expected_version.to_le_bytes());
+                }
+                if let Some(request_id) = request_id {
+                    payload.extend_from_slice(&request_id.to_le_bytes());
+                }
+                if let Some(metadata) = metadata {
+                    encode_bytes(&mut payload, metadata);
+                }
+                encode_bytes(&mut payload, key);
+                encode_bytes(&mut payload, value);
+            }
+            TraceOp::Delete { key } => {
+                payload.push(OP_DELETE);
+                encode_bytes(&mut payload, key);
+            }
+            TraceOp::PutManySorted { pairs } => {
+                payload.push(OP_PUT_MANY_SORTED);
+                payload.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+                for (key, value) in pairs {
+                    encode_bytes(&mut payload, key);
+                    encode_bytes(&mut payload, value);
+                }
+            }
+            TraceOp::SweepExpired { limit } => {
+                payload.push(OP_SWEEP_EXPIRED);
+                payload.extend_from_slice(&(*limit as u64).to_le_bytes());
+            }
+            TraceOp::SoftDelete { key } => {
+                payload.push(OP_SOFT_DELETE);
+                encode_bytes(&mut payload, key);
+            }
+            TraceOp::Undelete { key } => {
+                payload.push(OP_UNDELETE);
+                encode_bytes(&mut payload, key);
+            }
+            TraceOp::Rename { old, new, nx } => {
+                payload.push(OP_RENAME);
+                payload.push(*nx as u8);
+                encode_bytes(&mut payload, old);
+                encode_bytes(&mut payload, new);
+            }
+            TraceOp::Copy { src, dst, nx } => {
+                payload.push(OP_COPY);
+                payload.push(*nx as u8);
+                encode_bytes(&mut payload, src);
+                encode_bytes(&mut payload, dst);
+            }
+            TraceOp::PutAllIf { conditions, writes } => {
+                payload.push(OP_PUT_ALL_IF);
+                payload.extend_from_slice(&(conditions.len() as u32).to_le_bytes());
+                for condition in conditions {
+                    match condition {
+                        Condition::Version(key, version) => {
+                            payload.push(CONDITION_VERSION);
+                            encode_bytes(&mut payload, key);
+                            payload.extend_from_slice(&version.to_le_bytes());
+                        }
+                        Condition::Value(key, value) => {
+                            payload.push(CONDITION_VALUE);
+                            encode_bytes(&mut payload, key);
+                            encode_bytes(&mut payload, value);
+                        }
+                        Condition::Absent(key) => {
+                            payload.push(CONDITION_ABSENT);
+                            encode_bytes(&mut payload, key);
+                        }
+                    }
+                }
+                payload.extend_from_slice(&(writes.len() as u32).to_le_bytes());
+                for write in writes {
+                    match write {
+                        BatchWrite::Put(key, value) => {
+                            payload.push(WRITE_PUT);
+                            encode_bytes(&mut payload, key);
+                            encode_bytes(&mut payload, value);
+                        }
+                        BatchWrite::Delete(key) => {
+                            payload.push(WRITE_DELETE);
+                            encode_bytes(&mut payload, key);
+                        }
+                    }
+                }
+            }
+        }
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&CRC32.checksum(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        record
+    }
+
+    /// 从校验过的负载中解码记录。
+    fn decode(payload: &[u8]) -> Result<Self, BitCaskError> {
+        let mut cursor = PayloadCursor { payload };
+        let at = cursor.u64()?;
+        let op = match cursor.u8()? {
+            OP_PUT => {
+                let flags = cursor.u8()?;
+                let ttl = match flags & FLAG_TTL {
+                    0 => None,
+                    _ => Some(Duration::from_millis(cursor.u64()?)),
+                };
+                let expected_version = match flags & FLAG_EXPECTED_VERSION {
+                    0 => None,
+                    _ => Some(cursor.u64()?),
+                };
+                let request_id = match flags & FLAG_REQUEST_ID {
+                    0 => None,
+                    _ => Some(cursor.u64()?),
+                };
+                let metadata = match flags & FLAG_METADATA {
+                    0 => None,
+                    _ => Some(cursor.bytes()?),
+                };
+                TraceOp::Put {
+                    key: cursor.bytes()?,
+                    value: cursor.bytes()?,
+                    nx: flags & FLAG_NX != 0,
+                    xx: flags & FLAG_XX != 0,
+                    ttl,
+                    expected_version,
+                    sync: flags & FLAG_SYNC != 0,
+                    request_id,
+                    metadata,
+                }
+            }
+            OP_DELETE => TraceOp::Delete { key: cursor.bytes()? },
+            OP_PUT_MANY_SORTED => {
+                let count = cursor.u32()?;
+                let pairs = (0..count)
+                    .map(|_| Ok((cursor.bytes()?, cursor.bytes()?)))
+                    .collect::<Result<_, BitCaskError>>()?;
+                TraceOp::PutManySorted { pairs }
+            }
+            OP_SWEEP_EXPIRED => TraceOp::SweepExpired {
+                limit: cursor.u64()? as usize,
+            },
+            OP_SOFT_DELETE => TraceOp::SoftDelete { key: cursor.bytes()? },
+            OP_UNDELETE => TraceOp::Undelete { key: cursor.bytes()? },
+            OP_RENAME => TraceOp::Rename {
+                nx: cursor.u8()? != 0,
+                old: cursor.bytes()?,
+                new: cursor.bytes()?,
+            },
+            OP_COPY => TraceOp::Copy {
+                nx: cursor.u8()? != 0,
+                src: cursor.bytes()?,
+                dst: cursor.bytes()?,
+            },
+            OP_PUT_ALL_IF => {
+                let conditions = (0..cursor.u32()?)
+                    .map(|_| match cursor.u8()? {
+                        CONDITION_VERSION => Ok(Condition::Version(cursor.bytes()?, cursor.u64()?)),
+                        CONDITION_VALUE => Ok(Condition::Value(cursor.bytes()?, cursor.bytes()?)),
+                        CONDITION_ABSENT => Ok(Condition::Absent(cursor.bytes()?)),
+                        kind => Err(BitCaskError::CorruptedData(format!("unknown trace condition {}", kind))),
+                    })
+                    .collect::<Result<_, BitCaskError>>()?;
+                let writes = (0..cursor.u32()?)
+                    .map(|_| match cursor.u8()? {
+                        WRITE_PUT => Ok(BatchWrite::Put(cursor.bytes()?, cursor.bytes()?)),
+                        WRITE_DELETE => Ok(BatchWrite::Delete(cursor.bytes()?)),
+                        kind => Err(BitCaskError::CorruptedData(format!("unknown trace write {}", kind))),
+                    })
+                    .collect::<Result<_, BitCaskError>>()?;
+                TraceOp::PutAllIf { conditions, writes }
+            }
+            op => {
+                return Err(BitCaskError::CorruptedData(format!(
+                    "unknown trace operation {}",
+                    op
+                )))
+            }
+        };
+        Ok(Self { at, op })
+    }
+}
+
+/// 以`4字节长度 | 字节`的形式写入一段字节。
+pub(crate) fn encode_bytes(payload: &mut Vec<u8>, bytes: &[u8]) {
+    payload.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    payload.extend_from_slice(bytes);
+}
+
+/// 按顺序读取记录负载中的字段，也用于读取keydir文件。
+pub(crate) struct PayloadCursor<'a> {
+    payload: &'a [u8],
+}
+
+impl<'a> PayloadCursor<'a> {
+    /// 创建一个从`payload`开头读取的游标。
+    pub(crate) fn new(payload: &'a [u8]) -> Self {
+        Self { payload }
+    }
+
+    /// 是否已经读完所有字节。
+    pub(crate) fn is_empty(&self) -> bool {
+        self.payload.is_empty()
+    }
+
+    /// 返回剩余未读取的字节数。
+    pub(crate) fn remaining(&self) -> usize {
+        self.payload.len()
+    }
+
+    /// 取出接下来的`len`个字节。
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], BitCaskError> {
+        if self.payload.len() < len {
+            return Err(BitCaskError::CorruptedData("truncated record".to_string()));
+        }
+        let (head, rest) = self.payload.split_at(len);
+        self.payload = rest;
+        Ok(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, BitCaskError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, BitCaskError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, BitCaskError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<Vec<u8>, BitCaskError> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+/// `TraceWriter` 把修改操作追加到操作记录文件中，见`BitCaskOptions::trace_file`。
+pub(crate) struct TraceWriter {
+    file: File,
+}
+
+impl TraceWriter {
+    /// 以追加方式打开操作记录文件，不存在时创建。
+    pub(crate) fn open(path: &Path) -> Result<Self, BitCaskError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// 追加一条以当前时间为时间戳的记录，每条记录只需要一次写入。
+    pub(crate) fn record(&mut self, op: TraceOp) -> Result<(), BitCaskError> {
+        let record = TraceRecord { at: now_millis(), op };
+        self.file.write_all(&record.encode())?;
+        Ok(())
+    }
+}
+
+/// `TraceReader` 按顺序读取操作记录文件中的记录。
+///
+/// 记录文件末尾不完整的记录（例如进程在写入记录时崩溃）会被忽略，
+/// 校验和不匹配的记录则作为`BitCaskError::CorruptedData`返回。
+pub struct TraceReader {
+    reader: BufReader<File>,
+}
+
+impl TraceReader {
+    /// 打开操作记录文件。
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, BitCaskError> {
+        Ok(Self {
+            reader: BufReader::new(File::open(path)?),
+        })
+    }
+
+    /// 读取下一条记录，到达文件末尾时返回`None`。
+    fn read_record(&mut self) -> Result<Option<TraceRecord>, BitCaskError> {
+        let mut header = [0; RECORD_HEADER_SIZE];
+        if !self.read_full(&mut header)? {
+            return Ok(None);
+        }
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let check_sum = u32::from_le_bytes(header[4..].try_into().unwrap());
+        // 只按实际读到的数据分配内存，损坏的长度字段不会导致分配几个GB的缓冲区
+        let mut payload = Vec::new();
+        if (&mut self.reader).take(len as u64).read_to_end(&mut payload)? < len {
+            return Ok(None);
+        }
+        if CRC32.checksum(&payload) != check_sum {
+            return Err(BitCaskError::CorruptedData("trace record checksum mismatch".to_string()));
+        }
+        TraceRecord::decode(&payload).map(Some)
+    }
+
+    /// 填满`buf`，文件在读取完整之前结束时返回`false`。
+    fn read_full(&mut self, buf: &mut [u8]) -> Result<bool, BitCaskError> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = Result<TraceRecord, BitCaskError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// `ReplaySpeed` 决定`replay`执行记录的速度。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplaySpeed {
+    /// 按记录的时间间隔执行，重现原来的负载节奏。
+    Original,
+    /// 不等待，尽可能快地执行所有记录。
+    Max,
+}
+
+/// `ReplayReport` 是`replay`的结果统计。
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// 执行的操作数量。
+    pub operations: usize,
+    /// 执行失败的操作数量，例如条件不满足的写入；原来执行失败的操作重放时通常也会失败。
+    pub failed: usize,
+    /// 执行所有操作花费的时间。
+    pub elapsed: Duration,
+}
+
+/// 在给定的存储上按顺序重新执行操作记录文件中的所有操作。
+///
+/// 为了重现原来的结果，通常应该在一个空的存储上重放从空存储开始录制的记录。
+/// 单个操作失败不会中止重放，只会计入`ReplayReport::failed`；读取记录文件失败时返回错误。
+pub fn replay<P: AsRef<Path>>(
+    trace_file: P,
+    bitcask: &mut BitCask,
+    speed: ReplaySpeed,
+) -> Result<ReplayReport, BitCaskError> {
+    let started = std::time::Instant::now();
+    let mut report = ReplayReport::default();
+    let mut first_at = None;
+    for record in TraceReader::open(trace_file)? {
+        let record = record?;
+        if speed == ReplaySpeed::Original {
+            let first_at = *first_at.get_or_insert(record.at);
+            let due = Duration::from_millis(record.at.saturating_sub(first_at));
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+        let res = match record.op {
+            TraceOp::Put {
+                key,
+                value,
+                nx,
+                xx,
+                ttl,
+                expected_version,
+                sync,
+                request_id,
+                metadata,
+            } => {
+                let option = PutOption {
+                    nx,
+                    xx,
+                    ttl,
+                    expected_version,
+                    sync,
+                    request_id,
+                    metadata,
+                };
+                bitcask.put_with_option(&key, &value, Some(option))
+            }
+            TraceOp::Delete { key } => bitcask.delete(&key),
+            TraceOp::PutManySorted { pairs } => bitcask.put_many_sorted(&pairs),
+            TraceOp::SweepExpired { limit } => bitcask.sweep_expired(limit).map(|_| ()),
+            TraceOp::SoftDelete { key } => bitcask.soft_delete(&key),
+            TraceOp::Undelete { key } => bitcask.undelete(&key),
+            TraceOp::Rename { old, new, nx } => bitcask.rename(&old, &new, nx),
+            TraceOp::Copy { src, dst, nx } => bitcask.copy(&src, &dst, nx),
+            TraceOp::PutAllIf { conditions, writes } => bitcask.put_all_if(&conditions, &writes),
+        };
+        report.operations += 1;
+        if res.is_err() {
+            report.failed += 1;
+        }
+    }
+    report.elapsed = started.elapsed();
+    Ok(report)
+}
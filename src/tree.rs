@@ -0,0 +1,181 @@
+//! 以键前缀划分的子存储，通过`BitCask::open_tree`打开。
+//!
+//! 每个树映射为存储中的一个键前缀：2字节大端序的树名长度加上树名，因此同一个存储中的树互不重叠，
+//! 名字为空的树也不会与其他树的键混在一起。树与打开它的存储共享数据文件、写锁和快照，
+//! 写入、压缩和持久化的语义都与存储本身相同；`sled::Db::open_tree`打开的是同一组树。
+
+use crate::bitcask::{BitCask, KVStorage, Key, PutOption, Value, ValueMeta};
+use crate::error::BitCaskError;
+use std::time::{Duration, SystemTime};
+
+/// `Tree` 是存储中的一个键空间，克隆的树共享同一个存储。
+///
+/// 树实现了`KVStorage`，面向该trait编写的组件不需要知道自己使用的是整个存储还是其中的一个树。
+#[derive(Clone)]
+pub struct Tree {
+    bitcask: BitCask,
+    /// 树中的键在存储中的前缀。
+    prefix: Key,
+    /// 树中没有指定存活时间的写入使用的存活时间，见`Tree::with_default_ttl`。
+    default_ttl: Option<Duration>,
+}
+
+impl Tree {
+    pub(crate) fn new(bitcask: BitCask, name: &[u8]) -> Self {
+        let mut prefix = Vec::with_capacity(2 + name.len());
+        prefix.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        prefix.extend_from_slice(name);
+        Tree {
+            bitcask,
+            prefix,
+            default_ttl: None,
+        }
+    }
+
+    /// 返回共享同一个树、但没有通过`PutOption::ttl`指定存活时间的写入都在`ttl`后过期的句柄，
+    /// 优先于`BitCaskOptions::default_ttl`。默认值只属于返回的句柄，不会被持久化，
+    /// 同一个树的其他句柄和重新打开的树不受影响。
+    pub fn with_default_ttl(&self, ttl: Duration) -> Tree {
+        Tree {
+            default_ttl: Some(ttl),
+            ..self.clone()
+        }
+    }
+
+    /// 返回树的名字。
+    pub fn name(&self) -> &[u8] {
+        &self.prefix[2..]
+    }
+
+    /// 根据键获取值，读取失败时返回错误，见`BitCask::try_get`。
+    pub fn try_get(&self, key: &[u8]) -> Result<Option<Value>, BitCaskError> {
+        self.bitcask.try_get(&self.tree_key(key))
+    }
+
+    /// 返回键的剩余存活时间，见`BitCask::ttl`。
+    pub fn ttl(&self, key: &[u8]) -> Option<Duration> {
+        self.bitcask.ttl(&self.tree_key(key))
+    }
+
+    /// 返回键的过期时间，见`BitCask::expires_at`。
+    pub fn expires_at(&self, key: &[u8]) -> Option<SystemTime> {
+        self.bitcask.expires_at(&self.tree_key(key))
+    }
+
+    /// 读取键的值和元信息，见`BitCask::get_with_meta`。
+    pub fn get_with_meta(&self, key: &[u8]) -> Result<Option<ValueMeta>, BitCaskError> {
+        self.bitcask.get_with_meta(&self.tree_key(key))
+    }
+
+    /// 按键的顺序遍历树中的所有键值对，产生的键不包含树的前缀，快照语义与`BitCask::iter`相同。
+    pub fn iter(&self) -> Iter {
+        self.iter_prefix(&[])
+    }
+
+    /// 按键的顺序遍历树中以给定前缀开头的键值对。
+    pub fn iter_prefix(&self, prefix: &[u8]) -> Iter {
+        Iter {
+            inner: self.bitcask.iter_prefix(&self.tree_key(prefix)),
+            prefix_len: self.prefix.len(),
+        }
+    }
+
+    /// 以无状态的游标分页遍历树中的键，返回的键和游标都不包含树的前缀，语义见`BitCask::scan_keys`。
+    pub fn scan_keys(&self, cursor: Option<&[u8]>, count: usize) -> (Vec<Key>, Option<Key>) {
+        let cursor = cursor.map(|cursor| self.tree_key(cursor));
+        let (keys, next) = self.bitcask.scan_keys_with_prefix(&self.prefix, cursor.as_ref(), count);
+        let strip = |mut key: Key| key.split_off(self.prefix.len());
+        (keys.into_iter().map(strip).collect(), next.map(strip))
+    }
+
+    /// 返回树中未删除且未过期的键的数量，只遍历内存索引，不读取值。
+    pub fn len(&self) -> usize {
+        self.bitcask.count_prefix(&self.prefix)
+    }
+
+    /// 树中是否没有任何键。
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 删除树中的所有键，返回删除的键数量。
+    ///
+    /// 键被逐个删除，清空期间写入的键可能保留；删除失败时返回错误，已经删除的键不会恢复。
+    pub fn clear(&mut self) -> Result<usize, BitCaskError> {
+        let keys = self.bitcask.keys_with_prefix(&self.prefix);
+        for key in &keys {
+            self.bitcask.delete(key)?;
+        }
+        Ok(keys.len())
+    }
+
+    /// 将存储中目前为止的所有写入持久化到磁盘，见`BitCask::sync`，其他树的写入也会一起持久化。
+    pub fn sync(&self) -> Result<(), BitCaskError> {
+        self.bitcask.sync()
+    }
+
+    /// 返回树中的键在存储中的键。
+    fn tree_key(&self, key: &[u8]) -> Key {
+        let mut tree_key = Vec::with_capacity(self.prefix.len() + key.len());
+        tree_key.extend_from_slice(&self.prefix);
+        tree_key.extend_from_slice(key);
+        tree_key
+    }
+}
+
+impl KVStorage for Tree {
+    fn get(&self, key: &Key) -> Option<Value> {
+        self.bitcask.get(&self.tree_key(key))
+    }
+
+    fn put_with_option(&mut self, key: &Key, value: &Value, option: Option<PutOption>) -> Result<(), BitCaskError> {
+        let option = match (option, self.default_ttl) {
+            (option, None) => option,
+            (option, Some(ttl)) => {
+                let mut option = option.unwrap_or_default();
+                option.ttl = option.ttl.or(Some(ttl));
+                Some(option)
+            }
+        };
+        self.bitcask.put_with_option(&self.tree_key(key), value, option)
+    }
+
+    fn delete(&mut self, key: &Key) -> Result<(), BitCaskError> {
+        self.bitcask.delete(&self.tree_key(key))
+    }
+
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Result<(Key, Value), BitCaskError>> + '_> {
+        Box::new(self.iter())
+    }
+
+    fn contains_key(&self, key: &Key) -> bool {
+        self.bitcask.contains_key(&self.tree_key(key))
+    }
+
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn flush(&self) -> Result<(), BitCaskError> {
+        self.sync()
+    }
+}
+
+/// `Iter` 是`Tree::iter`和`Tree::iter_prefix`返回的迭代器，产生去掉了树前缀的键值对。
+pub struct Iter {
+    inner: crate::iter::Iter,
+    prefix_len: usize,
+}
+
+impl Iterator for Iter {
+    type Item = Result<(Key, Value), BitCaskError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pair = self.inner.next()?;
+        Some(pair.map(|(mut key, value)| (key.split_off(self.prefix_len), value)))
+    }
+}
@@ -0,0 +1,73 @@
+use crate::bitcask::{prefix_range, Key, Value};
+use crate::clock::now_millis;
+use crate::error::BitCaskError;
+use crate::iter::Iter;
+use crate::snapshot::ReadSnapshot;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+/// `ReadTxn` 是`BitCask::read_txn`返回的只读事务，提供可重复读的语义。
+///
+/// 事务在创建时固定一个读快照和当前时刻：事务存在期间的所有读取都看到同一个状态，
+/// 之后的写入、删除和压缩都不可见，键是否过期也始终按创建事务的时刻判断，
+/// 因此多步的读取逻辑（例如先统计数量再读取明细）看到的是一致的视图。
+///
+/// 与`Iter`相同，事务持有旧文件的句柄，长时间不释放会推迟旧文件所占磁盘空间的回收。
+pub struct ReadTxn {
+    snapshot: Arc<ReadSnapshot>,
+    /// 创建事务的时刻（Unix毫秒时间戳），用于判断键是否过期。
+    now: u64,
+}
+
+impl ReadTxn {
+    /// 在给定的快照上创建只读事务。
+    pub(crate) fn new(snapshot: Arc<ReadSnapshot>) -> Self {
+        Self {
+            snapshot,
+            now: now_millis(),
+        }
+    }
+
+    /// 根据键读取值，键不存在、已删除或已过期时返回`None`，读取磁盘日志失败时返回错误。
+    pub fn get(&self, key: &Key) -> Result<Option<Value>, BitCaskError> {
+        match self.snapshot.mem_index.lookup(key)? {
+            Some(mem_index_entry) if mem_index_entry.is_live(self.now) => {
+                self.snapshot.read_value(&mem_index_entry).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// 键是否存在且未过期，只查找索引。
+    pub fn contains_key(&self, key: &Key) -> Result<bool, BitCaskError> {
+        Ok(self
+            .snapshot
+            .mem_index
+            .lookup(key)?
+            .is_some_and(|mem_index_entry| mem_index_entry.is_live(self.now)))
+    }
+
+    /// 按键的顺序读取给定范围内的所有键值对，见`BitCask::scan`。
+    pub fn scan<R: RangeBounds<Key>>(&self, range: R) -> Result<Vec<(Key, Value)>, BitCaskError> {
+        self.iter(range).collect()
+    }
+
+    /// 按键的顺序遍历给定范围内的键值对，见`BitCask::iter`。
+    pub fn iter<R: RangeBounds<Key>>(&self, range: R) -> Iter {
+        Iter::at(self.snapshot.clone(), range, self.now)
+    }
+
+    /// 按键的顺序遍历以给定前缀开头的键值对，见`BitCask::iter_prefix`。
+    pub fn iter_prefix(&self, prefix: &[u8]) -> Iter {
+        self.iter(prefix_range(prefix))
+    }
+
+    /// 返回给定范围内未删除且未过期的键的数量，不读取值。
+    pub fn count<R: RangeBounds<Key>>(&self, range: R) -> usize {
+        self.snapshot
+            .full_index()
+            .range(range)
+            .filter(|(_, mem_index_entry)| mem_index_entry.is_live(self.now))
+            .count()
+    }
+}
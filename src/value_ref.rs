@@ -0,0 +1,75 @@
+use crate::bitcask::Value;
+use memmap2::Mmap;
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+/// `ValueRef` 是`BitCask::get_ref`返回的值，可以像`&[u8]`一样使用。
+///
+/// 日志记录中值的字节按原样连续存放在已知的偏移量处，不需要反序列化，
+/// 因此位于已封存数据文件中的未压缩值直接引用文件的内存映射，读取时不会复制；
+/// 其余的值（活跃文件中的值、压缩的值、内联的值和大值文件中的值）会被读取到一个新的缓冲区中。
+///
+/// 映射在最后一个引用它的`ValueRef`被丢弃之前保持有效，即使对应的文件已经被压缩替换。
+pub struct ValueRef {
+    inner: Inner,
+}
+
+enum Inner {
+    /// 引用内存映射中的一段字节。
+    Mapped { mmap: Arc<Mmap>, range: Range<usize> },
+    /// 读取到缓冲区中的值。
+    Owned(Value),
+}
+
+impl ValueRef {
+    /// 引用内存映射中`range`范围内的字节，调用方需要保证范围在映射之内。
+    pub(crate) fn mapped(mmap: Arc<Mmap>, range: Range<usize>) -> Self {
+        Self {
+            inner: Inner::Mapped { mmap, range },
+        }
+    }
+
+    /// 包装一个已经读取到缓冲区中的值。
+    pub(crate) fn owned(value: Value) -> Self {
+        Self {
+            inner: Inner::Owned(value),
+        }
+    }
+
+    /// 值是否直接引用了数据文件的内存映射，而没有被复制。
+    pub fn is_zero_copy(&self) -> bool {
+        matches!(self.inner, Inner::Mapped { .. })
+    }
+
+    /// 转换为`Value`，引用内存映射的值会在此时被复制。
+    pub fn into_value(self) -> Value {
+        match self.inner {
+            Inner::Mapped { mmap, range } => mmap[range].to_vec(),
+            Inner::Owned(value) => value,
+        }
+    }
+}
+
+impl Deref for ValueRef {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.inner {
+            Inner::Mapped { mmap, range } => &mmap[range.clone()],
+            Inner::Owned(value) => value,
+        }
+    }
+}
+
+impl AsRef<[u8]> for ValueRef {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for ValueRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
@@ -0,0 +1,154 @@
+use crate::bitcask::FileId;
+use crate::error::BitCaskError;
+use crate::options::{IoWatchdog, StallAction};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// 看门狗线程两次检查之间的最长间隔。
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `Watchdog` 监视正在进行的追加和fsync，见`BitCaskOptions::io_watchdog`。
+///
+/// 每个受监视的操作在开始时登记、结束时注销；后台线程定期检查登记的操作，
+/// 在操作仍然挂起时就报告超过期限的操作，操作完成之后由`Watchdog::run`按配置处理结果。
+pub(crate) struct Watchdog {
+    options: IoWatchdog,
+    /// 数据目录，用于诊断信息。
+    data_dir: PathBuf,
+    /// 正在进行的操作。
+    in_flight: Mutex<HashMap<u64, InFlight>>,
+    /// 下一个操作的编号。
+    next_id: AtomicU64,
+    /// 超过期限的操作数量。
+    stalls: AtomicU64,
+    /// 是否因为超过期限的操作进入了只读的降级模式，见`StallAction::ReadOnly`。
+    degraded: AtomicBool,
+}
+
+/// 一个正在进行的操作。
+struct InFlight {
+    op: &'static str,
+    file_id: FileId,
+    started: Instant,
+    /// 后台线程是否已经报告过这个操作。
+    reported: bool,
+}
+
+impl Watchdog {
+    /// 创建看门狗并启动检查正在进行的操作的后台线程，线程在看门狗被丢弃后退出。
+    pub(crate) fn start(options: IoWatchdog, data_dir: PathBuf) -> Arc<Self> {
+        let watchdog = Arc::new(Self {
+            options,
+            data_dir,
+            in_flight: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            stalls: AtomicU64::new(0),
+            degraded: AtomicBool::new(false),
+        });
+        let poll_interval = (watchdog.options.deadline / 2).clamp(Duration::from_millis(1), MAX_POLL_INTERVAL);
+        let weak = Arc::downgrade(&watchdog);
+        let spawned = std::thread::Builder::new()
+            .name("bitcask-io-watchdog".to_string())
+            .spawn(move || watch_loop(weak, poll_interval));
+        if let Err(e) = spawned {
+            warn!("Failed to start the IO watchdog thread, stalls are only reported after they finish: {:?}", e);
+        }
+        watchdog
+    }
+
+    /// 在看门狗的监视下执行一次IO操作。
+    ///
+    /// 操作超过期限时记录诊断信息，并按`StallAction`处理：`Fail`时成功的操作也返回`BitCaskError::IoStalled`，
+    /// 由调用方撤销已经完成的部分；`ReadOnly`时进入降级模式，操作本身的结果不变。
+    pub(crate) fn run<T>(
+        &self,
+        op: &'static str,
+        file_id: FileId,
+        f: impl FnOnce() -> Result<T, BitCaskError>,
+    ) -> Result<T, BitCaskError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let started = Instant::now();
+        self.in_flight().insert(
+            id,
+            InFlight {
+                op,
+                file_id,
+                started,
+                reported: false,
+            },
+        );
+        let res = f();
+        let elapsed = started.elapsed();
+        let reported = self.in_flight().remove(&id).is_some_and(|in_flight| in_flight.reported);
+        if elapsed <= self.options.deadline {
+            return res;
+        }
+        if reported {
+            warn!("{} on data file {} in {:?} finished after {:?}", op, file_id, self.data_dir, elapsed);
+        } else {
+            self.report(op, file_id, elapsed);
+        }
+        match self.options.action {
+            StallAction::Fail => res.and(Err(BitCaskError::IoStalled(format!("{} took {:?}", op, elapsed)))),
+            StallAction::Log | StallAction::ReadOnly => res,
+        }
+    }
+
+    /// 记录一次超过期限的操作，并在配置了降级模式时进入只读模式。
+    fn report(&self, op: &'static str, file_id: FileId, elapsed: Duration) {
+        warn!(
+            "{} on data file {} in {:?} has taken {:?}, exceeding the IO deadline of {:?}",
+            op, file_id, self.data_dir, elapsed, self.options.deadline
+        );
+        self.stalls.fetch_add(1, Ordering::Relaxed);
+        if self.options.action == StallAction::ReadOnly && !self.degraded.swap(true, Ordering::Relaxed) {
+            warn!("IO stalled in {:?}, entering read-only mode until writes are resumed", self.data_dir);
+        }
+    }
+
+    /// 返回超过期限的操作数量。
+    pub(crate) fn stalls(&self) -> u64 {
+        self.stalls.load(Ordering::Relaxed)
+    }
+
+    /// 存储是否处于只读的降级模式。
+    pub(crate) fn degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    /// 退出只读的降级模式，返回之前是否处于降级模式。
+    pub(crate) fn resume(&self) -> bool {
+        self.degraded.swap(false, Ordering::Relaxed)
+    }
+
+    /// 获取正在进行的操作的锁。持有锁时不会panic，因此忽略中毒。
+    fn in_flight(&self) -> std::sync::MutexGuard<'_, HashMap<u64, InFlight>> {
+        self.in_flight.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// 后台线程：定期报告仍在进行并且已经超过期限的操作，每个操作只报告一次。
+fn watch_loop(watchdog: Weak<Watchdog>, poll_interval: Duration) {
+    loop {
+        std::thread::sleep(poll_interval);
+        let Some(watchdog) = watchdog.upgrade() else {
+            return;
+        };
+        let stalled: Vec<_> = watchdog
+            .in_flight()
+            .values_mut()
+            .filter(|in_flight| !in_flight.reported && in_flight.started.elapsed() > watchdog.options.deadline)
+            .map(|in_flight| {
+                in_flight.reported = true;
+                (in_flight.op, in_flight.file_id, in_flight.started.elapsed())
+            })
+            .collect();
+        for (op, file_id, elapsed) in stalled {
+            watchdog.report(op, file_id, elapsed);
+        }
+    }
+}
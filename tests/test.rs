@@ -1,5 +1,31 @@
 use rand::Rng;
-use bitcask_engine_rs::bitcask::{BitCask, KVStorage, PutOption};
+use bitcask_engine_rs::actor::BitCaskActor;
+use bitcask_engine_rs::audit::{AuditOptions, AUDIT_FILE};
+use bitcask_engine_rs::auth::{AccessControl, Grant};
+use bitcask_engine_rs::compaction::SizeTiered;
+use bitcask_engine_rs::bitcask::{BatchWrite, BitCask, Condition, KVStorage, PutOption, MAX_METADATA_SIZE};
+use bitcask_engine_rs::dump::{dump, load, DumpFormat};
+use bitcask_engine_rs::error::BitCaskError;
+use bitcask_engine_rs::grpc::{BitCaskGrpc, ScanClient, ScanRequest};
+use bitcask_engine_rs::keyenc::{KeyDecoder, KeyEncoder};
+use bitcask_engine_rs::memcached;
+use bitcask_engine_rs::resp;
+use bitcask_engine_rs::options::{
+    BitCaskOptions, CapAction, CompactOnOpen, CompactionDecision, CompactionFilter, DictionaryCompression, DiskUsageCap,
+    ExpirySweep, FileEvent, FileHook, FileNaming, IndexBackendKind, IoWatchdog, SparseIndex, StallAction, SyncPolicy,
+};
+use bitcask_engine_rs::reader::ReadOnlyBitCask;
+use bitcask_engine_rs::salvage::{salvage, SALVAGE_REPORT};
+use bitcask_engine_rs::service::{Request, Response};
+use bitcask_engine_rs::shadow::ShadowStore;
+use bitcask_engine_rs::sled;
+use bitcask_engine_rs::tls::TlsOptions;
+use bitcask_engine_rs::trace::{replay, ReplaySpeed, TraceOp, TraceReader};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Waker};
+use std::time::{Duration, SystemTime};
+use tower_service::Service;
+use tracing_subscriber::fmt::format::FmtSpan;
 
 #[test]
 fn it_works() {
@@ -9,6 +35,60 @@ fn it_works() {
     assert_eq!(res, Some(vec![4, 5, 6]));
 }
 
+#[test]
+fn legacy_checksums() {
+    // A record written before CRC32C checksums: CRC_32_CKSUM | key size | value size | key | value.
+    let data_dir = generate_random_data_dir();
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let (key, value) = (b"old".to_vec(), b"value".to_vec());
+    let mut record = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM).checksum(&value).to_be_bytes().to_vec();
+    record.extend_from_slice(&(key.len() as u64).to_be_bytes());
+    record.extend_from_slice(&(value.len() as u64).to_be_bytes());
+    record.extend_from_slice(&key);
+    record.extend_from_slice(&value);
+    std::fs::write(format!("{}/0.bitcask", data_dir), record).unwrap();
+
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&key), Some(value));
+    bitcask.put(&b"new".to_vec(), &b"value".to_vec()).unwrap();
+    drop(bitcask);
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.scan(..).unwrap().len(), 2);
+}
+
+#[test]
+fn reserved_record_flags() {
+    // checksum | version 2 | flags (encrypted) | key size | value size | key | value
+    let data_dir = generate_random_data_dir();
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let mut record = crc32c::crc32c(b"value").to_be_bytes().to_vec();
+    record.extend_from_slice(&[2, 1 << 2, 1, 5]);
+    record.extend_from_slice(b"k");
+    record.extend_from_slice(b"value");
+    std::fs::write(format!("{}/0.bitcask", data_dir), record).unwrap();
+    assert!(matches!(BitCask::new(&data_dir), Err(BitCaskError::CorruptedData(_))));
+}
+
+#[test]
+fn empty_values() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put(&vec![1], &vec![]).unwrap();
+    bitcask.put(&vec![2], &vec![2]).unwrap();
+    bitcask.delete(&vec![2]).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![]));
+    assert_eq!(bitcask.get(&vec![2]), None);
+    assert_eq!(bitcask.scan(..).unwrap(), vec![(vec![1], vec![])]);
+    // an empty value survives reopening and compaction, and can still be deleted
+    drop(bitcask);
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![]));
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![]));
+    bitcask.delete(&vec![1]).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), None);
+}
+
 #[test]
 fn compaction() {
     let mut bitcask = generate_random_bitcask_instance();
@@ -43,14 +123,3080 @@ fn test_put_xx() {
     bitcask.put_with_option(&vec![1, 2, 3], &vec![4, 5, 6], PutOption::xx()).unwrap();
 }
 
+#[test]
+fn concurrent_reads_during_compaction() {
+    let mut bitcask = generate_random_bitcask_instance();
+    for i in 0..100u8 {
+        bitcask.put(&vec![i], &vec![i; 16]).unwrap();
+    }
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let bitcask = bitcask.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    for i in 0..100u8 {
+                        assert_eq!(bitcask.get(&vec![i]), Some(vec![i; 16]));
+                    }
+                }
+            })
+        })
+        .collect();
+    // file ids are no longer contiguous after the second compaction
+    for _ in 0..3 {
+        bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+        bitcask.put(&vec![200], &vec![0]).unwrap();
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert_eq!(bitcask.get(&vec![99]), Some(vec![99; 16]));
+}
+
+#[test]
+fn file_naming() {
+    let data_dir = generate_random_data_dir();
+    let naming = |prefix: &str, extension: &str| BitCaskOptions {
+        file_naming: FileNaming {
+            prefix: prefix.to_string(),
+            extension: extension.to_string(),
+        },
+        ..BitCaskOptions::default()
+    };
+    // stores with different names share the directory without seeing each other's files
+    let mut users = BitCask::new_with_options(&data_dir, naming("users-", "log")).unwrap();
+    let mut orders = BitCask::new_with_options(&data_dir, naming("orders-", "log")).unwrap();
+    let mut default = BitCask::new(&data_dir).unwrap();
+    users.put(&vec![1], &vec![1]).unwrap();
+    orders.put(&vec![1], &vec![2]).unwrap();
+    default.put(&vec![1], &vec![3]).unwrap();
+    assert!(std::path::Path::new(&data_dir).join("users-0.log").is_file());
+    assert!(std::path::Path::new(&data_dir).join("0.bitcask").is_file());
+    drop((users, orders));
+
+    let users = BitCask::new_with_options(&data_dir, naming("users-", "log")).unwrap();
+    let orders = BitCask::new_with_options(&data_dir, naming("orders-", "log")).unwrap();
+    assert_eq!(users.get(&vec![1]), Some(vec![1]));
+    assert_eq!(orders.get(&vec![1]), Some(vec![2]));
+    assert_eq!(default.get(&vec![1]), Some(vec![3]));
+
+    for (prefix, extension) in [("users1", "log"), ("a/b", "log"), ("users", ""), ("users", "a.b"), ("users", "tmp")] {
+        let res = BitCask::new_with_options(&data_dir, naming(prefix, extension));
+        assert!(matches!(res, Err(BitCaskError::IoError(_))));
+    }
+}
+
+#[test]
+fn leftover_temp_files() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    drop(bitcask);
+    // a crash while creating a data file leaves only a temporary file behind
+    let dir = std::path::Path::new(&data_dir);
+    std::fs::write(dir.join("1.bitcask.tmp"), b"garbage").unwrap();
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+    assert!(!dir.join("1.bitcask.tmp").exists());
+
+    let new_dir = generate_random_data_dir();
+    bitcask.compact_to_new_dir(&new_dir).unwrap();
+    let names: Vec<_> = std::fs::read_dir(&new_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert!(names.iter().all(|name| !name.ends_with(".tmp")));
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+}
+
+#[test]
+fn reusable_buffers() {
+    let mut bitcask = generate_random_bitcask_instance();
+    bitcask.put(&vec![0], &vec![0; 100]).unwrap();
+    let before = bitcask.stats();
+    for i in 1..=100u8 {
+        bitcask.put(&vec![i], &vec![i; 100]).unwrap();
+    }
+    let after = bitcask.stats();
+    assert_eq!(after.index_entries, 101);
+    assert_eq!(after.data_files, 1);
+    // tests run concurrently and share the counters, so only check that this thread reused its buffer
+    assert!(after.buffer_reuses - before.buffer_reuses >= 100);
+}
+
+#[test]
+fn size_histograms() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    for i in 0..10u8 {
+        bitcask.put(&vec![i], &vec![i; 10]).unwrap();
+    }
+    for i in 10..15u8 {
+        bitcask.put(&vec![i; 20], &vec![i; 1000]).unwrap();
+    }
+    // overwritten and deleted entries are not counted
+    bitcask.put(&vec![0], &vec![0; 3]).unwrap();
+    bitcask.delete(&vec![1]).unwrap();
+    let stats = bitcask.stats();
+    assert_eq!(stats.key_sizes.buckets(), vec![(1, 1, 9), (16, 31, 5)]);
+    assert_eq!(stats.value_sizes.buckets(), vec![(2, 3, 1), (8, 15, 8), (512, 1023, 5)]);
+    assert_eq!(stats.value_sizes.percentile(50.0), 15);
+    assert_eq!(stats.value_sizes.percentile(100.0), 1023);
+    // the histograms are rebuilt when the store is opened
+    drop(bitcask);
+    let reopened = BitCask::new(&data_dir).unwrap().stats();
+    assert_eq!(reopened.key_sizes, stats.key_sizes);
+    assert_eq!(reopened.value_sizes, stats.value_sizes);
+}
+
+#[test]
+fn commit_acks() {
+    let options = BitCaskOptions {
+        sync_policy: SyncPolicy::Manual,
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(generate_random_data_dir(), options).unwrap();
+    let ack = bitcask.put_with_ack(&vec![1], &vec![1]).unwrap();
+    assert!(!ack.wait_timeout(Duration::from_millis(10)));
+    bitcask.sync().unwrap();
+    assert!(ack.is_synced());
+    // a write with the sync option is fsynced right away, along with everything before it
+    let ack = bitcask.put_with_ack(&vec![2], &vec![2]).unwrap();
+    assert!(!ack.is_synced());
+    bitcask.put_with_option(&vec![3], &vec![3], PutOption::sync(true)).unwrap();
+    assert!(ack.is_synced());
+
+    let options = BitCaskOptions {
+        sync_policy: SyncPolicy::Interval(Duration::from_millis(5)),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(generate_random_data_dir(), options).unwrap();
+    let ack = bitcask.put_with_ack(&vec![1], &vec![1]).unwrap();
+    assert!(ack.wait_timeout(Duration::from_secs(10)));
+
+    let options = BitCaskOptions {
+        sync_policy: SyncPolicy::Always,
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(generate_random_data_dir(), options).unwrap();
+    assert!(bitcask.put_with_ack(&vec![1], &vec![1]).unwrap().is_synced());
+}
+
+#[test]
+fn checkpoint() {
+    let mut bitcask = generate_random_bitcask_instance();
+    bitcask.put(&vec![1, 2, 3], &vec![4, 5, 6]).unwrap();
+    // seal the first file so that the checkpoint contains a hard-linked file
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    bitcask.put(&vec![1, 2], &vec![3, 4]).unwrap();
+    let checkpoint_dir = generate_random_data_dir();
+    bitcask.checkpoint(checkpoint_dir.clone()).unwrap();
+    // writes after the checkpoint are not visible in it
+    bitcask.put(&vec![1, 2], &vec![5, 6]).unwrap();
+    let snapshot = BitCask::new(checkpoint_dir).unwrap();
+    assert_eq!(snapshot.get(&vec![1, 2, 3]), Some(vec![4, 5, 6]));
+    assert_eq!(snapshot.get(&vec![1, 2]), Some(vec![3, 4]));
+}
+
+#[test]
+fn backup_incremental() {
+    let mut bitcask = generate_random_bitcask_instance();
+    bitcask.put(&vec![1, 2, 3], &vec![4, 5, 6]).unwrap();
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    bitcask.put(&vec![1, 2], &vec![3, 4]).unwrap();
+    let backup_dir = generate_random_data_dir();
+    let report = bitcask.backup_incremental(backup_dir.clone()).unwrap();
+    assert_eq!(report.copied_files, 2);
+    // only the active file changed since the last backup
+    bitcask.put(&vec![1, 2], &vec![5, 6]).unwrap();
+    let report = bitcask.backup_incremental(backup_dir.clone()).unwrap();
+    assert_eq!((report.copied_files, report.skipped_files), (1, 1));
+    let restored = BitCask::new(backup_dir).unwrap();
+    assert_eq!(restored.get(&vec![1, 2, 3]), Some(vec![4, 5, 6]));
+    assert_eq!(restored.get(&vec![1, 2]), Some(vec![5, 6]));
+}
+
+#[test]
+fn dictionary_compression() {
+    let data_dir = generate_random_data_dir();
+    let options = BitCaskOptions {
+        dictionary_compression: Some(DictionaryCompression {
+            training_samples: 500,
+            ..DictionaryCompression::default()
+        }),
+        ..BitCaskOptions::default()
+    };
+    let value = |i: usize| {
+        format!(r#"{{"id":{},"user":"user-{}","status":"active","role":"member"}}"#, i, i).into_bytes()
+    };
+    let mut bitcask = BitCask::new_with_options(data_dir.clone(), options).unwrap();
+    for i in 0..1000 {
+        bitcask.put(&i.to_string().into_bytes(), &value(i)).unwrap();
+    }
+    assert!(std::path::Path::new(&data_dir).join("dictionary.zstd").exists());
+    assert_eq!(bitcask.get(&b"999".to_vec()), Some(value(999)));
+    // the dictionary is picked up again without the option
+    drop(bitcask);
+    let bitcask = BitCask::new(data_dir).unwrap();
+    assert_eq!(bitcask.get(&b"0".to_vec()), Some(value(0)));
+    assert_eq!(bitcask.get(&b"999".to_vec()), Some(value(999)));
+}
+
+#[test]
+fn dedup() {
+    let data_dir = generate_random_data_dir();
+    let options = BitCaskOptions {
+        dedup: true,
+        ..BitCaskOptions::default()
+    };
+    let blob = vec![7u8; 4096];
+    let mut bitcask = BitCask::new_with_options(data_dir.clone(), options.clone()).unwrap();
+    for i in 0..100u8 {
+        bitcask.put(&vec![i], &blob).unwrap();
+    }
+    let data_file = std::path::Path::new(&data_dir).join("0.bitcask");
+    assert!(std::fs::metadata(&data_file).unwrap().len() < 3 * 4096);
+    // overwriting the key owning the shared record keeps the other keys intact
+    bitcask.put(&vec![0], &vec![1]).unwrap();
+    assert_eq!(bitcask.get(&vec![99]), Some(blob.clone()));
+    let new_dir = generate_random_data_dir();
+    bitcask.compact_to_new_dir(new_dir.clone()).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(blob.clone()));
+    let compacted_file = std::path::Path::new(&new_dir).join("0.bitcask");
+    assert!(std::fs::metadata(compacted_file).unwrap().len() < 3 * 4096);
+    drop(bitcask);
+    let bitcask = BitCask::new_with_options(new_dir, options).unwrap();
+    assert_eq!(bitcask.get(&vec![0]), Some(vec![1]));
+    assert_eq!(bitcask.get(&vec![50]), Some(blob));
+}
+
+#[test]
+fn blob_files() {
+    let data_dir = generate_random_data_dir();
+    let options = BitCaskOptions {
+        blob_threshold: Some(1024),
+        ..BitCaskOptions::default()
+    };
+    let blob = vec![9u8; 64 * 1024];
+    let mut bitcask = BitCask::new_with_options(data_dir.clone(), options.clone()).unwrap();
+    bitcask.put(&vec![1], &blob).unwrap();
+    bitcask.put(&vec![2], &vec![2]).unwrap();
+    bitcask.put(&vec![3], &blob).unwrap();
+    bitcask.delete(&vec![3]).unwrap();
+    let data_file = std::path::Path::new(&data_dir).join("0.bitcask");
+    assert!(std::fs::metadata(data_file).unwrap().len() < 1024);
+    assert_eq!(bitcask.get(&vec![1]), Some(blob.clone()));
+    // only the live blob is carried over by compaction
+    let new_dir = generate_random_data_dir();
+    bitcask.compact_to_new_dir(new_dir.clone()).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(blob.clone()));
+    let blobs = std::fs::read_dir(std::path::Path::new(&new_dir).join("blobs")).unwrap();
+    assert_eq!(blobs.count(), 1);
+    drop(bitcask);
+    let bitcask = BitCask::new_with_options(new_dir, options).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(blob));
+    assert_eq!(bitcask.get(&vec![2]), Some(vec![2]));
+}
+
+#[test]
+fn inline_values() {
+    let data_dir = generate_random_data_dir();
+    let options = BitCaskOptions {
+        inline_value_threshold: Some(64),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(data_dir.clone(), options.clone()).unwrap();
+    bitcask.put(&vec![1], &vec![1; 8]).unwrap();
+    bitcask.put(&vec![2], &vec![2; 128]).unwrap();
+    drop(bitcask);
+    let bitcask = BitCask::new_with_options(data_dir.clone(), options).unwrap();
+    // inline values are served from the index even if the data file is gone
+    std::fs::write(std::path::Path::new(&data_dir).join("0.bitcask"), b"").unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1; 8]));
+    assert_eq!(bitcask.get(&vec![2]), None);
+}
+
+#[test]
+fn zero_copy_reads() {
+    let mut bitcask = generate_random_bitcask_instance();
+    bitcask.put(&vec![1], &vec![1; 4096]).unwrap();
+    // values in the active file are copied
+    let value = bitcask.get_ref(&vec![1]).unwrap().unwrap();
+    assert!(!value.is_zero_copy());
+    assert_eq!(&*value, &[1; 4096][..]);
+    // after compaction the value lives in a sealed file and is read from the mapping
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    bitcask.put(&vec![2], &vec![2]).unwrap();
+    let value = bitcask.get_ref(&vec![1]).unwrap().unwrap();
+    assert!(value.is_zero_copy());
+    assert_eq!(&*value, &[1; 4096][..]);
+    assert_eq!(value.into_value(), vec![1; 4096]);
+    assert!(bitcask.get_ref(&vec![3]).unwrap().is_none());
+}
+
+#[test]
+fn dump_and_load() {
+    let mut bitcask = generate_random_bitcask_instance();
+    bitcask.put(&vec![0, 255], &vec![b',', b'"', b'\n']).unwrap();
+    bitcask.put(&b"key".to_vec(), &b"value".to_vec()).unwrap();
+    bitcask.put(&vec![9], &vec![9]).unwrap();
+    bitcask.delete(&vec![9]).unwrap();
+    for format in [DumpFormat::Jsonl, DumpFormat::Csv] {
+        let mut text = Vec::new();
+        assert_eq!(dump(&bitcask, &mut text, format).unwrap(), 2);
+        let mut loaded = generate_random_bitcask_instance();
+        assert_eq!(load(&mut loaded, &text[..], format).unwrap(), 2);
+        assert_eq!(loaded.scan(..).unwrap(), bitcask.scan(..).unwrap());
+    }
+    let mut text = Vec::new();
+    dump(&bitcask, &mut text, DumpFormat::Jsonl).unwrap();
+    assert!(String::from_utf8(text).unwrap().starts_with(r#"{"key":"AP8=","value":"LCIK"}"#));
+    let invalid = load(&mut bitcask, &b"{\"key\":\"AP8=\"}\n"[..], DumpFormat::Jsonl);
+    assert!(matches!(invalid, Err(BitCaskError::CorruptedData(_))));
+}
+
+#[test]
+fn scan() {
+    let mut bitcask = generate_random_bitcask_instance();
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    bitcask.put(&vec![2], &vec![2]).unwrap();
+    bitcask.put(&vec![3], &vec![3]).unwrap();
+    bitcask.delete(&vec![2]).unwrap();
+    assert_eq!(
+        bitcask.scan(..).unwrap(),
+        vec![(vec![1], vec![1]), (vec![3], vec![3])]
+    );
+    assert_eq!(bitcask.scan(vec![2]..).unwrap(), vec![(vec![3], vec![3])]);
+}
+
+#[test]
+fn pinned_iteration() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    for i in 0..200u8 {
+        bitcask.put(&vec![i], &vec![i, 1]).unwrap();
+    }
+    let mut iter = bitcask.iter(..=vec![127]);
+    let first: Vec<_> = iter.by_ref().take(10).map(Result::unwrap).collect();
+    // writes, compaction and deleting the old files do not affect the running iterator
+    for i in 0..200u8 {
+        bitcask.put(&vec![i], &vec![i, 2]).unwrap();
+    }
+    bitcask.delete(&vec![50]).unwrap();
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    std::fs::remove_dir_all(&data_dir).unwrap();
+    let rest: Vec<_> = iter.map(Result::unwrap).collect();
+    let all: Vec<_> = first.into_iter().chain(rest).collect();
+    assert_eq!(all, (0..=127u8).map(|i| (vec![i], vec![i, 1])).collect::<Vec<_>>());
+    // a new iterator sees the latest state
+    assert_eq!(bitcask.iter(vec![49]..vec![52]).map(Result::unwrap).collect::<Vec<_>>(), vec![
+        (vec![49], vec![49, 2]),
+        (vec![51], vec![51, 2]),
+    ]);
+}
+
+#[test]
+fn scan_cursors() {
+    let mut bitcask = generate_random_bitcask_instance();
+    for i in 0..10u8 {
+        bitcask.put(&vec![i], &vec![i]).unwrap();
+    }
+    let mut keys = Vec::new();
+    let mut cursor = None;
+    loop {
+        let (page, next) = bitcask.scan_keys(cursor.as_ref(), 3);
+        keys.extend(page);
+        // concurrent writes neither duplicate nor hide keys that exist for the whole scan
+        bitcask.delete(&vec![9]).unwrap();
+        bitcask.put(&vec![0], &vec![1]).unwrap();
+        bitcask.put(&vec![4, 0], &vec![1]).unwrap();
+        match next {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+    let mut expected: Vec<_> = (0..9u8).map(|i| vec![i]).collect();
+    expected.insert(5, vec![4, 0]);
+    assert_eq!(keys, expected);
+    assert_eq!(bitcask.scan_keys(None, 100), (bitcask.scan(..).unwrap().into_iter().map(|(key, _)| key).collect(), None));
+}
+
+#[test]
+fn prefix_iteration() {
+    let mut bitcask = generate_random_bitcask_instance();
+    for key in [vec![1], vec![1, 0], vec![1, 255], vec![1, 255, 255], vec![2], vec![255, 255], vec![255, 255, 0]] {
+        bitcask.put(&key, &key).unwrap();
+    }
+    let keys = |prefix: &[u8]| -> Vec<Vec<u8>> {
+        bitcask.iter_prefix(prefix).map(|pair| pair.unwrap().0).collect()
+    };
+    assert_eq!(keys(&[1]), vec![vec![1], vec![1, 0], vec![1, 255], vec![1, 255, 255]]);
+    assert_eq!(keys(&[1, 255]), vec![vec![1, 255], vec![1, 255, 255]]);
+    assert_eq!(keys(&[255, 255]), vec![vec![255, 255], vec![255, 255, 0]]);
+    assert_eq!(keys(&[3]), Vec::<Vec<u8>>::new());
+    assert_eq!(keys(&[]).len(), 7);
+}
+
+#[test]
+fn short_and_long_keys() {
+    for index_backend in [IndexBackendKind::Bytes, IndexBackendKind::InlineKeys] {
+        let data_dir = generate_random_data_dir();
+        let options = BitCaskOptions {
+            index_backend,
+            ..BitCaskOptions::default()
+        };
+        let mut bitcask = BitCask::new_with_options(&data_dir, options.clone()).unwrap();
+        // keys around the inline threshold of the index sort the same as plain byte strings
+        let mut keys: Vec<Vec<u8>> = (1..40).map(|len| vec![7; len]).collect();
+        keys.push(vec![7; 22].into_iter().chain([0]).collect());
+        keys.push(vec![6; 100]);
+        for key in &keys {
+            bitcask.put(key, key).unwrap();
+        }
+        bitcask.delete(&vec![7; 23]).unwrap();
+        keys.retain(|key| key != &vec![7; 23]);
+        keys.sort();
+        let expected: Vec<_> = keys.iter().map(|key| (key.clone(), key.clone())).collect();
+        assert_eq!(bitcask.scan(..).unwrap(), expected);
+        assert_eq!(bitcask.get(&vec![7; 22]), Some(vec![7; 22]));
+        // compaction rebuilds the index with the same backend
+        let new_dir = generate_random_data_dir();
+        bitcask.compact_to_new_dir(new_dir.clone()).unwrap();
+        assert_eq!(bitcask.scan(..).unwrap(), expected);
+        drop(bitcask);
+        let bitcask = BitCask::new_with_options(&new_dir, options).unwrap();
+        assert_eq!(bitcask.scan(..).unwrap(), expected);
+        assert_eq!(bitcask.get(&vec![7; 23]), None);
+    }
+}
+
+#[test]
+fn put_many_sorted() {
+    let data_dir = generate_random_data_dir();
+    let options = BitCaskOptions {
+        blob_threshold: Some(1024),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(&data_dir, options.clone()).unwrap();
+    bitcask.put(&vec![0, 0, 0, 5], &vec![0]).unwrap();
+    let pairs: Vec<_> = (0..10_000u32)
+        .map(|i| (i.to_be_bytes().to_vec(), i.to_le_bytes().repeat(10)))
+        .collect();
+    bitcask.put_many_sorted(&pairs).unwrap();
+    bitcask.put_many_sorted(&[(vec![255], vec![1; 4096])]).unwrap();
+    assert_eq!(bitcask.get(&vec![0, 0, 0, 5]), Some(5u32.to_le_bytes().repeat(10)));
+    assert_eq!(bitcask.get(&vec![255]), Some(vec![1; 4096]));
+    assert_eq!(bitcask.size(), 10_001);
+
+    let unsorted = vec![(vec![2], vec![2]), (vec![1], vec![1])];
+    assert!(bitcask.put_many_sorted(&unsorted).is_err());
+    assert_eq!(bitcask.get(&vec![2]), None);
+
+    drop(bitcask);
+    let bitcask = BitCask::new_with_options(&data_dir, options).unwrap();
+    assert_eq!(bitcask.scan(..).unwrap().len(), 10_001);
+    assert_eq!(bitcask.get(&9999u32.to_be_bytes().to_vec()), Some(9999u32.to_le_bytes().repeat(10)));
+}
+
+#[test]
+fn put_many_sorted_index_matches_reload() {
+    let data_dir = generate_random_data_dir();
+    let options = BitCaskOptions {
+        default_ttl: Some(Duration::from_secs(3600)),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(&data_dir, options.clone()).unwrap();
+    for i in 0..100u32 {
+        bitcask.put(&i.to_be_bytes().to_vec(), &vec![1; i as usize % 7]).unwrap();
+    }
+    bitcask.delete(&vec![0, 0, 0, 20]).unwrap();
+    drop(bitcask);
+    // the reloaded index remembers the tombstone of key 20, which the batch below overwrites
+    let mut bitcask = BitCask::new_with_options(&data_dir, options.clone()).unwrap();
+    let pairs: Vec<_> = (10..150u32).map(|i| (i.to_be_bytes().to_vec(), vec![2; i as usize % 5])).collect();
+    bitcask.put_many_sorted(&pairs).unwrap();
+
+    // the index built in bulk carries the same bookkeeping as one rebuilt from the log
+    let summary = |bitcask: &BitCask| {
+        let stats = bitcask.stats();
+        let files: Vec<_> = stats
+            .files
+            .iter()
+            .map(|file| (file.file_id, file.live_entries, file.live_bytes))
+            .collect();
+        let expiring = bitcask.expiring_before(SystemTime::now() + Duration::from_secs(7200), usize::MAX);
+        (bitcask.merkle_tree(), stats.key_sizes, stats.value_sizes, files, expiring, KVStorage::len(bitcask))
+    };
+    let bulk = summary(&bitcask);
+    assert_eq!(bulk.5, 150);
+    drop(bitcask);
+    let bitcask = BitCask::new_with_options(&data_dir, options).unwrap();
+    assert_eq!(summary(&bitcask), bulk);
+    assert_eq!(bitcask.get(&vec![0, 0, 0, 20]), Some(vec![2; 0]));
+}
+
+#[test]
+fn put_many_sorted_dedup_across_files() {
+    let options = BitCaskOptions {
+        dedup: true,
+        max_file_size: 4096,
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(generate_random_data_dir(), options).unwrap();
+    // every value is written four times in a row, so some duplicates follow a rollover and share a record in the new file
+    let pairs: Vec<_> = (0..64u32)
+        .map(|i| (i.to_be_bytes().to_vec(), vec![(i / 4) as u8; 512]))
+        .collect();
+    bitcask.put_many_sorted(&pairs).unwrap();
+    assert!(bitcask.stats().data_files > 1);
+    for (key, value) in &pairs {
+        assert_eq!(bitcask.get(key).as_ref(), Some(value));
+    }
+}
+
+#[test]
+fn expiry_sweeper() {
+    let options = BitCaskOptions {
+        expiry_sweep: Some(ExpirySweep {
+            interval: Duration::from_millis(5),
+            batch_size: 3,
+        }),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(generate_random_data_dir(), options).unwrap();
+    for i in 0..10u8 {
+        bitcask.put_with_option(&vec![i], &vec![i], PutOption::ttl(Duration::from_millis(1))).unwrap();
+    }
+    bitcask.put(&vec![100], &vec![100]).unwrap();
+    let started = std::time::Instant::now();
+    while bitcask.stats().expired_swept < 10 {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    assert!(bitcask.expired_keys(100).is_empty());
+    assert_eq!(bitcask.get(&vec![100]), Some(vec![100]));
+    assert_eq!(bitcask.sweep_expired(100).unwrap(), 0);
+}
+
+#[test]
+fn record_and_replay() {
+    let trace_file = format!("{}.trace", generate_random_data_dir());
+    std::fs::create_dir_all("./data").unwrap();
+    let options = BitCaskOptions {
+        trace_file: Some(trace_file.clone().into()),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(generate_random_data_dir(), options).unwrap();
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    bitcask.put_with_option(&vec![1], &vec![2], PutOption::nx()).unwrap_err();
+    bitcask.put_with_option(&vec![1], &vec![3], PutOption::xx()).unwrap();
+    bitcask.put_many_sorted(&[(vec![2], vec![2]), (vec![3], vec![3])]).unwrap();
+    bitcask.delete(&vec![2]).unwrap();
+    let mut replayed = generate_random_bitcask_instance();
+    let report = replay(&trace_file, &mut replayed, ReplaySpeed::Max).unwrap();
+    assert_eq!((report.operations, report.failed), (5, 1));
+    assert_eq!(replayed.scan(..).unwrap(), bitcask.scan(..).unwrap());
+    let ops: Vec<_> = TraceReader::open(&trace_file).unwrap().map(|record| record.unwrap().op).collect();
+    assert_eq!(ops[4], TraceOp::Delete { key: vec![2] });
+}
+
+#[test]
+fn audit_log() {
+    let audit_dir = format!("{}.audit", generate_random_data_dir());
+    let options = BitCaskOptions {
+        audit: Some(AuditOptions {
+            max_file_size: 512,
+            max_files: 2,
+            ..AuditOptions::new(&audit_dir)
+        }),
+        ..BitCaskOptions::default()
+    };
+    let bitcask = BitCask::new_with_options(generate_random_data_dir(), options).unwrap();
+    let mut client = bitcask.with_client_id("billing");
+    client.put(&vec![1], &vec![1, 2, 3]).unwrap();
+    client.put_with_option(&vec![1], &vec![4], PutOption::nx()).unwrap_err();
+    assert_eq!(client.get(&vec![1]), Some(vec![1, 2, 3]));
+    client.delete(&vec![1]).unwrap();
+    let records = std::fs::read_to_string(format!("{}/{}", audit_dir, AUDIT_FILE)).unwrap();
+    let records: Vec<_> = records.lines().collect();
+    // Reads are not audited by default, and the key itself is never written.
+    assert_eq!(records.len(), 3);
+    assert!(records[0].contains(r#""client":"billing","op":"put""#));
+    assert!(records[0].contains(r#""size":3,"ok":true"#));
+    assert!(records[1].contains(r#""ok":false"#));
+    assert!(records[2].contains(r#""op":"delete""#));
+
+    for i in 0..100u8 {
+        client.put(&vec![i], &vec![i]).unwrap();
+    }
+    assert!(std::path::Path::new(&format!("{}/{}.2", audit_dir, AUDIT_FILE)).exists());
+    assert!(!std::path::Path::new(&format!("{}/{}.3", audit_dir, AUDIT_FILE)).exists());
+}
+
+#[test]
+fn shadow_writes() {
+    let primary = generate_random_bitcask_instance();
+    let options = BitCaskOptions {
+        inline_value_threshold: Some(64),
+        ..BitCaskOptions::default()
+    };
+    let secondary = BitCask::new_with_options(generate_random_data_dir(), options).unwrap();
+    let mut store = ShadowStore::new(primary, secondary, true);
+    store.put(&vec![1], &vec![1]).unwrap();
+    // writes rejected by the primary are not mirrored
+    store.put_with_option(&vec![1], &vec![2], PutOption::nx()).unwrap_err();
+    store.put_with_option(&vec![2], &vec![2], PutOption::ttl(Duration::from_secs(60))).unwrap();
+    store.delete(&vec![1]).unwrap();
+    assert_eq!(store.get(&vec![2]), Some(vec![2]));
+    assert_eq!(store.get(&vec![1]), None);
+    assert_eq!(store.shadow().scan(..).unwrap(), store.primary().scan(..).unwrap());
+    // a write that bypasses the wrapper shows up as a mismatch
+    store.shadow().clone().put(&vec![3], &vec![3]).unwrap();
+    assert_eq!(store.get(&vec![3]), None);
+    let report = store.report();
+    assert_eq!((report.mirrored_writes, report.compared_reads, report.mismatches), (3, 3, 1));
+    assert!(!report.is_consistent());
+}
+
+#[test]
+fn version_tokens() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get_with_meta(&vec![1]).unwrap(), None);
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    let version = bitcask.get_with_meta(&vec![1]).unwrap().unwrap().version;
+
+    bitcask.put_with_option(&vec![1], &vec![2], PutOption::expected_version(version)).unwrap();
+    let meta = bitcask.get_with_meta(&vec![1]).unwrap().unwrap();
+    assert_eq!(meta.value, vec![2]);
+    assert_ne!(meta.version, version);
+
+    // the old version is stale now
+    let res = bitcask.put_with_option(&vec![1], &vec![3], PutOption::expected_version(version));
+    assert!(matches!(res, Err(BitCaskError::VersionMismatch)));
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![2]));
+
+    // compaction keeps versions intact
+    bitcask.compact_to_new_dir(format!("./data/{}", generate_random_name())).unwrap();
+    assert_eq!(bitcask.get_with_meta(&vec![1]).unwrap().unwrap().version, meta.version);
+}
+
+#[test]
+fn hlc_timestamps() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    bitcask.put(&vec![2], &vec![2]).unwrap();
+    let first = bitcask.get_with_meta(&vec![1]).unwrap().unwrap().timestamp;
+    let second = bitcask.get_with_meta(&vec![2]).unwrap().unwrap().timestamp;
+    assert!(first < second);
+    // the physical part of the timestamp is the wall clock in milliseconds
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    assert!(now - (second >> 16) < 60_000);
+
+    // timestamps are persisted and the clock keeps moving forward after a reopen
+    drop(bitcask);
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get_with_meta(&vec![2]).unwrap().unwrap().timestamp, second);
+    bitcask.put(&vec![3], &vec![3]).unwrap();
+    assert!(bitcask.get_with_meta(&vec![3]).unwrap().unwrap().timestamp > second);
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    assert_eq!(bitcask.get_with_meta(&vec![1]).unwrap().unwrap().timestamp, first);
+}
+
+#[test]
+fn merge_from() {
+    let mut left = generate_random_bitcask_instance();
+    let right_dir = generate_random_data_dir();
+    let mut right = BitCask::new(&right_dir).unwrap();
+    // the two stores have independent clocks, keep conflicting writes in different milliseconds
+    let tick = || std::thread::sleep(Duration::from_millis(2));
+    left.put(&vec![1], &vec![1]).unwrap();
+    tick();
+    right.put(&vec![1], &vec![2]).unwrap();
+    right.put(&vec![2], &vec![2]).unwrap();
+    tick();
+    left.put(&vec![2], &vec![1]).unwrap();
+    right.put(&vec![3], &vec![3]).unwrap();
+    left.put(&vec![4], &vec![4]).unwrap();
+    tick();
+    right.put(&vec![4], &vec![4]).unwrap();
+    right.delete(&vec![4]).unwrap();
+    drop(right);
+
+    let report = left.merge_from(&right_dir).unwrap();
+    assert_eq!((report.imported, report.deleted, report.skipped), (2, 1, 1));
+    // the later write wins for every key, deletes included
+    assert_eq!(left.get(&vec![1]), Some(vec![2]));
+    assert_eq!(left.get(&vec![2]), Some(vec![1]));
+    assert_eq!(left.get(&vec![3]), Some(vec![3]));
+    assert_eq!(left.get(&vec![4]), None);
+    // merging again is a no-op, and later local writes still win
+    let report = left.merge_from(&right_dir).unwrap();
+    assert_eq!((report.imported, report.deleted), (0, 0));
+    left.put(&vec![3], &vec![5]).unwrap();
+    assert_eq!(left.get(&vec![3]), Some(vec![5]));
+}
+
+#[test]
+fn anti_entropy() {
+    let mut left = generate_random_bitcask_instance();
+    let mut right = generate_random_bitcask_instance();
+    for i in 0..100u8 {
+        left.put(&vec![i], &vec![i]).unwrap();
+    }
+    let (_, report) = left.anti_entropy(&mut right).unwrap();
+    assert_eq!(report.imported, 100);
+    assert_eq!(left.merkle_tree(), right.merkle_tree());
+
+    std::thread::sleep(Duration::from_millis(2));
+    left.delete(&vec![1]).unwrap();
+    right.put(&vec![2], &vec![20]).unwrap();
+    let segments = left.merkle_tree().diff(&right.merkle_tree());
+    assert!(!segments.is_empty() && segments.len() <= 2);
+    // only the differing segments are exchanged
+    let (local, remote) = left.anti_entropy(&mut right).unwrap();
+    assert_eq!((local.imported, remote.deleted), (1, 1));
+    assert_eq!(right.get(&vec![1]), None);
+    assert_eq!(left.get(&vec![2]), Some(vec![20]));
+    assert_eq!(left.merkle_tree().root(), right.merkle_tree().root());
+    assert!(left.merkle_tree().diff(&right.merkle_tree()).is_empty());
+}
+
+#[test]
+fn key_history() {
+    let mut bitcask = generate_random_bitcask_instance();
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    bitcask.put(&vec![2], &vec![2]).unwrap();
+    bitcask.put(&vec![1], &vec![3]).unwrap();
+    bitcask.delete(&vec![1]).unwrap();
+    let history = bitcask.history(&vec![1]).unwrap();
+    assert_eq!(history.len(), 3);
+    assert_eq!(
+        history.iter().map(|record| record.tombstone).collect::<Vec<_>>(),
+        vec![false, false, true]
+    );
+    assert!(history.windows(2).all(|pair| pair[0].offset < pair[1].offset));
+    assert!(history.windows(2).all(|pair| pair[0].timestamp < pair[1].timestamp));
+    assert!(bitcask.history(&vec![3]).unwrap().is_empty());
+}
+
+#[test]
+fn scans_during_writes() {
+    let mut bitcask = generate_random_bitcask_instance();
+    bitcask.put(&vec![0], &vec![0; 100]).unwrap();
+    let writer = {
+        let mut bitcask = bitcask.clone();
+        std::thread::spawn(move || {
+            for i in 0..2000u32 {
+                bitcask.put(&i.to_be_bytes().to_vec(), &vec![1; 100]).unwrap();
+            }
+        })
+    };
+    // full-file scans read the active file positionally and never see a torn cursor
+    while !writer.is_finished() {
+        assert_eq!(bitcask.history(&vec![0]).unwrap().len(), 1);
+    }
+    writer.join().unwrap();
+}
+
+#[test]
+fn compaction_estimate() {
+    let mut bitcask = generate_random_bitcask_instance();
+    for i in 0..100u8 {
+        bitcask.put(&vec![i], &vec![i; 100]).unwrap();
+    }
+    for i in 0..50u8 {
+        bitcask.put(&vec![i], &vec![i; 100]).unwrap();
+    }
+    for i in 50..60u8 {
+        bitcask.delete(&vec![i]).unwrap();
+    }
+    let estimate = bitcask.estimate_compaction().unwrap();
+    assert_eq!((estimate.input_entries, estimate.output_entries), (160, 90));
+    assert_eq!(estimate.dropped_entries(), 70);
+    assert!(estimate.reclaimable_bytes() > 0);
+
+    // the estimate matches the size of the compacted files
+    let new_dir = generate_random_data_dir();
+    bitcask.compact_to_new_dir(&new_dir).unwrap();
+    let after = bitcask.estimate_compaction().unwrap();
+    assert_eq!(after.output_bytes, estimate.output_bytes);
+    assert_eq!(after.input_bytes, estimate.output_bytes);
+    assert_eq!(after.reclaimable_bytes(), 0);
+}
+
+#[test]
+fn sparse_index() {
+    // Build three data files, each written by its own store and copied into one directory.
+    let data_dir = generate_random_data_dir();
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let writes: [fn(&mut BitCask); 3] = [
+        |bitcask| {
+            for i in 0..100u8 {
+                bitcask.put(&vec![i], &vec![i]).unwrap();
+            }
+        },
+        |bitcask| {
+            bitcask.put(&vec![1], &vec![101]).unwrap();
+            bitcask.delete(&vec![2]).unwrap();
+            bitcask.put(&vec![50], &vec![150]).unwrap();
+        },
+        |bitcask| {
+            bitcask.delete(&vec![3]).unwrap();
+            bitcask.put(&vec![4], &vec![104]).unwrap();
+        },
+    ];
+    for (file_id, write) in writes.iter().enumerate() {
+        let dir = generate_random_data_dir();
+        let mut bitcask = BitCask::new(&dir).unwrap();
+        write(&mut bitcask);
+        drop(bitcask);
+        std::fs::copy(format!("{}/0.bitcask", dir), format!("{}/{}.bitcask", data_dir, file_id)).unwrap();
+    }
+    let expected = BitCask::new(&data_dir).unwrap().scan(..).unwrap();
+
+    let options = BitCaskOptions {
+        sparse_index: Some(SparseIndex::default()),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(&data_dir, options).unwrap();
+    assert!(bitcask.stats().index_entries < 20);
+    for (key, value) in &expected {
+        assert_eq!(bitcask.get(key).as_ref(), Some(value));
+    }
+    for key in [2, 3, 100] {
+        assert_eq!(bitcask.get(&vec![key]), None);
+    }
+    assert_eq!(bitcask.scan(..).unwrap(), expected);
+    assert_eq!(bitcask.iter(..).map(Result::unwrap).collect::<Vec<_>>(), expected);
+    bitcask.put_with_option(&vec![5], &vec![0], PutOption::nx()).unwrap_err();
+
+    // writes after opening are fully indexed and shadow the sparsely indexed files
+    bitcask.delete(&vec![5]).unwrap();
+    bitcask.put(&vec![6], &vec![106]).unwrap();
+    assert_eq!(bitcask.get(&vec![5]), None);
+    assert_eq!(bitcask.get(&vec![6]), Some(vec![106]));
+    assert_eq!(bitcask.scan(..).unwrap().len(), expected.len() - 1);
+}
+
+#[test]
+fn keydir() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    for i in 0..10u8 {
+        bitcask.put(&vec![i], &vec![i; 10]).unwrap();
+    }
+    bitcask.delete(&vec![1]).unwrap();
+    bitcask.save_keydir().unwrap();
+    // writes after saving are loaded from the data file on the next open
+    bitcask.put(&vec![2], &vec![20]).unwrap();
+    bitcask.delete(&vec![3]).unwrap();
+    let expected = bitcask.scan(..).unwrap();
+    drop(bitcask);
+
+    // Corrupt the checksum of the first record: a full scan now fails, but the keydir covers it.
+    let path = format!("{}/0.bitcask", data_dir);
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[0] ^= 0xff;
+    std::fs::write(&path, bytes).unwrap();
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.scan(..).unwrap(), expected);
+    assert_eq!(bitcask.get(&vec![1]), None);
+    drop(bitcask);
+    std::fs::remove_file(format!("{}/keydir", data_dir)).unwrap();
+    assert!(BitCask::new(&data_dir).is_err());
+}
+
+#[test]
+fn clean_shutdown() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    for i in 0..10u8 {
+        bitcask.put(&vec![i], &vec![i; 10]).unwrap();
+    }
+    let expected = bitcask.scan(..).unwrap();
+    bitcask.close().unwrap();
+    let marker = format!("{}/clean-shutdown", data_dir);
+    assert!(std::path::Path::new(&marker).exists());
+
+    // Corrupt the first data record and the last keydir entry (its record offset, just before the
+    // entry checksum, the empty request-id section and the keydir trailer): only the keydir trusted
+    // by the marker can open the store.
+    let path = format!("{}/0.bitcask", data_dir);
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[0] ^= 0xff;
+    std::fs::write(&path, bytes).unwrap();
+    let keydir = format!("{}/keydir", data_dir);
+    let mut bytes = std::fs::read(&keydir).unwrap();
+    let len = bytes.len();
+    bytes[len - 17] ^= 0xff;
+    std::fs::write(&keydir, bytes).unwrap();
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.scan(..).unwrap(), expected);
+    assert!(!std::path::Path::new(&marker).exists());
+    drop(bitcask);
+
+    // the marker is consumed by the open, so the next open validates the keydir and falls back to a full scan
+    assert!(BitCask::new(&data_dir).is_err());
+}
+
+#[test]
+fn checksums_on_read() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put(&vec![0], &vec![0; 10]).unwrap();
+    bitcask.put(&vec![1], &vec![1; 10]).unwrap();
+    bitcask.save_keydir().unwrap();
+    drop(bitcask);
+
+    // Flip the first value byte of the first record (header 17 bytes: checksum, version, flags, sizes,
+    // timestamp, key).
+    let path = format!("{}/0.bitcask", data_dir);
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[FILE_HEADER_SIZE + 17] ^= 0xff;
+    std::fs::write(&path, bytes).unwrap();
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert!(matches!(bitcask.get_with_meta(&vec![0]), Err(BitCaskError::CorruptedData(_))));
+    assert!(matches!(bitcask.get_ref(&vec![0]), Err(BitCaskError::CorruptedData(_))));
+    assert_eq!(bitcask.get(&vec![0]), None);
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1; 10]));
+}
+
+#[test]
+fn paranoid_checks() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put(&vec![0], &vec![0; 10]).unwrap();
+    bitcask.put(&vec![1], &vec![1; 10]).unwrap();
+    // the keydir lets the store reopen without rescanning the corrupted record
+    bitcask.save_keydir().unwrap();
+    drop(bitcask);
+
+    // Set the compressed bit in the flags of the first record (after checksum and version). The
+    // checksum only covers the value, so only the paranoid check notices the header disagrees.
+    let path = format!("{}/0.bitcask", data_dir);
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[FILE_HEADER_SIZE + 5] ^= 1 << 1;
+    std::fs::write(&path, bytes).unwrap();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![0]), Some(vec![0; 10]));
+
+    let mut tunable = bitcask.tunable_options();
+    tunable.paranoid_checks = true;
+    bitcask.reconfigure(tunable).unwrap();
+    assert!(matches!(bitcask.get_with_meta(&vec![0]), Err(BitCaskError::CorruptedData(_))));
+    assert!(matches!(bitcask.get_ref(&vec![0]), Err(BitCaskError::CorruptedData(_))));
+    assert_eq!(bitcask.get(&vec![0]), None);
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1; 10]));
+}
+
+#[test]
+fn compact_on_open() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    for _ in 0..5 {
+        for i in 0..20u8 {
+            bitcask.put(&vec![i], &vec![i; 100]).unwrap();
+        }
+    }
+    bitcask.delete(&vec![0]).unwrap();
+    let expected = bitcask.scan(..).unwrap();
+    let estimate = bitcask.estimate_compaction().unwrap();
+    drop(bitcask);
+
+    let options = || BitCaskOptions {
+        compact_on_open: Some(CompactOnOpen::default()),
+        ..BitCaskOptions::default()
+    };
+    let bitcask = BitCask::new_with_options(&data_dir, options()).unwrap();
+    assert_eq!(bitcask.scan(..).unwrap(), expected);
+    let after = bitcask.estimate_compaction().unwrap();
+    assert_eq!((after.input_bytes, after.reclaimable_bytes()), (estimate.output_bytes, 0));
+    assert_eq!(bitcask.stats().data_files, 1);
+    // The deleted key stays deleted once the compacted file is the only one left.
+    drop(bitcask);
+    let mut bitcask = BitCask::new_with_options(&data_dir, options()).unwrap();
+    assert_eq!(bitcask.get(&vec![0]), None);
+    bitcask.put(&vec![0], &vec![0]).unwrap();
+    assert_eq!(bitcask.get(&vec![0]), Some(vec![0]));
+}
+
+#[test]
+fn key_locks() {
+    let bitcask = generate_random_bitcask_instance();
+    let guard = bitcask.lock_key(&vec![1], Duration::from_millis(10)).unwrap();
+    // other keys can be locked independently, while clones share the lock table
+    let other = bitcask.lock_key(&vec![2], Duration::from_millis(10)).unwrap();
+    let res = bitcask.clone().lock_key(&vec![1], Duration::from_millis(10));
+    assert!(matches!(res, Err(BitCaskError::LockTimeout)));
+
+    let waiter = {
+        let bitcask = bitcask.clone();
+        std::thread::spawn(move || bitcask.lock_key(&vec![1], Duration::from_secs(10)).is_ok())
+    };
+    std::thread::sleep(Duration::from_millis(20));
+    drop(guard);
+    assert!(waiter.join().unwrap());
+    drop(other);
+}
+
+#[test]
+fn ttl_expiry() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put_with_option(&vec![1], &vec![1], PutOption::ttl(Duration::from_millis(20))).unwrap();
+    bitcask.put_with_option(&vec![2], &vec![2], PutOption::ttl(Duration::from_secs(3600))).unwrap();
+    bitcask.put(&vec![3], &vec![3]).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+    assert!(bitcask.expired_keys(10).is_empty());
+
+    std::thread::sleep(Duration::from_millis(40));
+    assert_eq!(bitcask.get(&vec![1]), None);
+    assert_eq!(bitcask.expired_keys(10), vec![vec![1]]);
+    assert_eq!(
+        bitcask.scan(..).unwrap(),
+        vec![(vec![2], vec![2]), (vec![3], vec![3])]
+    );
+
+    // expiry survives a reopen
+    drop(bitcask);
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), None);
+    assert_eq!(bitcask.get(&vec![2]), Some(vec![2]));
+
+    // an expired key counts as absent for NX and leaves the expiry index once rewritten
+    bitcask.put_with_option(&vec![1], &vec![4], PutOption::nx()).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![4]));
+    assert!(bitcask.expired_keys(10).is_empty());
+}
+
+#[test]
+fn reconfigure() {
+    let mut bitcask = generate_random_bitcask_instance();
+    bitcask.put_with_option(&vec![1], &vec![1], PutOption::ttl(Duration::from_millis(1))).unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    assert_eq!(bitcask.expired_keys(10), vec![vec![1]]);
+
+    // Turning on the expiry sweep starts the background thread without reopening.
+    let mut options = bitcask.tunable_options();
+    assert!(options.expiry_sweep.is_none());
+    options.expiry_sweep = Some(ExpirySweep {
+        interval: Duration::from_millis(10),
+        ..ExpirySweep::default()
+    });
+    options.sync_policy = SyncPolicy::Always;
+    bitcask.reconfigure(options).unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(bitcask.expired_keys(10).is_empty());
+    assert_eq!(bitcask.stats().expired_swept, 1);
+
+    // Writes are synced immediately under the new sync policy.
+    let ack = bitcask.put_with_ack(&vec![2], &vec![2]).unwrap();
+    assert!(ack.is_synced());
+    let mut options = bitcask.tunable_options();
+    assert_eq!(options.sync_policy, SyncPolicy::Always);
+    options.expiry_sweep = None;
+    bitcask.reconfigure(options).unwrap();
+}
+
+#[test]
+fn slow_op_log() {
+    let options = BitCaskOptions {
+        slow_op_threshold: Some(Duration::ZERO),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(generate_random_data_dir(), options).unwrap();
+    bitcask.put(&vec![1, 2, 3], &vec![4, 5, 6]).unwrap();
+    assert_eq!(bitcask.get(&vec![1, 2, 3]), Some(vec![4, 5, 6]));
+    bitcask.delete(&vec![1, 2, 3]).unwrap();
+    assert_eq!(bitcask.get(&vec![1, 2, 3]), None);
+}
+
+#[test]
+fn operation_spans() {
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let output = Output::default();
+    let writer = output.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    tracing::subscriber::with_default(subscriber, || {
+        let mut bitcask = generate_random_bitcask_instance();
+        bitcask.put(&vec![1, 2, 3], &vec![4, 5]).unwrap();
+        bitcask.get(&vec![1, 2, 3]).unwrap();
+        bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    });
+    let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    let span = |op: &str| output.lines().find(|line| line.contains(&format!("op=\"{}\"", op))).unwrap().to_string();
+    let put = span("put");
+    assert!(put.contains("key_size=3") && put.contains("value_size=2") && put.contains("file_id=0"));
+    assert!(put.contains("duration_us="));
+    assert!(span("get").contains("key_size=3"));
+    for op in ["compaction_prepare", "compaction_merge", "compaction_finish"] {
+        // compaction phases are nested in the compaction span
+        assert!(span(op).contains("bitcask_op{otel.name=\"compaction\""));
+    }
+}
+
+#[test]
+fn health() {
+    let mut bitcask = generate_random_bitcask_instance();
+    bitcask.put(&vec![1, 2, 3], &vec![4, 5, 6]).unwrap();
+    let health = bitcask.health().unwrap();
+    assert!(health.active_file_writable);
+    assert_eq!(health.recent_ops, 1);
+    assert_eq!(health.recent_io_errors, 0);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn disk_full() {
+    // Every write to /dev/full fails with ENOSPC.
+    let data_dir = generate_random_data_dir();
+    std::fs::create_dir_all(&data_dir).unwrap();
+    std::os::unix::fs::symlink("/dev/full", format!("{}/0.bitcask", data_dir)).unwrap();
+    let options = BitCaskOptions {
+        read_only_on_disk_full: true,
+        min_available_space: u64::MAX,
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(&data_dir, options).unwrap();
+    assert!(matches!(bitcask.put(&vec![1], &vec![1]), Err(BitCaskError::DiskFull)));
+    assert!(matches!(bitcask.delete(&vec![1]), Err(BitCaskError::ReadOnly)));
+    assert!(bitcask.health().unwrap().read_only);
+    assert_eq!(bitcask.get(&vec![1]), None);
+
+    // once enough space is available, writes reach the disk again
+    let mut tunable = bitcask.tunable_options();
+    tunable.min_available_space = 0;
+    bitcask.reconfigure(tunable).unwrap();
+    assert!(matches!(bitcask.put(&vec![1], &vec![1]), Err(BitCaskError::DiskFull)));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn reserved_space() {
+    let options = || BitCaskOptions {
+        reserved_space: Some(64 * 1024),
+        ..BitCaskOptions::default()
+    };
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new_with_options(&data_dir, options()).unwrap();
+    assert_eq!(std::fs::metadata(format!("{}/reserve", data_dir)).unwrap().len(), 64 * 1024);
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    // compaction moves the reserve into the new data directory
+    let new_dir = generate_random_data_dir();
+    bitcask.compact_to_new_dir(&new_dir).unwrap();
+    assert!(!std::path::Path::new(&format!("{}/reserve", data_dir)).exists());
+    assert!(std::path::Path::new(&format!("{}/reserve", new_dir)).exists());
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+
+    // the first write that runs out of space releases the reserve
+    let data_dir = generate_random_data_dir();
+    std::fs::create_dir_all(&data_dir).unwrap();
+    std::os::unix::fs::symlink("/dev/full", format!("{}/0.bitcask", data_dir)).unwrap();
+    let mut bitcask = BitCask::new_with_options(&data_dir, options()).unwrap();
+    assert!(std::path::Path::new(&format!("{}/reserve", data_dir)).exists());
+    assert!(matches!(bitcask.put(&vec![1], &vec![1]), Err(BitCaskError::DiskFull)));
+    assert!(!std::path::Path::new(&format!("{}/reserve", data_dir)).exists());
+}
+
+#[test]
+fn request_ids() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put_with_option(&vec![1], &vec![1], PutOption::request_id(7)).unwrap();
+    // a retry with the same id is acknowledged without writing
+    bitcask.put_with_option(&vec![1], &vec![2], PutOption::request_id(7)).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+
+    // request ids are recovered both by scanning the data files and from the keydir
+    drop(bitcask);
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put_with_option(&vec![1], &vec![3], PutOption::request_id(7)).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+    bitcask.close().unwrap();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put_with_option(&vec![1], &vec![4], PutOption::request_id(7)).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+
+    // a new id writes
+    bitcask.put_with_option(&vec![1], &vec![5], PutOption::request_id(8)).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![5]));
+}
+
+#[test]
+fn soft_delete() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put(&vec![1], &vec![1; 10]).unwrap();
+    bitcask.put(&vec![2], &vec![]).unwrap();
+    bitcask.soft_delete(&vec![1]).unwrap();
+    bitcask.soft_delete(&vec![2]).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), None);
+    assert!(matches!(bitcask.soft_delete(&vec![1]), Err(BitCaskError::KeyNotFound)));
+    bitcask.undelete(&vec![1]).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1; 10]));
+    // only the last record of the key can be undone
+    assert!(matches!(bitcask.undelete(&vec![1]), Err(BitCaskError::KeyNotFound)));
+    bitcask.soft_delete(&vec![1]).unwrap();
+
+    // soft deletes survive a reopen, both from the data files and from the keydir
+    drop(bitcask);
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), None);
+    bitcask.close().unwrap();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), None);
+    bitcask.undelete(&vec![2]).unwrap();
+    assert_eq!(bitcask.get(&vec![2]), Some(vec![]));
+
+    // compaction discards soft-deleted values for good
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), None);
+    assert!(matches!(bitcask.undelete(&vec![1]), Err(BitCaskError::KeyNotFound)));
+    assert_eq!(bitcask.get(&vec![2]), Some(vec![]));
+}
+
+#[test]
+fn rename_and_copy() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    bitcask.put(&vec![2], &vec![2]).unwrap();
+    bitcask.copy(&vec![1], &vec![3], false).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+    assert_eq!(bitcask.get(&vec![3]), Some(vec![1]));
+
+    // NX refuses to replace an existing key, otherwise the destination is overwritten
+    assert!(matches!(bitcask.rename(&vec![1], &vec![2], true), Err(BitCaskError::KeyExists)));
+    assert!(matches!(bitcask.copy(&vec![1], &vec![2], true), Err(BitCaskError::KeyExists)));
+    bitcask.rename(&vec![1], &vec![2], false).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), None);
+    assert_eq!(bitcask.get(&vec![2]), Some(vec![1]));
+    assert!(matches!(bitcask.rename(&vec![1], &vec![4], false), Err(BitCaskError::KeyNotFound)));
+
+    drop(bitcask);
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), None);
+    assert_eq!(bitcask.get(&vec![2]), Some(vec![1]));
+    assert_eq!(bitcask.get(&vec![3]), Some(vec![1]));
+}
+
+#[test]
+fn offline_compaction() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    for i in 0..10u8 {
+        bitcask.put(&vec![1], &vec![i; 100]).unwrap();
+    }
+    bitcask.put(&vec![2], &vec![2]).unwrap();
+    // open stores share the directory lock, offline compaction needs it exclusively
+    let other = BitCask::new(&data_dir).unwrap();
+    drop(bitcask);
+    assert!(matches!(
+        BitCask::compact_offline(&data_dir, BitCaskOptions::default()),
+        Err(BitCaskError::DirectoryLocked)
+    ));
+    drop(other);
+
+    let estimate = BitCask::compact_offline(&data_dir, BitCaskOptions::default()).unwrap();
+    assert!(estimate.reclaimable_bytes() > 0);
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![9; 100]));
+    assert_eq!(bitcask.get(&vec![2]), Some(vec![2]));
+    assert_eq!(bitcask.estimate_compaction().unwrap().reclaimable_bytes(), 0);
+}
+
+#[test]
+fn file_hook() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    let options = || BitCaskOptions {
+        file_hook: Some(FileHook::new({
+            let recorded = recorded.clone();
+            move |event| recorded.lock().unwrap().push(event.clone())
+        })),
+        ..BitCaskOptions::default()
+    };
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new_with_options(&data_dir, options()).unwrap();
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    bitcask.put(&vec![1], &vec![2]).unwrap();
+    // compaction seals the active file, then every file of the old directory is superseded
+    let new_dir = generate_random_data_dir();
+    bitcask.compact_to_new_dir(&new_dir).unwrap();
+    let old_file = |id: u32| std::path::Path::new(&data_dir).join(format!("{}.bitcask", id));
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            FileEvent::Sealed(old_file(0)),
+            FileEvent::Superseded(old_file(0)),
+            FileEvent::Superseded(old_file(1)),
+        ]
+    );
+    drop(bitcask);
+
+    // offline compaction reports each file before deleting it
+    events.lock().unwrap().clear();
+    BitCask::compact_offline(&new_dir, options()).unwrap();
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 2);
+    assert!(events
+        .iter()
+        .all(|event| matches!(event, FileEvent::Superseded(path) if path.starts_with(&new_dir) && !path.exists())));
+}
+
+#[test]
+fn retention() {
+    let data_dir = generate_random_data_dir();
+    let options = BitCaskOptions {
+        retention: Some(Duration::from_millis(200)),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(&data_dir, options).unwrap();
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    bitcask.put(&vec![2], &vec![2]).unwrap();
+    std::thread::sleep(Duration::from_millis(300));
+    bitcask.put(&vec![2], &vec![3]).unwrap();
+    // records past the horizon stay readable until a compaction drops them
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), None);
+    assert_eq!(bitcask.get(&vec![2]), Some(vec![3]));
+}
+
+#[test]
+fn append_only() {
+    let data_dir = generate_random_data_dir();
+    let options = BitCaskOptions {
+        append_only: true,
+        retention: Some(Duration::from_millis(100)),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(&data_dir, options).unwrap();
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    assert!(matches!(bitcask.put(&vec![1], &vec![2]), Err(BitCaskError::ReadOnlyKey)));
+    assert!(matches!(bitcask.delete(&vec![1]), Err(BitCaskError::ReadOnlyKey)));
+    assert!(matches!(bitcask.rename(&vec![1], &vec![2], false), Err(BitCaskError::ReadOnlyKey)));
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+    // compaction keeps records even past the retention horizon
+    std::thread::sleep(Duration::from_millis(200));
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+}
+
+#[test]
+fn user_metadata() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask
+        .put_with_option(&vec![1], &vec![1], PutOption::metadata(b"text/plain".to_vec()))
+        .unwrap();
+    bitcask.put(&vec![2], &vec![2]).unwrap();
+    let too_large = PutOption::metadata(vec![0; MAX_METADATA_SIZE + 1]);
+    assert!(matches!(
+        bitcask.put_with_option(&vec![3], &vec![3], too_large),
+        Err(BitCaskError::MetadataTooLarge(_))
+    ));
+    assert_eq!(bitcask.get_with_metadata(&vec![1]).unwrap(), Some((vec![1], Some(b"text/plain".to_vec()))));
+    assert_eq!(bitcask.get_with_metadata(&vec![2]).unwrap(), Some((vec![2], None)));
+    // metadata survives compaction and reopening
+    let new_dir = generate_random_data_dir();
+    bitcask.compact_to_new_dir(new_dir.clone()).unwrap();
+    drop(bitcask);
+    let bitcask = BitCask::new(&new_dir).unwrap();
+    assert_eq!(bitcask.get_with_metadata(&vec![1]).unwrap(), Some((vec![1], Some(b"text/plain".to_vec()))));
+}
+
+#[test]
+fn record_format_upgrade() {
+    // Write a store by hand in record format version 1, the original layout: CRC_32_CKSUM checksum,
+    // 8 byte key and value sizes, then key and value; an empty value is a tombstone.
+    let data_dir = generate_random_data_dir();
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let crc = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM);
+    let mut bytes = Vec::new();
+    let records = [(vec![1u8], vec![1u8; 10]), (vec![2], vec![2; 10]), (vec![1], vec![3; 10]), (vec![2], vec![])];
+    for (key, value) in records {
+        bytes.extend_from_slice(&crc.checksum(&value).to_be_bytes());
+        bytes.extend_from_slice(&(key.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&(value.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&key);
+        bytes.extend_from_slice(&value);
+    }
+    let path = format!("{}/0.bitcask", data_dir);
+    std::fs::write(&path, &bytes).unwrap();
+
+    // both formats are readable, so the store opens and appends version 2 records after version 1 ones
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![3; 10]));
+    assert_eq!(bitcask.get(&vec![2]), None);
+    bitcask.put(&vec![3], &vec![4; 10]).unwrap();
+    drop(bitcask);
+
+    assert_eq!(BitCask::upgrade(&data_dir, BitCaskOptions::default()).unwrap(), 4);
+    assert_eq!(BitCask::upgrade(&data_dir, BitCaskOptions::default()).unwrap(), 0);
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![3; 10]));
+    assert_eq!(bitcask.get(&vec![2]), None);
+    assert_eq!(bitcask.get(&vec![3]), Some(vec![4; 10]));
+}
+
+#[test]
+fn record_extensions() {
+    // A version 2 record with an extension section: checksum, version, flags (extensions bit), key and
+    // value sizes, the extension section and then key and value.
+    fn record(extensions: &[u8], key: u8, value: &[u8]) -> Vec<u8> {
+        let mut bytes = crc32c::crc32c(value).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&[2, 0x80, 0x08, 1, value.len() as u8, extensions.len() as u8]);
+        bytes.extend_from_slice(extensions);
+        bytes.push(key);
+        bytes.extend_from_slice(value);
+        bytes
+    }
+
+    // unknown optional extensions (even types) are skipped
+    let data_dir = generate_random_data_dir();
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let mut bytes = record(&[2, 3, 7, 7, 7, 4, 0], 1, &[1; 10]);
+    bytes.extend(record(&[6, 1, 9], 2, &[2; 10]));
+    std::fs::write(format!("{}/0.bitcask", data_dir), &bytes).unwrap();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1; 10]));
+    assert_eq!(bitcask.get(&vec![2]), Some(vec![2; 10]));
+    bitcask.put(&vec![3], &vec![3; 10]).unwrap();
+    assert_eq!(bitcask.get(&vec![3]), Some(vec![3; 10]));
+
+    // an unknown critical extension (odd type) makes the record unreadable
+    let data_dir = generate_random_data_dir();
+    std::fs::create_dir_all(&data_dir).unwrap();
+    std::fs::write(format!("{}/0.bitcask", data_dir), record(&[3, 1, 9], 1, &[1; 10])).unwrap();
+    assert!(matches!(BitCask::new(&data_dir), Err(BitCaskError::CorruptedData(_))));
+}
+
+#[test]
+fn data_file_header() {
+    // new data files start with magic, marker, format version and the extension section size
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put(&vec![1], &vec![1; 10]).unwrap();
+    drop(bitcask);
+    let path = format!("{}/0.bitcask", data_dir);
+    let bytes = std::fs::read(&path).unwrap();
+    assert_eq!(&bytes[..FILE_HEADER_SIZE], b"BCSK\xff\x01\x00\x00");
+    let records = &bytes[FILE_HEADER_SIZE..];
+    let with_header = |version: u8, extensions: &[u8]| {
+        let mut file = b"BCSK\xff".to_vec();
+        file.push(version);
+        file.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        file.extend_from_slice(extensions);
+        file.extend_from_slice(records);
+        std::fs::write(&path, file).unwrap();
+    };
+
+    // unknown optional extensions (even types) in the header are skipped
+    with_header(1, &[2, 3, 7, 7, 7, 4, 0]);
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1; 10]));
+    bitcask.put(&vec![2], &vec![2]).unwrap();
+    drop(bitcask);
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.scan(..).unwrap(), vec![(vec![1], vec![1; 10]), (vec![2], vec![2])]);
+    drop(bitcask);
+
+    // an unknown critical extension (odd type) or format version makes the file unreadable
+    with_header(1, &[3, 1, 9]);
+    assert!(matches!(BitCask::new(&data_dir), Err(BitCaskError::CorruptedData(_))));
+    with_header(2, &[]);
+    assert!(matches!(BitCask::new(&data_dir), Err(BitCaskError::CorruptedData(_))));
+}
+
+#[test]
+fn kvdb_facade() {
+    use kvdb::KeyValueDB;
+    let bitcask = generate_random_bitcask_instance();
+    let db: &dyn KeyValueDB = &bitcask;
+    let mut transaction = db.transaction();
+    transaction.put(0, b"a1", b"x");
+    transaction.put(0, b"a2", b"y");
+    transaction.put(0, b"b1", b"z");
+    transaction.put(1, b"a1", b"other column");
+    db.write(transaction).unwrap();
+    assert_eq!(db.get(0, b"a1").unwrap(), Some(b"x".to_vec()));
+    assert_eq!(db.get(1, b"a1").unwrap(), Some(b"other column".to_vec()));
+    assert_eq!(db.get_by_prefix(0, b"a").unwrap(), Some(b"x".to_vec()));
+    let keys: Vec<Vec<u8>> = db.iter(0).map(|pair| pair.unwrap().0.to_vec()).collect();
+    assert_eq!(keys, vec![b"a1".to_vec(), b"a2".to_vec(), b"b1".to_vec()]);
+
+    let mut transaction = db.transaction();
+    transaction.delete_prefix(0, b"a");
+    transaction.delete(1, b"a1");
+    db.write(transaction).unwrap();
+    assert!(!db.has_prefix(0, b"a").unwrap());
+    assert!(!db.has_key(1, b"a1").unwrap());
+    assert_eq!(db.iter_with_prefix(0, b"b").count(), 1);
+}
+
+#[test]
+fn sled_shim() {
+    let data_dir = generate_random_data_dir();
+    let db = sled::open(&data_dir).unwrap();
+    assert_eq!(db.insert(b"a1", b"x").unwrap(), None);
+    assert_eq!(db.insert("a1", "y").unwrap(), Some(b"x".to_vec()));
+    db.insert(b"a2", vec![2]).unwrap();
+    db.insert(b"b1", vec![3]).unwrap();
+    let other = db.open_tree("other").unwrap();
+    other.insert(b"a1", b"z").unwrap();
+
+    assert_eq!(db.get(b"a1").unwrap(), Some(b"y".to_vec()));
+    assert_eq!(other.get(b"a1").unwrap(), Some(b"z".to_vec()));
+    let pairs: Vec<_> = db.scan_prefix(b"a").map(|pair| pair.unwrap()).collect();
+    assert_eq!(pairs, vec![(b"a1".to_vec(), b"y".to_vec()), (b"a2".to_vec(), vec![2])]);
+    assert_eq!(other.iter().count(), 1);
+
+    assert_eq!(db.remove(b"a1").unwrap(), Some(b"y".to_vec()));
+    assert_eq!(db.remove(b"a1").unwrap(), None);
+    assert!(!db.contains_key(b"a1").unwrap());
+    assert_eq!(db.flush().unwrap(), 0);
+}
+
+#[test]
+fn actor_handle() {
+    use std::future::Future;
+    let handle = BitCaskActor::spawn(generate_random_data_dir(), BitCaskOptions::default()).unwrap();
+    let writers: Vec<_> = (0..4u8)
+        .map(|i| {
+            let handle = handle.clone();
+            std::thread::spawn(move || handle.put(vec![i], vec![i; 10], None).wait().unwrap())
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert_eq!(handle.get(vec![3]).wait().unwrap(), Some(vec![3; 10]));
+
+    // replies can be awaited; commands run in order, so the delete has finished once the get is ready
+    handle.delete(vec![3]);
+    let mut reply = handle.get(vec![3]);
+    let mut cx = Context::from_waker(Waker::noop());
+    let value = loop {
+        if let std::task::Poll::Ready(value) = std::pin::Pin::new(&mut reply).poll(&mut cx) {
+            break value;
+        }
+        std::thread::yield_now();
+    };
+    assert_eq!(value.unwrap(), None);
+    assert_eq!(handle.call(|bitcask| Ok(bitcask.scan(..)?.len())).wait().unwrap(), 3);
+
+    handle.close().wait().unwrap();
+    assert!(matches!(handle.get(vec![0]).wait(), Err(BitCaskError::ActorStopped)));
+}
+
+#[test]
+fn prefix_trees() {
+    let data_dir = generate_random_data_dir();
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    let mut users = bitcask.open_tree("users").unwrap();
+    let mut jobs = bitcask.open_tree("jobs").unwrap();
+    assert_eq!(users.name(), b"users");
+    users.put(&b"1".to_vec(), &b"alice".to_vec()).unwrap();
+    users.put(&b"2".to_vec(), &b"bob".to_vec()).unwrap();
+    jobs.put(&b"1".to_vec(), &b"build".to_vec()).unwrap();
+
+    // keys are namespaced per tree and iteration strips the prefix
+    assert_eq!(users.get(&b"1".to_vec()), Some(b"alice".to_vec()));
+    assert_eq!(jobs.get(&b"1".to_vec()), Some(b"build".to_vec()));
+    assert_eq!(bitcask.get(&b"1".to_vec()), None);
+    let pairs: Vec<_> = users.iter().map(|pair| pair.unwrap()).collect();
+    assert_eq!(pairs, vec![(b"1".to_vec(), b"alice".to_vec()), (b"2".to_vec(), b"bob".to_vec())]);
+    assert_eq!((users.len(), jobs.len()), (2, 1));
+
+    // trees share the underlying files and the sled shim sees the same trees
+    assert_eq!(users.clear().unwrap(), 2);
+    assert!(users.is_empty());
+    assert_eq!(jobs.size(), 1);
+    bitcask.close().unwrap();
+    let db = sled::open(&data_dir).unwrap();
+    assert_eq!(db.open_tree("jobs").unwrap().get(b"1").unwrap(), Some(b"build".to_vec()));
+    assert_eq!(db.open_tree("users").unwrap().iter().count(), 0);
+}
+
+#[test]
+fn export_checkpoint() {
+    let mut bitcask = generate_random_bitcask_instance();
+    bitcask.put(&vec![1], &vec![1; 10]).unwrap();
+    bitcask.put(&vec![1], &vec![2; 10]).unwrap();
+    bitcask.put(&vec![2], &vec![3; 10]).unwrap();
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    bitcask.delete(&vec![2]).unwrap();
+    bitcask.put(&vec![3], &vec![4; 10]).unwrap();
+    let export_dir = generate_random_data_dir();
+    bitcask.export_checkpoint(&export_dir).unwrap();
+    // the source is unaffected and writes after the export are not visible in it
+    bitcask.put(&vec![1], &vec![5; 10]).unwrap();
+
+    // the export is a single compacted data file with a keydir and a clean shutdown marker
+    let mut names: Vec<_> = std::fs::read_dir(&export_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names.len(), 3, "{:?}", names);
+    assert!(names[0].ends_with(".bitcask"));
+    assert_eq!(names[1..], ["clean-shutdown".to_string(), "keydir".to_string()]);
+    let exported = BitCask::new(&export_dir).unwrap();
+    assert_eq!(exported.scan(..).unwrap(), vec![(vec![1], vec![2; 10]), (vec![3], vec![4; 10])]);
+    assert!(bitcask.export_checkpoint(&export_dir).is_err());
+}
+
+#[test]
+fn lock_poisoning() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    let panicked = Arc::new(AtomicBool::new(false));
+    let options = BitCaskOptions {
+        file_hook: Some(FileHook::new({
+            let panicked = panicked.clone();
+            move |_| {
+                if !panicked.swap(true, Ordering::SeqCst) {
+                    panic!("hook failure");
+                }
+            }
+        })),
+        ..BitCaskOptions::default()
+    };
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new_with_options(&data_dir, options).unwrap();
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    // the hook panics while compaction holds the storage write lock
+    let compacting = bitcask.clone();
+    let new_dir = generate_random_data_dir();
+    assert!(std::thread::spawn(move || compacting.compact_to_new_dir(new_dir)).join().is_err());
+
+    // callers get an error instead of a panic, and keep getting it until the store is reopened
+    assert!(matches!(bitcask.put(&vec![2], &vec![2]), Err(BitCaskError::Internal(_))));
+    assert!(matches!(bitcask.put(&vec![2], &vec![2]), Err(BitCaskError::Internal(_))));
+    assert!(matches!(bitcask.sync(), Err(BitCaskError::Internal(_))));
+    // reads keep serving the snapshot published before the panic
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+    assert_eq!(bitcask.get(&vec![2]), None);
+
+    drop(bitcask);
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put(&vec![2], &vec![2]).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+    assert_eq!(bitcask.get(&vec![2]), Some(vec![2]));
+}
+
+#[test]
+fn kv_storage_iteration() {
+    fn exercise<S: KVStorage>(mut store: S) {
+        store.put(&vec![2], &vec![2]).unwrap();
+        store.put(&vec![1], &vec![1]).unwrap();
+        store.put(&vec![3], &vec![3]).unwrap();
+        store.delete(&vec![3]).unwrap();
+        assert!(store.contains_key(&vec![1]));
+        assert!(!store.contains_key(&vec![3]));
+        assert_eq!(store.len(), 2);
+        assert!(!store.is_empty());
+        let pairs: Vec<_> = store.iter().map(|pair| pair.unwrap()).collect();
+        assert_eq!(pairs, vec![(vec![1], vec![1]), (vec![2], vec![2])]);
+        store.flush().unwrap();
+    }
+
+    let bitcask = generate_random_bitcask_instance();
+    exercise(bitcask.clone());
+    // the tombstone still counts towards size, but not towards len
+    assert_eq!((bitcask.size(), KVStorage::len(&bitcask)), (3, 2));
+    exercise(bitcask.open_tree("tree").unwrap());
+    exercise(ShadowStore::new(
+        generate_random_bitcask_instance(),
+        generate_random_bitcask_instance(),
+        true,
+    ));
+
+    // an implementor providing only the required methods gets the defaults
+    #[derive(Clone, Default)]
+    struct Minimal(Arc<Mutex<std::collections::BTreeMap<Vec<u8>, Vec<u8>>>>);
+    impl KVStorage for Minimal {
+        fn get(&self, key: &Vec<u8>) -> Option<Vec<u8>> {
+            self.0.lock().unwrap().get(key).cloned()
+        }
+        fn put_with_option(&mut self, key: &Vec<u8>, value: &Vec<u8>, _: Option<PutOption>) -> Result<(), BitCaskError> {
+            self.0.lock().unwrap().insert(key.clone(), value.clone());
+            Ok(())
+        }
+        fn delete(&mut self, key: &Vec<u8>) -> Result<(), BitCaskError> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+        fn size(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+        fn len(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+    }
+    let mut minimal = Minimal::default();
+    minimal.put(&vec![1], &vec![1]).unwrap();
+    assert!(minimal.contains_key(&vec![1]) && !minimal.is_empty());
+    assert!(matches!(minimal.iter().next(), Some(Err(BitCaskError::IoError(_)))));
+    minimal.flush().unwrap();
+}
+
+#[test]
+fn compaction_filter() {
+    let options = BitCaskOptions {
+        blob_threshold: Some(64),
+        compaction_filter: Some(CompactionFilter::new(|key, value, metadata| {
+            if key.starts_with(b"t1/") {
+                CompactionDecision::Drop
+            } else if metadata == Some(b"redact") {
+                CompactionDecision::Replace(vec![0; value.len()])
+            } else {
+                CompactionDecision::Keep
+            }
+        })),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(generate_random_data_dir(), options).unwrap();
+    bitcask.put(&b"t1/a".to_vec(), &vec![1; 10]).unwrap();
+    bitcask.put(&b"t1/big".to_vec(), &vec![1; 100]).unwrap();
+    bitcask.put(&b"t2/a".to_vec(), &vec![2; 10]).unwrap();
+    bitcask.put(&b"t2/big".to_vec(), &vec![2; 100]).unwrap();
+    bitcask
+        .put_with_option(&b"t2/secret".to_vec(), &vec![3; 10], PutOption::metadata(b"redact".to_vec()))
+        .unwrap();
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+
+    assert_eq!(bitcask.get(&b"t1/a".to_vec()), None);
+    assert_eq!(bitcask.get(&b"t1/big".to_vec()), None);
+    assert_eq!(bitcask.get(&b"t2/a".to_vec()), Some(vec![2; 10]));
+    assert_eq!(bitcask.get(&b"t2/big".to_vec()), Some(vec![2; 100]));
+    let (value, metadata) = bitcask.get_with_metadata(&b"t2/secret".to_vec()).unwrap().unwrap();
+    assert_eq!((value, metadata), (vec![0; 10], Some(b"redact".to_vec())));
+}
+
+#[test]
+fn default_ttl() {
+    let options = BitCaskOptions {
+        default_ttl: Some(Duration::from_secs(3600)),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(generate_random_data_dir(), options).unwrap();
+    let expire_at = |bitcask: &BitCask, key: &[u8]| bitcask.get_with_meta(&key.to_vec()).unwrap().unwrap().expire_at;
+    let hour_from_now = || {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+        now.as_millis() as u64 + 3_600_000
+    };
+
+    // writes without an explicit expiry get the default, explicit ones keep theirs
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    bitcask.put_many_sorted(&[(vec![2], vec![2])]).unwrap();
+    bitcask.put_with_option(&vec![3], &vec![3], PutOption::ttl(Duration::from_secs(10))).unwrap();
+    for key in [1, 2] {
+        assert!(expire_at(&bitcask, &[key]).unwrap().abs_diff(hour_from_now()) < 60_000);
+    }
+    assert!(expire_at(&bitcask, &[3]).unwrap() < hour_from_now() - 3_000_000);
+
+    // a tree handle can use its own default
+    let mut sessions = bitcask.open_tree("sessions").unwrap().with_default_ttl(Duration::from_millis(1));
+    sessions.put(&vec![1], &vec![1]).unwrap();
+    std::thread::sleep(Duration::from_millis(5));
+    assert_eq!(sessions.get(&vec![1]), None);
+
+    // the default is tunable at runtime
+    let mut tunable = bitcask.tunable_options();
+    tunable.default_ttl = None;
+    bitcask.reconfigure(tunable).unwrap();
+    bitcask.put(&vec![4], &vec![4]).unwrap();
+    assert_eq!(expire_at(&bitcask, &[4]), None);
+}
+
+#[test]
+fn file_stats() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    for i in 0..10u8 {
+        bitcask.put(&vec![i], &vec![i; 100]).unwrap();
+    }
+    for i in 0..5u8 {
+        bitcask.put(&vec![i], &vec![i; 50]).unwrap();
+    }
+    bitcask.delete(&vec![8]).unwrap();
+    bitcask.delete(&vec![9]).unwrap();
+    let stats = bitcask.stats();
+    assert_eq!(stats.files.len(), 1);
+    let file = &stats.files[0];
+    assert_eq!((file.entries, file.live_entries), (17, 8));
+    assert!(file.live_bytes > 5 * 50 + 3 * 100 && file.live_bytes < file.bytes);
+    assert!(file.live_ratio() < 0.7);
+    // the counts survive a reopen, both from the keydir and from a full scan
+    bitcask.close().unwrap();
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.stats().files, stats.files);
+    drop(bitcask);
+    std::fs::remove_file(format!("{}/keydir", data_dir)).unwrap();
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.stats().files, stats.files);
+    // a compacted file holds only live records
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    let files = bitcask.stats().files;
+    assert_eq!(files.iter().map(|file| file.entries).sum::<u64>(), 8);
+    let file = files.iter().find(|file| file.entries > 0).unwrap();
+    assert_eq!((file.entries, file.live_entries), (8, 8));
+    assert_eq!(file.live_ratio(), 1.0);
+}
+
+#[test]
+fn read_only_reader() {
+    let data_dir = generate_random_data_dir();
+    let mut writer = BitCask::new(&data_dir).unwrap();
+    writer.put(&vec![1], &vec![1; 10]).unwrap();
+    writer.save_keydir().unwrap();
+    let reader = ReadOnlyBitCask::open(&data_dir, BitCaskOptions::default()).unwrap();
+    assert_eq!(reader.get(&vec![1]), Some(vec![1; 10]));
+    // writes still being appended to the active file are not visible until the keydir is saved again
+    writer.put(&vec![2], &vec![2; 10]).unwrap();
+    assert!(!reader.refresh().unwrap());
+    assert_eq!(reader.get(&vec![2]), None);
+    writer.save_keydir().unwrap();
+    assert!(reader.refresh().unwrap());
+    assert_eq!(reader.get(&vec![2]), Some(vec![2; 10]));
+    // compacting seals the active file, which the reader then loads incrementally
+    writer.put(&vec![3], &vec![3; 10]).unwrap();
+    writer.delete(&vec![1]).unwrap();
+    writer.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    assert!(reader.refresh().unwrap());
+    assert_eq!(reader.get(&vec![3]), Some(vec![3; 10]));
+    assert_eq!(reader.get(&vec![1]), None);
+    assert_eq!(reader.len(), 2);
+    let keys: Vec<_> = reader.iter(..).map(|pair| pair.unwrap().0).collect();
+    assert_eq!(keys, vec![vec![2], vec![3]]);
+    // readers share the directory lock with the writer, so the directory cannot be compacted in place under them
+    assert!(matches!(
+        BitCask::compact_offline(&data_dir, BitCaskOptions::default()),
+        Err(BitCaskError::DirectoryLocked)
+    ));
+}
+
+#[test]
+fn io_watchdog() {
+    // every append exceeds a 1ns deadline; a failing watchdog rolls the write back
+    let watchdog = |action| IoWatchdog {
+        deadline: Duration::from_nanos(1),
+        action,
+    };
+    let data_dir = generate_random_data_dir();
+    let options = BitCaskOptions {
+        io_watchdog: Some(watchdog(StallAction::Fail)),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(&data_dir, options).unwrap();
+    assert!(matches!(bitcask.put(&vec![1], &vec![1]), Err(BitCaskError::IoStalled(_))));
+    assert_eq!(bitcask.get(&vec![1]), None);
+    assert!(bitcask.health().unwrap().io_stalls >= 1);
+    drop(bitcask);
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), None);
+    assert_eq!(bitcask.stats().files[0].bytes, 0);
+    // the read-only action keeps the write but degrades the store until writes are resumed
+    let options = BitCaskOptions {
+        io_watchdog: Some(watchdog(StallAction::ReadOnly)),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(generate_random_data_dir(), options).unwrap();
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    let health = bitcask.health().unwrap();
+    assert!(health.degraded && !health.is_healthy());
+    assert!(matches!(bitcask.put(&vec![2], &vec![2]), Err(BitCaskError::ReadOnly)));
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+    assert!(bitcask.resume_writes().unwrap());
+    assert!(!bitcask.health().unwrap().degraded);
+}
+
+#[test]
+fn try_get_reports_errors() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put(&vec![0], &vec![0; 10]).unwrap();
+    bitcask.put(&vec![1], &vec![1; 10]).unwrap();
+    bitcask.delete(&vec![1]).unwrap();
+    bitcask.put(&vec![2], &vec![2; 10]).unwrap();
+    bitcask.save_keydir().unwrap();
+    drop(bitcask);
+
+    // flip the first value byte of the first record so its checksum no longer matches
+    let path = format!("{}/0.bitcask", data_dir);
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes[FILE_HEADER_SIZE + 17] ^= 0xff;
+    std::fs::write(&path, bytes).unwrap();
+    let audit_dir = format!("{}.audit", data_dir);
+    let options = BitCaskOptions {
+        audit: Some(AuditOptions {
+            audit_reads: true,
+            ..AuditOptions::new(&audit_dir)
+        }),
+        ..BitCaskOptions::default()
+    };
+    let bitcask = BitCask::new_with_options(&data_dir, options).unwrap();
+
+    // get cannot tell the corrupted key from a missing one, try_get can
+    assert_eq!(bitcask.get(&vec![0]), None);
+    assert!(matches!(bitcask.try_get(&vec![0]), Err(BitCaskError::CorruptedData(_))));
+    assert_eq!(bitcask.try_get(&vec![1]).unwrap(), None);
+    assert_eq!(bitcask.try_get(&vec![2]).unwrap(), Some(vec![2; 10]));
+    assert_eq!(bitcask.try_get(&vec![3]).unwrap(), None);
+    // the audit log still records the failed get as a failure, unlike a miss
+    assert_eq!(bitcask.get(&vec![3]), None);
+    let records = std::fs::read_to_string(format!("{}/{}", audit_dir, AUDIT_FILE)).unwrap();
+    let outcomes: Vec<_> = records.lines().map(|record| record.contains(r#""ok":true"#)).collect();
+    assert_eq!(outcomes, vec![false, false, true, true, true, true]);
+}
+
+#[test]
+fn ttl_query() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    let option = PutOption {
+        ttl: Some(Duration::from_secs(60)),
+        ..PutOption::default()
+    };
+    let before = SystemTime::now();
+    bitcask.put_with_option(&vec![0], &vec![0], Some(option)).unwrap();
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+
+    let ttl = bitcask.ttl(&vec![0]).unwrap();
+    assert!(ttl <= Duration::from_secs(60) && ttl > Duration::from_secs(50));
+    let expires_at = bitcask.expires_at(&vec![0]).unwrap();
+    assert!(expires_at >= before + Duration::from_secs(59) && expires_at <= SystemTime::now() + Duration::from_secs(60));
+    // keys without an expiry and missing keys both have no ttl
+    assert_eq!(bitcask.ttl(&vec![1]), None);
+    assert!(bitcask.contains_key(&vec![1]));
+    assert_eq!(bitcask.ttl(&vec![2]), None);
+    assert_eq!(bitcask.expires_at(&vec![2]), None);
+
+    // the expiry is persisted with the record
+    drop(bitcask);
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.expires_at(&vec![0]), Some(expires_at));
+    let tree = bitcask.open_tree("t").unwrap();
+    assert_eq!(tree.ttl(&[0]), None);
+}
+
+#[test]
+fn expiring_before() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    for (key, ttl) in [(0u8, 300), (1, 100), (2, 200), (3, 10_000)] {
+        let option = PutOption {
+            ttl: Some(Duration::from_secs(ttl)),
+            ..PutOption::default()
+        };
+        bitcask.put_with_option(&vec![key], &vec![key], Some(option)).unwrap();
+    }
+    bitcask.put(&vec![4], &vec![4]).unwrap();
+    // an already expired key is not reported as expiring
+    let option = PutOption {
+        ttl: Some(Duration::from_millis(1)),
+        ..PutOption::default()
+    };
+    bitcask.put_with_option(&vec![5], &vec![5], Some(option)).unwrap();
+    std::thread::sleep(Duration::from_millis(5));
+
+    let before = SystemTime::now() + Duration::from_secs(1000);
+    let expiring = bitcask.expiring_before(before, 10);
+    let keys: Vec<_> = expiring.iter().map(|(key, _)| key[0]).collect();
+    assert_eq!(keys, vec![1, 2, 0]);
+    assert_eq!(expiring[0].1, bitcask.expires_at(&vec![1]).unwrap());
+    assert_eq!(bitcask.expiring_before(before, 2).len(), 2);
+    assert!(bitcask.expiring_before(SystemTime::now(), 10).is_empty());
+
+    // refreshing a key moves it out of the window
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    let keys: Vec<_> = bitcask.expiring_before(before, 10).into_iter().map(|(key, _)| key[0]).collect();
+    assert_eq!(keys, vec![2, 0]);
+}
+
+#[test]
+fn estimate_range_size() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    for i in 0..100u8 {
+        bitcask.put(&vec![i, 0], &vec![i; 10]).unwrap();
+    }
+    bitcask.put(&vec![5, 0], &vec![5; 30]).unwrap();
+    bitcask.delete(&vec![6, 0]).unwrap();
+
+    let all = bitcask.estimate_range_size(..);
+    assert_eq!(all.keys, 99);
+    assert_eq!(all.key_bytes, 99 * 2);
+    assert_eq!(all.value_bytes, 98 * 10 + 30);
+    assert_eq!(all.bytes(), all.key_bytes + all.value_bytes);
+
+    let part = bitcask.estimate_range_size(vec![0]..vec![10]);
+    assert_eq!(part.keys, 9);
+    assert_eq!(part.value_bytes, 8 * 10 + 30);
+    assert_eq!(bitcask.estimate_range_size(vec![200]..).keys, 0);
+}
+
+#[test]
+fn rotate_active_file() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = events.clone();
+    let options = BitCaskOptions {
+        file_hook: Some(FileHook::new(move |event| recorded.lock().unwrap().push(event.clone()))),
+        ..BitCaskOptions::default()
+    };
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new_with_options(&data_dir, options).unwrap();
+    // an empty active file is not rotated
+    assert_eq!(bitcask.rotate_active_file().unwrap(), None);
+    bitcask.put(&vec![0], &vec![0]).unwrap();
+    assert_eq!(bitcask.rotate_active_file().unwrap(), Some(0));
+    assert_eq!(bitcask.rotate_active_file().unwrap(), None);
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    assert_eq!(bitcask.stats().data_files, 2);
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![FileEvent::Sealed(std::path::Path::new(&data_dir).join("0.bitcask"))]
+    );
+    assert!(std::path::Path::new(&data_dir).join("keydir").exists());
+
+    // the rotated file is sealed, so a reader sees its writes without a keydir covering the new file
+    let reader = ReadOnlyBitCask::open(&data_dir, BitCaskOptions::default()).unwrap();
+    assert_eq!(reader.get(&vec![0]), Some(vec![0]));
+    drop(reader);
+    drop(bitcask);
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![0]), Some(vec![0]));
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![1]));
+}
+
+#[test]
+fn maintenance_pool() {
+    // a single maintenance thread runs fsyncs, sweeps and keydir saves
+    let options = BitCaskOptions {
+        maintenance_threads: 1,
+        sync_policy: SyncPolicy::Interval(Duration::from_millis(5)),
+        expiry_sweep: Some(ExpirySweep {
+            interval: Duration::from_millis(5),
+            batch_size: 2,
+        }),
+        keydir_interval: Some(Duration::from_millis(5)),
+        ..BitCaskOptions::default()
+    };
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new_with_options(&data_dir, options).unwrap();
+    for i in 0..10u8 {
+        bitcask.put_with_option(&vec![i], &vec![i], PutOption::ttl(Duration::from_millis(1))).unwrap();
+    }
+    let ack = bitcask.put_with_ack(&vec![100], &vec![100]).unwrap();
+    assert!(ack.wait_timeout(Duration::from_secs(10)));
+    let keydir = std::path::Path::new(&data_dir).join("keydir");
+    let started = std::time::Instant::now();
+    while bitcask.stats().expired_swept < 10 || !keydir.exists() {
+        assert!(started.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    // after reconfiguring, the old tasks stop and only the configured ones keep running
+    let mut tunable = bitcask.tunable_options();
+    tunable.keydir_interval = None;
+    bitcask.reconfigure(tunable).unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    std::fs::remove_file(&keydir).unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    assert!(!keydir.exists());
+    let ack = bitcask.put_with_ack(&vec![101], &vec![101]).unwrap();
+    assert!(ack.wait_timeout(Duration::from_secs(10)));
+}
+
+#[test]
+fn warm_up() {
+    let options = BitCaskOptions {
+        inline_value_threshold: Some(4),
+        blob_threshold: Some(1000),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(generate_random_data_dir(), options).unwrap();
+    for i in 0..10u8 {
+        bitcask.put(&vec![b'a', i], &vec![i; 100]).unwrap();
+    }
+    bitcask.put(&b"blob".to_vec(), &vec![1; 2000]).unwrap();
+    bitcask.put(&b"inline".to_vec(), &vec![1]).unwrap();
+    bitcask.delete(&vec![b'a', 9]).unwrap();
+
+    // inline values, deleted and missing keys are skipped
+    let keys = vec![b"blob".to_vec(), b"inline".to_vec(), vec![b'a', 0], vec![b'a', 9], b"missing".to_vec()];
+    assert_eq!(bitcask.warm_up(&keys).unwrap(), 2);
+    assert_eq!(bitcask.warm_up_prefix(b"a").unwrap(), 9);
+    assert_eq!(bitcask.warm_up_prefix(b"").unwrap(), 10);
+}
+
+#[test]
+fn composite_keys() {
+    let mut bitcask = generate_random_bitcask_instance();
+    let epoch = SystemTime::UNIX_EPOCH;
+    let rows: Vec<(u64, &str, i64)> = vec![
+        (2, "b", -1),
+        (1, "a\0b", 5),
+        (1, "a", 300),
+        (256, "", 0),
+        (1, "a", -300),
+        (1, "ab", 0),
+    ];
+    for (tenant, name, seq) in &rows {
+        let key = KeyEncoder::new()
+            .u64(*tenant)
+            .str(name)
+            .i64(*seq)
+            .timestamp(epoch + Duration::from_nanos(7))
+            .finish();
+        bitcask.put(&key, &vec![]).unwrap();
+    }
+
+    // keys come back in tuple order, decoded to the same components
+    let decoded: Vec<(u64, String, i64)> = bitcask
+        .iter(..)
+        .map(|pair| {
+            let key = pair.unwrap().0;
+            let mut decoder = KeyDecoder::new(&key);
+            let row = (decoder.u64().unwrap(), decoder.str().unwrap(), decoder.i64().unwrap());
+            assert_eq!(decoder.timestamp().unwrap(), epoch + Duration::from_nanos(7));
+            assert!(decoder.is_empty());
+            row
+        })
+        .collect();
+    let mut expected: Vec<_> = rows.iter().map(|(tenant, name, seq)| (*tenant, name.to_string(), *seq)).collect();
+    expected.sort();
+    assert_eq!(decoded, expected);
+
+    // a prefix of the components scans exactly the matching keys
+    let prefix = KeyEncoder::new().u64(1).str("a").finish();
+    assert_eq!(bitcask.iter_prefix(&prefix).count(), 2);
+    assert_eq!(bitcask.iter_prefix(&KeyEncoder::new().u64(1).finish()).count(), 4);
+
+    let mut decoder = KeyDecoder::new(&[0x61, 0x00]);
+    assert!(matches!(decoder.bytes(), Err(BitCaskError::InvalidKeyEncoding(_))));
+    assert!(matches!(KeyDecoder::new(&[1, 2]).u64(), Err(BitCaskError::InvalidKeyEncoding(_))));
+}
+
+#[test]
+fn get_entries() {
+    let options = BitCaskOptions {
+        inline_value_threshold: Some(2),
+        blob_threshold: Some(1000),
+        dictionary_compression: Some(DictionaryCompression::default()),
+        ..BitCaskOptions::default()
+    };
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new_with_options(&data_dir, options).unwrap();
+    let mut rng = rand::thread_rng();
+    let mut keys = Vec::new();
+    for i in 0..200u32 {
+        let key = i.to_be_bytes().to_vec();
+        let len = match i % 10 {
+            0 => 1,
+            1 => 5000,
+            _ => rng.gen_range(10..300),
+        };
+        bitcask.put(&key, &vec![i as u8; len]).unwrap();
+        keys.push(key);
+    }
+    bitcask.delete(&keys[3]).unwrap();
+    // ask in a scattered order, with a missing key and a duplicate
+    let mut wanted: Vec<_> = keys.iter().rev().step_by(3).cloned().collect();
+    wanted.push(b"missing".to_vec());
+    wanted.push(keys[7].clone());
+    wanted.push(keys[3].clone());
+    let results = bitcask.get_entries(&wanted);
+    assert_eq!(results.len(), wanted.len());
+    for (key, res) in wanted.iter().zip(results) {
+        assert_eq!(res.unwrap(), bitcask.get(key));
+    }
+
+    // a corrupted value fails only its own entry
+    bitcask.put(&vec![0], &vec![0; 10]).unwrap();
+    bitcask.save_keydir().unwrap();
+    drop(bitcask);
+    let path = format!("{}/0.bitcask", data_dir);
+    let mut bytes = std::fs::read(&path).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&path, bytes).unwrap();
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    let results = bitcask.get_entries(&[keys[5].clone(), vec![0], keys[6].clone()]);
+    assert!(results[0].as_ref().unwrap().is_some());
+    assert!(matches!(results[1], Err(BitCaskError::CorruptedData(_))));
+    assert!(results[2].as_ref().unwrap().is_some());
+}
+
+#[test]
+fn actor_op_timeout() {
+    use std::future::Future;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::Wake;
+
+    struct Flag(AtomicBool);
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let options = BitCaskOptions {
+        op_timeout: Some(Duration::from_millis(50)),
+        ..BitCaskOptions::default()
+    };
+    let handle = BitCaskActor::spawn(generate_random_data_dir(), options).unwrap();
+    handle.put(vec![1], vec![1], None).wait().unwrap();
+
+    // stall the actor thread, as a hung disk would
+    let stall = handle.call(|_| {
+        std::thread::sleep(Duration::from_millis(500));
+        Ok(())
+    });
+    let started = std::time::Instant::now();
+    assert!(matches!(handle.get(vec![1]).wait(), Err(BitCaskError::Timeout)));
+    assert!(started.elapsed() < Duration::from_millis(400));
+
+    // an awaiting caller is woken up with the timeout as well
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut reply = handle.put(vec![2], vec![2], None);
+    assert!(std::pin::Pin::new(&mut reply).poll(&mut cx).is_pending());
+    while !flag.0.load(Ordering::SeqCst) {
+        assert!(started.elapsed() < Duration::from_millis(400));
+        std::thread::yield_now();
+    }
+    assert!(matches!(std::pin::Pin::new(&mut reply).poll(&mut cx), std::task::Poll::Ready(Err(BitCaskError::Timeout))));
+
+    // calls without a timeout still wait, and the timed out write completes afterwards
+    stall.wait().unwrap();
+    assert_eq!(handle.get(vec![2]).wait().unwrap(), Some(vec![2]));
+    handle.close().wait().unwrap();
+}
+
+#[test]
+fn read_txn() {
+    let mut bitcask = generate_random_bitcask_instance();
+    for i in 0..10u8 {
+        bitcask.put(&vec![i], &vec![i]).unwrap();
+    }
+    bitcask
+        .put_with_option(&vec![20], &vec![20], PutOption::ttl(Duration::from_millis(200)))
+        .unwrap();
+    let txn = bitcask.read_txn();
+
+    // writes, deletes and compaction after the transaction started are not visible
+    bitcask.put(&vec![0], &vec![100]).unwrap();
+    bitcask.delete(&vec![1]).unwrap();
+    bitcask.put(&vec![10], &vec![10]).unwrap();
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    assert_eq!(txn.get(&vec![0]).unwrap(), Some(vec![0]));
+    assert_eq!(txn.get(&vec![1]).unwrap(), Some(vec![1]));
+    assert!(!txn.contains_key(&vec![10]).unwrap());
+    assert_eq!(txn.count(..), 11);
+    assert_eq!(txn.scan(vec![0]..vec![3]).unwrap().len(), 3);
+
+    // expiry is judged at the start of the transaction
+    std::thread::sleep(Duration::from_millis(250));
+    assert_eq!(bitcask.get(&vec![20]), None);
+    assert_eq!(txn.get(&vec![20]).unwrap(), Some(vec![20]));
+    assert_eq!(txn.iter_prefix(&[20]).count(), 1);
+    assert_eq!(txn.iter(..).count(), txn.count(..));
+
+    let txn = bitcask.read_txn();
+    assert_eq!(txn.get(&vec![0]).unwrap(), Some(vec![100]));
+    assert_eq!(txn.count(..), 10);
+}
+
+#[test]
+fn disk_usage_cap() {
+    let options = BitCaskOptions {
+        disk_usage_cap: Some(DiskUsageCap {
+            max_bytes: 4000,
+            action: CapAction::Fail,
+        }),
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(generate_random_data_dir(), options).unwrap();
+    let mut written = 0u8;
+    let err = loop {
+        match bitcask.put(&vec![written], &vec![written; 1000]) {
+            Ok(()) => written += 1,
+            Err(e) => break e,
+        }
+    };
+    assert!(matches!(err, BitCaskError::WriteStalled));
+    assert_eq!(written, 4);
+    assert_eq!(bitcask.stats().write_stalls, 1);
+    // deletes still go through, they are how space is reclaimed
+    for i in 1..written {
+        bitcask.delete(&vec![i]).unwrap();
+    }
+
+    // a blocking cap times out if nothing frees space
+    let mut tunable = bitcask.tunable_options();
+    tunable.disk_usage_cap = Some(DiskUsageCap {
+        max_bytes: 4000,
+        action: CapAction::Block(Some(Duration::from_millis(30))),
+    });
+    bitcask.reconfigure(tunable.clone()).unwrap();
+    assert!(matches!(bitcask.put(&vec![9], &vec![9]), Err(BitCaskError::WriteStalled)));
+    assert!(bitcask.stats().write_stall_time >= Duration::from_millis(30));
+
+    // without a timeout the write waits until compaction brings usage below the cap
+    tunable.disk_usage_cap.as_mut().unwrap().action = CapAction::Block(None);
+    bitcask.reconfigure(tunable).unwrap();
+    let writer = {
+        let mut bitcask = bitcask.clone();
+        std::thread::spawn(move || bitcask.put(&vec![9], &vec![9]))
+    };
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(!writer.is_finished());
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    writer.join().unwrap().unwrap();
+    assert_eq!(bitcask.get(&vec![9]), Some(vec![9]));
+    assert_eq!(bitcask.stats().write_stalls, 3);
+}
+
+#[test]
+fn bogus_record_sizes() {
+    // a size field far larger than the file is reported as corruption instead of being allocated
+    for (key_size, value_size) in [(1u64, 1u64 << 40), (1 << 50, 5)] {
+        let data_dir = generate_random_data_dir();
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let mut record = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM).checksum(b"value").to_be_bytes().to_vec();
+        record.extend_from_slice(&key_size.to_be_bytes());
+        record.extend_from_slice(&value_size.to_be_bytes());
+        record.extend_from_slice(b"k");
+        record.extend_from_slice(b"value");
+        std::fs::write(format!("{}/0.bitcask", data_dir), record).unwrap();
+        assert!(matches!(BitCask::new(&data_dir), Err(BitCaskError::CorruptedData(_))));
+    }
+
+    // a record cut off by a crash is reported the same way
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put(&vec![1], &vec![1; 100]).unwrap();
+    bitcask.put(&vec![2], &vec![2; 100]).unwrap();
+    drop(bitcask);
+    let path = format!("{}/0.bitcask", data_dir);
+    let len = std::fs::metadata(&path).unwrap().len();
+    std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 10).unwrap();
+    assert!(matches!(BitCask::new(&data_dir), Err(BitCaskError::CorruptedData(_))));
+}
+
+#[test]
+fn absurd_size_fields() {
+    // metadata longer than MAX_METADATA_SIZE cannot have been written and is rejected even if the bytes are there
+    let data_dir = generate_random_data_dir();
+    std::fs::create_dir_all(&data_dir).unwrap();
+    // checksum | version 2 | flags (metadata) | key size | value size | metadata length 1000 | metadata | key | value
+    let mut record = crc32c::crc32c(b"value").to_be_bytes().to_vec();
+    record.extend_from_slice(&[2, 0x80, 0x04, 1, 5, 0xe8, 0x07]);
+    record.extend_from_slice(&[0; 1000]);
+    record.extend_from_slice(b"k");
+    record.extend_from_slice(b"value");
+    std::fs::write(format!("{}/0.bitcask", data_dir), record).unwrap();
+    assert!(matches!(BitCask::new(&data_dir), Err(BitCaskError::CorruptedData(_))));
+
+    // a trace record claiming 4GB ends the trace like a torn write instead of allocating the buffer
+    let trace_file = format!("{}/trace", generate_random_data_dir());
+    std::fs::create_dir_all(std::path::Path::new(&trace_file).parent().unwrap()).unwrap();
+    let mut trace = u32::MAX.to_le_bytes().to_vec();
+    trace.extend_from_slice(&[0; 100]);
+    std::fs::write(&trace_file, trace).unwrap();
+    assert_eq!(TraceReader::open(&trace_file).unwrap().count(), 0);
+}
+
+#[test]
+fn put_all_if() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put(&vec![1], &vec![1]).unwrap();
+    bitcask.put(&vec![2], &vec![2]).unwrap();
+    let version = bitcask.get_with_meta(&vec![1]).unwrap().unwrap().version;
+
+    // a failing condition writes nothing and names the condition
+    let conditions = [Condition::Version(vec![1], version), Condition::Absent(vec![2])];
+    let res = bitcask.put_all_if(&conditions, &[BatchWrite::Put(vec![3], vec![3])]);
+    assert!(matches!(res, Err(BitCaskError::ConditionFailed(1))));
+    assert_eq!(bitcask.get(&vec![3]), None);
+
+    let conditions = [
+        Condition::Version(vec![1], version),
+        Condition::Value(vec![2], vec![2]),
+        Condition::Absent(vec![3]),
+    ];
+    let writes = [
+        BatchWrite::Put(vec![1], vec![10]),
+        BatchWrite::Delete(vec![2]),
+        BatchWrite::Put(vec![3], vec![30]),
+    ];
+    bitcask.put_all_if(&conditions, &writes).unwrap();
+    assert_eq!(bitcask.get(&vec![1]), Some(vec![10]));
+    assert_eq!(bitcask.get(&vec![2]), None);
+    assert_eq!(bitcask.get(&vec![3]), Some(vec![30]));
+    let res = bitcask.put_all_if(&[Condition::Version(vec![1], version)], &[]);
+    assert!(matches!(res, Err(BitCaskError::ConditionFailed(0))));
+    drop(bitcask);
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.scan(..).unwrap(), vec![(vec![1], vec![10]), (vec![3], vec![30])]);
+    drop(bitcask);
+
+    // a batch cut short by a crash is dropped as a whole when the store is opened again
+    let single_dir = generate_random_data_dir();
+    let mut single = BitCask::new(&single_dir).unwrap();
+    single.put_all_if(&[], &[BatchWrite::Put(vec![5], vec![5; 100])]).unwrap();
+    drop(single);
+    let last_record = std::fs::metadata(format!("{}/0.bitcask", single_dir)).unwrap().len() - FILE_HEADER_SIZE as u64;
+    let path = format!("{}/0.bitcask", data_dir);
+    let committed = std::fs::metadata(&path).unwrap().len();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    let writes = [BatchWrite::Put(vec![4], vec![4]), BatchWrite::Put(vec![5], vec![5; 100])];
+    bitcask.put_all_if(&[], &writes).unwrap();
+    drop(bitcask);
+    let len = std::fs::metadata(&path).unwrap().len();
+    std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - last_record).unwrap();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.get(&vec![4]), None);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), committed);
+    bitcask.put(&vec![6], &vec![6]).unwrap();
+    drop(bitcask);
+    let bitcask = BitCask::new(&data_dir).unwrap();
+    assert_eq!(bitcask.scan(..).unwrap().len(), 3);
+    assert_eq!(bitcask.get(&vec![6]), Some(vec![6]));
+}
+
+#[test]
+fn scan_filtered() {
+    let mut bitcask = generate_random_bitcask_instance();
+    for i in 0..10u8 {
+        let tag = if i % 2 == 0 { b"even".to_vec() } else { b"odd".to_vec() };
+        bitcask.put_with_option(&vec![i], &vec![i; i as usize], PutOption::metadata(tag)).unwrap();
+    }
+    bitcask.put(&vec![10], &vec![10; 10]).unwrap();
+    bitcask.delete(&vec![8]).unwrap();
+
+    let long = bitcask.scan_filtered(vec![2]..vec![10], |_, value| value.len() >= 6).unwrap();
+    assert_eq!(long, vec![(vec![6], vec![6; 6]), (vec![7], vec![7; 7]), (vec![9], vec![9; 9])]);
+
+    // the metadata filter runs first and sees only the index
+    let mut seen = Vec::new();
+    let even = bitcask
+        .scan_filtered_by_metadata(
+            ..,
+            |_, metadata| metadata == Some(b"even".as_slice()),
+            |key, _| {
+                seen.push(key.to_vec());
+                key[0] > 2
+            },
+        )
+        .unwrap();
+    assert_eq!(even, vec![(vec![4], vec![4; 4]), (vec![6], vec![6; 6])]);
+    assert_eq!(seen, vec![vec![0], vec![2], vec![4], vec![6]]);
+}
+
+#[test]
+fn verify_compaction() {
+    // the filter damages the first merged record in the temporary output file once it reaches the last key,
+    // standing in for a bug or a bad disk between writing the output and swapping it in
+    let output_dir = generate_random_data_dir();
+    let damaged_dir = output_dir.clone();
+    let options = BitCaskOptions {
+        verify_compaction: true,
+        dedup: true,
+        compaction_filter: Some(CompactionFilter::new(move |key, _, _| {
+            if key == b"z" {
+                use std::os::unix::fs::FileExt;
+                for path in std::fs::read_dir(&damaged_dir).unwrap().map(|entry| entry.unwrap().path()) {
+                    if path.extension() == Some("tmp".as_ref()) {
+                        let file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+                        file.write_at(&[0xAA; 4], 40).unwrap();
+                    }
+                }
+            }
+            CompactionDecision::Keep
+        })),
+        ..BitCaskOptions::default()
+    };
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new_with_options(&data_dir, options.clone()).unwrap();
+    bitcask.put(&b"a".to_vec(), &vec![1; 64]).unwrap();
+    bitcask.put(&b"z".to_vec(), &vec![2; 64]).unwrap();
+    assert!(matches!(bitcask.compact_to_new_dir(&output_dir), Err(BitCaskError::CorruptedData(_))));
+
+    // the corrupt output is discarded and the store keeps serving the original files
+    assert_eq!(bitcask.get(&b"a".to_vec()), Some(vec![1; 64]));
+    assert_eq!(bitcask.get(&b"z".to_vec()), Some(vec![2; 64]));
+    assert_eq!(std::fs::read_dir(&output_dir).unwrap().filter(|entry| entry.as_ref().unwrap().path().is_file()).count(), 0);
+
+    // an intact output passes verification, including shared values, metadata and expiry
+    let mut bitcask = BitCask::new_with_options(generate_random_data_dir(), BitCaskOptions {
+        verify_compaction: true,
+        dedup: true,
+        ..BitCaskOptions::default()
+    }).unwrap();
+    bitcask.put(&b"a".to_vec(), &vec![1; 64]).unwrap();
+    bitcask.put(&b"b".to_vec(), &vec![1; 64]).unwrap();
+    bitcask.put_with_option(&b"c".to_vec(), &vec![3; 8], PutOption::metadata(b"meta".to_vec())).unwrap();
+    bitcask.put_with_option(&b"d".to_vec(), &vec![4; 8], PutOption::ttl(Duration::from_secs(3600))).unwrap();
+    bitcask.put(&b"e".to_vec(), &vec![5; 8]).unwrap();
+    bitcask.delete(&b"e".to_vec()).unwrap();
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    assert_eq!(bitcask.get(&b"a".to_vec()), Some(vec![1; 64]));
+    assert_eq!(bitcask.get(&b"b".to_vec()), Some(vec![1; 64]));
+    let (value, metadata) = bitcask.get_with_metadata(&b"c".to_vec()).unwrap().unwrap();
+    assert_eq!((value, metadata), (vec![3; 8], Some(b"meta".to_vec())));
+    assert!(bitcask.get_with_meta(&b"d".to_vec()).unwrap().unwrap().expire_at.is_some());
+    assert_eq!(bitcask.get(&b"e".to_vec()), None);
+}
+
+#[test]
+fn salvage_corrupted_store() {
+    let data_dir = generate_random_data_dir();
+    let mut bitcask = BitCask::new(&data_dir).unwrap();
+    bitcask.put(&b"a".to_vec(), &vec![0xAA; 32]).unwrap();
+    bitcask.put(&b"b".to_vec(), &vec![0xBB; 32]).unwrap();
+    bitcask.put_with_option(&b"c".to_vec(), &vec![0xCC; 32], PutOption::metadata(b"meta".to_vec())).unwrap();
+    bitcask.put(&b"d".to_vec(), &vec![0xDD; 32]).unwrap();
+    bitcask.delete(&b"d".to_vec()).unwrap();
+    bitcask.put_with_option(&b"e".to_vec(), &vec![0xEE; 32], PutOption::ttl(Duration::from_secs(3600))).unwrap();
+    drop(bitcask);
+
+    // damage the header and value of b so that it can no longer be parsed
+    let path = format!("{}/0.bitcask", data_dir);
+    let mut data = std::fs::read(&path).unwrap();
+    let start = data.windows(32).position(|window| window == [0xBB; 32]).unwrap();
+    data[start - 6..start + 8].fill(0xFF);
+    std::fs::write(&path, data).unwrap();
+
+    let out_dir = generate_random_data_dir();
+    let report = salvage(&data_dir, &out_dir, BitCaskOptions::default()).unwrap();
+    assert_eq!((report.files, report.keys, report.unreadable_values), (1, 3, 0));
+    assert_eq!(report.skipped_regions.len(), 1);
+    assert!(report.skipped_bytes() >= 32);
+    assert!(std::fs::read_to_string(format!("{}/{}", out_dir, SALVAGE_REPORT)).unwrap().contains("keys written: 3"));
+
+    let salvaged = BitCask::new(&out_dir).unwrap();
+    assert_eq!(salvaged.get(&b"a".to_vec()), Some(vec![0xAA; 32]));
+    assert_eq!(salvaged.get(&b"b".to_vec()), None);
+    let (value, metadata) = salvaged.get_with_metadata(&b"c".to_vec()).unwrap().unwrap();
+    assert_eq!((value, metadata), (vec![0xCC; 32], Some(b"meta".to_vec())));
+    assert_eq!(salvaged.get(&b"d".to_vec()), None);
+    assert!(salvaged.get_with_meta(&b"e".to_vec()).unwrap().unwrap().expire_at.is_some());
+
+    // the output directory must be empty so a salvage never mixes into an existing store
+    assert!(salvage(&data_dir, &out_dir, BitCaskOptions::default()).is_err());
+}
+
+#[test]
+fn sync_directory() {
+    // rotations, compaction installs and in-place compaction behave the same with and without directory fsyncs
+    for sync_directory in [true, false] {
+        let options = BitCaskOptions {
+            sync_directory,
+            ..BitCaskOptions::default()
+        };
+        let data_dir = generate_random_data_dir();
+        let mut bitcask = BitCask::new_with_options(&data_dir, options.clone()).unwrap();
+        bitcask.put(&b"a".to_vec(), &b"1".to_vec()).unwrap();
+        assert_eq!(bitcask.rotate_active_file().unwrap(), Some(0));
+        bitcask.put(&b"a".to_vec(), &b"2".to_vec()).unwrap();
+        bitcask.put(&b"b".to_vec(), &b"3".to_vec()).unwrap();
+        let new_dir = generate_random_data_dir();
+        bitcask.compact_to_new_dir(&new_dir).unwrap();
+        assert_eq!(bitcask.get(&b"a".to_vec()), Some(b"2".to_vec()));
+        drop(bitcask);
+
+        BitCask::compact_offline(&new_dir, options.clone()).unwrap();
+        let bitcask = BitCask::new_with_options(&new_dir, options).unwrap();
+        assert_eq!(bitcask.get(&b"a".to_vec()), Some(b"2".to_vec()));
+        assert_eq!(bitcask.get(&b"b".to_vec()), Some(b"3".to_vec()));
+    }
+}
+
+#[test]
+fn size_tiered_compaction() {
+    let options = BitCaskOptions {
+        compaction_strategy: Arc::new(SizeTiered::default()),
+        verify_compaction: true,
+        ..BitCaskOptions::default()
+    };
+    let mut bitcask = BitCask::new_with_options(generate_random_data_dir(), options.clone()).unwrap();
+    // file 0 is large and fully live, files 1 and 2 are small and mostly overwritten
+    for i in 0..100u32 {
+        bitcask.put(&i.to_be_bytes().to_vec(), &vec![0; 100]).unwrap();
+    }
+    bitcask.put(&b"gone".to_vec(), &b"old".to_vec()).unwrap();
+    bitcask.rotate_active_file().unwrap();
+    for i in 0..20u8 {
+        bitcask.put(&b"x".to_vec(), &vec![i; 10]).unwrap();
+    }
+    bitcask.delete(&b"gone".to_vec()).unwrap();
+    bitcask.rotate_active_file().unwrap();
+    for i in 20..40u8 {
+        bitcask.put(&b"x".to_vec(), &vec![i; 10]).unwrap();
+    }
+    let large = bitcask.stats().files[0].bytes;
+
+    let new_dir = generate_random_data_dir();
+    bitcask.compact_to_new_dir(&new_dir).unwrap();
+    // only files 1 and 2 were merged, into file 1; file 0 was copied as is and 3 is the new active file
+    let files = bitcask.stats().files;
+    assert_eq!(files.iter().map(|file| file.file_id).collect::<Vec<_>>(), vec![0, 1, 3]);
+    assert_eq!(files[0].bytes, large);
+    // the tombstone in the merged files still hides the value in the older, unmerged file
+    for bitcask in [bitcask, BitCask::new_with_options(&new_dir, options.clone()).unwrap()] {
+        assert_eq!(bitcask.get(&b"gone".to_vec()), None);
+        assert_eq!(bitcask.get(&b"x".to_vec()), Some(vec![39; 10]));
+        assert_eq!(bitcask.get(&7u32.to_be_bytes().to_vec()), Some(vec![0; 100]));
+    }
+
+    // nothing qualifies: the store moves to the new directory without merging
+    let bitcask = BitCask::new_with_options(&new_dir, options).unwrap();
+    bitcask.compact_to_new_dir(generate_random_data_dir()).unwrap();
+    assert_eq!(bitcask.stats().files.iter().map(|file| file.file_id).collect::<Vec<_>>(), vec![0, 1, 3, 4]);
+    assert_eq!(bitcask.get(&b"x".to_vec()), Some(vec![39; 10]));
+}
+
+#[test]
+fn write_receipt() {
+    let mut bitcask = generate_random_bitcask_instance();
+    let first = bitcask.put_with_receipt(&b"a".to_vec(), &b"1".to_vec(), PutOption::none()).unwrap();
+    let second = bitcask.put_with_receipt(&b"b".to_vec(), &b"2".to_vec(), PutOption::request_id(7)).unwrap();
+    assert_eq!((first.file_id, first.offset), (0, FILE_HEADER_SIZE as u64));
+    assert_eq!(second.file_id, 0);
+    assert!(second.offset > first.offset);
+    // each receipt carries the sequence assigned to its own write
+    assert_eq!(second.sequence, first.sequence + 1);
+    assert_eq!(second.timestamp, bitcask.get_with_meta(&b"b".to_vec()).unwrap().unwrap().timestamp);
+
+    // a retried request writes nothing and reports the record it already wrote
+    let retried = bitcask.put_with_receipt(&b"b".to_vec(), &b"2".to_vec(), PutOption::request_id(7)).unwrap();
+    assert_eq!((retried.file_id, retried.offset, retried.timestamp), (0, second.offset, second.timestamp));
+
+    bitcask.rotate_active_file().unwrap();
+    let third = bitcask.put_with_receipt(&b"a".to_vec(), &b"3".to_vec(), PutOption::none()).unwrap();
+    assert_eq!((third.file_id, third.offset), (1, FILE_HEADER_SIZE as u64));
+    assert!(third.sequence > second.sequence && third.timestamp > second.timestamp);
+    assert!(matches!(
+        bitcask.put_with_receipt(&b"a".to_vec(), &b"4".to_vec(), PutOption::nx()),
+        Err(BitCaskError::KeyExists)
+    ));
+}
+
+#[test]
+fn memcached_protocol() {
+    let bitcask = generate_random_bitcask_instance();
+    let requests = concat!(
+        "set a 5 0 3\r\nabc\r\n",
+        "add a 0 0 1\r\nx\r\n",
+        "replace b 0 0 1\r\nx\r\n",
+        "set n 0 3600 2\r\n10\r\n",
+        "incr n 5\r\n",
+        "decr n 100\r\n",
+        "incr a 1\r\n",
+        "set gone 0 -1 1\r\nx\r\n",
+        "get a n gone missing\r\n",
+        "delete a noreply\r\n",
+        "delete a\r\n",
+        "bogus\r\n",
+        "quit\r\n",
+        "get n\r\n",
+    );
+    let mut responses = Vec::new();
+    memcached::handle_connection(requests.as_bytes(), &mut responses, bitcask.clone()).unwrap();
+    assert_eq!(String::from_utf8(responses).unwrap(), concat!(
+        "STORED\r\n",
+        "NOT_STORED\r\n",
+        "NOT_STORED\r\n",
+        "STORED\r\n",
+        "15\r\n",
+        "0\r\n",
+        "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n",
+        "STORED\r\n",
+        "VALUE a 5 3\r\nabc\r\nVALUE n 0 1\r\n0\r\nEND\r\n",
+        "NOT_FOUND\r\n",
+        "ERROR\r\n",
+    ));
+    // incr keeps the expiration of the item
+    assert!(bitcask.get_with_meta(&b"n".to_vec()).unwrap().unwrap().expire_at.is_some());
+}
+
+#[test]
+fn resp_scan_cursors() {
+    fn command(args: &[&str]) -> String {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        request
+    }
+    let session = |bitcask: &BitCask, requests: &[&[&str]]| {
+        let requests: String = requests.iter().map(|args| command(args)).collect();
+        let mut responses = Vec::new();
+        resp::handle_connection(requests.as_bytes(), &mut responses, bitcask.clone()).unwrap();
+        String::from_utf8(responses).unwrap()
+    };
+
+    // the cursor encodes the last key of the page ("b" is "1" followed by "098"), so it can be resumed
+    // on any connection, and MATCH filters the page after it is taken
+    let bitcask = generate_random_bitcask_instance();
+    assert_eq!(
+        session(&bitcask, &[&["SET", "a", "1"], &["SET", "b", "2"], &["set", "c", "3"], &["SCAN", "0", "COUNT", "2"]]),
+        "+OK\r\n+OK\r\n+OK\r\n*2\r\n$4\r\n1098\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n"
+    );
+    assert_eq!(
+        session(&bitcask, &[&["DEL", "b", "d"], &["SET", "bb", "4"], &["SCAN", "1098", "COUNT", "2"]]),
+        ":1\r\n+OK\r\n*2\r\n$1\r\n0\r\n*2\r\n$2\r\nbb\r\n$1\r\nc\r\n"
+    );
+    assert_eq!(
+        session(&bitcask, &[&["SCAN", "0", "MATCH", "[ac]"], &["SCAN", "0", "MATCH", "b*", "COUNT", "1"]]),
+        "*2\r\n$1\r\n0\r\n*2\r\n$1\r\na\r\n$1\r\nc\r\n*2\r\n$4\r\n1097\r\n*0\r\n"
+    );
+    assert_eq!(
+        session(&bitcask, &[&["SCAN", "42"], &["SCAN", "0", "COUNT", "0"], &["GET"], &["FOO"]]),
+        concat!(
+            "-ERR invalid cursor\r\n",
+            "-ERR syntax error\r\n",
+            "-ERR wrong number of arguments for 'get' command\r\n",
+            "-ERR unknown command 'FOO'\r\n",
+        )
+    );
+
+    // hashes are trees, and HSCAN pages through the fields of one of them
+    let bitcask = generate_random_bitcask_instance();
+    assert_eq!(
+        session(&bitcask, &[
+            &["HSET", "h", "f1", "v1", "f2", "v2"],
+            &["HSET", "h", "f1", "x"],
+            &["HSET", "other", "f0", "v0"],
+            &["HSCAN", "h", "0", "COUNT", "1"],
+            &["HSCAN", "h", "1102049"],
+            &["HDEL", "h", "f2", "f3"],
+            &["HGET", "h", "f2"],
+            &["HGET", "h", "f1"],
+        ]),
+        concat!(
+            ":2\r\n:0\r\n:1\r\n",
+            "*2\r\n$7\r\n1102049\r\n*2\r\n$2\r\nf1\r\n$1\r\nx\r\n",
+            "*2\r\n$1\r\n0\r\n*2\r\n$2\r\nf2\r\n$2\r\nv2\r\n",
+            ":1\r\n$-1\r\n$1\r\nx\r\n",
+        )
+    );
+    assert_eq!(bitcask.open_tree("h").unwrap().scan_keys(None, 10), (vec![b"f1".to_vec()], None));
+
+    // inline commands are accepted, and a protocol error closes the connection
+    let mut responses = Vec::new();
+    resp::handle_connection(&b"PING\r\n*1\r\n$x\r\nPING\r\n"[..], &mut responses, bitcask.clone()).unwrap();
+    assert_eq!(String::from_utf8(responses).unwrap(), "+PONG\r\n-ERR Protocol error: invalid bulk length\r\n");
+}
+
+#[test]
+fn grpc_streaming_scan() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut bitcask = generate_random_bitcask_instance();
+    for i in 0..10u8 {
+        bitcask.put(&vec![b'a', b'0' + i], &vec![i]).unwrap();
+        bitcask.put(&vec![b'b', b'0' + i], &vec![i]).unwrap();
+    }
+    let listener = runtime.block_on(tokio::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+    let addr = listener.local_addr().unwrap();
+    runtime.spawn(
+        tonic::transport::Server::builder()
+            .add_service(BitCaskGrpc::new(bitcask.clone()))
+            .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener)),
+    );
+
+    runtime.block_on(async {
+        let mut client = ScanClient::connect(format!("http://{}", addr)).await.unwrap();
+        let scan_request = |prefix: &[u8], start: &[u8], end: &[u8], batch_size| ScanRequest {
+            prefix: prefix.to_vec(),
+            start: start.to_vec(),
+            end: end.to_vec(),
+            batch_size,
+        };
+
+        // pairs arrive in key order, in batches of at most batch_size
+        let mut stream = client.scan(scan_request(b"a", b"", b"", 4)).await.unwrap();
+        let mut batches = Vec::new();
+        while let Some(response) = stream.message().await.unwrap() {
+            batches.push(response.pairs.iter().map(|pair| pair.key.clone()).collect::<Vec<_>>());
+        }
+        assert_eq!(batches.iter().map(Vec::len).collect::<Vec<_>>(), vec![4, 4, 2]);
+        assert_eq!(batches.concat(), (0..10u8).map(|i| vec![b'a', b'0' + i]).collect::<Vec<_>>());
+
+        // the prefix and [start, end) are intersected, and an empty range ends the stream at once
+        let mut stream = client.scan(scan_request(b"b", b"a5", b"b3", 0)).await.unwrap();
+        let response = stream.message().await.unwrap().unwrap();
+        assert_eq!(
+            response.pairs.iter().map(|pair| pair.key.clone()).collect::<Vec<_>>(),
+            vec![b"b0".to_vec(), b"b1".to_vec(), b"b2".to_vec()]
+        );
+        assert!(stream.message().await.unwrap().is_none());
+        let mut stream = client.scan(scan_request(b"b", b"c", b"", 0)).await.unwrap();
+        assert!(stream.message().await.unwrap().is_none());
+
+        // the whole stream reads the snapshot taken when the call was received
+        let mut stream = client.scan(scan_request(b"", b"", b"", 1)).await.unwrap();
+        let first = stream.message().await.unwrap().unwrap();
+        assert_eq!(first.pairs[0].key, b"a0".to_vec());
+        bitcask.delete(&b"a5".to_vec()).unwrap();
+        bitcask.put(&b"a55".to_vec(), &vec![0]).unwrap();
+        bitcask.put(&b"b9".to_vec(), &vec![42]).unwrap();
+        let mut pairs = first.pairs;
+        while let Some(response) = stream.message().await.unwrap() {
+            pairs.extend(response.pairs);
+        }
+        assert_eq!(pairs.len(), 20);
+        assert!(pairs.iter().any(|pair| pair.key == b"a5"));
+        assert!(pairs.iter().all(|pair| pair.key != b"a55"));
+        assert_eq!(pairs.last().unwrap().value, vec![9]);
+
+        let status = client.scan(scan_request(b"", b"", b"", 10_001)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    });
+}
+
+#[test]
+fn memcached_item_size_limit() {
+    let bitcask = generate_random_bitcask_instance();
+    // an oversized data block is rejected without allocating it, and the connection stays usable
+    let mut requests = b"set big 0 0 1048577\r\n".to_vec();
+    requests.extend(std::iter::repeat_n(b'x', 1048577));
+    requests.extend_from_slice(b"\r\nget big\r\n");
+    let mut responses = Vec::new();
+    memcached::handle_connection(&requests[..], &mut responses, bitcask.clone()).unwrap();
+    assert_eq!(
+        String::from_utf8(responses).unwrap(),
+        "SERVER_ERROR object too large for cache\r\nEND\r\n"
+    );
+    // a length that would overflow closes the connection once the client stops sending
+    let mut responses = Vec::new();
+    let requests = "set big 0 0 18446744073709551615\r\nabc\r\n";
+    assert!(memcached::handle_connection(requests.as_bytes(), &mut responses, bitcask.clone()).is_err());
+    assert!(bitcask.get(&b"big".to_vec()).is_none());
+}
+
+#[test]
+fn memcached_access_control() {
+    let bitcask = generate_random_bitcask_instance();
+    let mut access = AccessControl::default();
+    access.add_token("reader-token", Grant::read_only());
+    access.add_user("tenant", "secret", Grant::read_write().with_prefix(b"tenant:"));
+    let session = |requests: &str| {
+        let mut responses = Vec::new();
+        memcached::handle_authenticated_connection(requests.as_bytes(), &mut responses, bitcask.clone(), &access)
+            .unwrap();
+        String::from_utf8(responses).unwrap()
+    };
+    // a failed attempt closes the connection, so the retry below is never read
+    assert_eq!(
+        session("get a\r\nset auth 0 0 11\r\ntenant nope\r\nset auth 0 0 13\r\ntenant secret\r\n"),
+        "CLIENT_ERROR unauthenticated\r\nCLIENT_ERROR authentication failure\r\n"
+    );
+    assert_eq!(session("set auth 0 0 11\r\nwrong-token\r\nversion\r\n"), "CLIENT_ERROR authentication failure\r\n");
+    assert_eq!(
+        session("set auth 0 0 13\r\ntenant secret\r\nset tenant:a 0 0 1\r\nx\r\nset other 0 0 1\r\nx\r\n"),
+        "STORED\r\nSTORED\r\nCLIENT_ERROR access denied\r\n"
+    );
+    assert_eq!(
+        session("set auth 0 0 12\r\nreader-token\r\nget tenant:a\r\ndelete tenant:a\r\n"),
+        "STORED\r\nVALUE tenant:a 0 1\r\nx\r\nEND\r\nCLIENT_ERROR access denied\r\n"
+    );
+    // an oversized credential is refused before it is read and the connection is closed
+    assert_eq!(
+        session("set auth 0 0 100000000000\r\nreader-token\r\nget tenant:a\r\n"),
+        "CLIENT_ERROR authentication failure\r\n"
+    );
+}
+
+#[test]
+fn memcached_mutual_tls() {
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use std::io::{BufRead, BufReader, Write};
+
+    let fixture = |name: &str| format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name);
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let options = memcached::ServerOptions {
+        tls: Some(TlsOptions {
+            client_ca_file: Some(fixture("ca.pem").into()),
+            ..TlsOptions::new(fixture("server.pem"), fixture("server.key"))
+        }),
+        ..memcached::ServerOptions::default()
+    };
+    let bitcask = generate_random_bitcask_instance();
+    std::thread::spawn(move || memcached::serve_with_options(listener, bitcask, options));
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_file(fixture("ca.pem")).unwrap()).unwrap();
+    let connect = |config: rustls::ClientConfig| {
+        let conn = rustls::ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut stream = rustls::StreamOwned::new(conn, std::net::TcpStream::connect(addr).unwrap());
+        stream.write_all(b"set a 0 0 1\r\nx\r\nget a\r\n")?;
+        let mut response = String::new();
+        let mut reader = BufReader::new(stream);
+        while !response.ends_with("END\r\n") {
+            if reader.read_line(&mut response)? == 0 {
+                break;
+            }
+        }
+        Ok::<_, std::io::Error>(response)
+    };
+
+    let with_cert = rustls::ClientConfig::builder()
+        .with_root_certificates(roots.clone())
+        .with_client_auth_cert(
+            vec![CertificateDer::from_pem_file(fixture("client.pem")).unwrap()],
+            PrivateKeyDer::from_pem_file(fixture("client.key")).unwrap(),
+        )
+        .unwrap();
+    assert_eq!(connect(with_cert).unwrap(), "STORED\r\nVALUE a 0 1\r\nx\r\nEND\r\n");
+    // Without a client certificate the handshake is rejected.
+    let without_cert = rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+    assert!(connect(without_cert).map_or(true, |response| response.is_empty()));
+}
+
+#[cfg(unix)]
+#[test]
+fn memcached_unix_socket() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::create_dir_all("./data").unwrap();
+    let path = format!("{}.sock", generate_random_data_dir());
+    let listener = memcached::bind_unix(&path, 0o600).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    // A socket that is still being served is not replaced.
+    assert_eq!(memcached::bind_unix(&path, 0o600).unwrap_err().kind(), std::io::ErrorKind::AddrInUse);
+
+    let bitcask = generate_random_bitcask_instance();
+    std::thread::spawn(move || memcached::serve_unix(listener, bitcask, memcached::ServerOptions::default()));
+    let mut stream = std::os::unix::net::UnixStream::connect(&path).unwrap();
+    stream.write_all(b"set a 0 0 1\r\nx\r\nget a\r\n").unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut response = String::new();
+    while !response.ends_with("END\r\n") {
+        reader.read_line(&mut response).unwrap();
+    }
+    assert_eq!(response, "STORED\r\nVALUE a 0 1\r\nx\r\nEND\r\n");
+}
+
+#[test]
+fn server_shutdown() {
+    use bitcask_engine_rs::server::BitCaskServer;
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let bitcask = generate_random_bitcask_instance();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = BitCaskServer::start(listener, bitcask.clone(), memcached::ServerOptions::default()).unwrap();
+    let addr = server.local_addr();
+    let mut idle = std::net::TcpStream::connect(addr).unwrap();
+    let mut client = BufReader::new(std::net::TcpStream::connect(addr).unwrap());
+    client.get_mut().write_all(b"set a 0 0 1\r\nx\r\n").unwrap();
+    let mut response = String::new();
+    client.read_line(&mut response).unwrap();
+    assert_eq!(response, "STORED\r\n");
+
+    server.shutdown(Duration::from_secs(5)).unwrap();
+    // Open connections are closed and no new connections are accepted.
+    assert_eq!(idle.read(&mut [0; 16]).unwrap(), 0);
+    assert!(std::net::TcpStream::connect(addr).is_err());
+    assert_eq!(bitcask.get(&b"a".to_vec()), Some(b"\0\0\0\0x".to_vec()));
+}
+
+#[test]
+fn tower_service() {
+    let mut service = generate_random_bitcask_instance();
+    let mut call = |request: Request| -> Result<Response, BitCaskError> {
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(service.poll_ready(&mut cx).is_ready());
+        service.call(request).into_inner()
+    };
+    let put = Request::Put { key: vec![1], value: vec![2], ttl: None };
+    assert_eq!(call(put).unwrap(), Response::Done);
+    assert_eq!(call(Request::Get { key: vec![1] }).unwrap(), Response::Value(Some(vec![2])));
+    assert_eq!(call(Request::Delete { key: vec![1] }).unwrap(), Response::Done);
+    assert_eq!(call(Request::Get { key: vec![1] }).unwrap(), Response::Value(None));
+}
+
+#[test]
+fn ffi_round_trip() {
+    use bitcask_engine_rs::ffi::*;
+    use std::ffi::CString;
+
+    let path = CString::new(generate_random_data_dir()).unwrap();
+    unsafe {
+        let db = bitcask_open(path.as_ptr());
+        assert!(!db.is_null());
+        let (key, value) = (vec![1u8, 2, 3], vec![4u8, 5, 6]);
+        assert_eq!(bitcask_put(db, key.as_ptr(), key.len(), value.as_ptr(), value.len()), BITCASK_OK);
+        let mut out = std::ptr::null_mut();
+        let mut out_len = 0;
+        assert_eq!(bitcask_get(db, key.as_ptr(), key.len(), &mut out, &mut out_len), BITCASK_OK);
+        assert_eq!(std::slice::from_raw_parts(out, out_len), &value[..]);
+        bitcask_free_value(out, out_len);
+        assert_eq!(bitcask_delete(db, key.as_ptr(), key.len()), BITCASK_OK);
+        assert_eq!(bitcask_get(db, key.as_ptr(), key.len(), &mut out, &mut out_len), BITCASK_NOT_FOUND);
+        assert_eq!(bitcask_put(db, key.as_ptr(), key.len(), value.as_ptr(), value.len()), BITCASK_OK);
+        assert_eq!(bitcask_close(db), BITCASK_OK);
+        assert_eq!(bitcask_close(std::ptr::null_mut()), BITCASK_OK);
+    }
+
+    // a record that fails its checksum is an error, not a missing key
+    let data_dir = path.to_str().unwrap();
+    let file = format!("{}/0.bitcask", data_dir);
+    let mut bytes = std::fs::read(&file).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&file, bytes).unwrap();
+    unsafe {
+        let db = bitcask_open(path.as_ptr());
+        assert!(!db.is_null());
+        let (key, mut out, mut out_len) = (vec![1u8, 2, 3], std::ptr::null_mut(), 0);
+        assert_eq!(bitcask_get(db, key.as_ptr(), key.len(), &mut out, &mut out_len), BITCASK_ERROR);
+        assert!(out.is_null());
+        assert_eq!(bitcask_close(db), BITCASK_OK);
+    }
+}
+
 fn generate_random_bitcask_instance() -> BitCask {
     let file_name = generate_random_name();
     let data_dir = format!("./data/{}", file_name);
     BitCask::new(data_dir).unwrap()
 }
 
+/// Size of the header at the start of each data file written by this version: magic, marker, format
+/// version and an empty extension section.
+const FILE_HEADER_SIZE: usize = 8;
+
+fn generate_random_data_dir() -> String {
+    format!("./data/{}", generate_random_name())
+}
+
 fn generate_random_name() -> String {
-    let mut rng = rand::thread_rng();
+    let rng = rand::thread_rng();
     let rand_string: String = rng
         .sample_iter(rand::distributions::Alphanumeric)
         .take(10)